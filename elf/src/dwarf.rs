@@ -0,0 +1,1194 @@
+//! Source locations from the DWARF debug information
+//!
+//! The line number programs in `.debug_line` map addresses to files and
+//! lines, and the functions and inlined functions in `.debug_info` name the
+//! frames at an address. DWARF versions 2 to 5 are supported, debug
+//! information that can't be understood is skipped a unit at a time.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use crate::Section;
+
+/// Unit types, `DW_UT_`
+const DW_UT_COMPILE: u8 = 0x01;
+const DW_UT_PARTIAL: u8 = 0x03;
+
+/// Tags, `DW_TAG_`
+const DW_TAG_INLINED_SUBROUTINE: u64 = 0x1d;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_PARTIAL_UNIT: u64 = 0x3c;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+
+/// Attributes, `DW_AT_`
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_ABSTRACT_ORIGIN: u64 = 0x31;
+const DW_AT_SPECIFICATION: u64 = 0x47;
+const DW_AT_RANGES: u64 = 0x55;
+const DW_AT_CALL_COLUMN: u64 = 0x57;
+const DW_AT_CALL_FILE: u64 = 0x58;
+const DW_AT_CALL_LINE: u64 = 0x59;
+const DW_AT_LINKAGE_NAME: u64 = 0x6e;
+const DW_AT_STR_OFFSETS_BASE: u64 = 0x72;
+const DW_AT_ADDR_BASE: u64 = 0x73;
+const DW_AT_RNGLISTS_BASE: u64 = 0x74;
+const DW_AT_MIPS_LINKAGE_NAME: u64 = 0x2007;
+
+/// Line number program content types, `DW_LNCT_`
+const DW_LNCT_PATH: u64 = 0x1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 0x2;
+
+/// A position in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    /// Path of the source file, relative to the compilation directory unless
+    /// it is elsewhere
+    pub file: &'a str,
+
+    /// Line, starting at 1, or 0 for code that isn't from any line
+    pub line: u32,
+
+    /// Column, starting at 1, or 0 if unknown
+    pub column: u32,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A function the code at an address is in, with each inlined function as a
+/// frame of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// Name of the function
+    pub function: Option<&'a str>,
+
+    /// Where in the function the code is, for inlined functions the location
+    /// in the outer frame is where it was inlined
+    pub location: Option<Location<'a>>,
+}
+
+/// A row of the line table, the location of the code from `addr` up to the
+/// next row
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    addr: u32,
+
+    /// Index into the file names
+    file: usize,
+
+    line: u32,
+    column: u32,
+
+    /// Is this the end of a sequence, the first address after its code
+    end: bool,
+}
+
+/// The code of a function or inlined function
+#[derive(Debug)]
+struct Scope {
+    ranges: Vec<Range<u32>>,
+
+    /// How deeply nested the debug information entry is, inlined functions
+    /// are nested in the functions they were inlined into
+    depth: usize,
+
+    name: Option<String>,
+
+    /// For inlined functions the file, line and column of the call
+    call: Option<(usize, u32, u32)>,
+}
+
+/// The line table and functions from the DWARF sections of an ELF
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    /// Paths of the source files
+    files: Vec<String>,
+
+    /// Line table rows of all sequences, by address
+    rows: Vec<Row>,
+
+    /// Functions and inlined functions with code
+    scopes: Vec<Scope>,
+}
+
+impl DebugInfo {
+    /// Read the debug information in `sections`, empty if there is none
+    pub fn parse(sections: &[Section]) -> DebugInfo {
+        let section = |name: &str| {
+            sections.iter().find(|section| section.name == name).map_or(&[][..], |section| &section.data)
+        };
+
+        let mut parser = Parser {
+            info: section(".debug_info"),
+            abbrev: section(".debug_abbrev"),
+            line: section(".debug_line"),
+            line_str: section(".debug_line_str"),
+            str: section(".debug_str"),
+            str_offsets: section(".debug_str_offsets"),
+            addr: section(".debug_addr"),
+            ranges: section(".debug_ranges"),
+            rnglists: section(".debug_rnglists"),
+            debug: DebugInfo::default(),
+            file_ids: HashMap::new(),
+            unit_files: HashMap::new(),
+        };
+
+        parser.parse_lines();
+        parser.parse_units();
+        parser.debug
+    }
+
+    /// The source location of the code at `addr`
+    pub fn addr_to_line(&self, addr: u32) -> Option<Location<'_>> {
+        let index = self.rows.partition_point(|row| row.addr <= addr).checked_sub(1)?;
+        let row = &self.rows[index];
+        if row.end {
+            return None;
+        }
+
+        Some(Location { file: self.file(row.file), line: row.line, column: row.column })
+    }
+
+    /// The functions the code at `addr` is in, innermost first
+    ///
+    /// There is a frame for each function inlined at `addr` followed by one
+    /// for the function they were inlined into. Without function information
+    /// there is a single frame with only the location.
+    pub fn addr_to_frames(&self, addr: u32) -> Vec<Frame<'_>> {
+        let mut scopes: Vec<&Scope> = self.scopes.iter()
+            .filter(|scope| scope.ranges.iter().any(|range| range.contains(&addr)))
+            .collect();
+        scopes.sort_by_key(|scope| std::cmp::Reverse(scope.depth));
+
+        let mut location = self.addr_to_line(addr);
+        let mut frames = vec![];
+        for scope in scopes {
+            frames.push(Frame { function: scope.name.as_deref(), location });
+
+            // the function that isn't inlined is the outermost frame
+            let Some((file, line, column)) = scope.call else {
+                break;
+            };
+            location = Some(Location { file: self.file(file), line, column });
+        }
+
+        if frames.is_empty() && location.is_some() {
+            frames.push(Frame { function: None, location });
+        }
+
+        frames
+    }
+
+    /// Path of the file with index `file`
+    fn file(&self, file: usize) -> &str {
+        self.files.get(file).map_or("??", String::as_str)
+    }
+}
+
+/// A cursor in a section
+#[derive(Debug, Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+
+    /// Offset of the next byte to read from the start of the section
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    /// Read a little endian value of `size` bytes, up to 8
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        if size > 8 {
+            return None;
+        }
+
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(self.bytes(size)?);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    /// Read a NUL terminated string, without the NUL
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.iter().position(|&byte| byte == 0)?;
+        self.pos += len + 1;
+        Some(&rest[..len])
+    }
+
+    /// Read the initial length of a unit, returns a reader for the rest of
+    /// the unit and the size of section offsets in it, 4 or 8 bytes
+    fn unit(&mut self) -> Option<(Reader<'a>, usize)> {
+        let (len, offset_size) = match self.uint(4)? {
+            0xffff_ffff => (self.uint(8)?, 8),
+            len => (len, 4),
+        };
+
+        let end = self.pos.checked_add(usize::try_from(len).ok()?)?;
+        let unit = Reader::new(self.data.get(..end)?, self.pos);
+        self.pos = end;
+        Some((unit, offset_size))
+    }
+}
+
+/// The header fields of a unit needed to read its attribute values
+#[derive(Debug, Clone, Copy)]
+struct UnitHeader {
+    /// Offset of the unit in its section
+    offset: u64,
+
+    version: u16,
+    offset_size: usize,
+    address_size: usize,
+}
+
+/// An attribute value, by the class of its form
+#[derive(Debug, Clone, Copy)]
+enum Value<'a> {
+    Addr(u64),
+
+    /// Index into `.debug_addr`
+    AddrIndex(u64),
+
+    Unsigned(u64),
+    Signed(i64),
+    String(&'a [u8]),
+
+    /// Offset into `.debug_str`
+    StrOffset(u64),
+
+    /// Offset into `.debug_line_str`
+    LineStrOffset(u64),
+
+    /// Index into `.debug_str_offsets`
+    StrIndex(u64),
+
+    /// Reference to an entry, relative to the start of the unit
+    UnitRef(u64),
+
+    /// Reference to an entry, relative to the start of `.debug_info`
+    InfoRef(u64),
+
+    /// Index into the offsets at the start of `.debug_rnglists`
+    RngListIndex(u64),
+
+    /// Blocks, expressions and other values that are skipped over
+    Other,
+}
+
+impl Value<'_> {
+    fn unsigned(self) -> Option<u64> {
+        match self {
+            Value::Unsigned(value) => Some(value),
+            Value::Signed(value) => u64::try_from(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Read a value of form `form`, `implicit` is the value of an
+/// `DW_FORM_implicit_const` from the abbreviation
+fn read_value<'a>(reader: &mut Reader<'a>, form: u64, header: &UnitHeader, implicit: i64)
+    -> Option<Value<'a>>
+{
+    let value = match form {
+        // DW_FORM_addr
+        0x01 => Value::Addr(reader.uint(header.address_size)?),
+        // DW_FORM_block2, DW_FORM_block4, DW_FORM_block, DW_FORM_block1,
+        // DW_FORM_exprloc
+        0x03 => { let len = reader.uint(2)?; reader.bytes(len as usize)?; Value::Other },
+        0x04 => { let len = reader.uint(4)?; reader.bytes(len as usize)?; Value::Other },
+        0x09 | 0x18 => { let len = reader.uleb()?; reader.bytes(usize::try_from(len).ok()?)?; Value::Other },
+        0x0a => { let len = reader.u8()?; reader.bytes(len as usize)?; Value::Other },
+        // DW_FORM_data2, DW_FORM_data4, DW_FORM_data8, DW_FORM_data1
+        0x05 => Value::Unsigned(reader.uint(2)?),
+        0x06 => Value::Unsigned(reader.uint(4)?),
+        0x07 => Value::Unsigned(reader.uint(8)?),
+        0x0b => Value::Unsigned(reader.uint(1)?),
+        // DW_FORM_data16
+        0x1e => { reader.bytes(16)?; Value::Other },
+        // DW_FORM_string
+        0x08 => Value::String(reader.cstr()?),
+        // DW_FORM_flag, DW_FORM_flag_present
+        0x0c => Value::Unsigned(reader.uint(1)?),
+        0x19 => Value::Unsigned(1),
+        // DW_FORM_sdata, DW_FORM_udata
+        0x0d => Value::Signed(reader.sleb()?),
+        0x0f => Value::Unsigned(reader.uleb()?),
+        // DW_FORM_strp, DW_FORM_line_strp
+        0x0e => Value::StrOffset(reader.uint(header.offset_size)?),
+        0x1f => Value::LineStrOffset(reader.uint(header.offset_size)?),
+        // DW_FORM_ref_addr, the size of an address before DWARF 3
+        0x10 if header.version == 2 => Value::InfoRef(reader.uint(header.address_size)?),
+        0x10 => Value::InfoRef(reader.uint(header.offset_size)?),
+        // DW_FORM_ref1, DW_FORM_ref2, DW_FORM_ref4, DW_FORM_ref8,
+        // DW_FORM_ref_udata
+        0x11 => Value::UnitRef(reader.uint(1)?),
+        0x12 => Value::UnitRef(reader.uint(2)?),
+        0x13 => Value::UnitRef(reader.uint(4)?),
+        0x14 => Value::UnitRef(reader.uint(8)?),
+        0x15 => Value::UnitRef(reader.uleb()?),
+        // DW_FORM_indirect
+        0x16 => {
+            let form = reader.uleb()?;
+            return read_value(reader, form, header, implicit);
+        },
+        // DW_FORM_sec_offset
+        0x17 => Value::Unsigned(reader.uint(header.offset_size)?),
+        // DW_FORM_strx, DW_FORM_strx1-4, DW_FORM_GNU_str_index
+        0x1a | 0x1f02 => Value::StrIndex(reader.uleb()?),
+        0x25 => Value::StrIndex(reader.uint(1)?),
+        0x26 => Value::StrIndex(reader.uint(2)?),
+        0x27 => Value::StrIndex(reader.uint(3)?),
+        0x28 => Value::StrIndex(reader.uint(4)?),
+        // DW_FORM_addrx, DW_FORM_addrx1-4, DW_FORM_GNU_addr_index
+        0x1b | 0x1f01 => Value::AddrIndex(reader.uleb()?),
+        0x29 => Value::AddrIndex(reader.uint(1)?),
+        0x2a => Value::AddrIndex(reader.uint(2)?),
+        0x2b => Value::AddrIndex(reader.uint(3)?),
+        0x2c => Value::AddrIndex(reader.uint(4)?),
+        // DW_FORM_ref_sup4, DW_FORM_ref_sig8, DW_FORM_ref_sup8
+        0x1c => { reader.uint(4)?; Value::Other },
+        0x20 | 0x24 => { reader.uint(8)?; Value::Other },
+        // DW_FORM_strp_sup, DW_FORM_GNU_ref_alt, DW_FORM_GNU_strp_alt
+        0x1d | 0x1f20 | 0x1f21 => { reader.uint(header.offset_size)?; Value::Other },
+        // DW_FORM_implicit_const
+        0x21 => Value::Signed(implicit),
+        // DW_FORM_loclistx
+        0x22 => { reader.uleb()?; Value::Other },
+        // DW_FORM_rnglistx
+        0x23 => Value::RngListIndex(reader.uleb()?),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// An abbreviation, the tag and attribute forms of debug information entries
+#[derive(Debug)]
+struct Abbrev {
+    tag: u64,
+    children: bool,
+
+    /// Attribute name, form and value of `DW_FORM_implicit_const` forms
+    attrs: Vec<(u64, u64, i64)>,
+}
+
+/// A debug information entry for a unit, function or inlined function, with
+/// the attributes needed to find its code and name
+#[derive(Debug)]
+struct Die<'a> {
+    /// Offset in `.debug_info`
+    offset: u64,
+
+    tag: u64,
+    depth: usize,
+    attrs: Vec<(u64, Value<'a>)>,
+}
+
+impl<'a> Die<'a> {
+    fn attr(&self, name: u64) -> Option<Value<'a>> {
+        self.attrs.iter().find(|&&(attr, _)| attr == name).map(|&(_, value)| value)
+    }
+}
+
+/// The unit attributes needed to read the values of the entries in it
+#[derive(Debug)]
+struct UnitContext<'b> {
+    header: UnitHeader,
+    str_offsets_base: u64,
+    addr_base: u64,
+    rnglists_base: u64,
+
+    /// Address ranges are relative to, the low pc of the unit
+    base_address: u64,
+
+    /// Index into the file names of each file of the unit's line table
+    files: &'b [usize],
+}
+
+/// State for reading the DWARF sections
+struct Parser<'a> {
+    info: &'a [u8],
+    abbrev: &'a [u8],
+    line: &'a [u8],
+    line_str: &'a [u8],
+    str: &'a [u8],
+    str_offsets: &'a [u8],
+    addr: &'a [u8],
+    ranges: &'a [u8],
+    rnglists: &'a [u8],
+
+    debug: DebugInfo,
+
+    /// Index of each path in the file names
+    file_ids: HashMap<String, usize>,
+
+    /// Index into the file names of each file of each line table, by the
+    /// offset of the line table
+    unit_files: HashMap<u64, Vec<usize>>,
+}
+
+impl<'a> Parser<'a> {
+    /// Run the line number programs in `.debug_line`
+    fn parse_lines(&mut self) {
+        let mut reader = Reader::new(self.line, 0);
+        while !reader.is_empty() {
+            let offset = reader.pos as u64;
+            let Some((unit, offset_size)) = reader.unit() else {
+                break;
+            };
+
+            if let Some(files) = self.parse_line_unit(unit, offset_size) {
+                self.unit_files.insert(offset, files);
+            }
+        }
+
+        // a sequence ending where another starts sorts before its start
+        self.debug.rows.sort_by_key(|row| (row.addr, !row.end));
+    }
+
+    /// Run the line number program of a unit, returns the index into the
+    /// file names of each file of its line table
+    fn parse_line_unit(&mut self, mut unit: Reader<'a>, offset_size: usize) -> Option<Vec<usize>> {
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+
+        let mut address_size = 4;
+        if version >= 5 {
+            address_size = unit.u8()? as usize;
+            let _segment_selector_size = unit.u8()?;
+        }
+        if !valid_address_size(address_size) {
+            return None;
+        }
+        let header = UnitHeader { offset: 0, version, offset_size, address_size };
+
+        let header_length = usize::try_from(unit.uint(offset_size)?).ok()?;
+        let program = unit.pos.checked_add(header_length)?;
+
+        let min_inst_length = unit.u8()? as u64;
+        if version >= 4 {
+            let _max_ops_per_inst = unit.u8()?;
+        }
+        let _default_is_stmt = unit.u8()?;
+        let line_base = unit.u8()? as i8 as i64;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        if line_range == 0 {
+            return None;
+        }
+        let opcode_lengths = unit.bytes(opcode_base.saturating_sub(1) as usize)?;
+
+        let mut files = vec![];
+        if version < 5 {
+            // directory 0 is the compilation directory, paths in it are
+            // left relative
+            let mut dirs = vec![String::new()];
+            loop {
+                let dir = unit.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(String::from_utf8_lossy(dir).into_owned());
+            }
+
+            // files are numbered from 1
+            files.push(usize::MAX);
+            loop {
+                let name = unit.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = unit.uleb()?;
+                let _mtime = unit.uleb()?;
+                let _size = unit.uleb()?;
+
+                let dir = dirs.get(dir as usize).map_or("", String::as_str);
+                let path = join_path(dir, &String::from_utf8_lossy(name));
+                files.push(self.file_id(path));
+            }
+        } else {
+            // like before DWARF 5, leave paths in the compilation directory
+            // relative
+            let mut dirs = self.path_entries(&mut unit, &header)?;
+            if let Some((dir, _)) = dirs.first_mut() {
+                dir.clear();
+            }
+            for (path, dir) in self.path_entries(&mut unit, &header)? {
+                let dir = dirs.get(dir as usize).map_or("", |(dir, _)| dir.as_str());
+                let path = join_path(dir, &path);
+                files.push(self.file_id(path));
+            }
+        }
+
+        let mut reader = Reader::new(unit.data, program);
+        let mut sequence: Vec<Row> = vec![];
+        let mut addr = 0u64;
+        let mut file = 1u64;
+        let mut line = 1i64;
+        let mut column = 0u64;
+        while !reader.is_empty() {
+            let mut row = false;
+
+            let opcode = reader.u8()?;
+            if opcode >= opcode_base {
+                // special opcodes advance the address and line, and add a row
+                let adjusted = (opcode - opcode_base) as u64;
+                addr = addr.wrapping_add(adjusted / line_range as u64 * min_inst_length);
+                line = line.wrapping_add(line_base + (adjusted % line_range as u64) as i64);
+                row = true;
+            } else {
+                match opcode {
+                    // extended opcodes
+                    0 => {
+                        let len = usize::try_from(reader.uleb()?).ok()?;
+                        let mut ext = Reader::new(reader.bytes(len)?, 0);
+                        match ext.u8()? {
+                            // DW_LNE_end_sequence
+                            1 => {
+                                let end = addr as u32;
+                                sequence.retain(|row| row.addr < end);
+                                sequence.push(Row { addr: end, file: 0, line: 0, column: 0, end: true });
+
+                                // the sequences of code the linker dropped
+                                // are moved to address 0
+                                if sequence[0].addr != 0 {
+                                    self.debug.rows.append(&mut sequence);
+                                }
+                                sequence.clear();
+
+                                addr = 0;
+                                file = 1;
+                                line = 1;
+                                column = 0;
+                            },
+                            // DW_LNE_set_address
+                            2 => addr = ext.uint(len - 1)?,
+                            // DW_LNE_define_file
+                            3 => {
+                                let name = ext.cstr()?;
+                                let path = String::from_utf8_lossy(name).into_owned();
+                                files.push(self.file_id(path));
+                            },
+                            _ => (),
+                        }
+                    },
+                    // DW_LNS_copy
+                    1 => row = true,
+                    // DW_LNS_advance_pc
+                    2 => addr = addr.wrapping_add(reader.uleb()?.wrapping_mul(min_inst_length)),
+                    // DW_LNS_advance_line
+                    3 => line = line.wrapping_add(reader.sleb()?),
+                    // DW_LNS_set_file
+                    4 => file = reader.uleb()?,
+                    // DW_LNS_set_column
+                    5 => column = reader.uleb()?,
+                    // DW_LNS_const_add_pc
+                    8 => {
+                        let adjusted = (255 - opcode_base) as u64;
+                        addr = addr.wrapping_add(adjusted / line_range as u64 * min_inst_length);
+                    },
+                    // DW_LNS_fixed_advance_pc
+                    9 => addr = addr.wrapping_add(reader.uint(2)?),
+                    // skip the operands of the rest, which don't affect the
+                    // address or location
+                    _ => {
+                        for _ in 0..opcode_lengths[opcode as usize - 1] {
+                            reader.uleb()?;
+                        }
+                    },
+                }
+            }
+
+            if row {
+                sequence.push(Row {
+                    addr: addr as u32,
+                    file: files.get(file as usize).copied().unwrap_or(usize::MAX),
+                    line: line as u32,
+                    column: column as u32,
+                    end: false,
+                });
+            }
+        }
+
+        Some(files)
+    }
+
+    /// Read the directory or file name entries of a DWARF 5 line table
+    /// header, returns the path and directory index of each
+    fn path_entries(&self, unit: &mut Reader<'a>, header: &UnitHeader) -> Option<Vec<(String, u64)>> {
+        let format_count = unit.u8()?;
+        let mut format = vec![];
+        for _ in 0..format_count {
+            format.push((unit.uleb()?, unit.uleb()?));
+        }
+
+        let count = unit.uleb()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let mut path = String::new();
+            let mut dir = 0;
+            for &(content, form) in &format {
+                let value = read_value(unit, form, header, 0)?;
+                match content {
+                    DW_LNCT_PATH => path = self.string(value, None).unwrap_or_default(),
+                    DW_LNCT_DIRECTORY_INDEX => dir = value.unsigned().unwrap_or(0),
+                    _ => (),
+                }
+            }
+            entries.push((path, dir));
+        }
+
+        Some(entries)
+    }
+
+    /// Index of `path` in the file names, adding it if it's new
+    fn file_id(&mut self, path: String) -> usize {
+        let files = &mut self.debug.files;
+        *self.file_ids.entry(path).or_insert_with_key(|path| {
+            files.push(path.clone());
+            files.len() - 1
+        })
+    }
+
+    /// Find the functions and inlined functions in `.debug_info`
+    fn parse_units(&mut self) {
+        let mut abbrevs: HashMap<u64, HashMap<u64, Abbrev>> = HashMap::new();
+
+        // functions with code, and the name and origin of every function by
+        // offset, the name of a function can be in the entry it's an inlined
+        // or out of line instance of
+        let mut scopes = vec![];
+        let mut names: HashMap<u64, (Option<String>, Option<u64>)> = HashMap::new();
+
+        let mut reader = Reader::new(self.info, 0);
+        while !reader.is_empty() {
+            let offset = reader.pos as u64;
+            let Some((unit, offset_size)) = reader.unit() else {
+                break;
+            };
+
+            // skip units that can't be read
+            let _ = self.parse_unit(unit, offset, offset_size, &mut abbrevs, &mut scopes, &mut names);
+        }
+
+        // follow the origins to the entry with the name
+        for (offset, mut scope) in scopes {
+            let mut offset = Some(offset);
+            for _ in 0..8 {
+                let Some((name, origin)) = offset.and_then(|offset| names.get(&offset)) else {
+                    break;
+                };
+                if name.is_some() {
+                    scope.name = name.clone();
+                    break;
+                }
+                offset = *origin;
+            }
+            self.debug.scopes.push(scope);
+        }
+    }
+
+    /// Read the entries of a unit, adding the functions with code to
+    /// `scopes` and the names of all functions to `names`
+    fn parse_unit(&self, mut unit: Reader<'a>, offset: u64, offset_size: usize,
+        abbrevs: &mut HashMap<u64, HashMap<u64, Abbrev>>,
+        scopes: &mut Vec<(u64, Scope)>, names: &mut HashMap<u64, (Option<String>, Option<u64>)>)
+        -> Option<()>
+    {
+        let version = unit.u16()?;
+        let (abbrev_offset, address_size) = match version {
+            2..=4 => {
+                let abbrev_offset = unit.uint(offset_size)?;
+                (abbrev_offset, unit.u8()? as usize)
+            },
+            5 => {
+                let unit_type = unit.u8()?;
+                if unit_type != DW_UT_COMPILE && unit_type != DW_UT_PARTIAL {
+                    return None;
+                }
+                let address_size = unit.u8()? as usize;
+                (unit.uint(offset_size)?, address_size)
+            },
+            _ => return None,
+        };
+        if !valid_address_size(address_size) {
+            return None;
+        }
+        let header = UnitHeader { offset, version, offset_size, address_size };
+
+        let abbrevs = abbrevs.entry(abbrev_offset)
+            .or_insert_with(|| parse_abbrevs(self.abbrev, abbrev_offset).unwrap_or_default());
+
+        // read the entries that are units and functions
+        let mut dies = vec![];
+        let mut depth = 0usize;
+        while !unit.is_empty() {
+            let offset = unit.pos as u64;
+
+            // a null entry ends a list of children
+            let code = unit.uleb()?;
+            if code == 0 {
+                depth = depth.saturating_sub(1);
+                continue;
+            }
+
+            let abbrev = abbrevs.get(&code)?;
+            let mut die = Die { offset, tag: abbrev.tag, depth, attrs: vec![] };
+            for &(name, form, implicit) in &abbrev.attrs {
+                let value = read_value(&mut unit, form, &header, implicit)?;
+                if matches!(name, DW_AT_NAME | DW_AT_LINKAGE_NAME | DW_AT_MIPS_LINKAGE_NAME |
+                        DW_AT_STMT_LIST | DW_AT_LOW_PC | DW_AT_HIGH_PC | DW_AT_RANGES |
+                        DW_AT_ABSTRACT_ORIGIN | DW_AT_SPECIFICATION | DW_AT_CALL_FILE |
+                        DW_AT_CALL_LINE | DW_AT_CALL_COLUMN | DW_AT_STR_OFFSETS_BASE |
+                        DW_AT_ADDR_BASE | DW_AT_RNGLISTS_BASE) {
+                    die.attrs.push((name, value));
+                }
+            }
+            if abbrev.children {
+                depth += 1;
+            }
+
+            if matches!(die.tag, DW_TAG_COMPILE_UNIT | DW_TAG_PARTIAL_UNIT | DW_TAG_SUBPROGRAM |
+                    DW_TAG_INLINED_SUBROUTINE) {
+                dies.push(die);
+            }
+        }
+
+        let root = dies.first().filter(|die| die.tag == DW_TAG_COMPILE_UNIT || die.tag == DW_TAG_PARTIAL_UNIT)?;
+        let base = |name| root.attr(name).and_then(Value::unsigned).unwrap_or(0);
+        let files = root.attr(DW_AT_STMT_LIST)
+            .and_then(Value::unsigned)
+            .and_then(|offset| self.unit_files.get(&offset))
+            .map_or(&[][..], Vec::as_slice);
+        let mut ctx = UnitContext {
+            header,
+            str_offsets_base: base(DW_AT_STR_OFFSETS_BASE),
+            addr_base: base(DW_AT_ADDR_BASE),
+            rnglists_base: base(DW_AT_RNGLISTS_BASE),
+            base_address: 0,
+            files,
+        };
+        ctx.base_address = root.attr(DW_AT_LOW_PC).and_then(|value| self.address(value, &ctx)).unwrap_or(0);
+
+        for die in &dies[1..] {
+            let name = die.attr(DW_AT_NAME)
+                .or_else(|| die.attr(DW_AT_LINKAGE_NAME))
+                .or_else(|| die.attr(DW_AT_MIPS_LINKAGE_NAME))
+                .and_then(|value| self.string(value, Some(&ctx)));
+            let origin = die.attr(DW_AT_ABSTRACT_ORIGIN)
+                .or_else(|| die.attr(DW_AT_SPECIFICATION))
+                .and_then(|value| match value {
+                    Value::UnitRef(offset) => header.offset.checked_add(offset),
+                    Value::InfoRef(offset) => Some(offset),
+                    _ => None,
+                });
+            names.insert(die.offset, (name, origin));
+
+            let ranges = self.die_ranges(die, &ctx);
+            if ranges.is_empty() {
+                continue;
+            }
+
+            let call = (die.tag == DW_TAG_INLINED_SUBROUTINE).then(|| {
+                let attr = |name| die.attr(name).and_then(Value::unsigned).unwrap_or(0);
+                let file = ctx.files.get(attr(DW_AT_CALL_FILE) as usize).copied().unwrap_or(usize::MAX);
+                (file, attr(DW_AT_CALL_LINE) as u32, attr(DW_AT_CALL_COLUMN) as u32)
+            });
+
+            scopes.push((die.offset, Scope { ranges, depth: die.depth, name: None, call }));
+        }
+
+        Some(())
+    }
+
+    /// The address ranges of the code of an entry, without the ranges of
+    /// code the linker dropped
+    fn die_ranges(&self, die: &Die<'a>, ctx: &UnitContext) -> Vec<Range<u32>> {
+        let mut ranges = vec![];
+        if let Some(low) = die.attr(DW_AT_LOW_PC).and_then(|value| self.address(value, ctx)) {
+            // the high pc is either an address or the size of the code
+            let high = match die.attr(DW_AT_HIGH_PC) {
+                Some(Value::Unsigned(size)) => Some(low.wrapping_add(size)),
+                Some(value) => self.address(value, ctx),
+                None => None,
+            };
+            if let Some(high) = high {
+                ranges.push(low..high);
+            }
+        } else if let Some(value) = die.attr(DW_AT_RANGES) {
+            ranges = self.range_list(value, ctx).unwrap_or_default();
+        }
+
+        ranges.into_iter()
+            .filter(|range| range.start != 0 && range.start < range.end && range.end <= u32::MAX as u64)
+            .map(|range| range.start as u32..range.end as u32)
+            .collect()
+    }
+
+    /// Read the range list of a `DW_AT_ranges` value
+    fn range_list(&self, value: Value, ctx: &UnitContext) -> Option<Vec<Range<u64>>> {
+        let header = &ctx.header;
+        let mut ranges = vec![];
+        let mut base = ctx.base_address;
+
+        if header.version < 5 {
+            // pairs of addresses in .debug_ranges, relative to the base
+            // address unless the start is all ones
+            let max = u64::MAX >> (64 - 8 * header.address_size as u32);
+            let mut reader = Reader::new(self.ranges, usize::try_from(value.unsigned()?).ok()?);
+            loop {
+                let start = reader.uint(header.address_size)?;
+                let end = reader.uint(header.address_size)?;
+                match (start, end) {
+                    (0, 0) => break,
+                    (start, end) if start == max => base = end,
+                    (start, end) => ranges.push(base.wrapping_add(start)..base.wrapping_add(end)),
+                }
+            }
+            return Some(ranges);
+        }
+
+        let offset = match value {
+            Value::RngListIndex(index) => {
+                let size = header.offset_size as u64;
+                let offset = ctx.rnglists_base.checked_add(index.checked_mul(size)?)?;
+                let mut reader = Reader::new(self.rnglists, usize::try_from(offset).ok()?);
+                ctx.rnglists_base.checked_add(reader.uint(header.offset_size)?)?
+            },
+            value => value.unsigned()?,
+        };
+
+        let mut reader = Reader::new(self.rnglists, usize::try_from(offset).ok()?);
+        loop {
+            match reader.u8()? {
+                // DW_RLE_end_of_list
+                0x0 => break,
+                // DW_RLE_base_addressx
+                0x1 => base = self.address(Value::AddrIndex(reader.uleb()?), ctx)?,
+                // DW_RLE_startx_endx
+                0x2 => {
+                    let start = self.address(Value::AddrIndex(reader.uleb()?), ctx)?;
+                    let end = self.address(Value::AddrIndex(reader.uleb()?), ctx)?;
+                    ranges.push(start..end);
+                },
+                // DW_RLE_startx_length
+                0x3 => {
+                    let start = self.address(Value::AddrIndex(reader.uleb()?), ctx)?;
+                    ranges.push(start..start.wrapping_add(reader.uleb()?));
+                },
+                // DW_RLE_offset_pair
+                0x4 => {
+                    let start = reader.uleb()?;
+                    let end = reader.uleb()?;
+                    ranges.push(base.wrapping_add(start)..base.wrapping_add(end));
+                },
+                // DW_RLE_base_address
+                0x5 => base = reader.uint(header.address_size)?,
+                // DW_RLE_start_end
+                0x6 => {
+                    let start = reader.uint(header.address_size)?;
+                    ranges.push(start..reader.uint(header.address_size)?);
+                },
+                // DW_RLE_start_length
+                0x7 => {
+                    let start = reader.uint(header.address_size)?;
+                    ranges.push(start..start.wrapping_add(reader.uleb()?));
+                },
+                _ => return None,
+            }
+        }
+
+        Some(ranges)
+    }
+
+    /// The address an address class value is
+    fn address(&self, value: Value, ctx: &UnitContext) -> Option<u64> {
+        match value {
+            Value::Addr(addr) => Some(addr),
+            Value::AddrIndex(index) => {
+                let size = ctx.header.address_size as u64;
+                let offset = ctx.addr_base.checked_add(index.checked_mul(size)?)?;
+                Reader::new(self.addr, usize::try_from(offset).ok()?).uint(ctx.header.address_size)
+            },
+            _ => None,
+        }
+    }
+
+    /// The string a string class value is, the unit is needed for indices
+    /// into `.debug_str_offsets`
+    fn string(&self, value: Value, ctx: Option<&UnitContext>) -> Option<String> {
+        let (section, offset) = match value {
+            Value::String(string) => return Some(String::from_utf8_lossy(string).into_owned()),
+            Value::StrOffset(offset) => (self.str, offset),
+            Value::LineStrOffset(offset) => (self.line_str, offset),
+            Value::StrIndex(index) => {
+                let ctx = ctx?;
+                let size = ctx.header.offset_size as u64;
+                let offset = ctx.str_offsets_base.checked_add(index.checked_mul(size)?)?;
+                let mut reader = Reader::new(self.str_offsets, usize::try_from(offset).ok()?);
+                (self.str, reader.uint(ctx.header.offset_size)?)
+            },
+            _ => return None,
+        };
+
+        let string = Reader::new(section, usize::try_from(offset).ok()?).cstr()?;
+        Some(String::from_utf8_lossy(string).into_owned())
+    }
+}
+
+/// Is `size` the size of an address in a unit header we can read
+fn valid_address_size(size: usize) -> bool {
+    matches!(size, 1 | 2 | 4 | 8)
+}
+
+/// Read the abbreviation table at `offset` in `.debug_abbrev`
+fn parse_abbrevs(data: &[u8], offset: u64) -> Option<HashMap<u64, Abbrev>> {
+    let mut reader = Reader::new(data, usize::try_from(offset).ok()?);
+    let mut abbrevs = HashMap::new();
+    loop {
+        let code = reader.uleb()?;
+        if code == 0 {
+            break;
+        }
+
+        let tag = reader.uleb()?;
+        let children = reader.u8()? != 0;
+        let mut attrs = vec![];
+        loop {
+            let name = reader.uleb()?;
+            let form = reader.uleb()?;
+            if name == 0 && form == 0 {
+                break;
+            }
+
+            // DW_FORM_implicit_const has its value in the abbreviation
+            let implicit = if form == 0x21 { reader.sleb()? } else { 0 };
+            attrs.push((name, form, implicit));
+        }
+
+        abbrevs.insert(code, Abbrev { tag, children, attrs });
+    }
+
+    Some(abbrevs)
+}
+
+/// Join a file name to the directory it is in, unless it is absolute
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{name}", dir.trim_end_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{SectionFlags, SHT_PROGBITS};
+
+    fn section(name: &str, data: Vec<u8>) -> Section {
+        Section {
+            name: name.to_string(),
+            typ: SHT_PROGBITS,
+            flags: SectionFlags(0),
+            addr: 0,
+            offset: 0,
+            size: data.len() as u32,
+            link: 0,
+            info: 0,
+            entsize: 0,
+            align: 1,
+            data: data.into_boxed_slice(),
+        }
+    }
+
+    /// `contents` with the 32 bit initial length of a unit
+    fn unit(contents: Vec<u8>) -> Vec<u8> {
+        [(contents.len() as u32).to_le_bytes().to_vec(), contents].concat()
+    }
+
+    /// A DWARF 4 line table with files `a.c` and `inc/b.h`, lines 10 and 11
+    /// of `a.c` from 0x1000 and line 11 of `b.h` from 0x100c to 0x1010
+    fn line_table_section() -> Section {
+        // the header, without the lengths
+        let mut header = vec![1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend(b"inc\0\0");
+        header.extend(b"a.c\0\0\0\0b.h\0\x01\0\0\0");
+
+        let program = [
+            // DW_LNE_set_address 0x1000, line 10, copy
+            0x00, 0x05, 0x02, 0x00, 0x10, 0x00, 0x00,
+            0x03, 0x09,
+            0x01,
+            // special opcode: 4 bytes and 1 line on
+            75,
+            // file 2, special opcode: 8 bytes on
+            0x04, 0x02,
+            130,
+            // 4 bytes on, DW_LNE_end_sequence
+            0x02, 0x04,
+            0x00, 0x01, 0x01,
+        ];
+
+        let mut contents = 4u16.to_le_bytes().to_vec();
+        contents.extend((header.len() as u32).to_le_bytes());
+        contents.extend(header);
+        contents.extend(program);
+        section(".debug_line", unit(contents))
+    }
+
+    #[test]
+    fn line_table() {
+        let debug = DebugInfo::parse(&[line_table_section()]);
+
+        let line = |addr| debug.addr_to_line(addr).map(|loc| loc.to_string());
+        assert_eq!(line(0xfff), None);
+        assert_eq!(line(0x1000).as_deref(), Some("a.c:10"));
+        assert_eq!(line(0x1003).as_deref(), Some("a.c:10"));
+        assert_eq!(line(0x1004).as_deref(), Some("a.c:11"));
+        assert_eq!(line(0x100c).as_deref(), Some("inc/b.h:11"));
+        assert_eq!(line(0x1010), None);
+
+        assert_eq!(debug.addr_to_frames(0x1004), [Frame {
+            function: None,
+            location: Some(Location { file: "a.c", line: 11, column: 0 }),
+        }]);
+    }
+
+    #[test]
+    fn inlined_frames() {
+        // a DWARF 5 unit with `outer` at 0x1000..0x1010, which has `inner`
+        // inlined at a.c:11:3 for 0x1008..0x1010
+        let abbrev = vec![
+            // the unit: DW_AT_stmt_list, DW_AT_str_offsets_base,
+            // DW_AT_addr_base and DW_AT_rnglists_base as DW_FORM_sec_offset,
+            // DW_AT_low_pc as DW_FORM_addr
+            1, 0x11, 1, 0x10, 0x17, 0x72, 0x17, 0x73, 0x17, 0x74, 0x17, 0x11, 0x01, 0, 0,
+            // the abstract instance of `inner`: DW_AT_name as DW_FORM_strx1
+            2, 0x2e, 0, 0x03, 0x25, 0, 0,
+            // `outer`: DW_AT_name, DW_AT_low_pc as DW_FORM_addrx1 and
+            // DW_AT_high_pc as DW_FORM_data4
+            3, 0x2e, 1, 0x03, 0x25, 0x11, 0x29, 0x12, 0x06, 0, 0,
+            // the inlined `inner`: DW_AT_abstract_origin as DW_FORM_ref4,
+            // DW_AT_ranges as DW_FORM_rnglistx, DW_AT_call_file,
+            // DW_AT_call_line and DW_AT_call_column as DW_FORM_data1
+            4, 0x1d, 0, 0x31, 0x13, 0x55, 0x23, 0x58, 0x0b, 0x59, 0x0b, 0x57, 0x0b, 0, 0,
+            0,
+        ];
+
+        let mut info = vec![5, 0, DW_UT_COMPILE, 4, 0, 0, 0, 0, 1];
+        for field in [0u32, 8, 8, 12, 0] {
+            info.extend(field.to_le_bytes());
+        }
+        // offsets are from the start of the unit, before its length
+        let inner = info.len() as u32 + 4;
+        info.extend([2, 1]);
+        info.extend([3, 0, 0]);
+        info.extend(0x10u32.to_le_bytes());
+        info.push(4);
+        info.extend(inner.to_le_bytes());
+        info.extend([0, 1, 11, 3]);
+        info.extend([0, 0]);
+
+        // each with a header of 8 bytes, or 12 for the range lists, the
+        // bases point after them
+        let mut str_offsets = vec![5, 0, 0, 0];
+        str_offsets.extend([0u32, 6].iter().flat_map(|offset| offset.to_le_bytes()));
+        let mut addr = vec![5, 0, 4, 0];
+        addr.extend(0x1000u32.to_le_bytes());
+        let mut rnglists = vec![5, 0, 4, 0, 1, 0, 0, 0];
+        rnglists.extend(4u32.to_le_bytes());
+        // DW_RLE_base_addressx 0, DW_RLE_offset_pair 8 0x10, DW_RLE_end_of_list
+        rnglists.extend([0x01, 0, 0x04, 0x08, 0x10, 0x00]);
+
+        let debug = DebugInfo::parse(&[
+            line_table_section(),
+            section(".debug_info", unit(info)),
+            section(".debug_abbrev", abbrev),
+            section(".debug_str", b"outer\0inner\0".to_vec()),
+            section(".debug_str_offsets", unit(str_offsets)),
+            section(".debug_addr", unit(addr)),
+            section(".debug_rnglists", unit(rnglists)),
+        ]);
+
+        assert_eq!(debug.addr_to_frames(0x1004), [Frame {
+            function: Some("outer"),
+            location: Some(Location { file: "a.c", line: 11, column: 0 }),
+        }]);
+        assert_eq!(debug.addr_to_frames(0x100c), [
+            Frame {
+                function: Some("inner"),
+                location: Some(Location { file: "inc/b.h", line: 11, column: 0 }),
+            },
+            Frame {
+                function: Some("outer"),
+                location: Some(Location { file: "a.c", line: 11, column: 3 }),
+            },
+        ]);
+        assert!(debug.addr_to_frames(0x1010).is_empty());
+    }
+}
@@ -1,16 +1,24 @@
-#![feature(stmt_expr_attributes)]
-#![feature(split_array)]
-#![feature(new_uninit)]
+use std::collections::HashMap;
 
-use std::io::{Read, Seek};
+mod dwarf;
+
+pub use dwarf::{DebugInfo, Frame, Location};
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to read the file
     ReadFile(std::io::Error),
 
-    ReadFailure(std::io::Error),
-    SeekFailure(std::io::Error),
+    /// A header or table entry that runs past the end of the file, with the
+    /// offset and name of the first field that's missing
+    Truncated { offset: u64, field: String },
+
+    /// A field, at `offset` in the file, pointing at contents that are
+    /// outside the file
+    OutOfBounds { offset: u64, field: String },
+
+    /// A field, at `offset` in the file, with a value that can't be right
+    InvalidField { offset: u64, field: String },
 
     /// Elf magic number was wrong
     InvalidElfMagic,
@@ -20,8 +28,54 @@ pub enum Error {
     InvalidOs(u8),
     InvalidElfType,
     InvalidMachine,
+
+    /// Relocation, at `offset` in the file, with a type that can't be
+    /// applied
+    UnsupportedRelocation { offset: u64, field: String, typ: u32 },
+
+    /// Relocation, at `offset` in the file, against a symbol that isn't
+    /// defined in the file
+    UndefinedSymbol { offset: u64, field: String, name: String },
+
+    /// Relocation, at `offset` in the file, with a value that doesn't fit in
+    /// the instruction
+    RelocationOverflow { offset: u64, field: String, typ: u32 },
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ReadFile(err) => write!(fmt, "failed to read file: {err}"),
+            Error::Truncated { offset, field } => {
+                write!(fmt, "{field} past the end of the file (at offset {offset:#x})")
+            },
+            Error::OutOfBounds { offset, field } => {
+                write!(fmt, "{field} out of file bounds (at offset {offset:#x})")
+            },
+            Error::InvalidField { offset, field } => {
+                write!(fmt, "{field} is invalid (at offset {offset:#x})")
+            },
+            Error::InvalidElfMagic => write!(fmt, "not an ELF file"),
+            Error::InvalidBitness => write!(fmt, "ELF header e_ident class is not 32 bit"),
+            Error::InvalidEndianness => write!(fmt, "ELF header e_ident data is not little endian"),
+            Error::InvalidOs(abi) => write!(fmt, "ELF header e_ident OS ABI {abi} is not System V"),
+            Error::InvalidElfType => write!(fmt, "ELF header e_type is not an executable or object"),
+            Error::InvalidMachine => write!(fmt, "ELF header e_machine is not RISC-V"),
+            Error::UnsupportedRelocation { offset, field, typ } => {
+                write!(fmt, "{field} has unsupported type {typ} (at offset {offset:#x})")
+            },
+            Error::UndefinedSymbol { offset, field, name } => {
+                write!(fmt, "{field} is against undefined symbol {name} (at offset {offset:#x})")
+            },
+            Error::RelocationOverflow { offset, field, typ } => {
+                write!(fmt, "{field} of type {typ} is out of range (at offset {offset:#x})")
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 
 type Result<Res> = std::result::Result<Res, Error>;
 
@@ -69,6 +123,61 @@ impl std::fmt::Debug for Flags {
     }
 }
 
+/// File types, `e_type`
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+/// Address objects and position independent executables are loaded at by
+/// [`Elf::load`], where static executables usually start
+pub const DEFAULT_BASE: u32 = 0x10000;
+
+/// Section types, `sh_type`
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+pub const SHT_DYNSYM: u32 = 11;
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct SectionFlags(pub u32);
+
+impl SectionFlags {
+    /// Is the section writable
+    pub fn write(self) -> bool {
+        self.0 & 0x1 != 0
+    }
+
+    /// Is the section loaded into memory
+    pub fn alloc(self) -> bool {
+        self.0 & 0x2 != 0
+    }
+
+    /// Is the section code
+    pub fn exec(self) -> bool {
+        self.0 & 0x4 != 0
+    }
+}
+
+impl std::fmt::Debug for SectionFlags {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut flags = String::new();
+        if self.write() {
+            flags += "W";
+        }
+        if self.alloc() {
+            flags += "A";
+        }
+        if self.exec() {
+            flags += "X";
+        }
+        write!(fmt, "{flags}")
+    }
+}
 
 /// A segment in an ELF file
 ///
@@ -95,163 +204,289 @@ pub struct Segment {
     pub data: Box<[u8]>,
 }
 
+/// A section from the section header table
+#[derive(Debug, Clone)]
+pub struct Section {
+    /// Name from the section name string table
+    pub name: String,
+
+    /// Type, one of the `SHT_` constants
+    pub typ: u32,
+
+    pub flags: SectionFlags,
+
+    /// Address in memory, 0 if the section isn't loaded
+    pub addr: u32,
+
+    /// Offset in file
+    pub offset: u32,
+
+    /// Size in memory, and in the file unless the type is [`SHT_NOBITS`]
+    pub size: u32,
+
+    /// Index of a related section, like the string table of a symbol table
+    pub link: u32,
+
+    /// Type specific extra information
+    pub info: u32,
+
+    /// Size of each entry for sections holding a table
+    pub entsize: u32,
+
+    /// Alignment of the address
+    pub align: u32,
+
+    /// Contents of the section, empty for [`SHT_NOBITS`] sections like
+    /// `.bss`
+    pub data: Box<[u8]>,
+}
+
+/// Symbol types, `STT_`
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+/// Symbol bindings, `STB_`
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+/// Section index of undefined symbols
+pub const SHN_UNDEF: u16 = 0;
+
+/// Section index of absolute symbols
+pub const SHN_ABS: u16 = 0xfff1;
+
+/// A symbol from the symbol table
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// Name of the symbol
+    pub name: String,
+
+    /// Value, the address for functions and objects
+    pub value: u32,
+
+    /// Size of the object or function
+    pub size: u32,
+
+    /// Type, one of the `STT_` constants
+    pub typ: u8,
+
+    /// Binding, one of the `STB_` constants
+    pub binding: u8,
+
+    /// Index of the section the symbol is in, or [`SHN_UNDEF`] or
+    /// [`SHN_ABS`]
+    pub section: u16,
+}
+
+impl Symbol {
+    /// Is this a symbol for an address in the program, rather than a file,
+    /// section, absolute value or undefined symbol
+    pub fn is_address(&self) -> bool {
+        matches!(self.typ, STT_NOTYPE | STT_OBJECT | STT_FUNC) &&
+            self.section != SHN_UNDEF && self.section < 0xff00 &&
+            // mapping symbols like $x mark code and data, not things in it
+            !self.name.starts_with('$') &&
+            // neither do the assembler's local labels, objects keep the ones
+            // %pcrel_lo relocations refer to
+            !(self.binding == STB_LOCAL && self.name.starts_with(".L"))
+    }
+}
+
 #[derive(Debug)]
 pub struct Elf {
+    /// File type, one of the `ET_` constants
+    pub typ: u16,
+
     /// Entry point for the program
     pub entry: u32,
 
     /// Loadable segments
     pub load_segments: Vec<Segment>,
-}
 
-/// Consume a value which implements `from_le_bytes` from a buffer, advancing
-/// the buffer beyond the bytes that were consumed
-macro_rules! consume {
-    ($buf:expr, $ty:ty) => {{
-        const SIZE: usize = std::mem::size_of::<$ty>();
-
-        // check that we have enough bytes to extract a $ty
-        // + 1 instead of >= because >= confuses llvm/rustc so it
-        // refuses to fuse multiple checks with multiple consume! calls
-        if $buf.len() + 1 > SIZE {
-            // split into &[u8; SIZE] and &[u8]
-            let (x, rest) = $buf.split_array_ref::<SIZE>();
-
-            // get the val
-            let val = <$ty>::from_le_bytes(*x);
-
-            // advance the buffer
-            #[allow(unused_assignments)]
-            $buf = rest;
-            Some(val)
-        } else {
-            None
-        }
-    }}
+    /// Named symbols from the symbol table and dynamic symbol table, empty if
+    /// the file is stripped
+    symbols: Vec<Symbol>,
+
+    /// Index into `symbols` of the first symbol with each name
+    by_name: HashMap<String, usize>,
+
+    /// Indices into `symbols` of the symbols that are addresses, by address
+    by_addr: Vec<usize>,
+
+    /// Sections, empty if the file has no section header table
+    sections: Vec<Section>,
+
+    /// Line table and functions from the DWARF debug information
+    debug: DebugInfo,
+
+    /// How far a position independent executable was loaded from the
+    /// addresses in it, which the debug information still uses
+    bias: u32,
 }
 
+mod reloc;
+
 impl Elf {
     /// Read a file, verify it is a linux ELF exe and find the load segments.
     ///
+    /// Objects and position independent executables are loaded at
+    /// [`DEFAULT_BASE`].
     pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        let file = std::fs::File::open(path);
-        let mut file = file.map_err(Error::ReadFile)?;
+        Self::load_at(path, DEFAULT_BASE)
+    }
 
-        // the elf program header is 52 bytes on a 32 bit system
-        let mut buf = [0u8; 52];
-        file.read_exact(&mut buf[..]).map_err(Error::ReadFailure)?;
+    /// Read a file like [`Elf::load`], loading objects and position
+    /// independent executables at `base`
+    ///
+    /// The sections of an object are laid out from `base` and its
+    /// relocations applied, its entry point is `_start` or the start of its
+    /// code. Static executables are always loaded where they say.
+    pub fn load_at<P: AsRef<std::path::Path>>(path: P, base: u32) -> Result<Self> {
+        let data = std::fs::read(path).map_err(Error::ReadFile)?;
+        Self::parse_at(&data, base)
+    }
 
-        let mut buf = &buf[..];
+    /// Parse an ELF file that is already in memory, like [`Elf::load`]
+    ///
+    /// Malformed files are reported as errors, never panics.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_at(data, DEFAULT_BASE)
+    }
+
+    /// Parse an ELF file that is already in memory, like [`Elf::load_at`]
+    pub fn parse_at(data: &[u8], base: u32) -> Result<Self> {
+        // the elf header is 52 bytes on a 32 bit system
+        let mut header = Entry::new(data, "ELF header".into(), 0);
 
         // check the ELF magic number at the start of the file
-        let magic = consume!(buf, u32).unwrap();
+        let magic = header.u32("e_ident magic")?;
         if magic != u32::from_le_bytes([0x7f, 0x45, 0x4c, 0x46]) {
             return Err(Error::InvalidElfMagic);
         }
 
         // check that it is a 32 bit executable
-        let class = consume!(buf, u8).unwrap();
+        let class = header.u8("e_ident class")?;
         if class != 1 {
             return Err(Error::InvalidBitness);
         }
 
         // check that it is little endian code
-        let endianness = consume!(buf, u8).unwrap();
+        let endianness = header.u8("e_ident data")?;
         if endianness != 1 {
             return Err(Error::InvalidEndianness);
         }
 
-        let _version = consume!(buf, u8).unwrap();
+        let _version = header.u8("e_ident version")?;
 
         // check that it is a system v executable (0)
         // TODO: should be linux? (0x03) or maybe not? abi is sysv?
-        let abi = consume!(buf, u8).unwrap();
+        let abi = header.u8("e_ident OS ABI")?;
         if abi != 0 {
             return Err(Error::InvalidOs(abi));
         }
 
         // skip abi version and padding
-        buf = &buf[8..];
+        header.skip(8);
 
-        // check file type, should be a static exe ET_EXEC
-        let typ = consume!(buf, u16).unwrap();
-        if typ != 0x02 {
-            return Err(Error::InvalidElfType);
-        }
+        // check file type, an exe or an object
+        let typ = header.u16("e_type")?;
+        let bias = match typ {
+            ET_EXEC | ET_REL => 0,
+            ET_DYN => base,
+            _ => return Err(Error::InvalidElfType),
+        };
 
         // check machine type, should be RISC-V
-        let machine = consume!(buf, u16).unwrap();
+        let machine = header.u16("e_machine")?;
         if machine != 0xf3 {
             return Err(Error::InvalidMachine);
         }
 
         // skip another version
-        let _version = consume!(buf, u32).unwrap();
+        let _version = header.u32("e_version")?;
 
         // get the entry point for the program
-        let entry = consume!(buf, u32).unwrap();
+        let entry = header.u32("e_entry")?;
 
         // get the program header table offset
-        let e_phoff = consume!(buf, u32).unwrap() as u64;
+        let e_phoff = header.u32("e_phoff")? as u64;
 
-        // skip shoff, flags and header size
-        buf = &buf[10..];
+        // get the section header table offset
+        let e_shoff = header.u32("e_shoff")? as u64;
+
+        // skip flags and header size
+        header.skip(6);
 
         // get the size of a program header entry
-        let e_phentsize = consume!(buf, u16).unwrap() as u64;
+        let e_phentsize = header.u16("e_phentsize")? as u64;
 
         // get the number of program header entries
-        let e_phnum = consume!(buf, u16).unwrap() as u64;
+        let e_phnum = header.u16("e_phnum")? as u64;
+
+        // get the size of a section header entry
+        let e_shentsize = header.u16("e_shentsize")? as u64;
+
+        // get the number of section header entries
+        let e_shnum = header.u16("e_shnum")? as u64;
+
+        // get the index of the section holding section names
+        let e_shstrndx = header.u16("e_shstrndx")? as u64;
+
+        // entries overlapping each other would have every field misread
+        if e_phnum > 0 && e_phentsize < 0x20 {
+            return Err(header.invalid(0x2a, "e_phentsize"));
+        }
+        if e_shnum > 0 && e_shentsize < 0x28 {
+            return Err(header.invalid(0x2e, "e_shentsize"));
+        }
 
         // process all program header entries
         let mut load_segments = vec![];
+        let mut dynamic = None;
         for entry_no in 0..e_phnum {
-            // seek to the start of the entry
-            file.seek(std::io::SeekFrom::Start(e_phoff + entry_no * e_phentsize))
-                .map_err(Error::SeekFailure)?;
+            let mut header = Entry::new(data, format!("program header {entry_no}"),
+                e_phoff + entry_no * e_phentsize);
 
-            let mut buf = [0u8; 0x20];
-            file.read_exact(&mut buf[..]).map_err(Error::ReadFailure)?;
+            // get the entry type
+            let p_type = header.u32("p_type")?;
 
-            let mut buf = &buf[..];
+            // get the file offset for the load segment
+            let file_offset = header.u32("p_offset")?;
 
-            // get the entry type
-            let p_type = consume!(buf, u32).unwrap();
+            // get the load address
+            let load_address = header.u32("p_vaddr")?.wrapping_add(bias);
+
+            if p_type == 0x2 {
+                // PT_DYNAMIC, where to find the dynamic relocations
+                dynamic = Some((load_address, header));
+                continue;
+            }
 
             if p_type != 0x1 {
                 // skip if type is not PT_LOAD
                 continue;
             }
 
-            // get the file offset for the load segment
-            let file_offset = consume!(buf, u32).unwrap();
-
-            // get the load address
-            let load_address = consume!(buf, u32).unwrap();
-
             // skip p_paddr
-            let _paddr = consume!(buf, u32);
+            let _paddr = header.u32("p_paddr")?;
 
             // get the file size for the load segment
-            let file_size = consume!(buf, u32).unwrap();
+            let file_size = header.u32("p_filesz")?;
 
             // get the memory size for the load segment
-            let size = consume!(buf, u32).unwrap();
+            let size = header.u32("p_memsz")?;
 
             // get the flags for the load segment
-            let flags = consume!(buf, u32).unwrap();
+            let flags = header.u32("p_flags")?;
             let flags = Flags(flags);
 
             // read the data
-            file.seek(std::io::SeekFrom::Start(file_offset as u64))
-                .map_err(Error::SeekFailure)?;
-
-            let data = Box::new_zeroed_slice(file_size as usize);
-
-            // safety: zero is a good value for u8
-            let mut data = unsafe { data.assume_init() };
-
-            file.read_exact(&mut data[..]).map_err(Error::ReadFailure)?;
+            let data = file_slice(data, file_offset as u64, file_size as u64)
+                .ok_or_else(|| header.out_of_bounds(0x4, "p_offset"))?;
 
             load_segments.push(Segment {
                 file_offset,
@@ -259,17 +494,316 @@ impl Elf {
                 load_address,
                 size,
                 flags,
-                data,
+                data: data.into(),
+            });
+        }
+
+        let mut sections = Self::load_sections(data, e_shoff, e_shentsize, e_shnum, e_shstrndx)?;
+
+        match typ {
+            ET_DYN => {
+                if let Some((dynamic, header)) = dynamic {
+                    reloc::relocate_dynamic(&mut load_segments, dynamic, &header, bias)?;
+                }
+                for section in sections.iter_mut().filter(|section| section.flags.alloc()) {
+                    section.addr = section.addr.wrapping_add(bias);
+                }
+            },
+            ET_REL => {
+                reloc::layout(&mut sections, base).map_err(|err| Error::InvalidField {
+                    offset: e_shoff + err.index as u64 * e_shentsize + err.at,
+                    field: format!("section header {} {}", err.index, err.field),
+                })?;
+                reloc::relocate(&mut sections)?;
+                load_segments = reloc::segments(&sections);
+            },
+            _ => (),
+        }
+
+        let mut symbols = Self::load_symbols(&sections);
+        for sym in symbols.iter_mut().filter(|sym| sym.section != SHN_UNDEF && sym.section < 0xff00) {
+            // the symbols of objects are relative to their section
+            sym.value = sym.value.wrapping_add(match typ {
+                ET_REL => sections.get(sym.section as usize).map_or(0, |section| section.addr),
+                _ => bias,
             });
         }
 
-        Ok(Elf {
-            entry,
+        let debug = DebugInfo::parse(&sections);
+
+        let mut elf = Elf {
+            typ,
+            entry: entry.wrapping_add(bias),
             load_segments,
-        })
+            symbols,
+            by_name: HashMap::new(),
+            by_addr: vec![],
+            sections,
+            debug,
+            bias,
+        };
+        elf.index_symbols();
+
+        if typ == ET_REL {
+            let start = elf.lookup_by_name("_start").filter(|sym| sym.is_address()).map(|sym| sym.value);
+            let code = elf.sections.iter().find(|section| section.flags.exec()).map(|section| section.addr);
+            elf.entry = start.or(code).unwrap_or(base);
+        }
+
+        Ok(elf)
+    }
+
+    /// All named symbols, in symbol table order
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Find a symbol by name
+    pub fn lookup_by_name(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|&index| &self.symbols[index])
+    }
+
+    /// Find the symbol `addr` is in, as the closest address symbol at or
+    /// before it, and the offset of `addr` from that symbol
+    ///
+    /// Functions are preferred over other symbols at the same address, and
+    /// global symbols over local ones.
+    pub fn addr_to_symbol(&self, addr: u32) -> Option<(&str, u32)> {
+        let after = self.by_addr.partition_point(|&index| self.symbols[index].value <= addr);
+        let sym = &self.symbols[*self.by_addr[..after].last()?];
+        Some((&sym.name, addr - sym.value))
+    }
+
+    /// The source file and line of the code at `addr`, from the DWARF line
+    /// table
+    pub fn addr_to_line(&self, addr: u32) -> Option<Location<'_>> {
+        self.debug.addr_to_line(addr.wrapping_sub(self.bias))
+    }
+
+    /// The functions the code at `addr` is in, innermost first, with a frame
+    /// for each inlined function, see [`DebugInfo::addr_to_frames`]
+    pub fn addr_to_frames(&self, addr: u32) -> Vec<Frame<'_>> {
+        self.debug.addr_to_frames(addr.wrapping_sub(self.bias))
+    }
+
+    /// Build the indices for symbol lookups
+    fn index_symbols(&mut self) {
+        for (index, sym) in self.symbols.iter().enumerate() {
+            self.by_name.entry(sym.name.clone()).or_insert(index);
+        }
+
+        // the preferred symbol at each address sorts last
+        let preference = |sym: &Symbol| (sym.typ == STT_FUNC, sym.binding != STB_LOCAL);
+        let symbols = &self.symbols;
+        self.by_addr = (0..symbols.len()).filter(|&index| symbols[index].is_address()).collect();
+        self.by_addr.sort_by_key(|&index| (symbols[index].value, preference(&symbols[index])));
+    }
+
+    /// All sections, in section header table order
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Find a section by name
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// Read the section header table, and the section contents
+    fn load_sections(data: &[u8], e_shoff: u64, e_shentsize: u64, e_shnum: u64,
+        e_shstrndx: u64) -> Result<Vec<Section>>
+    {
+        let mut sections = vec![];
+        let mut names = vec![];
+        for index in 0..e_shnum {
+            let mut header = Entry::new(data, format!("section header {index}"),
+                e_shoff + index * e_shentsize);
+
+            let sh_name = header.u32("sh_name")?;
+            let typ = header.u32("sh_type")?;
+            let flags = SectionFlags(header.u32("sh_flags")?);
+            let addr = header.u32("sh_addr")?;
+            let offset = header.u32("sh_offset")?;
+            let size = header.u32("sh_size")?;
+            let link = header.u32("sh_link")?;
+            let info = header.u32("sh_info")?;
+            let align = header.u32("sh_addralign")?;
+            let entsize = header.u32("sh_entsize")?;
+
+            let data = match typ {
+                SHT_NULL | SHT_NOBITS => Box::default(),
+                _ => file_slice(data, offset as u64, size as u64)
+                    .ok_or_else(|| header.out_of_bounds(0x10, "sh_offset"))?
+                    .into(),
+            };
+
+            names.push(sh_name);
+            sections.push(Section {
+                name: String::new(),
+                typ,
+                flags,
+                addr,
+                offset,
+                size,
+                link,
+                info,
+                entsize,
+                align,
+                data,
+            });
+        }
+
+        // names are offsets into the section name string table
+        if let Some(shstrtab) = sections.get(e_shstrndx as usize) {
+            let names: Vec<String> = names.iter()
+                .map(|&name| string_at(&shstrtab.data, name))
+                .collect();
+            for (section, name) in sections.iter_mut().zip(names) {
+                section.name = name;
+            }
+        }
+
+        Ok(sections)
+    }
+
+    /// Read the named symbols from the symbol table and dynamic symbol table
+    /// sections
+    fn load_symbols(sections: &[Section]) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = vec![];
+
+        for typ in [SHT_SYMTAB, SHT_DYNSYM] {
+            let Some(symtab) = sections.iter().find(|section| section.typ == typ) else {
+                continue;
+            };
+
+            // the linked section holds the symbol names
+            let strtab = sections.get(symtab.link as usize).map_or(&[][..], |strtab| &strtab.data);
+
+            // each symbol is 16 bytes
+            for entry in symtab.data.chunks_exact(0x10) {
+                let (st_name, value, size, st_info, section) = symbol_fields(entry);
+
+                let name = string_at(strtab, st_name);
+                if name.is_empty() {
+                    continue;
+                }
+
+                // dynamic symbols are usually in the symbol table too
+                if typ == SHT_DYNSYM && symbols.iter().any(|sym| sym.name == name && sym.value == value) {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name,
+                    value,
+                    size,
+                    typ: st_info & 0xf,
+                    binding: st_info >> 4,
+                    section,
+                });
+            }
+        }
+
+        symbols
+    }
+}
+
+/// The `st_name`, `st_value`, `st_size`, `st_info` and `st_shndx` fields of a
+/// 16 byte symbol table entry
+fn symbol_fields(entry: &[u8]) -> (u32, u32, u32, u8, u16) {
+    let u32_at = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+    (u32_at(0), u32_at(4), u32_at(8), entry[12], u16::from_le_bytes([entry[14], entry[15]]))
+}
+
+/// The `size` bytes at `offset` in the file, if they are all in it
+fn file_slice(data: &[u8], offset: u64, size: u64) -> Option<&[u8]> {
+    let end = usize::try_from(offset.checked_add(size)?).ok()?;
+    data.get(usize::try_from(offset).ok()?..end)
+}
+
+/// Reads the fields of a header or table entry in the file one after the
+/// other, for errors that say which field of which entry is broken
+struct Entry<'a> {
+    /// The whole file, or the part of it at `base`
+    data: &'a [u8],
+
+    /// Offset in the file of `data`
+    base: u64,
+
+    /// What the entry is, like "program header 2"
+    name: String,
+
+    /// Offset in `data` of the entry
+    start: u64,
+
+    /// Offset in `data` of the next field
+    offset: u64,
+}
+
+impl<'a> Entry<'a> {
+    fn new(data: &'a [u8], name: String, start: u64) -> Self {
+        Self::within(data, 0, name, start)
+    }
+
+    /// An entry in `data`, the contents of a section or segment at `base` in
+    /// the file
+    fn within(data: &'a [u8], base: u64, name: String, start: u64) -> Self {
+        Entry { data, base, name, start, offset: start }
+    }
+
+    /// Read the next field, `SIZE` bytes long
+    fn field<const SIZE: usize>(&mut self, field: &str) -> Result<[u8; SIZE]> {
+        let bytes = file_slice(self.data, self.offset, SIZE as u64)
+            .and_then(|bytes| bytes.first_chunk::<SIZE>())
+            .ok_or_else(|| Error::Truncated {
+                offset: self.base + self.offset,
+                field: format!("{} {field}", self.name),
+            })?;
+        self.offset += SIZE as u64;
+        Ok(*bytes)
+    }
+
+    fn u8(&mut self, field: &str) -> Result<u8> {
+        self.field(field).map(u8::from_le_bytes)
+    }
+
+    fn u16(&mut self, field: &str) -> Result<u16> {
+        self.field(field).map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self, field: &str) -> Result<u32> {
+        self.field(field).map(u32::from_le_bytes)
+    }
+
+    /// Skip fields that aren't needed
+    fn skip(&mut self, size: u64) {
+        self.offset += size;
+    }
+
+    /// The error for the field `at` bytes into the entry pointing outside the
+    /// file
+    fn out_of_bounds(&self, at: u64, field: &str) -> Error {
+        Error::OutOfBounds { offset: self.base + self.start + at, field: format!("{} {field}", self.name) }
+    }
+
+    /// The error for the field `at` bytes into the entry having a bad value
+    fn invalid(&self, at: u64, field: &str) -> Error {
+        Error::InvalidField { offset: self.base + self.start + at, field: format!("{} {field}", self.name) }
+    }
+
+    /// Offset in the file and name of the entry as a whole
+    fn location(&self) -> (u64, String) {
+        (self.base + self.start, self.name.clone())
     }
 }
 
+/// The NUL terminated string at `offset` in a string table
+fn string_at(strtab: &[u8], offset: u32) -> String {
+    let name = strtab.get(offset as usize..).unwrap_or(&[]);
+    let name = name.split(|&ch| ch == 0).next().unwrap_or(&[]);
+    String::from_utf8_lossy(name).into_owned()
+}
+
 /*
 $ ../riscv-rv32i/bin/riscv32-unknown-elf-readelf -lh --dynamic ../test/test
 ELF Header:
@@ -308,3 +842,372 @@ Program Headers:
 There is no dynamic section in this file.
 */
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A static executable with one segment holding an `ecall`
+    fn exe() -> Vec<u8> {
+        let mut data = vec![];
+        // e_ident
+        data.extend([0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags
+        data.extend(ET_EXEC.to_le_bytes());
+        data.extend(0xf3u16.to_le_bytes());
+        for field in [1u32, 0x10054, 0x34, 0, 0] {
+            data.extend(field.to_le_bytes());
+        }
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        for field in [0x34u16, 0x20, 1, 0x28, 0, 0] {
+            data.extend(field.to_le_bytes());
+        }
+        // a PT_LOAD of the whole file
+        for field in [1u32, 0, 0x10000, 0x10000, 0x58, 0x58, 0x5, 0x1000] {
+            data.extend(field.to_le_bytes());
+        }
+        data.extend(0x00000073u32.to_le_bytes());
+        data
+    }
+
+    /// A section for [`file`], the null section and the section name string
+    /// table are added, so the first of these has index 1
+    ///
+    /// Sections without contents are 8 bytes.
+    struct Sec<'a> {
+        name: &'a str,
+        typ: u32,
+        flags: u32,
+        link: u32,
+        info: u32,
+        align: u32,
+        entsize: u32,
+        data: &'a [u8],
+    }
+
+    impl<'a> Sec<'a> {
+        fn new(name: &'a str, typ: u32, flags: u32, data: &'a [u8]) -> Self {
+            Sec { name, typ, flags, link: 0, info: 0, align: 4, entsize: 0, data }
+        }
+    }
+
+    /// An ELF file of type `typ` with program headers of `(p_type, p_vaddr,
+    /// contents)` and `sections`, which are at address 0
+    fn file(typ: u16, entry: u32, segments: &[(u32, u32, &[u8])], sections: &[Sec]) -> Vec<u8> {
+        let phoff = 0x34;
+        let mut contents = phoff + segments.len() * 0x20;
+
+        let mut phdrs = vec![];
+        for &(p_type, vaddr, data) in segments {
+            for field in [p_type, contents as u32, vaddr, vaddr, data.len() as u32, data.len() as u32, 0x7, 0x1000] {
+                phdrs.extend(field.to_le_bytes());
+            }
+            contents += data.len();
+        }
+
+        let mut shstrtab = vec![0];
+        let mut names = vec![];
+        for name in sections.iter().map(|sec| sec.name).chain([".shstrtab"]) {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend(name.as_bytes());
+            shstrtab.push(0);
+        }
+        let shstrtab = Sec::new(".shstrtab", SHT_STRTAB, 0, &shstrtab);
+
+        let mut shdrs = vec![0; 0x28];
+        let mut offset = contents;
+        for (sec, name) in sections.iter().chain([&shstrtab]).zip(names) {
+            let size = if sec.typ == SHT_NOBITS { 8 } else { sec.data.len() as u32 };
+            for field in [name, sec.typ, sec.flags, 0, offset as u32, size, sec.link, sec.info, sec.align, sec.entsize] {
+                shdrs.extend(field.to_le_bytes());
+            }
+            offset += sec.data.len();
+        }
+        let shoff = offset;
+
+        let mut data = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend(typ.to_le_bytes());
+        data.extend(0xf3u16.to_le_bytes());
+        for field in [1, entry, phoff as u32, shoff as u32, 0] {
+            data.extend(field.to_le_bytes());
+        }
+        let shnum = sections.len() as u16 + 2;
+        for field in [0x34, 0x20, segments.len() as u16, 0x28, shnum, shnum - 1] {
+            data.extend(field.to_le_bytes());
+        }
+        data.extend(phdrs);
+        for &(_, _, contents) in segments {
+            data.extend(contents);
+        }
+        for sec in sections.iter().chain([&shstrtab]) {
+            data.extend(sec.data);
+        }
+        data.extend(shdrs);
+        data
+    }
+
+    /// Little endian bytes of `words`
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let elf = Elf::parse(&exe()).unwrap();
+        assert_eq!(elf.entry, 0x10054);
+        assert_eq!(elf.load_segments.len(), 1);
+        assert_eq!(elf.load_segments[0].data[0x54..], 0x00000073u32.to_le_bytes());
+
+        // every part of the file is needed
+        let data = exe();
+        for len in 0..data.len() {
+            assert!(Elf::parse(&data[..len]).is_err());
+        }
+        let err = Elf::parse(&data[..0x20]).unwrap_err();
+        assert_eq!(err.to_string(), "ELF header e_shoff past the end of the file (at offset 0x20)");
+
+        // p_offset of the segment pointing beyond the end
+        let mut data = exe();
+        data[0x38..0x3c].copy_from_slice(&0x1000u32.to_le_bytes());
+        let err = Elf::parse(&data).unwrap_err();
+        assert!(matches!(err, Error::OutOfBounds { offset: 0x38, .. }));
+        assert_eq!(err.to_string(), "program header 0 p_offset out of file bounds (at offset 0x38)");
+    }
+
+    #[test]
+    fn position_independent() {
+        // an ecall, a pointer to 0x100 and the dynamic section with a
+        // relocation for the pointer after it
+        let image = words(&[
+            0x00000073,
+            0,
+            7, 0x28, 8, 0xc, 9, 0xc, 0, 0,
+            0x4, 3, 0x100,
+        ]);
+        let data = file(ET_DYN, 0, &[(1, 0, &image), (2, 0x8, &[])], &[]);
+
+        let elf = Elf::parse_at(&data, 0x40000).unwrap();
+        assert_eq!(elf.entry, 0x40000);
+        assert_eq!(elf.load_segments.len(), 1);
+        assert_eq!(elf.load_segments[0].load_address, 0x40000);
+        assert_eq!(elf.load_segments[0].data[4..8], 0x40100u32.to_le_bytes());
+    }
+
+    /// An object whose `_start` calls `func` and loads the upper address of
+    /// `value`, which holds the address of `func`, with `rela_text` as the
+    /// relocations of the code
+    ///
+    /// Symbol 4 is `undefined`.
+    fn object(text_align: u32, rela_text: &[u32]) -> Vec<u8> {
+        let text = words(&[
+            0x00000097, // auipc ra, 0
+            0x000080e7, // jalr ra, 0(ra)
+            0x00000537, // lui a0, 0
+            0x00000073, // ecall
+            0x00008067, // ret
+        ]);
+        let data = [0; 4];
+        let mut symtab = vec![0; 0x10];
+        let mut strtab = vec![0];
+        let symbols = [
+            ("_start", 0, 1u16, STT_FUNC),
+            ("func", 0x10, 1, STT_FUNC),
+            ("value", 0, 2, STT_OBJECT),
+            ("undefined", 0, SHN_UNDEF, STT_NOTYPE),
+        ];
+        for (name, value, section, typ) in symbols {
+            symtab.extend(words(&[strtab.len() as u32, value, 4]));
+            symtab.extend([STB_GLOBAL << 4 | typ, 0]);
+            symtab.extend(section.to_le_bytes());
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+        let rela_text = words(rela_text);
+        // R_RISCV_32 of func
+        let rela_data = words(&[0, 2 << 8 | 1, 0]);
+
+        let sections = [
+            Sec { align: text_align, ..Sec::new(".text", SHT_PROGBITS, 0x6, &text) },
+            Sec::new(".data", SHT_PROGBITS, 0x3, &data),
+            Sec { align: 8, ..Sec::new(".bss", SHT_NOBITS, 0x3, &[]) },
+            Sec { link: 5, info: 1, entsize: 0x10, ..Sec::new(".symtab", SHT_SYMTAB, 0, &symtab) },
+            Sec::new(".strtab", SHT_STRTAB, 0, &strtab),
+            Sec { link: 4, info: 1, entsize: 0xc, ..Sec::new(".rela.text", SHT_RELA, 0, &rela_text) },
+            Sec { link: 4, info: 2, entsize: 0xc, ..Sec::new(".rela.data", SHT_RELA, 0, &rela_data) },
+        ];
+        file(ET_REL, 0, &[], &sections)
+    }
+
+    /// R_RISCV_CALL of func and R_RISCV_HI20 of value
+    const RELA_TEXT: [u32; 6] = [0, 2 << 8 | 18, 0, 0x8, 3 << 8 | 26, 0];
+
+    /// Offset in the file of the section header of section `index`
+    fn section_header(data: &[u8], index: u64) -> u64 {
+        u32::from_le_bytes(data[0x20..0x24].try_into().unwrap()) as u64 + index * 0x28
+    }
+
+    /// Offset in the file of the contents of section `index`
+    fn section_offset(data: &[u8], index: u64) -> u64 {
+        let at = section_header(data, index) as usize + 0x10;
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as u64
+    }
+
+    #[test]
+    fn relocatable() {
+        let elf = Elf::parse_at(&object(4, &RELA_TEXT), 0x10000).unwrap();
+        assert_eq!(elf.entry, 0x10000);
+        assert_eq!(elf.lookup_by_name("func").unwrap().value, 0x10010);
+
+        // code and data each start on a page, .bss after .data
+        let addrs: Vec<u32> = elf.sections()[1..4].iter().map(|section| section.addr).collect();
+        assert_eq!(addrs, [0x10000, 0x11000, 0x11008]);
+
+        let segments: Vec<_> = elf.load_segments.iter()
+            .map(|segment| (segment.load_address, segment.size, segment.file_size, segment.flags.0))
+            .collect();
+        assert_eq!(segments, [(0x10000, 0x14, 0x14, 0x5), (0x11000, 0x10, 0x8, 0x6)]);
+        assert_eq!(elf.load_segments[0].data[..12], words(&[0x00000097, 0x010080e7, 0x00011537]));
+        assert_eq!(elf.load_segments[1].data[..4], 0x10010u32.to_le_bytes());
+
+        // alignments have to be powers of two, up to a page
+        for align in [3, 0x2000] {
+            let data = object(align, &RELA_TEXT);
+            let err = Elf::parse_at(&data, 0x10000).unwrap_err();
+            assert!(matches!(err, Error::InvalidField { offset, .. } if offset == section_header(&data, 1) + 0x20));
+            assert!(err.to_string().starts_with("section header 1 sh_addralign"));
+        }
+
+        // an allocated null section of any size has no contents either, the
+        // string table made writable comes before it
+        let mut data = object(4, &RELA_TEXT);
+        let at = section_header(&data, 3) as usize;
+        data[at + 0x4..at + 0x8].copy_from_slice(&SHT_NULL.to_le_bytes());
+        data[at + 0x14..at + 0x18].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        let at = section_header(&data, 5) as usize;
+        data[at + 0x8..at + 0xc].copy_from_slice(&0x3u32.to_le_bytes());
+        let elf = Elf::parse_at(&data, 0x10000).unwrap();
+        let segment = &elf.load_segments[1];
+        assert_eq!((segment.load_address, segment.size, segment.file_size), (0x11000, 0x8000_0028, 0x28));
+    }
+
+    #[test]
+    fn malformed_sections() {
+        let data = object(4, &RELA_TEXT);
+
+        // contents past the end of the file
+        let mut bad = data.clone();
+        let at = section_header(&data, 1) as usize + 0x10;
+        bad[at..at + 4].copy_from_slice(&0x10000u32.to_le_bytes());
+        let err = Elf::parse(&bad).unwrap_err();
+        assert!(matches!(err, Error::OutOfBounds { offset, .. } if offset == at as u64));
+        assert!(err.to_string().starts_with("section header 1 sh_offset out of file bounds"));
+
+        // more section headers than there are
+        let mut bad = data.clone();
+        bad[0x30..0x32].copy_from_slice(&10u16.to_le_bytes());
+        let err = Elf::parse(&bad).unwrap_err();
+        assert!(err.to_string().starts_with("section header 9 sh_name past the end of the file"));
+
+        // a size that doesn't fit in memory
+        let mut bad = data.clone();
+        let at = section_header(&data, 3) as usize + 0x14;
+        bad[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Elf::parse(&bad).unwrap_err();
+        assert!(matches!(err, Error::InvalidField { offset, .. } if offset == at as u64));
+        assert!(err.to_string().starts_with("section header 3 sh_size is invalid"));
+    }
+
+    #[test]
+    fn malformed_relocations() {
+        let rela_text = |entry: [u32; 3]| {
+            let data = object(4, &[&RELA_TEXT[..3], &entry].concat());
+            // the second entry of .rela.text
+            let at = section_offset(&data, 6) + 0xc;
+            (Elf::parse(&data).unwrap_err(), at)
+        };
+
+        // past the end of the code
+        let (err, at) = rela_text([0x14, 1, 0]);
+        assert!(matches!(err, Error::OutOfBounds { offset, .. } if offset == at));
+        assert!(err.to_string().starts_with("relocation section 6 entry 1 r_offset out of file bounds"));
+
+        // against a symbol that doesn't exist
+        let (err, at) = rela_text([0x8, 9 << 8 | 26, 0]);
+        assert!(matches!(err, Error::InvalidField { offset, .. } if offset == at + 4));
+        assert!(err.to_string().starts_with("relocation section 6 entry 1 r_info is invalid"));
+
+        let (err, at) = rela_text([0x8, 4 << 8 | 26, 0]);
+        assert!(matches!(&err, Error::UndefinedSymbol { offset, name, .. } if *offset == at && name == "undefined"));
+
+        let (err, at) = rela_text([0x8, 2 << 8 | 200, 0]);
+        assert!(matches!(err, Error::UnsupportedRelocation { offset, typ: 200, .. } if offset == at));
+        assert!(err.to_string().starts_with("relocation section 6 entry 1 has unsupported type 200"));
+
+        // a branch to func from too far away
+        let (err, at) = rela_text([0x8, 2 << 8 | 16, 0x10000]);
+        assert!(matches!(err, Error::RelocationOverflow { offset, typ: 16, .. } if offset == at));
+
+        // a dynamic relocation table outside the file
+        let image = words(&[0x00000073, 0, 7, 0x1000, 8, 0xc, 0, 0]);
+        let data = file(ET_DYN, 0, &[(1, 0, &image), (2, 0x8, &[])], &[]);
+        let err = Elf::parse(&data).unwrap_err();
+        assert!(matches!(err, Error::InvalidField { offset: 0x80, .. }));
+        assert!(err.to_string().starts_with("dynamic entry 0 d_val is invalid"));
+    }
+
+    #[test]
+    fn malformed_debug_info() {
+        let parse = |sections: &[Sec]| Elf::parse(&file(ET_EXEC, 0, &[], sections)).unwrap();
+        let uleb = |mut value: u64| {
+            let mut bytes = vec![];
+            while value >= 0x80 {
+                bytes.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            bytes.push(value as u8);
+            bytes
+        };
+        let unit = |contents: Vec<u8>| [(contents.len() as u32).to_le_bytes().to_vec(), contents].concat();
+
+        // a line table moving the line past the largest line twice
+        let mut line = vec![4, 0, 20, 0, 0, 0, 1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0, 0];
+        line.extend([0x00, 0x05, 0x02, 0x00, 0x10, 0x00, 0x00]);
+        for _ in 0..2 {
+            line.extend([0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        }
+        line.extend([0x01, 0x02, 0x04, 0x00, 0x01, 0x01]);
+        let line = unit(line);
+        let elf = parse(&[Sec::new(".debug_line", SHT_PROGBITS, 0, &line)]);
+        assert_eq!(elf.addr_to_frames(0x1000).len(), 1);
+
+        // a unit, then functions with a range list in .debug_ranges and an
+        // origin past the end of the unit, or in .debug_rnglists by an index
+        // past the end of the offsets
+        let abbrev = [
+            1, 0x11, 1, 0, 0,
+            2, 0x2e, 0, 0x55, 0x17, 0x31, 0x15, 0, 0,
+            3, 0x2e, 0, 0x55, 0x23, 0, 0,
+            0,
+        ];
+        let ranges = [0; 8];
+        let info = |address_size: u8| {
+            let mut v4 = vec![4, 0, 0, 0, 0, 0, address_size, 1, 2, 0, 0, 0, 0];
+            v4.extend(uleb(u64::MAX));
+            v4.push(0);
+            let mut v5 = vec![5, 0, 1, address_size, 0, 0, 0, 0, 1, 3];
+            v5.extend(uleb(u64::MAX / 2));
+            v5.push(0);
+            [unit(v4.clone()), unit(v4), unit(v5)].concat()
+        };
+        for address_size in [0, 3, 4, 8, 9, 255] {
+            let info = info(address_size);
+            let elf = parse(&[
+                Sec::new(".debug_info", SHT_PROGBITS, 0, &info),
+                Sec::new(".debug_abbrev", SHT_PROGBITS, 0, &abbrev),
+                Sec::new(".debug_ranges", SHT_PROGBITS, 0, &ranges),
+                Sec::new(".debug_rnglists", SHT_PROGBITS, 0, &ranges),
+            ]);
+            assert!(elf.addr_to_frames(0x1000).is_empty());
+        }
+    }
+}
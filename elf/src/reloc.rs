@@ -0,0 +1,471 @@
+//! Loading of relocatable objects and position independent executables
+//!
+//! Objects have their allocated sections laid out in memory from a base
+//! address, like a linker would with a single input file, and their
+//! relocations applied. Position independent executables only need their
+//! dynamic `R_RISCV_RELATIVE` relocations applied for the base they are
+//! loaded at.
+
+use std::collections::HashMap;
+
+use crate::{Entry, Error, Flags, Result, Section, Segment, SHN_ABS, SHN_UNDEF,
+    SHT_RELA, STB_WEAK};
+
+/// Relocation types, `R_RISCV_`
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_32: u32 = 1;
+const R_RISCV_RELATIVE: u32 = 3;
+const R_RISCV_BRANCH: u32 = 16;
+const R_RISCV_JAL: u32 = 17;
+const R_RISCV_CALL: u32 = 18;
+const R_RISCV_CALL_PLT: u32 = 19;
+const R_RISCV_PCREL_HI20: u32 = 23;
+const R_RISCV_PCREL_LO12_I: u32 = 24;
+const R_RISCV_PCREL_LO12_S: u32 = 25;
+const R_RISCV_HI20: u32 = 26;
+const R_RISCV_LO12_I: u32 = 27;
+const R_RISCV_LO12_S: u32 = 28;
+const R_RISCV_ADD8: u32 = 33;
+const R_RISCV_ADD16: u32 = 34;
+const R_RISCV_ADD32: u32 = 35;
+const R_RISCV_SUB8: u32 = 37;
+const R_RISCV_SUB16: u32 = 38;
+const R_RISCV_SUB32: u32 = 39;
+const R_RISCV_ALIGN: u32 = 43;
+const R_RISCV_RELAX: u32 = 51;
+const R_RISCV_SUB6: u32 = 52;
+const R_RISCV_SET6: u32 = 53;
+const R_RISCV_SET8: u32 = 54;
+const R_RISCV_SET16: u32 = 55;
+const R_RISCV_SET32: u32 = 56;
+const R_RISCV_32_PCREL: u32 = 57;
+
+/// Dynamic section tags, `DT_`
+const DT_NULL: u32 = 0;
+const DT_RELA: u32 = 7;
+const DT_RELASZ: u32 = 8;
+const DT_RELAENT: u32 = 9;
+
+/// Section header field that keeps an object from being laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutError {
+    /// Index of the section
+    pub index: usize,
+
+    /// Offset of the field in the section header
+    pub at: u64,
+
+    pub field: &'static str,
+}
+
+/// Give the allocated sections of an object addresses from `base`, code
+/// first, then read only data and then writable data, each starting on a new
+/// page
+///
+/// Fails at the first section with an alignment that isn't a power of two or
+/// is larger than a page, or that doesn't fit below 4 GiB.
+pub fn layout(sections: &mut [Section], base: u32) -> std::result::Result<(), LayoutError> {
+    let size_error = |index| LayoutError { index, at: 0x14, field: "sh_size" };
+
+    let mut addr = base;
+    for (write, exec) in [(false, true), (false, false), (true, false)] {
+        // sections without all their contents in the file, like .bss, go at
+        // the end, so the rest can be loaded from one buffer
+        for nobits in [false, true] {
+            for (index, section) in sections.iter_mut().enumerate() {
+                let flags = section.flags;
+                if !flags.alloc() || flags.write() != write || flags.exec() != exec ||
+                        (section.data.len() < section.size as usize) != nobits {
+                    continue;
+                }
+
+                // 0 and 1 both mean no alignment
+                if !section.align.max(1).is_power_of_two() || section.align > 0x1000 {
+                    return Err(LayoutError { index, at: 0x20, field: "sh_addralign" });
+                }
+
+                let align = section.align.max(1);
+                addr = addr.checked_next_multiple_of(align).ok_or_else(|| size_error(index))?;
+                section.addr = addr;
+                addr = addr.checked_add(section.size).ok_or_else(|| size_error(index))?;
+            }
+        }
+        // a following section that doesn't fit is the one to blame
+        addr = addr.checked_next_multiple_of(0x1000).unwrap_or(u32::MAX);
+    }
+
+    Ok(())
+}
+
+/// Make the load segments of an object from its laid out sections, one for
+/// each kind of section
+pub fn segments(sections: &[Section]) -> Vec<Segment> {
+    let mut segments = vec![];
+    for (write, exec) in [(false, true), (false, false), (true, false)] {
+        let group: Vec<&Section> = sections.iter()
+            .filter(|section| {
+                section.flags.alloc() && section.flags.write() == write && section.flags.exec() == exec
+            })
+            .collect();
+        let Some(start) = group.iter().map(|section| section.addr).min() else {
+            continue;
+        };
+
+        let end = group.iter().map(|section| section.addr + section.size).max().unwrap();
+        let file_end = group.iter()
+            .map(|section| section.addr + section.data.len() as u32)
+            .max()
+            .unwrap_or(start);
+
+        let mut data = vec![0; (file_end - start) as usize];
+        for section in &group {
+            let offset = (section.addr - start) as usize;
+            data[offset..offset + section.data.len()].copy_from_slice(&section.data);
+        }
+
+        let flags = 0x4 | if write { 0x2 } else { 0 } | if exec { 0x1 } else { 0 };
+        segments.push(Segment {
+            file_offset: 0,
+            file_size: file_end - start,
+            load_address: start,
+            size: end - start,
+            flags: Flags(flags),
+            data: data.into_boxed_slice(),
+        });
+    }
+
+    segments
+}
+
+/// Apply the relocations of an object to the contents of its sections, after
+/// they have been laid out
+///
+/// Relocations of sections that aren't loaded, like the debug information,
+/// are applied where possible and otherwise left alone.
+pub fn relocate(sections: &mut [Section]) -> Result<()> {
+    for index in 0..sections.len() {
+        if sections[index].typ != SHT_RELA {
+            continue;
+        }
+
+        let rela = &sections[index];
+        let target = rela.info as usize;
+        let Some(symtab) = sections.get(rela.link as usize) else {
+            continue;
+        };
+        if target >= sections.len() || target == index {
+            continue;
+        }
+        let strtab = sections.get(symtab.link as usize).map_or(&[][..], |strtab| &strtab.data);
+
+        // the address of each symbol, or the name of an undefined one
+        let symbols: Vec<std::result::Result<u32, String>> = symtab.data.chunks_exact(0x10)
+            .map(|entry| {
+                let (name, value, _size, info, section) = crate::symbol_fields(entry);
+
+                match section {
+                    SHN_UNDEF if info >> 4 == STB_WEAK => Ok(0),
+                    SHN_ABS => Ok(value),
+                    // common symbols are only given space by a linker
+                    section if section == SHN_UNDEF || section >= 0xff00 => {
+                        Err(crate::string_at(strtab, name))
+                    },
+                    section => Ok(sections.get(section as usize)
+                        .map_or(0, |section| section.addr)
+                        .wrapping_add(value)),
+                }
+            })
+            .collect();
+        let resolve = |entry: &Entry, sym: u32| match symbols.get(sym as usize) {
+            // relocations that aren't against a symbol use index 0
+            _ if sym == 0 => Ok(0),
+            Some(Ok(addr)) => Ok(*addr),
+            Some(Err(name)) => {
+                let (offset, field) = entry.location();
+                Err(Error::UndefinedSymbol { offset, field, name: name.clone() })
+            },
+            None => Err(entry.invalid(0x4, "r_info")),
+        };
+
+        // copied, so that the entries can be pointed at in errors while the
+        // target section changes
+        let table = rela.data.clone();
+        let mut relocs = vec![];
+        for entry_no in 0..table.len() as u64 / 0xc {
+            let mut entry = Entry::within(&table, rela.offset as u64,
+                format!("relocation section {index} entry {entry_no}"), entry_no * 0xc);
+            let offset = entry.u32("r_offset")?;
+            let info = entry.u32("r_info")?;
+            let addend = entry.u32("r_addend")?;
+            relocs.push((entry, offset, info & 0xff, info >> 8, addend));
+        }
+
+        let loaded = sections[target].flags.alloc();
+        let target_addr = sections[target].addr;
+
+        // the offsets of the %pcrel_hi relocations, which the %pcrel_lo
+        // relocations refer to by the address of their instruction
+        let mut pcrel_hi = HashMap::new();
+        for (entry, offset, typ, sym, addend) in &relocs {
+            if *typ == R_RISCV_PCREL_HI20 {
+                let value = resolve(entry, *sym).unwrap_or(0).wrapping_add(*addend);
+                let pc = target_addr.wrapping_add(*offset);
+                pcrel_hi.insert(pc, value.wrapping_sub(pc));
+            }
+        }
+
+        let mut data = std::mem::take(&mut sections[target].data);
+        for (entry, offset, typ, sym, addend) in &relocs {
+            let res = resolve(entry, *sym).and_then(|value| {
+                let pc = target_addr.wrapping_add(*offset);
+                let value = value.wrapping_add(*addend);
+                apply(&mut data, *offset, *typ, value, pc, &pcrel_hi).map_err(|err| err.at(entry, *typ))
+            });
+
+            // only code and data that's loaded has to be right
+            if loaded {
+                if let Err(err) = res {
+                    sections[target].data = data;
+                    return Err(err);
+                }
+            }
+        }
+        sections[target].data = data;
+    }
+
+    Ok(())
+}
+
+/// Apply the dynamic relocations of a position independent executable loaded
+/// `base` bytes after its addresses to its load segments
+///
+/// `header` is the program header of the dynamic section at `dynamic`.
+pub fn relocate_dynamic(segments: &mut [Segment], dynamic: u32, header: &Entry, base: u32) -> Result<()> {
+    // find the relocation table in the dynamic section
+    let mut rela = None;
+    let mut rela_size = None;
+    let mut rela_entsize = None;
+    let (table, table_offset) = contents(segments, dynamic).ok_or_else(|| header.invalid(0x8, "p_vaddr"))?;
+    for entry_no in 0..table.len() as u64 / 8 {
+        let mut entry = Entry::within(table, table_offset, format!("dynamic entry {entry_no}"), entry_no * 8);
+        let tag = entry.u32("d_tag")?;
+        let value = entry.u32("d_val")?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some((value.wrapping_add(base), entry)),
+            DT_RELASZ => rela_size = Some((value, entry)),
+            DT_RELAENT => rela_entsize = Some((value, entry)),
+            _ => (),
+        }
+    }
+    let Some((rela_size, size_entry)) = rela_size.filter(|&(size, _)| size != 0) else {
+        return Ok(());
+    };
+    let rela_entsize = match rela_entsize {
+        Some((entsize, entry)) if entsize < 0xc => return Err(entry.invalid(0x4, "d_val")),
+        Some((entsize, _)) => entsize,
+        None => 0xc,
+    };
+    let (rela, rela_entry) = rela.ok_or_else(|| size_entry.invalid(0x4, "d_val"))?;
+
+    // copied, the segment it's in may be relocated too
+    let (table, rela_offset) = contents(segments, rela).ok_or_else(|| rela_entry.invalid(0x4, "d_val"))?;
+    let table = table.get(..rela_size as usize).ok_or_else(|| size_entry.invalid(0x4, "d_val"))?.to_vec();
+
+    for entry_no in 0..table.len() as u64 / rela_entsize as u64 {
+        let mut entry = Entry::within(&table, rela_offset, format!("dynamic relocation {entry_no}"),
+            entry_no * rela_entsize as u64);
+        let offset = entry.u32("r_offset")?;
+        let info = entry.u32("r_info")?;
+        let addend = entry.u32("r_addend")?;
+
+        match info & 0xff {
+            R_RISCV_NONE => (),
+            R_RISCV_RELATIVE => {
+                let addr = offset.wrapping_add(base);
+                let segment = segments.iter_mut()
+                    .find(|segment| addr >= segment.load_address && addr - segment.load_address < segment.file_size)
+                    .ok_or_else(|| entry.out_of_bounds(0, "r_offset"))?;
+                apply(&mut segment.data, addr - segment.load_address, R_RISCV_32,
+                    addend.wrapping_add(base), addr, &HashMap::new())
+                    .map_err(|err| err.at(&entry, R_RISCV_RELATIVE))?;
+            },
+            typ => {
+                let (offset, field) = entry.location();
+                return Err(Error::UnsupportedRelocation { offset, field, typ });
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// The contents of the load segments from `addr` to the end of the segment
+/// it is in, and their offset in the file
+fn contents(segments: &[Segment], addr: u32) -> Option<(&[u8], u64)> {
+    let segment = segments.iter().find(|segment| {
+        addr >= segment.load_address && addr - segment.load_address < segment.file_size
+    })?;
+    let offset = addr - segment.load_address;
+    Some((segment.data.get(offset as usize..)?, segment.file_offset as u64 + offset as u64))
+}
+
+/// Why a relocation couldn't be applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyError {
+    /// The bytes it changes aren't all in the section
+    OutOfBounds,
+
+    /// A `%pcrel_lo` that doesn't refer to a `%pcrel_hi`
+    NoPcrelHi,
+
+    /// The value doesn't fit in the instruction
+    Overflow,
+
+    Unsupported,
+}
+
+impl ApplyError {
+    /// The error for the relocation `entry` of type `typ`
+    fn at(self, entry: &Entry, typ: u32) -> Error {
+        let (offset, field) = entry.location();
+        match self {
+            ApplyError::OutOfBounds => entry.out_of_bounds(0, "r_offset"),
+            ApplyError::NoPcrelHi => entry.invalid(0x4, "r_info"),
+            ApplyError::Overflow => Error::RelocationOverflow { offset, field, typ },
+            ApplyError::Unsupported => Error::UnsupportedRelocation { offset, field, typ },
+        }
+    }
+}
+
+/// Apply a relocation of type `typ` at `offset` in `data`, the contents of a
+/// section at `pc`, `value` is the symbol plus addend
+fn apply(data: &mut [u8], offset: u32, typ: u32, value: u32, pc: u32,
+    pcrel_hi: &HashMap<u32, u32>) -> std::result::Result<(), ApplyError>
+{
+    let size = match typ {
+        R_RISCV_NONE | R_RISCV_RELAX | R_RISCV_ALIGN => return Ok(()),
+        R_RISCV_ADD8 | R_RISCV_SUB8 | R_RISCV_SUB6 | R_RISCV_SET6 | R_RISCV_SET8 => 1,
+        R_RISCV_ADD16 | R_RISCV_SUB16 | R_RISCV_SET16 => 2,
+        R_RISCV_CALL | R_RISCV_CALL_PLT => 8,
+        _ => 4,
+    };
+    let offset = offset as usize;
+    let bytes = offset.checked_add(size)
+        .and_then(|end| data.get_mut(offset..end))
+        .ok_or(ApplyError::OutOfBounds)?;
+
+    let mut buf = [0u8; 8];
+    buf[..size].copy_from_slice(bytes);
+    let old = u64::from_le_bytes(buf);
+    let insn = old as u32;
+
+    let pcrel = value.wrapping_sub(pc);
+    let new = match typ {
+        R_RISCV_32 | R_RISCV_SET32 => value as u64,
+        R_RISCV_32_PCREL => pcrel as u64,
+        R_RISCV_ADD8 | R_RISCV_ADD16 | R_RISCV_ADD32 => old.wrapping_add(value as u64),
+        R_RISCV_SUB8 | R_RISCV_SUB16 | R_RISCV_SUB32 => old.wrapping_sub(value as u64),
+        R_RISCV_SUB6 => (old & !0x3f) | (old.wrapping_sub(value as u64) & 0x3f),
+        R_RISCV_SET6 => (old & !0x3f) | (value as u64 & 0x3f),
+        R_RISCV_SET8 | R_RISCV_SET16 => value as u64,
+        R_RISCV_HI20 => set_hi20(insn, value) as u64,
+        R_RISCV_LO12_I => set_lo12_i(insn, value) as u64,
+        R_RISCV_LO12_S => set_lo12_s(insn, value) as u64,
+        R_RISCV_PCREL_HI20 => set_hi20(insn, pcrel) as u64,
+        R_RISCV_PCREL_LO12_I | R_RISCV_PCREL_LO12_S => {
+            // the symbol is the instruction with the %pcrel_hi
+            let &hi = pcrel_hi.get(&value).ok_or(ApplyError::NoPcrelHi)?;
+            if typ == R_RISCV_PCREL_LO12_I {
+                set_lo12_i(insn, hi) as u64
+            } else {
+                set_lo12_s(insn, hi) as u64
+            }
+        },
+        R_RISCV_BRANCH => {
+            if !fits(pcrel, 13) {
+                return Err(ApplyError::Overflow);
+            }
+            let imm = ((pcrel >> 12) & 1) << 31 | ((pcrel >> 5) & 0x3f) << 25 |
+                ((pcrel >> 1) & 0xf) << 8 | ((pcrel >> 11) & 1) << 7;
+            ((insn & 0x01fff07f) | imm) as u64
+        },
+        R_RISCV_JAL => {
+            if !fits(pcrel, 21) {
+                return Err(ApplyError::Overflow);
+            }
+            let imm = ((pcrel >> 20) & 1) << 31 | ((pcrel >> 1) & 0x3ff) << 21 |
+                ((pcrel >> 11) & 1) << 20 | ((pcrel >> 12) & 0xff) << 12;
+            ((insn & 0xfff) | imm) as u64
+        },
+        R_RISCV_CALL | R_RISCV_CALL_PLT => {
+            // an auipc and a jalr
+            let auipc = set_hi20(insn, pcrel);
+            let jalr = set_lo12_i((old >> 32) as u32, pcrel);
+            auipc as u64 | (jalr as u64) << 32
+        },
+        _ => return Err(ApplyError::Unsupported),
+    };
+
+    bytes.copy_from_slice(&new.to_le_bytes()[..size]);
+    Ok(())
+}
+
+/// Set the immediate of a U-type instruction to the upper part of `value`,
+/// rounded for the sign extended lower 12 bits added to it
+fn set_hi20(insn: u32, value: u32) -> u32 {
+    (insn & 0xfff) | (value.wrapping_add(0x800) & 0xfffff000)
+}
+
+/// Set the immediate of an I-type instruction to the lower 12 bits of `value`
+fn set_lo12_i(insn: u32, value: u32) -> u32 {
+    (insn & 0x000fffff) | (value & 0xfff) << 20
+}
+
+/// Set the immediate of an S-type instruction to the lower 12 bits of `value`
+fn set_lo12_s(insn: u32, value: u32) -> u32 {
+    (insn & 0x01fff07f) | (value & 0x1f) << 7 | ((value >> 5) & 0x7f) << 25
+}
+
+/// Does `value` fit in a `bits` wide signed immediate
+fn fits(value: u32, bits: u32) -> bool {
+    let value = value as i32;
+    let half = 1 << (bits - 1);
+    (-half..half).contains(&value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let apply = |insn: u32, typ, value, pc| {
+            let mut data = insn.to_le_bytes();
+            apply(&mut data, 0, typ, value, pc, &HashMap::new()).map(|()| u32::from_le_bytes(data))
+        };
+
+        // lui a0, %hi(0x12345fff) and addi a0, a0, %lo(0x12345fff)
+        assert_eq!(apply(0x00000537, R_RISCV_HI20, 0x12345fff, 0).unwrap(), 0x12346537);
+        assert_eq!(apply(0x00050513, R_RISCV_LO12_I, 0x12345fff, 0).unwrap(), 0xfff50513);
+        // sw a1, %lo(0x10804)(a0)
+        assert_eq!(apply(0x00b52023, R_RISCV_LO12_S, 0x10804, 0).unwrap(), 0x80b52223);
+
+        // beq a0, a1, and jal ra, 0x800 bytes back
+        assert_eq!(apply(0x00b50063, R_RISCV_BRANCH, 0x10000, 0x10800).unwrap(), 0x80b500e3);
+        assert_eq!(apply(0x000000ef, R_RISCV_JAL, 0x10000, 0x10800).unwrap(), 0x801ff0ef);
+        assert!(matches!(apply(0x00b50063, R_RISCV_BRANCH, 0x20000, 0x10000),
+            Err(ApplyError::Overflow)));
+
+        // auipc ra, 0 and jalr ra, 0(ra)
+        let mut call = [0x00000097u32.to_le_bytes(), 0x000080e7u32.to_le_bytes()].concat();
+        super::apply(&mut call, 0, R_RISCV_CALL, 0x10ffc, 0x10000, &HashMap::new()).unwrap();
+        assert_eq!(call, [0x00001097u32.to_le_bytes(), 0xffc080e7u32.to_le_bytes()].concat());
+
+        // the %pcrel_lo of a %pcrel_hi at 0x10000
+        let pcrel_hi = HashMap::from([(0x10000, 0x1234)]);
+        let mut data = 0x00050513u32.to_le_bytes();
+        super::apply(&mut data, 0, R_RISCV_PCREL_LO12_I, 0x10000, 0x10004, &pcrel_hi).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x23450513);
+    }
+}
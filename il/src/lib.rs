@@ -214,7 +214,7 @@ pub fn print_function(name: &str, fun: &Function) {
             use Instr::*;
             match instr {
                 Phi { assignments } => {
-                    out += &"phi ";
+                    out += "phi ";
                     for (block, var) in assignments {
                         out += &format!("[ {}: {} ] ", block, var);
                     }
@@ -264,6 +264,12 @@ pub struct BlockGen {
     current: u32,
 }
 
+impl Default for BlockGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BlockGen {
     /// Create a new [`BlockId`] generator
     pub fn new() -> Self {
@@ -273,6 +279,7 @@ impl BlockGen {
     }
 
     /// Get the next free [`BlockId`]
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> BlockId {
         let ret = BlockId(self.current);
         self.current += 1;
@@ -286,6 +293,12 @@ pub struct NameGen {
     current: u32,
 }
 
+impl Default for NameGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NameGen {
     /// Create a new [`name`] generator
     pub fn new() -> Self {
@@ -295,6 +308,7 @@ impl NameGen {
     }
 
     /// Get the next available [`Name`]
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Name {
         let ret = Name(self.current);
         self.current += 1;
@@ -346,7 +360,7 @@ fn test() {
     };
 
     print_function("max", &max);
-    println!("");
+    println!();
 
     let mut bg = BlockGen::new();
     let mut ng = NameGen::new();
@@ -396,7 +410,7 @@ fn test() {
     };
 
     print_function("write10", &write10);
-    println!("");
+    println!();
 
     let mut bg = BlockGen::new();
     let mut ng = NameGen::new();
@@ -465,5 +479,5 @@ fn test() {
     };
 
     print_function("memcpy", &memcpy);
-    println!("");
+    println!();
 }
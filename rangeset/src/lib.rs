@@ -6,7 +6,7 @@ pub enum Error {
 }
 
 /// Set of ranges
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RangeSet {
     /// ranges that are in the set
     /// (inclusive, exclusive)
@@ -74,42 +74,36 @@ impl RangeSet {
         Ok((start, start + size))
     }
 
-    /// Insert a range into the set
+    /// Insert a range into the set, merging it with any ranges it overlaps
+    /// or touches
     ///
-    pub fn insert(&mut self, _start: u32, _end: u32) -> Result<(), Error> {
-        // find the place to insert the range
-        //
-        // New range cases:
-        // - a. end is before the start
-        //     new: <   >
-        //     set:       <    >    <   > ...
-        // - b. range is after all other ranges
-        //     new:                 <   >
-        //     set: ... <    > <   >
-        //
-        // Merge cases:
-        // - c. start before start, end after start
-        //     new: <   >
-        //     set:     <    >    <   > ...
-        //
-        // - d. start is before the end
-        //     new:      <   >
-        //     set: <    > ...
+    pub fn insert(&mut self, start: u32, end: u32) -> Result<(), Error> {
+        if start >= end {
+            return Err(Error::BadRange);
+        }
+
+        // the ranges are sorted and disjoint, find the ones the new range
+        // overlaps or touches:
         //
-        // - e. new spans many ranges
-        //     new: <                 >
-        //     set:    < >   < >    <    >
-
-        /*
-        let place = self.ranges.iter_mut().enumerate()
-            .find(|(ii, &mut range)|
-                // case a and c
-                end <= range.0 ||
-                // case d
-                start <= range.1);
-        */
-
-        todo!("implement this :)");
+        //     new:      <         >
+        //     set: < > <  >   < >   <   >   < >
+        //              ^first         ^last
+        let first = self.ranges.iter()
+            .position(|range| range.1 >= start)
+            .unwrap_or(self.ranges.len());
+        let last = self.ranges[first..].iter()
+            .position(|range| range.0 > end)
+            .map_or(self.ranges.len(), |ii| first + ii);
+
+        let mut new = (start, end);
+        if first < last {
+            new.0 = new.0.min(self.ranges[first].0);
+            new.1 = new.1.max(self.ranges[last - 1].1);
+        }
+
+        self.ranges.splice(first..last, [new]);
+
+        Ok(())
     }
 }
 
@@ -146,4 +140,21 @@ mod test {
         assert!(rs.remove_first_fit(12).is_ok());
         println!("{rs:?}");
     }
+
+    #[test]
+    fn insert() {
+        use super::*;
+
+        let mut rs = RangeSet::new(0, 1024);
+        assert!(rs.remove(0, 1024).is_ok());
+        assert!(rs.insert(10, 20).is_ok());
+        assert!(rs.insert(30, 40).is_ok());
+        assert!(rs.insert(0, 5).is_ok());
+        assert_eq!(rs.ranges, [(0, 5), (10, 20), (30, 40)]);
+        assert!(rs.insert(20, 30).is_ok());
+        assert_eq!(rs.ranges, [(0, 5), (10, 40)]);
+        assert!(rs.insert(3, 50).is_ok());
+        assert_eq!(rs.ranges, [(0, 50)]);
+        assert!(rs.insert(5, 5).is_err());
+    }
 }
@@ -0,0 +1,529 @@
+//! Assembler for RV32I
+//!
+//! Takes GNU style assembly, one statement per line:
+//!
+//! ```text
+//! loop:                   # labels end with a colon
+//!     addi a0, a0, -1     # instructions, x or abi register names
+//!     sw a0, 8(sp)        # loads and stores take offset(register)
+//!     bnez a0, loop       # branch and jump targets are labels or addresses
+//!     .word 0x12345678    # .word and .byte emit data
+//! ```
+//!
+//! Besides the RV32I instructions it knows the pseudo-instructions `li`, `la`,
+//! `mv`, `j`, `call`, `ret`, `nop`, `not`, `neg`, `jr`, `beqz` and `bnez`.
+
+use std::collections::HashMap;
+
+use crate::instructions::*;
+
+/// Error assembling a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub msg: String,
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// Assemble `text` to be loaded at `addr`
+pub fn assemble(addr: u32, text: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with_labels(addr, text).map(|(code, _)| code)
+}
+
+/// Like [`assemble`], also returning the address of every label
+pub fn assemble_with_labels(addr: u32, text: &str) -> Result<(Vec<u8>, HashMap<String, u32>), AsmError> {
+    let mut asm = Assembler { labels: HashMap::new(), line: 0, pc: addr };
+
+    // split lines into labels and statements
+    let mut statements = Vec::new();
+    for (ii, line) in text.lines().enumerate() {
+        asm.line = ii + 1;
+
+        let mut line = line.split('#').next().unwrap().trim();
+        while let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if !is_symbol(label) {
+                break;
+            }
+            if asm.labels.insert(label, asm.pc).is_some() {
+                return Err(asm.error(format!("label {label} defined twice")));
+            }
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operands: Vec<&str> = if operands.trim().is_empty() {
+            Vec::new()
+        } else {
+            operands.split(',').map(str::trim).collect()
+        };
+
+        let size = asm.size(mnemonic, &operands)?;
+        statements.push((asm.line, asm.pc, mnemonic, operands));
+        asm.pc = asm.pc.wrapping_add(size);
+    }
+
+    // now that all labels are known, emit the code
+    let mut code = Vec::new();
+    for (line, pc, mnemonic, operands) in statements {
+        asm.line = line;
+        asm.pc = pc;
+        asm.statement(mnemonic, &operands, &mut code)?;
+    }
+
+    let labels = asm.labels.into_iter().map(|(label, addr)| (label.to_string(), addr)).collect();
+    Ok((code, labels))
+}
+
+/// Is `name` usable as a label
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Split `value` into the upper immediate and the sign extended lower 12
+/// bits that add up to it, as for `lui`/`auipc` followed by `addi`
+fn split_imm(value: u32) -> (u32, u32) {
+    let lo = ((value << 20) as i32 >> 20) as u32;
+    (value.wrapping_sub(lo), lo)
+}
+
+/// Instructions loading `value` into `rd`
+fn li(rd: Reg, value: u32) -> Vec<Instruction> {
+    let (hi, lo) = split_imm(value);
+    let zero = RegName::Zero.as_reg();
+
+    if hi == 0 {
+        vec![Instruction::Addi { rd, rs1: zero, imm: lo }]
+    } else if lo == 0 {
+        vec![Instruction::Lui { rd, imm: hi }]
+    } else {
+        vec![Instruction::Lui { rd, imm: hi }, Instruction::Addi { rd, rs1: rd, imm: lo }]
+    }
+}
+
+struct Assembler<'a> {
+    /// Address of every label
+    labels: HashMap<&'a str, u32>,
+
+    /// Line being assembled, for errors
+    line: usize,
+
+    /// Address of the statement being assembled
+    pc: u32,
+}
+
+impl<'a> Assembler<'a> {
+    fn error(&self, msg: String) -> AsmError {
+        AsmError { line: self.line, msg }
+    }
+
+    /// Number of bytes `mnemonic` with `operands` assembles to
+    fn size(&self, mnemonic: &str, operands: &[&str]) -> Result<u32, AsmError> {
+        Ok(match mnemonic {
+            ".word" => 4 * operands.len() as u32,
+            ".byte" => operands.len() as u32,
+            "la" | "call" => 8,
+            "li" => {
+                let [rd, value] = self.operands(operands)?;
+                4 * li(self.reg(rd)?, self.value(value)?).len() as u32
+            },
+            _ => 4,
+        })
+    }
+
+    /// Assemble a statement onto `code`
+    fn statement(&self, mnemonic: &str, operands: &[&str], code: &mut Vec<u8>) -> Result<(), AsmError> {
+        match mnemonic {
+            ".word" => {
+                for &value in operands {
+                    let value = self.value(value)?;
+                    code.extend(value.to_le_bytes());
+                }
+            },
+            ".byte" => {
+                for &value in operands {
+                    let value = self.value(value)?;
+                    if value > 0xff && value < -0x80i32 as u32 {
+                        return Err(self.error(format!("{value:#x} doesn't fit in a byte")));
+                    }
+                    code.push(value as u8);
+                }
+            },
+            _ => {
+                for instr in self.instruction(mnemonic, operands)? {
+                    code.extend(encode(instr).to_le_bytes());
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Assemble an instruction or pseudo-instruction
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Vec<Instruction>, AsmError> {
+        let zero = RegName::Zero.as_reg();
+        let ra = RegName::Ra.as_reg();
+
+        let instr = match mnemonic {
+            "lui" | "auipc" => {
+                let [rd, imm] = self.operands(operands)?;
+                let rd = self.reg(rd)?;
+                let imm = self.value(imm)?;
+                if imm >= 1 << 20 {
+                    return Err(self.error(format!("{imm:#x} doesn't fit in 20 bits")));
+                }
+                match mnemonic {
+                    "lui" => Instruction::Lui { rd, imm: imm << 12 },
+                    _ => Instruction::Auipc { rd, imm: imm << 12 },
+                }
+            },
+            "jal" => {
+                let (rd, target) = match *operands {
+                    [target] => (ra, target),
+                    [rd, target] => (self.reg(rd)?, target),
+                    _ => return Err(self.error("expected 1 or 2 operands".into())),
+                };
+                Instruction::Jal { rd, imm: self.offset(target, 21)? }
+            },
+            "jalr" => {
+                let (rd, rs1, imm) = match *operands {
+                    [rs1] => (ra, self.reg(rs1)?, 0),
+                    [rd, mem] if mem.ends_with(')') => {
+                        let (rs1, imm) = self.mem(mem)?;
+                        (self.reg(rd)?, rs1, imm)
+                    },
+                    [rd, rs1] => (self.reg(rd)?, self.reg(rs1)?, 0),
+                    [rd, rs1, imm] => (self.reg(rd)?, self.reg(rs1)?, self.imm(imm, 12)?),
+                    _ => return Err(self.error("expected 1 to 3 operands".into())),
+                };
+                Instruction::Jalr { rd, rs1, imm }
+            },
+
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                let [rs1, rs2, target] = self.operands(operands)?;
+                let (rs1, rs2) = (self.reg(rs1)?, self.reg(rs2)?);
+                let imm = self.offset(target, 13)?;
+                match mnemonic {
+                    "beq" => Instruction::Beq { rs1, rs2, imm },
+                    "bne" => Instruction::Bne { rs1, rs2, imm },
+                    "blt" => Instruction::Blt { rs1, rs2, imm },
+                    "bge" => Instruction::Bge { rs1, rs2, imm },
+                    "bltu" => Instruction::Bltu { rs1, rs2, imm },
+                    _ => Instruction::Bgeu { rs1, rs2, imm },
+                }
+            },
+
+            "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+                let [rd, mem] = self.operands(operands)?;
+                let rd = self.reg(rd)?;
+                let (rs1, imm) = self.mem(mem)?;
+                match mnemonic {
+                    "lb" => Instruction::Lb { rd, rs1, imm },
+                    "lh" => Instruction::Lh { rd, rs1, imm },
+                    "lw" => Instruction::Lw { rd, rs1, imm },
+                    "lbu" => Instruction::Lbu { rd, rs1, imm },
+                    _ => Instruction::Lhu { rd, rs1, imm },
+                }
+            },
+
+            "sb" | "sh" | "sw" => {
+                let [rs2, mem] = self.operands(operands)?;
+                let rs2 = self.reg(rs2)?;
+                let (rs1, imm) = self.mem(mem)?;
+                match mnemonic {
+                    "sb" => Instruction::Sb { rs1, rs2, imm },
+                    "sh" => Instruction::Sh { rs1, rs2, imm },
+                    _ => Instruction::Sw { rs1, rs2, imm },
+                }
+            },
+
+            "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" => {
+                let [rd, rs1, imm] = self.operands(operands)?;
+                let (rd, rs1) = (self.reg(rd)?, self.reg(rs1)?);
+                let imm = self.imm(imm, 12)?;
+                match mnemonic {
+                    "addi" => Instruction::Addi { rd, rs1, imm },
+                    "slti" => Instruction::Slti { rd, rs1, imm },
+                    "sltiu" => Instruction::Sltiu { rd, rs1, imm },
+                    "xori" => Instruction::Xori { rd, rs1, imm },
+                    "ori" => Instruction::Ori { rd, rs1, imm },
+                    _ => Instruction::Andi { rd, rs1, imm },
+                }
+            },
+
+            "slli" | "srli" | "srai" => {
+                let [rd, rs1, shamt] = self.operands(operands)?;
+                let (rd, rs1) = (self.reg(rd)?, self.reg(rs1)?);
+                let shamt = self.value(shamt)?;
+                if shamt >= 32 {
+                    return Err(self.error(format!("shift amount {shamt} out of range")));
+                }
+                match mnemonic {
+                    "slli" => Instruction::Slli { rd, rs1, shamt },
+                    "srli" => Instruction::Srli { rd, rs1, shamt },
+                    _ => Instruction::Srai { rd, rs1, shamt },
+                }
+            },
+
+            "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" => {
+                let [rd, rs1, rs2] = self.operands(operands)?;
+                let (rd, rs1, rs2) = (self.reg(rd)?, self.reg(rs1)?, self.reg(rs2)?);
+                match mnemonic {
+                    "add" => Instruction::Add { rd, rs1, rs2 },
+                    "sub" => Instruction::Sub { rd, rs1, rs2 },
+                    "sll" => Instruction::Sll { rd, rs1, rs2 },
+                    "slt" => Instruction::Slt { rd, rs1, rs2 },
+                    "sltu" => Instruction::Sltu { rd, rs1, rs2 },
+                    "xor" => Instruction::Xor { rd, rs1, rs2 },
+                    "srl" => Instruction::Srl { rd, rs1, rs2 },
+                    "sra" => Instruction::Sra { rd, rs1, rs2 },
+                    "or" => Instruction::Or { rd, rs1, rs2 },
+                    _ => Instruction::And { rd, rs1, rs2 },
+                }
+            },
+
+            "fence" => Instruction::Fence,
+            "ecall" => Instruction::Ecall,
+            "ebreak" => Instruction::Ebreak,
+
+            // pseudo-instructions
+            "nop" => {
+                let [] = self.operands(operands)?;
+                Instruction::Addi { rd: zero, rs1: zero, imm: 0 }
+            },
+            "li" => {
+                let [rd, value] = self.operands(operands)?;
+                return Ok(li(self.reg(rd)?, self.value(value)?));
+            },
+            "la" | "call" => {
+                let (rd, target) = match mnemonic {
+                    "la" => {
+                        let [rd, target] = self.operands(operands)?;
+                        (self.reg(rd)?, target)
+                    },
+                    _ => {
+                        let [target] = self.operands(operands)?;
+                        (ra, target)
+                    },
+                };
+                let (hi, lo) = split_imm(self.value(target)?.wrapping_sub(self.pc));
+                let second = match mnemonic {
+                    "la" => Instruction::Addi { rd, rs1: rd, imm: lo },
+                    _ => Instruction::Jalr { rd, rs1: rd, imm: lo },
+                };
+                return Ok(vec![Instruction::Auipc { rd, imm: hi }, second]);
+            },
+            "mv" => {
+                let [rd, rs1] = self.operands(operands)?;
+                Instruction::Addi { rd: self.reg(rd)?, rs1: self.reg(rs1)?, imm: 0 }
+            },
+            "not" => {
+                let [rd, rs1] = self.operands(operands)?;
+                Instruction::Xori { rd: self.reg(rd)?, rs1: self.reg(rs1)?, imm: !0 }
+            },
+            "neg" => {
+                let [rd, rs2] = self.operands(operands)?;
+                Instruction::Sub { rd: self.reg(rd)?, rs1: zero, rs2: self.reg(rs2)? }
+            },
+            "j" => {
+                let [target] = self.operands(operands)?;
+                Instruction::Jal { rd: zero, imm: self.offset(target, 21)? }
+            },
+            "jr" => {
+                let [rs1] = self.operands(operands)?;
+                Instruction::Jalr { rd: zero, rs1: self.reg(rs1)?, imm: 0 }
+            },
+            "ret" => {
+                let [] = self.operands(operands)?;
+                Instruction::Jalr { rd: zero, rs1: ra, imm: 0 }
+            },
+            "beqz" | "bnez" => {
+                let [rs1, target] = self.operands(operands)?;
+                let rs1 = self.reg(rs1)?;
+                let imm = self.offset(target, 13)?;
+                match mnemonic {
+                    "beqz" => Instruction::Beq { rs1, rs2: zero, imm },
+                    _ => Instruction::Bne { rs1, rs2: zero, imm },
+                }
+            },
+
+            _ => return Err(self.error(format!("unknown instruction {mnemonic}"))),
+        };
+
+        Ok(vec![instr])
+    }
+
+    /// Check there are exactly `N` operands
+    fn operands<'b, const N: usize>(&self, operands: &[&'b str]) -> Result<[&'b str; N], AsmError> {
+        operands.try_into()
+            .map_err(|_| self.error(format!("expected {N} operands, found {}", operands.len())))
+    }
+
+    fn reg(&self, name: &str) -> Result<Reg, AsmError> {
+        if name == "fp" {
+            return Ok(RegName::S0.as_reg());
+        }
+        (0..32).map(Reg)
+            .find(|reg| reg.name() == name || reg.abi_name() == name)
+            .ok_or_else(|| self.error(format!("unknown register {name}")))
+    }
+
+    /// A number or the address of a label
+    fn value(&self, value: &str) -> Result<u32, AsmError> {
+        if let Some(&addr) = self.labels.get(value) {
+            return Ok(addr);
+        }
+
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value),
+        };
+        let num = match digits.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => digits.parse(),
+        };
+
+        match num {
+            Ok(num) if !negative => Ok(num),
+            Ok(num) if num <= 1 << 31 => Ok(num.wrapping_neg()),
+            _ if is_symbol(value) => Err(self.error(format!("unknown label {value}"))),
+            _ => Err(self.error(format!("invalid number {value}"))),
+        }
+    }
+
+    /// A signed immediate of `bits` bits
+    fn imm(&self, imm: &str, bits: u32) -> Result<u32, AsmError> {
+        let value = self.value(imm)?;
+        let fits = (value as i32) >> (bits - 1);
+        if fits != 0 && fits != -1 {
+            return Err(self.error(format!("{imm} doesn't fit in {bits} bits")));
+        }
+        Ok(value)
+    }
+
+    /// Offset from the current instruction to the label or address `target`,
+    /// as a `bits` bit immediate
+    fn offset(&self, target: &str, bits: u32) -> Result<u32, AsmError> {
+        let offset = self.value(target)?.wrapping_sub(self.pc);
+        let fits = (offset as i32) >> (bits - 1);
+        if fits != 0 && fits != -1 {
+            return Err(self.error(format!("{target} is out of range")));
+        }
+        if offset % 2 != 0 {
+            return Err(self.error(format!("{target} is misaligned")));
+        }
+        Ok(offset)
+    }
+
+    /// A memory operand, `offset(reg)`
+    fn mem(&self, mem: &str) -> Result<(Reg, u32), AsmError> {
+        let (imm, reg) = mem.strip_suffix(')')
+            .and_then(|mem| mem.split_once('('))
+            .ok_or_else(|| self.error(format!("expected offset(register), found {mem}")))?;
+
+        let imm = if imm.trim().is_empty() { 0 } else { self.imm(imm.trim(), 12)? };
+        Ok((self.reg(reg.trim())?, imm))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(code: &[u8]) -> Vec<u32> {
+        code.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn instructions() {
+        let code = assemble(0x10000, "
+            addi a0, a0, -1
+            sw a0, 8(sp)
+            lbu a2, 0x7ff(a1)
+            jalr ra, -8(ra)
+            slli a0, a0, 10
+            sub a0, a0, a1
+            lui t0, 0x12
+            ecall
+        ").unwrap();
+        assert_eq!(words(&code), [
+            0xfff50513, 0x00a12423, 0x7ff5c603, 0xff8080e7,
+            0x00a51513, 0x40b50533, 0x000122b7, 0x00000073,
+        ]);
+    }
+
+    #[test]
+    fn labels_and_pseudo_instructions() {
+        let code = assemble(0x10000, "
+            start:
+                li a0, 10
+                li a1, 0x12345678
+                la a2, data
+            loop: addi a0, a0, -1
+                bnez a0, loop
+                call func
+                j start
+            func:
+                mv a0, a1
+                nop
+                ret
+            data:
+                .word 0xdeadbeef, data
+                .byte 1, 2, -1
+        ").unwrap();
+
+        let mut expected = Vec::new();
+        for instr in [
+            // li a0, 10
+            Instruction::Addi { rd: Reg(10), rs1: Reg(0), imm: 10 },
+            // li a1, 0x12345678
+            Instruction::Lui { rd: Reg(11), imm: 0x12345000 },
+            Instruction::Addi { rd: Reg(11), rs1: Reg(11), imm: 0x678 },
+            // la a2, data
+            Instruction::Auipc { rd: Reg(12), imm: 0 },
+            Instruction::Addi { rd: Reg(12), rs1: Reg(12), imm: 0x28 },
+            // loop
+            Instruction::Addi { rd: Reg(10), rs1: Reg(10), imm: -1i32 as u32 },
+            Instruction::Bne { rs1: Reg(10), rs2: Reg(0), imm: -4i32 as u32 },
+            // call func
+            Instruction::Auipc { rd: Reg(1), imm: 0 },
+            Instruction::Jalr { rd: Reg(1), rs1: Reg(1), imm: 12 },
+            // j start
+            Instruction::Jal { rd: Reg(0), imm: -36i32 as u32 },
+            // func
+            Instruction::Addi { rd: Reg(10), rs1: Reg(11), imm: 0 },
+            Instruction::Addi { rd: Reg(0), rs1: Reg(0), imm: 0 },
+            Instruction::Jalr { rd: Reg(0), rs1: Reg(1), imm: 0 },
+        ] {
+            expected.extend(encode(instr).to_le_bytes());
+        }
+        expected.extend(0xdeadbeefu32.to_le_bytes());
+        expected.extend(0x10034u32.to_le_bytes());
+        expected.extend([1, 2, 0xff]);
+
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text| assemble(0, text).unwrap_err();
+
+        assert_eq!(err("nop\nfoo a0"), AsmError { line: 2, msg: "unknown instruction foo".into() });
+        assert_eq!(err("addi a0, a0, 2048").msg, "2048 doesn't fit in 12 bits");
+        assert_eq!(err("add a0, a1").msg, "expected 3 operands, found 2");
+        assert_eq!(err("mv a0, x32").msg, "unknown register x32");
+        assert_eq!(err("j nowhere").msg, "unknown label nowhere");
+        assert_eq!(err("a: nop\na: nop").msg, "label a defined twice");
+    }
+}
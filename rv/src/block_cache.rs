@@ -0,0 +1,119 @@
+//! Cache of decoded basic blocks
+//!
+//! The interpreter would otherwise fetch every instruction with a permission
+//! check and decode it again every time it runs. Instead straight line runs of
+//! instructions are fetched and decoded once, the first time execution
+//! reaches them, and kept until memory they were fetched from is modified.
+
+use std::collections::HashMap;
+
+use crate::emulator::*;
+use crate::instructions::*;
+
+/// Maximum number of instructions in a block
+pub const MAX_BLOCK_LEN: usize = 64;
+
+/// Straight line run of decoded instructions
+#[derive(Debug, Clone)]
+struct Block {
+    /// Raw and decoded instructions, invalid ones always end the block
+    instrs: Vec<(u32, Result<Instruction, DecodeError>)>,
+
+    /// Blocks recently jumped to from the end of this block, by address, so
+    /// that hot edges skip the lookup in [`BlockCache::index`]
+    links: [Option<(u32, usize)>; 2],
+}
+
+/// Decoded blocks of the code the guest has run
+///
+/// Memory the blocks were fetched from is marked with [`Memory::mark_code`],
+/// the owner must [`BlockCache::flush`] the cache when
+/// [`Memory::code_modified`] says it is stale.
+///
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+
+    /// Index into `blocks` of the block starting at each address
+    index: HashMap<u32, usize>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget all blocks
+    pub fn flush(&mut self, mem: &mut Memory) {
+        self.blocks.clear();
+        self.index.clear();
+        mem.clear_code();
+    }
+
+    /// Find the block starting at `pc`, decoding it if it isn't cached
+    ///
+    /// `from` is the block execution is coming from, whose links are followed
+    /// and updated. Fails like fetching `pc` from `mem` with [`PERM_EXEC`]
+    /// would.
+    pub fn lookup(&mut self, mem: &mut Memory, from: Option<usize>, pc: u32) -> Result<usize, MemoryError> {
+        if let Some(from) = from {
+            let link = self.blocks[from].links.iter().flatten()
+                .find(|&&(target, _)| target == pc);
+            if let Some(&(_, block)) = link {
+                return Ok(block);
+            }
+        }
+
+        let block = match self.index.get(&pc) {
+            Some(&block) => block,
+            None => self.translate(mem, pc)?,
+        };
+
+        if let Some(from) = from {
+            let links = &mut self.blocks[from].links;
+            if links[0].is_none() {
+                links[0] = Some((pc, block));
+            } else {
+                links[1] = Some((pc, block));
+            }
+        }
+
+        Ok(block)
+    }
+
+    /// The raw and decoded instructions of `block`
+    pub fn instrs(&self, block: usize) -> &[(u32, Result<Instruction, DecodeError>)] {
+        &self.blocks[block].instrs
+    }
+
+    /// Fetch and decode the block starting at `pc`
+    fn translate(&mut self, mem: &mut Memory, pc: u32) -> Result<usize, MemoryError> {
+        let mut instrs = Vec::new();
+        let mut addr = pc;
+
+        while instrs.len() < MAX_BLOCK_LEN {
+            let instr = match mem.read_u32(addr, PERM_EXEC) {
+                Ok(instr) => instr,
+                Err(err) if instrs.is_empty() => return Err(err),
+                // fault once execution gets here
+                Err(_) => break,
+            };
+
+            let op = decode(instr);
+            instrs.push((instr, op));
+            addr += 4;
+
+            if op.map_or(true, Instruction::ends_block) {
+                break;
+            }
+        }
+
+        mem.mark_code(pc..addr);
+
+        let block = self.blocks.len();
+        self.blocks.push(Block { instrs, links: [None; 2] });
+        self.index.insert(pc, block);
+
+        Ok(block)
+    }
+}
@@ -0,0 +1,448 @@
+//! Control flow graph recovery
+//!
+//! Functions are found from the symbols in an ELF, its entry point and the
+//! targets of calls in the code reached from them. Each function is split
+//! into basic blocks at branches, jumps, calls and the targets of branches
+//! and jumps. Indirect jumps other than returns can't be followed statically
+//! and end their block with [`Exit::Unresolved`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use elf::{Elf, STT_FUNC, STT_NOTYPE};
+
+use crate::disassemble::*;
+use crate::instructions::*;
+
+/// Executable memory to recover control flow from
+#[derive(Debug, Clone, Default)]
+pub struct Code<'a> {
+    /// Start address and contents of each region
+    regions: Vec<(u32, &'a [u8])>,
+}
+
+impl<'a> Code<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The executable sections of `elf`, see [`code_regions`]
+    pub fn from_elf(elf: &'a Elf) -> Self {
+        let mut code = Self::new();
+        for (_, addr, bytes) in code_regions(elf) {
+            code.add(addr, bytes);
+        }
+        code
+    }
+
+    /// Add `bytes` of code loaded at `addr`
+    pub fn add(&mut self, addr: u32, bytes: &'a [u8]) {
+        self.regions.push((addr, bytes));
+    }
+
+    /// Is `addr` in the code
+    pub fn contains(&self, addr: u32) -> bool {
+        self.fetch(addr).is_some()
+    }
+
+    /// The instruction at `addr`
+    pub fn fetch(&self, addr: u32) -> Option<u32> {
+        self.regions.iter().find_map(|&(start, bytes)| {
+            let offset = addr.checked_sub(start)? as usize;
+            let word = bytes.get(offset..offset.checked_add(4)?)?;
+            Some(u32::from_le_bytes(word.try_into().unwrap()))
+        })
+    }
+}
+
+/// How execution leaves a basic block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Runs into the next block, which something else jumps to
+    Fallthrough,
+
+    /// Unconditional jump
+    Jump(u32),
+
+    /// Conditional branch to the target, or on to the next block
+    Branch(u32),
+
+    /// Call to the target, `None` for indirect calls, returning to the next
+    /// block
+    Call(Option<u32>),
+
+    /// Jump to the start of another function
+    TailCall(u32),
+
+    Return,
+
+    /// Indirect jump whose targets aren't known
+    Unresolved,
+
+    /// Invalid instruction, or running off the end of the code
+    Invalid,
+}
+
+/// Straight line code with a single entry and exit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Address of the first instruction
+    pub start: u32,
+
+    /// Address after the last instruction
+    pub end: u32,
+
+    pub exit: Exit,
+
+    /// Blocks in the same function execution can continue in
+    pub succs: Vec<u32>,
+
+    /// Blocks in the same function that can continue here
+    pub preds: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Symbol name, or `sub_<addr>` for functions only found as call targets
+    pub name: String,
+
+    pub entry: u32,
+
+    /// Basic blocks by start address
+    pub blocks: BTreeMap<u32, BasicBlock>,
+}
+
+/// Control flow graph of all the functions that were found
+#[derive(Debug, Clone, Default)]
+pub struct Cfg {
+    /// Functions by entry point
+    pub functions: BTreeMap<u32, Function>,
+}
+
+/// How control leaves the instruction `instr` at `pc`, `None` if it just
+/// goes on to the next one
+///
+/// `prev` is the instruction before `instr`, which resolves the target of a
+/// `jalr` after an `auipc` of its base register, as in `call` and `tail`.
+fn exit(pc: u32, instr: Result<Instruction, DecodeError>, prev: Option<(u32, Instruction)>,
+    entry: u32, entries: &BTreeMap<u32, String>) -> Option<Exit>
+{
+    let zero = RegName::Zero.as_reg();
+    let ra = RegName::Ra.as_reg();
+
+    // jumping to the start of another function is a tail call
+    let jump = |target| if target != entry && entries.contains_key(&target) {
+        Exit::TailCall(target)
+    } else {
+        Exit::Jump(target)
+    };
+
+    let exit = match instr {
+        Err(_) => Exit::Invalid,
+        Ok(Instruction::Jal { rd, imm }) if rd == zero => jump(pc.wrapping_add(imm)),
+        Ok(Instruction::Jal { imm, .. }) => Exit::Call(Some(pc.wrapping_add(imm))),
+        Ok(Instruction::Jalr { rd, rs1, imm }) => {
+            let target = match prev {
+                Some((prev_pc, Instruction::Auipc { rd, imm: hi })) if rd == rs1 =>
+                    Some(prev_pc.wrapping_add(hi).wrapping_add(imm)),
+                _ => None,
+            };
+            match (rd == zero, target) {
+                (true, _) if rs1 == ra && imm == 0 => Exit::Return,
+                (true, Some(target)) => jump(target),
+                (true, None) => Exit::Unresolved,
+                (false, target) => Exit::Call(target),
+            }
+        },
+        Ok(Instruction::Beq { imm, .. } | Instruction::Bne { imm, .. } |
+            Instruction::Blt { imm, .. } | Instruction::Bge { imm, .. } |
+            Instruction::Bltu { imm, .. } | Instruction::Bgeu { imm, .. }) =>
+            Exit::Branch(pc.wrapping_add(imm)),
+        Ok(_) => return None,
+    };
+
+    Some(exit)
+}
+
+impl Function {
+    /// Find the blocks of the function at `entry`, and the functions it
+    /// calls
+    fn recover(code: &Code, entry: u32, entries: &BTreeMap<u32, String>) -> (Function, Vec<u32>) {
+        let mut instrs = BTreeMap::new();
+        let mut leaders = BTreeSet::from([entry]);
+        let mut calls = vec![];
+
+        // follow all paths through the function, every jump, branch and
+        // call return target starts a block
+        let mut work = vec![entry];
+        while let Some(mut pc) = work.pop() {
+            let mut prev = None;
+            while !instrs.contains_key(&pc) {
+                let Some(word) = code.fetch(pc) else { break };
+                let instr = decode(word);
+                instrs.insert(pc, instr);
+
+                let next = pc.wrapping_add(4);
+                match exit(pc, instr, prev, entry, entries) {
+                    None => {
+                        prev = instr.ok().map(|instr| (pc, instr));
+                        pc = next;
+                        continue;
+                    },
+                    Some(Exit::Jump(target)) => {
+                        leaders.insert(target);
+                        work.push(target);
+                    },
+                    Some(Exit::Branch(target)) => {
+                        leaders.extend([target, next]);
+                        work.extend([target, next]);
+                    },
+                    Some(Exit::Call(target)) => {
+                        calls.extend(target);
+                        leaders.insert(next);
+                        work.push(next);
+                    },
+                    Some(Exit::TailCall(target)) => calls.push(target),
+                    Some(_) => (),
+                }
+                break;
+            }
+        }
+
+        // split the instructions into blocks
+        let mut blocks = BTreeMap::new();
+        let mut start = None;
+        let mut prev: Option<(u32, Instruction)> = None;
+        let mut last = 0u32;
+        for (&pc, &instr) in &instrs {
+            if let Some(block_start) = start {
+                if last.wrapping_add(4) != pc {
+                    // the code after the block couldn't be fetched
+                    blocks.insert(block_start, (last.wrapping_add(4), Exit::Invalid));
+                    start = None;
+                } else if leaders.contains(&pc) {
+                    blocks.insert(block_start, (pc, Exit::Fallthrough));
+                    start = None;
+                }
+            }
+            if start.is_none() {
+                start = Some(pc);
+                prev = None;
+            }
+
+            if let Some(exit) = exit(pc, instr, prev, entry, entries) {
+                blocks.insert(start.take().unwrap(), (pc.wrapping_add(4), exit));
+            }
+            prev = instr.ok().map(|instr| (pc, instr));
+            last = pc;
+        }
+        if let Some(block_start) = start {
+            blocks.insert(block_start, (last.wrapping_add(4), Exit::Invalid));
+        }
+
+        // connect the blocks
+        let mut blocks: BTreeMap<u32, BasicBlock> = blocks.into_iter()
+            .map(|(start, (end, exit))| {
+                let succs = match exit {
+                    Exit::Fallthrough | Exit::Call(_) => vec![end],
+                    Exit::Jump(target) => vec![target],
+                    Exit::Branch(target) if target == end => vec![target],
+                    Exit::Branch(target) => vec![target, end],
+                    _ => vec![],
+                };
+                (start, BasicBlock { start, end, exit, succs, preds: vec![] })
+            })
+            .collect();
+
+        let edges: Vec<(u32, u32)> = blocks.values()
+            .flat_map(|block| block.succs.iter().map(|&succ| (block.start, succ)))
+            .collect();
+        for (from, to) in edges {
+            match blocks.get_mut(&to) {
+                Some(block) => block.preds.push(from),
+                // off the end of the code
+                None => blocks.get_mut(&from).unwrap().succs.retain(|&succ| succ != to),
+            }
+        }
+
+        let name = entries.get(&entry).cloned().unwrap_or_else(|| format!("sub_{entry:x}"));
+        (Function { name, entry, blocks }, calls)
+    }
+}
+
+impl Cfg {
+    /// Recover the functions in the executable sections of `elf`, starting
+    /// from its entry point and the symbols in them
+    pub fn from_elf(elf: &Elf) -> Cfg {
+        let code = Code::from_elf(elf);
+
+        let symbols: Vec<_> = elf.symbols().iter()
+            .filter(|sym| code.contains(sym.value) && sym.is_address())
+            .collect();
+
+        // functions, and labels that aren't inside a sized symbol
+        let mut entries: Vec<(u32, String)> = symbols.iter()
+            .filter(|sym| sym.typ == STT_FUNC || sym.typ == STT_NOTYPE && !symbols.iter().any(|outer| {
+                outer.value < sym.value && sym.value < outer.value.wrapping_add(outer.size)
+            }))
+            .map(|sym| (sym.value, sym.name.clone()))
+            .collect();
+        if !entries.iter().any(|&(addr, _)| addr == elf.entry) {
+            entries.push((elf.entry, "_start".to_string()));
+        }
+
+        Cfg::recover(&code, entries)
+    }
+
+    /// Recover the functions in `code` reachable from `entries`, the entry
+    /// points and names of known functions
+    pub fn recover(code: &Code, entries: impl IntoIterator<Item = (u32, String)>) -> Cfg {
+        let mut entries: BTreeMap<u32, String> = entries.into_iter().collect();
+        let mut functions = BTreeMap::new();
+
+        let mut work: Vec<u32> = entries.keys().copied().collect();
+        while let Some(entry) = work.pop() {
+            if functions.contains_key(&entry) || !code.contains(entry) {
+                continue;
+            }
+
+            let (function, calls) = Function::recover(code, entry, &entries);
+            functions.insert(entry, function);
+
+            for call in calls {
+                entries.entry(call).or_insert_with(|| format!("sub_{call:x}"));
+                work.push(call);
+            }
+        }
+
+        Cfg { functions }
+    }
+
+    /// Write the graph in Graphviz dot format, with each function as a
+    /// cluster of blocks listing their instructions from `code`
+    ///
+    /// Taken branches are green and not taken ones red, calls are dashed
+    /// and blocks ending in unresolved jumps are red.
+    ///
+    pub fn dot(&self, code: &Code, out: &mut impl Write) -> io::Result<()> {
+        let node = |entry: u32, block: u32| format!("\"{entry:x}_{block:x}\"");
+
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        for function in self.functions.values() {
+            let name = function.name.replace('\\', "\\\\").replace('"', "\\\"");
+
+            writeln!(out, "    subgraph \"cluster_{:x}\" {{", function.entry)?;
+            writeln!(out, "        label=\"{name}\";")?;
+
+            for block in function.blocks.values() {
+                let mut label = format!("{:x}:\\l", block.start);
+                for pc in (block.start..block.end).step_by(4) {
+                    let instr = disassemble_one(pc, code.fetch(pc).unwrap());
+                    label += &format!("  {}\\l", instr.pseudo());
+                }
+
+                let color = if block.exit == Exit::Unresolved { ", color=red" } else { "" };
+                writeln!(out, "        {} [label=\"{label}\"{color}];",
+                    node(function.entry, block.start))?;
+            }
+
+            writeln!(out, "    }}")?;
+        }
+
+        for function in self.functions.values() {
+            for block in function.blocks.values() {
+                let from = node(function.entry, block.start);
+
+                for &succ in &block.succs {
+                    let style = match block.exit {
+                        Exit::Branch(target) if target == block.end => "",
+                        Exit::Branch(target) if target == succ => " [color=green]",
+                        Exit::Branch(_) => " [color=red]",
+                        _ => "",
+                    };
+                    writeln!(out, "    {from} -> {}{style};", node(function.entry, succ))?;
+                }
+
+                if let Exit::Call(Some(callee)) | Exit::TailCall(callee) = block.exit {
+                    if self.functions.contains_key(&callee) {
+                        writeln!(out, "    {from} -> {} [style=dashed];", node(callee, callee))?;
+                    }
+                }
+            }
+        }
+
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn recover() {
+        let bytes = assemble(0x10000, "
+            main:
+                li a0, 5
+                call fact
+                jr a1
+            fact:
+                mv a1, a0
+                li a0, 1
+            loop:
+                beqz a1, done
+                jal mul
+                addi a1, a1, -1
+                j loop
+            done:
+                ret
+            mul:
+                j mul_body
+            mul_body:
+                .word 0
+        ").unwrap();
+        let mut code = Code::new();
+        code.add(0x10000, &bytes);
+
+        let cfg = Cfg::recover(&code, [(0x10000, "main".to_string()), (0x10030, "mul_body".to_string())]);
+
+        let blocks = |entry| -> Vec<BasicBlock> {
+            cfg.functions[&entry].blocks.values().cloned().collect()
+        };
+        let block = |start, end, exit, succs: &[u32], preds: &[u32]| BasicBlock {
+            start, end, exit, succs: succs.to_vec(), preds: preds.to_vec(),
+        };
+
+        assert_eq!(cfg.functions.keys().copied().collect::<Vec<_>>(),
+            [0x10000, 0x10010, 0x1002c, 0x10030]);
+        assert_eq!(cfg.functions[&0x10010].name, "sub_10010");
+
+        assert_eq!(blocks(0x10000), [
+            block(0x10000, 0x1000c, Exit::Call(Some(0x10010)), &[0x1000c], &[]),
+            block(0x1000c, 0x10010, Exit::Unresolved, &[], &[0x10000]),
+        ]);
+        assert_eq!(blocks(0x10010), [
+            block(0x10010, 0x10018, Exit::Fallthrough, &[0x10018], &[]),
+            block(0x10018, 0x1001c, Exit::Branch(0x10028), &[0x10028, 0x1001c], &[0x10010, 0x10020]),
+            block(0x1001c, 0x10020, Exit::Call(Some(0x1002c)), &[0x10020], &[0x10018]),
+            block(0x10020, 0x10028, Exit::Jump(0x10018), &[0x10018], &[0x1001c]),
+            block(0x10028, 0x1002c, Exit::Return, &[], &[0x10018]),
+        ]);
+        assert_eq!(blocks(0x1002c), [
+            block(0x1002c, 0x10030, Exit::TailCall(0x10030), &[], &[]),
+        ]);
+        assert_eq!(blocks(0x10030), [
+            block(0x10030, 0x10034, Exit::Invalid, &[], &[]),
+        ]);
+
+        let mut dot = Vec::new();
+        cfg.dot(&code, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("\"10010_10018\" -> \"10010_10028\" [color=green];"));
+        assert!(dot.contains("\"10000_10000\" -> \"10010_10010\" [style=dashed];"));
+        assert!(dot.contains("\"10000_1000c\" [label=\"1000c:\\l  jr a1\\l\", color=red];"));
+    }
+}
@@ -1,387 +1,418 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use elf::{Elf, Symbol, SHT_PROGBITS, STT_OBJECT};
+
 use crate::instructions::*;
 
-pub fn disassemble(addr: u32, instrs: &[u8]) {
-    for (ii, instr) in instrs.array_chunks::<4>().enumerate() {
-        disassemble_one(addr + (ii * 4) as u32, u32::from_le_bytes(*instr), false);
+const ZERO: Operand = Operand::Reg(Reg(0));
+const RA: Operand = Operand::Reg(Reg(1));
+
+/// Operand of a disassembled instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg),
+
+    /// Immediate, shown in decimal
+    Imm(i32),
+
+    /// Immediate shown in hex: the upper immediate of `lui` and `auipc`, and
+    /// words that aren't instructions
+    Hex(u32),
+
+    /// Memory at `base` + `offset`, for loads, stores and `jalr`
+    Mem { base: Reg, offset: i32 },
+
+    /// Address a jump or branch goes to
+    Target(u32),
+}
+
+/// A disassembled instruction
+///
+/// Displays as assembly with abi register names, or with x register names
+/// using the alternate flag (`{:#}`).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    /// Address of the instruction
+    pub addr: u32,
+
+    /// Raw instruction
+    pub instr: u32,
+
+    /// `.word` for words that aren't valid instructions
+    pub mnemonic: &'static str,
+
+    pub operands: Vec<Operand>,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |reg: Reg| reg.name2(!f.alternate());
+
+        match *self {
+            Operand::Reg(reg) => write!(f, "{}", name(reg)),
+            Operand::Imm(imm) => write!(f, "{imm}"),
+            Operand::Hex(imm) => write!(f, "{imm:#x}"),
+            Operand::Mem { base, offset } => write!(f, "{offset}({})", name(base)),
+            Operand::Target(addr) => write!(f, "{addr:#x}"),
+        }
     }
 }
 
-pub fn disassemble_one(addr: u32, instr: u32, abi_name: bool) {
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
 
-    // first 7 bits are the opcode
-    let opcode: u32 = instr & ((1 << 7) - 1);
+        for (ii, operand) in self.operands.iter().enumerate() {
+            let sep = if ii == 0 { " " } else { ", " };
+            if f.alternate() {
+                write!(f, "{sep}{operand:#}")?;
+            } else {
+                write!(f, "{sep}{operand}")?;
+            }
+        }
 
-    // follow the table on page 130 in the riscv spec
-    match opcode {
-        // LUI
-        0b0110111 => {
-            let typ = UType::parse(instr);
-            println!("lui {}, imm={:#08x}", typ.rd.name2(abi_name), typ.imm);
-        },
-        // AUIPC
-        0b0010111 => {
-            let typ = UType::parse(instr);
-            println!("auipc {}, imm={:#08x}", typ.rd.name2(abi_name), typ.imm);
-        },
-        // JAL
-        0b1101111 => {
-            let typ = JType::parse(instr);
-            println!("jal {}, rel={}, abs={:#08x}",
-                typ.rd.name2(abi_name),
-                typ.imm,
-                (addr as i32 + typ.imm as i32) as u32);
-        },
-        // JALR
-        0b1100111 => {
-            let typ = IType::parse(instr);
+        Ok(())
+    }
+}
 
-            assert!(typ.funct3 == 0,
-                "JALR should have funct3 == 0");
+/// A [`Disassembly`] displayed like GNU objdump does, see [`Disassembly::gnu`]
+pub struct Gnu<'a> {
+    instr: Disassembly,
+    symbolize: &'a dyn Fn(u32) -> Option<(&'a str, u32)>,
+}
 
-            println!("jalr {}, {}, rel={}",
-                typ.rd.name2(abi_name),
-                typ.rs1.name2(abi_name),
-                typ.imm as i32)
-        },
+impl fmt::Display for Gnu<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.instr.mnemonic)?;
 
-        // BRANCH
-        0b1100011 => {
-            let typ = BType::parse(instr);
-            match typ.funct3 {
-                // BEQ
-                0b000 => {
-                    println!("beq {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                // BNE
-                0b001 => {
-                    println!("bne {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                // BLT
-                0b100 => {
-                    println!("blt {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                // BGE
-                0b101 => {
-                    println!("bge {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                // BLTU
-                0b110 => {
-                    println!("bltu {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm as i32,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                // BGEU
-                0b111 => {
-                    println!("bgeu {}, {}, rel={}, abs={:#08x}",
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name),
-                        typ.imm,
-                        (addr as i32 + typ.imm as i32) as u32);
-                },
-                funct3 => {
-                    panic!("Unknown BRANCH: {funct3:#03b}");
-                },
-            };
-        },
+        for (ii, operand) in self.instr.operands.iter().enumerate() {
+            write!(f, "{}", if ii == 0 { "\t" } else { "," })?;
 
-        // LOAD
-        0b0000011 => {
-            let typ = IType::parse(instr);
-            match typ.funct3 {
-                // LB
-                0b000 => {
-                    println!("lb {}, {}, rel={}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // LH
-                0b001 => {
-                    println!("lh {}, {}, rel={}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // LW
-                0b010 => {
-                    println!("lw {}, {}, rel={}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // LBU
-                0b100 => {
-                    println!("lbu {}, {}, rel={}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // LHU
-                0b101 => {
-                    println!("lhu {}, {}, rel={}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                funct3 => {
-                    panic!("Uknown LOAD: {funct3:#03b}");
-                },
+            let Operand::Target(addr) = *operand else {
+                write!(f, "{operand}")?;
+                continue;
             };
-        },
 
-        // STORE
-        0b0100011 => {
-            let typ = SType::parse(instr);
-            match typ.funct3 {
-                // SB
-                0b000 => {
-                    println!("sb {}, {}, rel={}",
-                        typ.rs2.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // SH
-                0b001 => {
-                    println!("sh {}, {}, rel={}",
-                        typ.rs2.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // SW
-                0b010 => {
-                    println!("sw {}, {}, rel={}",
-                        typ.rs2.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                funct3 => {
-                    panic!("Uknown LOAD: {funct3:#03b}");
-                },
-            };
+            write!(f, "{addr:x}")?;
+            match (self.symbolize)(addr) {
+                Some((name, 0)) => write!(f, " <{name}>")?,
+                Some((name, offset)) => write!(f, " <{name}+{offset:#x}>")?,
+                None => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Disassembly {
+    /// The pseudo-instruction GNU objdump shows this instruction as, like
+    /// `mv a0, a1` for `addi a0, a1, 0`, or the instruction itself if there
+    /// is none
+    pub fn pseudo(&self) -> Disassembly {
+        use Operand::{Imm, Mem};
+
+        let (mnemonic, operands) = match (self.mnemonic, &self.operands[..]) {
+            ("addi", [ZERO, ZERO, Imm(0)]) => ("nop", vec![]),
+            ("addi", [rd, ZERO, imm]) => ("li", vec![*rd, *imm]),
+            ("addi", [rd, rs, Imm(0)]) => ("mv", vec![*rd, *rs]),
+            ("xori", [rd, rs, Imm(-1)]) => ("not", vec![*rd, *rs]),
+            ("sub", [rd, ZERO, rs]) => ("neg", vec![*rd, *rs]),
+            ("sltiu", [rd, rs, Imm(1)]) => ("seqz", vec![*rd, *rs]),
+            ("sltu", [rd, ZERO, rs]) => ("snez", vec![*rd, *rs]),
+            ("slt", [rd, rs, ZERO]) => ("sltz", vec![*rd, *rs]),
+            ("slt", [rd, ZERO, rs]) => ("sgtz", vec![*rd, *rs]),
+
+            ("beq", [rs, ZERO, target]) => ("beqz", vec![*rs, *target]),
+            ("bne", [rs, ZERO, target]) => ("bnez", vec![*rs, *target]),
+            ("blt", [rs, ZERO, target]) => ("bltz", vec![*rs, *target]),
+            ("bge", [rs, ZERO, target]) => ("bgez", vec![*rs, *target]),
+            ("blt", [ZERO, rs, target]) => ("bgtz", vec![*rs, *target]),
+            ("bge", [ZERO, rs, target]) => ("blez", vec![*rs, *target]),
+
+            ("jal", [ZERO, target]) => ("j", vec![*target]),
+            ("jal", [RA, target]) => ("jal", vec![*target]),
+            ("jalr", [ZERO, Mem { base: Reg(1), offset: 0 }]) => ("ret", vec![]),
+            ("jalr", [ZERO, Mem { base, offset: 0 }]) => ("jr", vec![Operand::Reg(*base)]),
+            ("jalr", [ZERO, mem]) => ("jr", vec![*mem]),
+            ("jalr", [RA, Mem { base, offset: 0 }]) => ("jalr", vec![Operand::Reg(*base)]),
+            ("jalr", [RA, mem]) => ("jalr", vec![*mem]),
+
+            _ => return self.clone(),
+        };
+
+        Disassembly { addr: self.addr, instr: self.instr, mnemonic, operands }
+    }
+
+    /// Display in the syntax of GNU objdump: pseudo-instructions, no spaces
+    /// between operands and jump and branch targets annotated with the
+    /// symbol and offset `symbolize` finds for them, as in
+    /// `j\t100d4 <main+0x58>`
+    pub fn gnu<'a>(&self, symbolize: &'a dyn Fn(u32) -> Option<(&'a str, u32)>) -> Gnu<'a> {
+        Gnu { instr: self.pseudo(), symbolize }
+    }
+}
+
+/// The code in `elf` with a title for each part: its executable sections, or
+/// its executable segments if it has no section headers
+pub fn code_regions(elf: &Elf) -> Vec<(String, u32, &[u8])> {
+    let sections: Vec<_> = elf.sections().iter()
+        .filter(|section| section.flags.exec() && section.typ == SHT_PROGBITS)
+        .map(|section| (format!("section {}", section.name), section.addr, &section.data[..]))
+        .collect();
+    if !sections.is_empty() {
+        return sections;
+    }
+
+    elf.load_segments.iter()
+        .filter(|segment| segment.flags.x())
+        .map(|segment| {
+            let end = segment.load_address.saturating_add(segment.file_size);
+            (format!("segment {:08x}-{end:08x}", segment.load_address), segment.load_address, &segment.data[..])
+        })
+        .collect()
+}
+
+/// Write an objdump style listing of the code in `elf` that is in `range`,
+/// with a header where each symbol starts
+///
+/// Words that aren't instructions, or that are in data objects or past the
+/// size of the symbol they follow, are data and listed as `.word`. With
+/// `line_numbers` the source location is shown wherever it changes, like
+/// `objdump -l`.
+pub fn listing(elf: &Elf, range: Range<u32>, line_numbers: bool, out: &mut impl Write)
+    -> io::Result<()>
+{
+    let symbolize = |addr| elf.addr_to_symbol(addr);
+
+    // the first symbol at each address
+    let mut starts: BTreeMap<u32, &Symbol> = BTreeMap::new();
+    for sym in elf.symbols().iter().filter(|sym| sym.is_address()) {
+        starts.entry(sym.value).or_insert(sym);
+    }
+
+    for (title, start, data) in code_regions(elf) {
+        let mut addr = start.max(range.start & !3);
+        let end = start.saturating_add(data.len() as u32).min(range.end);
+        if addr >= end {
+            continue;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Disassembly of {title}:")?;
+
+        let mut symbol = starts.range(..=addr).next_back().map(|(_, &sym)| sym);
+        if symbol.is_some_and(|sym| sym.value != addr) {
+            writeln!(out)?;
+            let (name, offset) = symbolize(addr).unwrap();
+            writeln!(out, "{addr:08x} <{name}+{offset:#x}>:")?;
         }
 
-        // OP-IMM
-        0b0010011 => {
-            let typ = IType::parse(instr);
-
-            // arithmetic right shift?
-            // imm[11:5]
-            let arithmetic = (typ.imm & ((1 << 12) - 1)) >> 5;
-
-            // imm[4:0]
-            let shamt = typ.imm & 0b11111;
-
-            match typ.funct3 {
-                // ADDI
-                0b000 => {
-                    println!("addi {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // SLTI
-                0b010 => {
-                    println!("slti {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm as i32);
-                },
-                // SLTIU
-                0b011 => {
-                    println!("sltiu {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm);
-                },
-                // XORI
-                0b100 => {
-                    println!("xori {}, {}, {:#08x}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm);
-                },
-                // ORI
-                0b110 => {
-                    println!("ori {}, {}, {:#08x}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm);
-                },
-                // ANDI
-                0b111 => {
-                    println!("andi {}, {}, {:#08x}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.imm);
-                },
-                // SLLI
-                0b001 => {
-                    assert!(arithmetic == 0b0);
-                    println!("slli {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        shamt);
-                },
-                // SRLI & SRAI
-                0b101 => {
-                    match arithmetic {
-                        // SRLI
-                        0b0 => {
-                            println!("srli {}, {}, {}",
-                                typ.rd.name2(abi_name),
-                                typ.rs1.name2(abi_name),
-                                shamt);
-                                },
-                        // SRAI
-                        0b0100000 => {
-                            println!("srai {}, {}, {}",
-                                typ.rd.name2(abi_name),
-                                typ.rs1.name2(abi_name),
-                                shamt);
-                                },
-                        _ => {
-                            panic!("Uknown SRLI/SRAI: {arithmetic:#07b}");
-                        },
-                    };
-                },
-                funct3 => {
-                    panic!("Uknown OP-IMM: {funct3:#03b}");
-                },
+        let mut location = None;
+        while addr < end {
+            if let Some(&sym) = starts.get(&addr) {
+                symbol = Some(sym);
+                writeln!(out)?;
+                writeln!(out, "{addr:08x} <{}>:", sym.name)?;
+                location = None;
+            }
+
+            if line_numbers {
+                let here = elf.addr_to_line(addr);
+                let line = here.map(|loc| (loc.file, loc.line));
+                if let Some(here) = here.filter(|_| line != location) {
+                    writeln!(out, "{here}")?;
+                }
+                location = line;
+            }
+
+            let data = &data[(addr - start) as usize..];
+            let Some(word) = data.get(..4) else {
+                // trailing bytes
+                for (ii, byte) in data.iter().enumerate() {
+                    writeln!(out, "{:8x}:\t{byte:02x}                \t.byte\t{byte:#x}", addr + ii as u32)?;
+                }
+                break;
             };
-        },
+            let word = u32::from_le_bytes(word.try_into().unwrap());
 
-        // OP
-        0b0110011 => {
-            let typ = RType::parse(instr);
-
-            match (typ.funct3, typ.funct7) {
-                // ADD
-                (0b000, 0b0000000) => {
-                    println!("add {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SUB
-                (0b000, 0b0100000) => {
-                    println!("sub {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SLL
-                (0b001, 0b0000000) => {
-                    println!("sll {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SLT
-                (0b010, 0b0000000) => {
-                    println!("slt {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SLTU
-                (0b011, 0b0000000) => {
-                    println!("sltu {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // XOR
-                (0b100, 0b0000000) => {
-                    println!("xor {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SRL
-                (0b101, 0b0000000) => {
-                    println!("slr {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // SRA
-                (0b101, 0b0100000) => {
-                    println!("sra {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // OR
-                (0b110, 0b0000000) => {
-                    println!("or {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                // AND
-                (0b111, 0b0000000) => {
-                    println!("and {}, {}, {}",
-                        typ.rd.name2(abi_name),
-                        typ.rs1.name2(abi_name),
-                        typ.rs2.name2(abi_name));
-                },
-                (funct3, funct7) => {
-                    panic!("Uknown OP-IMM: funct3={funct3:#03b}, funct7={funct7:#07b}");
-                },
+            let code = symbol.is_none_or(|sym| {
+                sym.typ != STT_OBJECT && (sym.size == 0 || addr < sym.value.saturating_add(sym.size))
+            });
+            let text = if code {
+                disassemble_one(addr, word).gnu(&symbolize).to_string()
+            } else {
+                format!(".word\t{word:#x}")
             };
+            writeln!(out, "{addr:8x}:\t{word:08x}          \t{text}")?;
+
+            addr += 4;
         }
+    }
 
-        // MISC-MEM
-        0b0001111 => {
-            let typ = IType::parse(instr);
-            // FENCE
-            assert!(typ.funct3 == 0b000,
-                "FENCE must have funct3=0b00, found {:#03b}", typ.funct3);
-            // TODO: more junk to print?
-            println!("fence");
-        },
+    Ok(())
+}
 
-        // SYSTEM
-        0b1110011 => {
-            let typ = IType::parse(instr);
-            assert!(typ.rs1.0 == 0,
-                "rs1 must be 0 for SYSTEM instruction, found {:#02x}", typ.rs1.0);
-            assert!(typ.rd.0 == 0,
-                "rd must be 0 for SYSTEM instruction, found {:#02x}", typ.rd.0);
-            assert!(typ.funct3 == 0,
-                "funct3 must be 0 for SYSTEM instruction, found {:#03b}", typ.funct3);
-            match typ.imm {
-                // ECALL
-                0b0 => {
-                    println!("ecall");
-                },
-                0b1 => {
-                    println!("ebreak");
-                },
-                imm => {
-                    panic!("unknown SYSTEM instruction {imm:#011b}");
-                },
-            }
+/// Disassemble the instruction `instr` at `addr`
+pub fn disassemble_one(addr: u32, instr: u32) -> Disassembly {
+    let op = match decode(instr) {
+        Ok(op) => op,
+        Err(_) => return Disassembly {
+            addr,
+            instr,
+            mnemonic: ".word",
+            operands: vec![Operand::Hex(instr)],
         },
+    };
+
+    let target = |imm: u32| Operand::Target(addr.wrapping_add(imm));
+    let mem = |base, imm: u32| Operand::Mem { base, offset: imm as i32 };
+
+    let operands = match op {
+        Instruction::Lui { rd, imm } | Instruction::Auipc { rd, imm } =>
+            vec![Operand::Reg(rd), Operand::Hex(imm >> 12)],
+        Instruction::Jal { rd, imm } => vec![Operand::Reg(rd), target(imm)],
+        Instruction::Jalr { rd, rs1, imm } => vec![Operand::Reg(rd), mem(rs1, imm)],
+
+        Instruction::Beq { rs1, rs2, imm } | Instruction::Bne { rs1, rs2, imm } |
+        Instruction::Blt { rs1, rs2, imm } | Instruction::Bge { rs1, rs2, imm } |
+        Instruction::Bltu { rs1, rs2, imm } | Instruction::Bgeu { rs1, rs2, imm } =>
+            vec![Operand::Reg(rs1), Operand::Reg(rs2), target(imm)],
+
+        Instruction::Lb { rd, rs1, imm } | Instruction::Lh { rd, rs1, imm } |
+        Instruction::Lw { rd, rs1, imm } | Instruction::Lbu { rd, rs1, imm } |
+        Instruction::Lhu { rd, rs1, imm } =>
+            vec![Operand::Reg(rd), mem(rs1, imm)],
+
+        Instruction::Sb { rs1, rs2, imm } | Instruction::Sh { rs1, rs2, imm } |
+        Instruction::Sw { rs1, rs2, imm } =>
+            vec![Operand::Reg(rs2), mem(rs1, imm)],
+
+        Instruction::Addi { rd, rs1, imm } | Instruction::Slti { rd, rs1, imm } |
+        Instruction::Sltiu { rd, rs1, imm } | Instruction::Xori { rd, rs1, imm } |
+        Instruction::Ori { rd, rs1, imm } | Instruction::Andi { rd, rs1, imm } =>
+            vec![Operand::Reg(rd), Operand::Reg(rs1), Operand::Imm(imm as i32)],
+
+        Instruction::Slli { rd, rs1, shamt } | Instruction::Srli { rd, rs1, shamt } |
+        Instruction::Srai { rd, rs1, shamt } =>
+            vec![Operand::Reg(rd), Operand::Reg(rs1), Operand::Imm(shamt as i32)],
+
+        Instruction::Add { rd, rs1, rs2 } | Instruction::Sub { rd, rs1, rs2 } |
+        Instruction::Sll { rd, rs1, rs2 } | Instruction::Slt { rd, rs1, rs2 } |
+        Instruction::Sltu { rd, rs1, rs2 } | Instruction::Xor { rd, rs1, rs2 } |
+        Instruction::Srl { rd, rs1, rs2 } | Instruction::Sra { rd, rs1, rs2 } |
+        Instruction::Or { rd, rs1, rs2 } | Instruction::And { rd, rs1, rs2 } =>
+            vec![Operand::Reg(rd), Operand::Reg(rs1), Operand::Reg(rs2)],
+
+        Instruction::Fence | Instruction::Ecall | Instruction::Ebreak => Vec::new(),
+    };
+
+    Disassembly { addr, instr, mnemonic: op.mnemonic(), operands }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn display() {
+        let code = [
+            0xfff50513u32, // addi a0, a0, -1
+            0x00a12423, // sw a0, 8(sp)
+            0xfe051ce3, // bne a0, zero, -8
+            0x000122b7, // lui t0, 0x12
+            0x00000073, // ecall
+            0x00000000, // invalid
+        ];
+        let instrs: Vec<Disassembly> = code.iter().enumerate()
+            .map(|(ii, &instr)| disassemble_one(0x10000 + ii as u32 * 4, instr))
+            .collect();
+
+        let text: Vec<String> = instrs.iter().map(|instr| instr.to_string()).collect();
+        assert_eq!(text, [
+            "addi a0, a0, -1",
+            "sw a0, 8(sp)",
+            "bne a0, zero, 0x10000",
+            "lui t0, 0x12",
+            "ecall",
+            ".word 0x0",
+        ]);
+        assert_eq!(format!("{:#}", instrs[1]), "sw x10, 8(x2)");
+
+        assert_eq!(instrs[2].mnemonic, "bne");
+        assert_eq!(instrs[2].operands,
+            [Operand::Reg(Reg(10)), Operand::Reg(Reg(0)), Operand::Target(0x10000)]);
+    }
+
+    #[test]
+    fn gnu() {
+        let symbolize = |addr| match addr {
+            0x10000..=0x1000f => Some(("_start", addr - 0x10000)),
+            0x10010.. => Some(("main", addr - 0x10010)),
+            _ => None,
+        };
+
+        let gnu = |addr, instr| disassemble_one(addr, instr).gnu(&symbolize).to_string();
+        assert_eq!(gnu(0x10000, 0x00000013), "nop");
+        assert_eq!(gnu(0x10000, 0x00a00513), "li\ta0,10");
+        assert_eq!(gnu(0x10000, 0x00058513), "mv\ta0,a1");
+        assert_eq!(gnu(0x10000, 0xff010113), "addi\tsp,sp,-16");
+        assert_eq!(gnu(0x10000, 0x00112623), "sw\tra,12(sp)");
+        assert_eq!(gnu(0x10000, 0x000122b7), "lui\tt0,0x12");
+        assert_eq!(gnu(0x10000, 0x00008067), "ret");
+        assert_eq!(gnu(0x10000, 0x000780e7), "jalr\ta5");
+        assert_eq!(gnu(0x10000, 0x010000ef), "jal\t10010 <main>");
+        assert_eq!(gnu(0x10010, 0x0100006f), "j\t10020 <main+0x10>");
+        assert_eq!(gnu(0x10018, 0xfe051ce3), "bnez\ta0,10010 <main>");
+        assert_eq!(gnu(0x10000, 0x00b54863), "blt\ta0,a1,10010 <main>");
+    }
+
+    #[test]
+    fn listing() {
+        let elf = testing::elf("
+            _start:
+                li a0, 10
+                call main
+                j _start
+            main:
+                beqz a0, .done
+                addi a0, a0, -1
+            .done:
+                ret
+        ");
+
+        let listing = |range| {
+            let mut out = Vec::new();
+            super::listing(&elf, range, false, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(listing(0..u32::MAX), "
+Disassembly of section .text:
+
+00010000 <_start>:
+   10000:\t00a00513          \tli\ta0,10
+   10004:\t00000097          \tauipc\tra,0x0
+   10008:\t00c080e7          \tjalr\t12(ra)
+   1000c:\tff5ff06f          \tj\t10000 <_start>
+
+00010010 <main>:
+   10010:\t00050463          \tbeqz\ta0,10018 <main+0x8>
+   10014:\tfff50513          \taddi\ta0,a0,-1
+   10018:\t00008067          \tret
+");
+
+        // starting in the middle of a function
+        assert_eq!(listing(0x10014..0x10018), "
+Disassembly of section .text:
 
-        _ => { panic!("Unknown opcode: {opcode:032b}"); },
+00010014 <main+0x4>:
+   10014:\tfff50513          \taddi\ta0,a0,-1
+");
     }
 }
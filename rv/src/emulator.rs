@@ -1,5 +1,8 @@
 // use std::io::Write;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::ops::Range;
+use std::rc::Rc;
 
 use elf::Elf;
 
@@ -7,6 +10,10 @@ use rangeset::RangeSet;
 
 use crate::instructions::*;
 use crate::disassemble::*;
+use crate::block_cache::*;
+use crate::jit::Jit;
+use crate::taint::Taint;
+use crate::sanitizer::{Sanitizer, SanitizerError};
 
 #[cfg(feature = "trace")]
 const TRACE: bool = true;
@@ -28,6 +35,13 @@ fn test_perm(permission: u8, byte: u8) -> bool {
     (permission & byte) != 0
 }
 
+/// Format permission bits as `rwx` plus `a` for read after write
+pub fn format_perms(perm: u8) -> String {
+    [(PERM_READ, 'r'), (PERM_WRITE, 'w'), (PERM_EXEC, 'x'), (PERM_RAW, 'a')].iter()
+        .map(|&(bit, ch)| if perm & bit != 0 { ch } else { '-' })
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum MemoryError {
     BadPermissions {
@@ -49,11 +63,93 @@ impl MemoryError {
     }
 }
 
+/// Granularity of the dirty tracking used to reset memory to a snapshot
+pub const DIRTY_BLOCK_SIZE: usize = 4096;
+
+/// Addresses below this are never handed out, so that null pointers (plus
+/// some offset) always fault, like linux's `vm.mmap_min_addr`
+pub const NULL_PAGE_SIZE: u32 = 0x10000;
+
+/// A memory access by the guest, as seen by a memory hook
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAccess {
+    /// pc of the instruction making the access
+    pub pc: u32,
+
+    pub addr: u32,
+
+    /// Size of the access in bytes
+    pub size: u32,
+
+    /// [`PERM_READ`], [`PERM_WRITE`] or [`PERM_EXEC`]
+    pub access: u8,
+
+    /// The value read, the value about to be written or the instruction about
+    /// to be executed, hooks can change it
+    pub value: u32,
+}
+
+/// What a memory hook wants the emulator to do after it ran
 #[derive(Debug)]
+pub enum HookResult {
+    Continue,
+
+    /// Stop the run with this exit
+    ///
+    /// For reads and writes the access completes first and the guest stops
+    /// before the next instruction. For execution the guest stops before the
+    /// instruction, and resuming runs it without calling the hooks again.
+    Stop(EmulatorExit),
+}
+
+/// Callback of a memory hook
+pub type HookFn = dyn FnMut(&mut MemoryAccess) -> HookResult;
+
+#[derive(Clone)]
+struct MemoryHook {
+    range: Range<u32>,
+
+    /// Kinds of access the hook is called for, a combination of
+    /// [`PERM_READ`], [`PERM_WRITE`] and [`PERM_EXEC`]
+    access: u8,
+
+    callback: Rc<RefCell<HookFn>>,
+}
+
+impl std::fmt::Debug for MemoryHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryHook")
+            .field("range", &self.range)
+            .field("access", &format_perms(self.access))
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Memory {
     pub mem: Box<[u8]>,
     pub perms: Box<[u8]>,
     pub free: RangeSet,
+
+    /// Hooks called on guest accesses to memory
+    hooks: Vec<MemoryHook>,
+
+    /// Union of the access kinds of all hooks, so that unhooked accesses
+    /// only cost a bit test
+    hooked: u8,
+
+    /// Indices of the blocks that have been modified since the last reset
+    dirty: Vec<usize>,
+
+    /// One bit per block, set if the block is in `dirty`
+    dirty_bitmap: Vec<u64>,
+
+    /// One bit per block, set if instructions in the block are in a
+    /// [`BlockCache`]
+    code_bitmap: Vec<u64>,
+
+    /// Set when a block in `code_bitmap` is modified or reset
+    code_modified: bool,
 }
 
 macro_rules! readu_impl {
@@ -61,7 +157,7 @@ macro_rules! readu_impl {
         pub fn $name(&self, addr: u32, perm: u8) -> Result<u32, MemoryError> {
             const SIZE: usize = std::mem::size_of::<$ty>();
 
-            let slice = self.read(addr..addr+(SIZE as u32), perm)?;
+            let slice = self.read(addr..addr.wrapping_add(SIZE as u32), perm)?;
 
             Ok(<$ty>::from_le_bytes(slice.try_into().unwrap()) as u32)
         }
@@ -73,7 +169,7 @@ macro_rules! readi_impl {
         pub fn $name(&self, addr: u32, perm: u8) -> Result<u32, MemoryError> {
             const SIZE: usize = std::mem::size_of::<$ty>();
 
-            let slice = self.read(addr..addr+(SIZE as u32), perm)?;
+            let slice = self.read(addr..addr.wrapping_add(SIZE as u32), perm)?;
 
             Ok(<$ty>::from_le_bytes(slice.try_into().unwrap()) as i32 as u32)
         }
@@ -96,11 +192,102 @@ impl Memory {
         let perms = Box::new_zeroed_slice(size as usize);
         let perms = unsafe { perms.assume_init() };
 
+        let blocks = (size as usize).div_ceil(DIRTY_BLOCK_SIZE);
+
         Memory {
             mem,
             perms,
-            free: RangeSet::new(0, size),
+            free: RangeSet::new(NULL_PAGE_SIZE.min(size), size),
+            hooks: Vec::new(),
+            hooked: PERM_NONE,
+            dirty: Vec::new(),
+            dirty_bitmap: vec![0; blocks.div_ceil(64)],
+            code_bitmap: vec![0; blocks.div_ceil(64)],
+            code_modified: false,
+        }
+    }
+
+    /// Mark the blocks covering `range` as modified
+    #[inline]
+    fn mark_dirty(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let first = range.start as usize / DIRTY_BLOCK_SIZE;
+        let last = (range.end as usize - 1) / DIRTY_BLOCK_SIZE;
+
+        for block in first..=last {
+            let (word, bit) = (block / 64, block % 64);
+            if self.code_bitmap[word] & (1 << bit) != 0 {
+                self.code_modified = true;
+            }
+            if self.dirty_bitmap[word] & (1 << bit) == 0 {
+                self.dirty_bitmap[word] |= 1 << bit;
+                self.dirty.push(block);
+            }
+        }
+    }
+
+    /// Forget about all modifications, the current state becomes the clean state
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+        self.dirty_bitmap.fill(0);
+    }
+
+    /// Restore memory to the state of `other`
+    ///
+    /// Only the blocks modified since the last reset are copied, so `other`
+    /// must be the memory this one was forked from.
+    pub fn reset(&mut self, other: &Memory) {
+        for &block in &self.dirty {
+            let start = block * DIRTY_BLOCK_SIZE;
+            let end = (start + DIRTY_BLOCK_SIZE).min(self.mem.len());
+
+            self.mem[start..end].copy_from_slice(&other.mem[start..end]);
+            self.perms[start..end].copy_from_slice(&other.perms[start..end]);
+
+            self.dirty_bitmap[block / 64] = 0;
+            if self.code_bitmap[block / 64] & (1 << (block % 64)) != 0 {
+                self.code_modified = true;
+            }
         }
+        self.dirty.clear();
+
+        self.free.clone_from(&other.free);
+    }
+
+    /// Note that instructions in `range` were decoded into a [`BlockCache`],
+    /// so that modifying them sets [`Memory::code_modified`]
+    pub fn mark_code(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let first = range.start as usize / DIRTY_BLOCK_SIZE;
+        let last = (range.end as usize - 1) / DIRTY_BLOCK_SIZE;
+
+        for block in first..=last {
+            self.code_bitmap[block / 64] |= 1 << (block % 64);
+        }
+    }
+
+    /// Have the contents or permissions of memory marked with
+    /// [`Memory::mark_code`] changed since the last [`Memory::clear_code`]
+    pub fn code_modified(&self) -> bool {
+        self.code_modified
+    }
+
+    /// Forget about all memory marked as code
+    pub fn clear_code(&mut self) {
+        self.code_bitmap.fill(0);
+        self.code_modified = false;
+    }
+
+    /// The dirty and code bitmaps, for generated code that checks them
+    /// before writing
+    pub fn bitmaps(&self) -> (*const u64, *const u64) {
+        (self.dirty_bitmap.as_ptr(), self.code_bitmap.as_ptr())
     }
 
     pub fn allocate(&mut self, size: u32, perms: u8) -> Result<(u32, u32), MemoryError> {
@@ -109,8 +296,11 @@ impl Memory {
         Ok((start, end))
     }
 
+    #[inline]
     fn check_bounds(&self, range: Range<u32>) -> Result<(), MemoryError> {
-        if range.start as usize >= self.mem.len() || range.end as usize >= self.mem.len() {
+        // ranges that wrapped around the end of the address space have their
+        // end before their start
+        if range.start > range.end || range.start as usize >= self.mem.len() || range.end as usize >= self.mem.len() {
             return Err(MemoryError::OutOfBounds {
                 addr: range
             });
@@ -121,6 +311,7 @@ impl Memory {
 
     pub fn set_permissions(&mut self, range: Range<u32>, perm: u8) -> Result<(), MemoryError> {
         self.check_bounds(range.clone())?;
+        self.mark_dirty(range.clone());
 
         for ii in range {
             self.perms[ii as usize] = perm;
@@ -129,21 +320,20 @@ impl Memory {
         Ok(())
     }
 
+    #[inline]
     pub fn check_permission(&self, range: Range<u32>, perm: u8) -> Result<(), MemoryError> {
-        for addr in range.clone() {
-            let byte = self.perms[addr as usize];
-            if !test_perm(perm, byte) {
-                return Err(MemoryError::BadPermissions {
-                    addr: range,
-                    access: perm as u8,
-                    perms: byte,
-                });
-            }
+        let perms = &self.perms[range.start as usize..range.end as usize];
+        match perms.iter().find(|&&byte| !test_perm(perm, byte)) {
+            None => Ok(()),
+            Some(&byte) => Err(MemoryError::BadPermissions {
+                addr: range,
+                access: perm,
+                perms: byte,
+            }),
         }
-
-        Ok(())
     }
 
+    #[inline]
     pub fn read(&self, range: Range<u32>, perm: u8) -> Result<&[u8], MemoryError> {
         self.check_bounds(range.clone())?;
 
@@ -162,8 +352,9 @@ impl Memory {
     readi_impl!(read_i8, i8);
     readi_impl!(read_i16, i16);
 
+    #[inline]
     pub fn write(&mut self, addr: u32, perm: u8, data: &[u8]) -> Result<(), MemoryError> {
-        let range = addr..addr+data.len() as u32;
+        let range = addr..addr.wrapping_add(data.len() as u32);
 
         self.check_bounds(range.clone())?;
 
@@ -171,13 +362,13 @@ impl Memory {
             self.check_permission(range.clone(), perm)?;
         }
 
+        self.mark_dirty(range.clone());
+
         // reset the RAW bit and set the READ bit
-        for ii in range {
-            let mut perm = self.perms[ii as usize];
-            if perm & PERM_RAW != 0 {
-                perm &= !PERM_RAW;
-                perm |= PERM_READ;
-                self.perms[ii as usize] = perm;
+        for perm in &mut self.perms[range.start as usize..range.end as usize] {
+            if *perm & PERM_RAW != 0 {
+                *perm &= !PERM_RAW;
+                *perm |= PERM_READ;
             }
         }
 
@@ -190,21 +381,229 @@ impl Memory {
     write_impl!(write_u16, u16);
     write_impl!(write_u32, u32);
 
+    /// Call `callback` whenever an instruction accesses memory in `range` in
+    /// one of the ways in `access`, a combination of [`PERM_READ`],
+    /// [`PERM_WRITE`] and [`PERM_EXEC`]
+    ///
+    /// Accesses made by the kernel on behalf of the guest, like the buffer of
+    /// a `read` syscall, don't call hooks.
+    pub fn add_hook<F>(&mut self, range: Range<u32>, access: u8, callback: F)
+        where F: FnMut(&mut MemoryAccess) -> HookResult + 'static
+    {
+        self.hooks.push(MemoryHook {
+            range,
+            access,
+            callback: Rc::new(RefCell::new(callback)),
+        });
+        self.hooked |= access;
+    }
+
+    /// Are there any hooks for accesses of kind `access`
+    pub fn hooked(&self, access: u8) -> bool {
+        self.hooked & access != 0
+    }
+
+    /// Call the hooks for `access`, in the order they were added, until one
+    /// of them stops
+    pub fn call_hooks(&self, access: &mut MemoryAccess) -> HookResult {
+        let range = access.addr..access.addr.saturating_add(access.size);
+
+        for hook in &self.hooks {
+            if hook.access & access.access == 0 ||
+                hook.range.start >= range.end || range.start >= hook.range.end
+            {
+                continue;
+            }
+
+            if let HookResult::Stop(exit) = (hook.callback.borrow_mut())(access) {
+                return HookResult::Stop(exit);
+            }
+        }
+
+        HookResult::Continue
+    }
+
+    /// Copy the bytes in `src` along with their permissions to `dest`
+    pub fn copy_within(&mut self, src: Range<u32>, dest: u32) -> Result<(), MemoryError> {
+        let dest = dest..dest + src.len() as u32;
+
+        self.check_bounds(src.clone())?;
+        self.check_bounds(dest.clone())?;
+        self.mark_dirty(dest.clone());
+
+        let src = src.start as usize..src.end as usize;
+        self.mem.copy_within(src.clone(), dest.start as usize);
+        self.perms.copy_within(src, dest.start as usize);
+
+        Ok(())
+    }
+
 }
 
+/// Number of entries in the edge coverage map, must be a power of two
+pub const COVERAGE_MAP_SIZE: usize = 1 << 16;
+
+/// Maximum number of comparisons recorded per run when cmplog is enabled
+pub const CMPLOG_MAX: usize = 4096;
+
+/// A stack frame the guest allocated by moving the stack pointer down
+#[derive(Debug, Clone, Copy)]
+pub struct StackFrame {
+    /// Stack pointer after the allocation, the lowest address of the frame
+    pub sp: u32,
+
+    /// Stack pointer before the allocation, the end of the frame
+    pub top: u32,
+
+    /// pc of the instruction that moved the stack pointer
+    pub pc: u32,
+}
+
+/// What a pc hook wants the emulator to do after it ran
 #[derive(Debug)]
+pub enum PcHookResult {
+    /// Carry on at [`Emulator::pc`], if the hook left it alone the hooked
+    /// instruction runs as usual
+    Continue,
+
+    /// Return from the hooked function to `ra`, as if it ran a `ret`
+    Return,
+
+    /// Stop the run with this exit, resuming runs the hooked instruction
+    /// without calling the hook again
+    Stop(EmulatorExit),
+}
+
+/// Callback of a pc hook
+pub type PcHookFn = dyn FnMut(&mut Emulator) -> PcHookResult;
+
+#[derive(Clone)]
+struct PcHook(Rc<RefCell<PcHookFn>>);
+
+impl std::fmt::Debug for PcHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PcHook")
+    }
+}
+
+/// Compiled code of an emulator, which isn't shared: clones of the emulator
+/// interpret until they enable the JIT themselves
+#[derive(Debug, Default)]
+struct CompiledCode(Option<Jit>);
+
+impl Clone for CompiledCode {
+    fn clone(&self) -> Self {
+        CompiledCode(None)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Emulator {
     pub pc: u32,
     pub regs: [u32; 31],
     pub mem: Memory,
+
+    /// Edge coverage hit counts, indexed by a hash of the (from, to) pc pair
+    ///
+    /// Only collected if this is `Some`.
+    pub coverage: Option<Box<[u8]>>,
+
+    /// Operands of the comparisons executed by the guest
+    ///
+    /// Only collected if this is `Some`.
+    pub cmplog: Option<Vec<(u32, u32)>>,
+
+    /// Shadow call stack, the return addresses of the active calls with the
+    /// innermost call last
+    pub call_stack: Vec<u32>,
+
+    /// Addresses to stop at before executing them
+    pub breakpoints: HashSet<u32>,
+
+    /// Breakpoint we last stopped at, so that resuming from it doesn't stop
+    /// again straight away
+    at_breakpoint: Option<u32>,
+
+    /// Like `at_breakpoint`, for a pc hook or execute hook that stopped
+    at_hook: Option<u32>,
+
+    /// Host functions called when the guest is about to execute an address
+    pc_hooks: HashMap<u32, PcHook>,
+
+    /// Number of instructions retired
+    pub instret: u64,
+
+    /// Stop with [`EmulatorExit::Timeout`] once `instret` reaches this
+    pub instruction_limit: Option<u64>,
+
+    /// Live stack frames, outermost first
+    ///
+    /// Only tracked if this is `Some`, which also marks every newly allocated
+    /// frame as uninitialized so that reads of stale stack slots fault.
+    pub stack_frames: Option<Vec<StackFrame>>,
+
+    /// Unmapped region below the stack, accesses to it exit with
+    /// [`EmulatorExit::StackOverflow`] instead of a memory error
+    pub stack_guard: Option<Range<u32>>,
+
+    /// Taint tracking state, only tracked if this is `Some`
+    pub taint: Option<Taint>,
+
+    /// Heap sanitizer state, see [`Sanitizer::install`]
+    pub sanitizer: Option<Sanitizer>,
+
+    /// Decoded instructions, kept across runs and resets for as long as the
+    /// memory they came from is unchanged
+    blocks: BlockCache,
+
+    /// Compiled code, see [`Emulator::enable_jit`]
+    jit: CompiledCode,
 }
 
 #[derive(Debug)]
 pub enum EmulatorExit {
     Syscall,
     Break,
+    /// Reached an address in [`Emulator::breakpoints`]
+    Breakpoint,
+    /// Reached [`Emulator::instruction_limit`]
+    Timeout,
     InvalidInstruction(u32),
     InvalidMemoryAccess(MemoryError),
+    /// Accessed memory watched with [`Emulator::add_watchpoint`]
+    Watchpoint {
+        /// pc of the instruction making the access
+        pc: u32,
+        addr: u32,
+        access: u8,
+    },
+    /// Accessed memory below [`NULL_PAGE_SIZE`]
+    NullDereference {
+        addr: u32,
+    },
+    /// Accessed [`Emulator::stack_guard`]
+    StackOverflow {
+        sp: u32,
+        /// Number of active calls
+        depth: usize,
+    },
+    /// The heap sanitizer caught the guest misusing the allocator
+    Sanitizer(SanitizerError),
+}
+
+/// What to do after executing an instruction
+enum Step {
+    /// Carry on with the next instruction
+    Next,
+
+    /// Carry on at this address
+    Jump(u32),
+
+    /// Stop at the instruction, it didn't retire
+    Exit(EmulatorExit),
+
+    /// Stop after the instruction, it retired but a hook asked to stop
+    Stop(EmulatorExit),
 }
 
 impl Emulator {
@@ -215,6 +614,176 @@ impl Emulator {
             pc: 0,
             regs: [0; 31],
             mem: Memory::new(memory_size),
+            coverage: None,
+            cmplog: None,
+            call_stack: Vec::new(),
+            breakpoints: HashSet::new(),
+            at_breakpoint: None,
+            at_hook: None,
+            pc_hooks: HashMap::new(),
+            instret: 0,
+            instruction_limit: None,
+            stack_frames: None,
+            stack_guard: None,
+            taint: None,
+            sanitizer: None,
+            blocks: BlockCache::new(),
+            jit: CompiledCode::default(),
+        }
+    }
+
+    /// Create a copy of this emulator which can later be reset back to this one
+    /// with [`Emulator::reset`]
+    pub fn fork(&self) -> Self {
+        let mut emu = self.clone();
+        emu.mem.clear_dirty();
+        emu
+    }
+
+    /// Reset the guest state to that of `other`, which this emulator must have
+    /// been forked from
+    ///
+    /// Coverage and cmplog are left alone.
+    pub fn reset(&mut self, other: &Emulator) {
+        self.pc = other.pc;
+        self.regs = other.regs;
+        self.mem.reset(&other.mem);
+        self.call_stack.clone_from(&other.call_stack);
+        self.at_breakpoint = other.at_breakpoint;
+        self.at_hook = other.at_hook;
+        self.instret = other.instret;
+        self.stack_frames.clone_from(&other.stack_frames);
+        self.taint.clone_from(&other.taint);
+        self.sanitizer.clone_from(&other.sanitizer);
+    }
+
+    /// Stop with [`EmulatorExit::Watchpoint`] when the guest accesses memory
+    /// in `range` in one of the ways in `access`
+    pub fn add_watchpoint(&mut self, range: Range<u32>, access: u8) {
+        self.mem.add_hook(range, access, |access| HookResult::Stop(EmulatorExit::Watchpoint {
+            pc: access.pc,
+            addr: access.addr,
+            access: access.access,
+        }))
+    }
+
+    /// Call `hook` whenever the guest is about to execute `addr`, replacing
+    /// any hook already there
+    ///
+    /// The hook gets the emulator with [`Emulator::pc`] set to `addr`.
+    pub fn add_pc_hook<F>(&mut self, addr: u32, hook: F)
+        where F: FnMut(&mut Emulator) -> PcHookResult + 'static
+    {
+        self.pc_hooks.insert(addr, PcHook(Rc::new(RefCell::new(hook))));
+    }
+
+    /// Hook the function `name` from the symbols of `elf`, see
+    /// [`Emulator::add_pc_hook`]
+    ///
+    /// Returns false if there is no such symbol.
+    pub fn hook_symbol<F>(&mut self, elf: &Elf, name: &str, hook: F) -> bool
+        where F: FnMut(&mut Emulator) -> PcHookResult + 'static
+    {
+        let Some(symbol) = elf.lookup_by_name(name) else {
+            return false;
+        };
+        self.add_pc_hook(symbol.value, hook);
+        true
+    }
+
+    pub fn has_pc_hook(&self, addr: u32) -> bool {
+        self.pc_hooks.contains_key(&addr)
+    }
+
+    /// Addresses with a pc hook
+    pub fn hooked_pcs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pc_hooks.keys().copied()
+    }
+
+    /// Compile the guest to native code instead of interpreting it
+    ///
+    /// With `check` every run of compiled code is repeated by the interpreter
+    /// and the results compared, panicking if they differ.
+    pub fn enable_jit(&mut self, check: bool) -> std::io::Result<()> {
+        let mut jit = Jit::new()?;
+        jit.check = check;
+        self.jit = CompiledCode(Some(jit));
+        Ok(())
+    }
+
+    /// Set the return value of a function a hook runs in place of the guest's
+    ///
+    /// The value isn't computed from anything the guest has, so it clears the
+    /// taint of `a0`.
+    pub fn set_return_value(&mut self, value: u32) {
+        self.write_reg(RegName::A0.as_reg(), value);
+        if let Some(taint) = &mut self.taint {
+            taint.set_reg(RegName::A0.as_reg(), 0);
+        }
+    }
+
+    /// Return from the current function to `ra`, as if it ran a `ret`
+    pub fn emulate_return(&mut self) {
+        let ra = self.read_reg(RegName::Ra.as_reg());
+        if self.call_stack.last() == Some(&ra) {
+            self.call_stack.pop();
+        }
+        self.record_edge(self.pc, ra);
+        self.pc = ra;
+    }
+
+    /// Update the live stack frames after the instruction at `pc` moved the
+    /// stack pointer from `old_sp`
+    fn track_stack(&mut self, pc: u32, old_sp: u32) {
+        let sp = self.read_reg(RegName::Sp.as_reg());
+        let Some(frames) = &mut self.stack_frames else { return };
+
+        if sp < old_sp {
+            frames.push(StackFrame { sp, top: old_sp, pc });
+            // whatever is there belongs to a frame that is gone
+            let _ = self.mem.set_permissions(sp..old_sp, PERM_RAW | PERM_WRITE);
+        } else {
+            while frames.last().is_some_and(|frame| frame.sp < sp) {
+                frames.pop();
+            }
+        }
+    }
+
+    /// Update the shadow call stack for a jump, following the return address
+    /// stack hints in the spec (table 2.1)
+    pub fn track_call(&mut self, rd: Reg, rs1: Option<Reg>, return_addr: u32) {
+        // x1 and x5 are link registers
+        let is_link = |reg: Reg| reg.0 == 1 || reg.0 == 5;
+
+        let rd_link = is_link(rd);
+        let rs1_link = rs1.is_some_and(is_link);
+
+        // pop
+        if rs1_link && (!rd_link || rs1.unwrap().0 != rd.0) {
+            self.call_stack.pop();
+        }
+
+        // push
+        if rd_link {
+            self.call_stack.push(return_addr);
+        }
+    }
+
+    /// Record a control flow edge in the coverage map
+    fn record_edge(&mut self, from: u32, to: u32) {
+        if let Some(coverage) = &mut self.coverage {
+            let hash = ((from >> 1) ^ to).wrapping_mul(0x9e3779b1) >> 16;
+            let entry = &mut coverage[hash as usize & (COVERAGE_MAP_SIZE - 1)];
+            *entry = entry.saturating_add(1);
+        }
+    }
+
+    /// Record the operands of a comparison
+    pub fn record_cmp(&mut self, a: u32, b: u32) {
+        if let Some(cmplog) = &mut self.cmplog {
+            if a != b && cmplog.len() < CMPLOG_MAX {
+                cmplog.push((a, b));
+            }
         }
     }
 
@@ -285,39 +854,58 @@ impl Emulator {
     }
     */
 
-    fn trace_print2(&self, pc: u32) {
-        print!("  pc {pc:#010x}");
+    /// Format pc and the registers, four to a line
+    pub fn format_regs(&self, pc: u32) -> String {
+        let mut out = format!("  pc {pc:#010x}");
         for i in 1u8..4 {
             let reg = Reg(i);
-            print!(" {:>3} {:#010x}", reg.abi_name(), self.read_reg(reg));
+            out += &format!(" {:>3} {:#010x}", reg.abi_name(), self.read_reg(reg));
         }
-        println!();
+        out += "\n";
         for i in (4u8..31).step_by(4) {
             for j in 0..4 {
                 let reg = Reg(i + j);
-                print!(" {:>3} {:#010x}", reg.abi_name(), self.read_reg(reg));
+                out += &format!(" {:>3} {:#010x}", reg.abi_name(), self.read_reg(reg));
             }
-            println!();
+            out += "\n";
         }
+        out
     }
 
     fn trace_print(&self, pc: u32) {
-        println!(" pc {:#010x}  x1 {:#010x}  x2 {:010x}  x3 {:#010x}",
-            pc, self.regs[0], self.regs[1], self.regs[2]);
-        println!(" x4 {:#010x}  x5 {:#010x}  x6 {:#010x}  x7 {:#010x}",
-            self.regs[3], self.regs[4], self.regs[5], self.regs[6]);
-        println!(" x8 {:#010x}  x9 {:#010x} x10 {:#010x} x11 {:#010x}",
-            self.regs[7], self.regs[8], self.regs[9], self.regs[10]);
-        println!("x12 {:#010x} x13 {:#010x} x14 {:#010x} x15 {:#010x}",
-            self.regs[11], self.regs[12], self.regs[13], self.regs[14]);
-        println!("x16 {:#010x} x17 {:#010x} x18 {:#010x} x19 {:#010x}",
-            self.regs[15], self.regs[16], self.regs[17], self.regs[18]);
-        println!("x20 {:#010x} x21 {:#010x} x22 {:#010x} x23 {:#010x}",
-            self.regs[19], self.regs[20], self.regs[21], self.regs[22]);
-        println!("x24 {:#010x} x25 {:#010x} x26 {:#010x} x27 {:#010x}",
-            self.regs[23], self.regs[24], self.regs[25], self.regs[26]);
-        println!("x28 {:#010x} x29 {:#010x} x30 {:#010x} x31 {:#010x}",
-            self.regs[27], self.regs[28], self.regs[29], self.regs[30]);
+        print!("{}", self.format_regs(pc));
+    }
+
+    /// Does anything need to look at every instruction before it runs:
+    /// memory hooks, stack frame tracking or taint tracking
+    ///
+    /// Breakpoints and pc hooks are only at some addresses, blocks containing
+    /// them are stepped through one instruction at a time instead.
+    fn instrumented(&self) -> bool {
+        self.stack_frames.is_some() ||
+            self.mem.hooked(PERM_READ | PERM_WRITE | PERM_EXEC) ||
+            self.taint.is_some()
+    }
+
+    /// Can compiled code run the guest, only breakpoints and pc hooks are
+    /// handled by exiting to the interpreter
+    fn jit_compatible(&self) -> bool {
+        !TRACE &&
+            self.stack_frames.is_none() &&
+            !self.mem.hooked(PERM_READ | PERM_WRITE | PERM_EXEC) &&
+            self.taint.is_none()
+    }
+
+    /// Is there a breakpoint or pc hook on one of the `len` instructions
+    /// starting at `pc`
+    fn stops_in(&self, pc: u32, len: usize) -> bool {
+        if self.breakpoints.is_empty() && self.pc_hooks.is_empty() {
+            return false;
+        }
+
+        (0..len as u32).map(|ii| pc.wrapping_add(ii * 4)).any(|addr| {
+            self.breakpoints.contains(&addr) || self.pc_hooks.contains_key(&addr)
+        })
     }
 
     pub fn run(&mut self) -> EmulatorExit {
@@ -333,355 +921,578 @@ impl Emulator {
             }}
         }
 
-        'next_instruction: loop {
+        let mut resume_from = self.at_breakpoint.take();
+        let mut resume_from_hook = self.at_hook.take();
 
-            let instr =
-                self.mem.read_u32(pc, PERM_EXEC);
-            let instr = match instr {
-                Err(memerr) => exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
-                Ok(instr) => instr,
-            };
+        // exit a read or write hook asked for, taken before the next
+        // instruction
+        let mut stop = None;
 
-            if TRACE {
-                self.trace_print2(pc);
-                disassemble_one(pc as u32, instr, true);
-                println!("");
-            }
+        // for tracking stack frames
+        let mut sp = self.read_reg(RegName::Sp.as_reg());
+        let mut last_pc = pc;
+
+        // only pc hooks can change this while running
+        let mut instrumented = self.instrumented();
 
-            // before bzero bss
-            if pc == 0x000100ec {
-                println!("start: {:08x}, end: {:08x}, len: {:}",
-                    self.read_reg(RegName::A0.as_reg()),
-                    self.read_reg(RegName::A2.as_reg()),
-                    self.read_reg(RegName::A2.as_reg()) - self.read_reg(RegName::A0.as_reg())
-                )
+        // the block cache is taken out while running so that blocks can be
+        // borrowed from it while they execute
+        let mut blocks = std::mem::take(&mut self.blocks);
+
+        // the block we are in, and the rest of its instructions when going one
+        // instruction at a time
+        let mut block = None;
+        let mut block_instrs: &[(u32, Result<Instruction, DecodeError>)] = &[];
+        let mut block_pc = 0;
+
+        // compiled code runs until it reaches an instruction it leaves to the
+        // interpreter, which then runs that one instruction
+        let mut jit = self.jit.0.take();
+        let mut interpret_next = false;
+
+        loop {
+
+            if self.mem.code_modified() {
+                block = None;
+                block_instrs = &[];
+                blocks.flush(&mut self.mem);
+                if let Some(jit) = &mut jit {
+                    jit.flush();
+                }
             }
 
-            // first 7 bits are the opcode
-            let opcode: u32 = instr & ((1 << 7) - 1);
+            let limit = self.instruction_limit.unwrap_or(u64::MAX);
 
-            // follow the table on page 130 in the riscv spec
-            match opcode {
-                // LUI
-                0b0110111 => {
-                    let typ = UType::parse(instr);
-                    self.write_reg(typ.rd, typ.imm);
-                },
-                // AUIPC
-                0b0010111 => {
-                    let typ = UType::parse(instr);
-                    self.write_reg(typ.rd, pc + typ.imm);
-                },
-                // JAL
-                0b1101111 => {
-                    let typ = JType::parse(instr);
-                    let old_pc = pc;
-                    // offset is in multiples of 2 bytes ??
-                    pc = pc.wrapping_add(typ.imm);
-                    self.write_reg(typ.rd, old_pc + 4);
-                    continue 'next_instruction;
-                },
-                // JALR
-                0b1100111 => {
-                    let typ = IType::parse(instr);
+            let interpret = std::mem::take(&mut interpret_next);
+            if let Some(jit) = jit.as_mut().filter(|_| !interpret && self.jit_compatible()) {
+                // resuming only skips a breakpoint or hook at the first
+                // instruction, and compiled code never runs those
+                resume_from = resume_from.filter(|&addr| addr == pc);
+                resume_from_hook = resume_from_hook.filter(|&addr| addr == pc);
+
+                block = None;
+                block_instrs = &[];
+                pc = jit.run(self, &mut blocks, pc, limit);
+                interpret_next = true;
+                continue;
+            }
 
-                    if typ.funct3 != 0 {
-                        exit!(EmulatorExit::InvalidInstruction(instr));
+            // without instrumentation we can run a whole block at a time, as
+            // long as the instruction limit, a breakpoint or a pc hook isn't
+            // in the middle of it. Breakpoints can be outside of the guest's
+            // code, like a return address, so they are checked before looking
+            // up the block
+            if !instrumented && !interpret && (pc != block_pc || block_instrs.is_empty()) &&
+                !self.stops_in(pc, 1)
+            {
+                let next = match blocks.lookup(&mut self.mem, block, pc) {
+                    Err(memerr) => exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
+                    Ok(next) => next,
+                };
+                block = Some(next);
+                block_instrs = blocks.instrs(next);
+                block_pc = pc;
+
+                if limit.saturating_sub(self.instret) >= block_instrs.len() as u64 &&
+                    !self.stops_in(pc, block_instrs.len()) {
+                    let mut exit = None;
+
+                    for &(instr, op) in block_instrs {
+                        match self.execute(pc, instr, op) {
+                            Step::Next => pc += 4,
+                            Step::Jump(target) => pc = target,
+                            Step::Exit(err) => {
+                                exit = Some(err);
+                                break;
+                            },
+                            Step::Stop(err) => {
+                                pc += 4;
+                                exit = Some(err);
+                                break;
+                            },
+                        }
+
+                        // the rest of the block might be stale
+                        if self.mem.code_modified() {
+                            break;
+                        }
                     }
 
-                    let old_pc = pc;
-                    pc = self.read_reg(typ.rs1).wrapping_add(typ.imm);
-                    self.write_reg(typ.rd, old_pc + 4);
-                    continue 'next_instruction;
+                    if let Some(exit) = exit {
+                        exit!(exit);
+                    }
+                    block_instrs = &[];
+                    continue;
+                }
+            }
 
-                },
+            if instrumented {
+                if self.stack_frames.is_some() {
+                    if self.read_reg(RegName::Sp.as_reg()) != sp {
+                        self.track_stack(last_pc, sp);
+                        sp = self.read_reg(RegName::Sp.as_reg());
+                    }
+                    last_pc = pc;
+                }
 
-                // BRANCH
-                0b1100011 => {
-                    let typ = BType::parse(instr);
+                if let Some(exit) = stop.take() {
+                    exit!(exit);
+                }
+            }
 
-                    let take_branch = match typ.funct3 {
-                        // BEQ
-                        0b000 => self.read_reg(typ.rs1) == self.read_reg(typ.rs2),
+            if !self.breakpoints.is_empty() {
+                let resuming = resume_from.take() == Some(pc);
+                if !resuming && self.breakpoints.contains(&pc) {
+                    self.at_breakpoint = Some(pc);
+                    exit!(EmulatorExit::Breakpoint);
+                }
+            }
 
-                        // BNE
-                        0b001 => self.read_reg(typ.rs1) != self.read_reg(typ.rs2),
+            if self.instret >= limit {
+                exit!(EmulatorExit::Timeout);
+            }
 
-                        // BLT
-                        0b100 => (self.read_reg(typ.rs1) as i32) < self.read_reg(typ.rs2) as i32,
+            let resuming_hook = resume_from_hook.take() == Some(pc);
 
-                        // BGE
-                        0b101 => self.read_reg(typ.rs1) as i32 >= self.read_reg(typ.rs2) as i32,
+            if !self.pc_hooks.is_empty() && !resuming_hook {
+                if let Some(PcHook(hook)) = self.pc_hooks.get(&pc).cloned() {
+                    self.pc = pc;
+                    let result = (hook.borrow_mut())(self);
+                    instrumented = self.instrumented();
 
-                        // BLTU
-                        0b110 => self.read_reg(typ.rs1) < self.read_reg(typ.rs2),
+                    match result {
+                        PcHookResult::Continue => (),
+                        PcHookResult::Return => self.emulate_return(),
+                        PcHookResult::Stop(exit) => {
+                            self.at_hook = Some(pc);
+                            exit!(exit);
+                        },
+                    }
 
-                        // BGEU
-                        0b111 => self.read_reg(typ.rs1) >= self.read_reg(typ.rs2),
+                    // the hook moved somewhere else
+                    if self.pc != pc {
+                        pc = self.pc;
+                        self.instret += 1;
+                        continue;
+                    }
 
-                        _ => exit!(EmulatorExit::InvalidInstruction(instr)),
-                    };
+                    // or changed the code
+                    if self.mem.code_modified() {
+                        continue;
+                    }
+                }
+            }
 
-                    if take_branch {
-                        pc = pc.wrapping_add(typ.imm);
-                        continue 'next_instruction;
+            // execute hooks can change the instruction, so it is only taken
+            // from the block cache when there are none
+            let (instr, op) = if instrumented && self.mem.hooked(PERM_EXEC) {
+                let mut instr = match self.mem.read_u32(pc, PERM_EXEC) {
+                    Err(memerr) => exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
+                    Ok(instr) => instr,
+                };
+
+                if !resuming_hook {
+                    let mut access = MemoryAccess { pc, addr: pc, size: 4, access: PERM_EXEC, value: instr };
+                    if let HookResult::Stop(exit) = self.mem.call_hooks(&mut access) {
+                        self.at_hook = Some(pc);
+                        exit!(exit);
                     }
-                },
+                    instr = access.value;
+                }
 
-                // LOAD
-                0b0000011 => {
-                    let typ = IType::parse(instr);
-
-                    let addr = self.read_reg(typ.rs1).wrapping_add(typ.imm);
-
-                    let data = match typ.funct3 {
-                        // LB
-                        0b000 => self.mem.read_i8(addr, PERM_READ),
-                        // LH
-                        0b001 => self.mem.read_i16(addr, PERM_READ),
-                        // LW
-                        0b010 => self.mem.read_u32(addr, PERM_READ),
-                        // LBU
-                        0b100 => self.mem.read_u8(addr, PERM_READ),
-                        // LHU
-                        0b101 => self.mem.read_u16(addr, PERM_READ),
-                        _ => {
-                            exit!(EmulatorExit::InvalidInstruction(instr));
-                        },
+                (instr, decode(instr))
+            } else {
+                // carry on in the current block if we can, or move to the next
+                if pc != block_pc || block_instrs.is_empty() {
+                    block = match blocks.lookup(&mut self.mem, block, pc) {
+                        Err(memerr) => exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
+                        Ok(next) => Some(next),
                     };
-                    match data {
-                        Err(memerr) =>
-                            exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
-                        Ok(data) =>
-                            self.write_reg(typ.rd, data as i32 as u32),
-                    }
-                },
+                    block_instrs = blocks.instrs(block.unwrap());
+                    block_pc = pc;
+                }
 
-                // STORE
-                0b0100011 => {
-                    let typ = SType::parse(instr);
+                let (&instr, rest) = block_instrs.split_first().unwrap();
+                block_instrs = rest;
+                block_pc += 4;
+                instr
+            };
 
-                    let addr = self.read_reg(typ.rs1) + typ.imm;
-                    let data = self.read_reg(typ.rs2);
+            if let (Some(taint), Ok(op)) = (&mut self.taint, op) {
+                taint.step(&self.regs, pc, op);
+            }
 
-                    let res = match typ.funct3 {
-                        // SB
-                        0b000 => self.mem.write_u8(addr, PERM_WRITE, data as u8),
+            match self.execute(pc, instr, op) {
+                Step::Next => pc += 4,
+                Step::Jump(target) => pc = target,
+                Step::Exit(err) => exit!(err),
+                Step::Stop(err) => {
+                    pc += 4;
+                    stop = Some(err);
+                },
+            }
+        }
 
-                        // SH
-                        0b001 => self.mem.write_u16(addr, PERM_WRITE, data as u16),
+        self.pc = pc;
+        self.blocks = blocks;
+        self.jit = CompiledCode(jit);
 
-                        // SW
-                        0b010 => self.mem.write_u32(addr, PERM_WRITE, data as u32),
+        match ret {
+            EmulatorExit::InvalidMemoryAccess(err) => self.memory_fault(err),
+            ret => ret,
+        }
+    }
 
-                        _ => exit!(EmulatorExit::InvalidInstruction(instr)),
-                    };
+    /// Execute the instruction `instr` at `pc`, decoded as `op`
+    ///
+    /// Retired instructions are counted in [`Emulator::instret`].
+    #[inline(always)]
+    fn execute(&mut self, pc: u32, instr: u32, op: Result<Instruction, DecodeError>) -> Step {
+        if TRACE {
+            self.trace_print(pc);
+            println!("{}", disassemble_one(pc, instr));
+        }
 
-                    match res {
-                        Err(memerr) => exit!(EmulatorExit::InvalidMemoryAccess(memerr)),
-                        Ok(()) => (),
+        let Ok(op) = op else {
+            return Step::Exit(EmulatorExit::InvalidInstruction(instr));
+        };
+
+        // exit a read or write hook asked for
+        let mut stop = None;
+
+        match op {
+            Instruction::Lui { rd, imm } => self.write_reg(rd, imm),
+            Instruction::Auipc { rd, imm } => self.write_reg(rd, pc.wrapping_add(imm)),
+            Instruction::Jal { rd, imm } => {
+                // offset is in multiples of 2 bytes ??
+                let target = pc.wrapping_add(imm);
+                self.write_reg(rd, pc + 4);
+                self.record_edge(pc, target);
+                self.track_call(rd, None, pc + 4);
+                self.instret += 1;
+                return Step::Jump(target);
+            },
+            Instruction::Jalr { rd, rs1, imm } => {
+                let target = self.read_reg(rs1).wrapping_add(imm);
+                self.write_reg(rd, pc + 4);
+                self.record_edge(pc, target);
+                self.track_call(rd, Some(rs1), pc + 4);
+                self.instret += 1;
+                return Step::Jump(target);
+            },
+
+            Instruction::Beq { rs1, rs2, imm } | Instruction::Bne { rs1, rs2, imm } |
+            Instruction::Blt { rs1, rs2, imm } | Instruction::Bge { rs1, rs2, imm } |
+            Instruction::Bltu { rs1, rs2, imm } | Instruction::Bgeu { rs1, rs2, imm } => {
+                let rs1 = self.read_reg(rs1);
+                let rs2 = self.read_reg(rs2);
+
+                self.record_cmp(rs1, rs2);
+
+                let take_branch = match op {
+                    Instruction::Beq { .. } => rs1 == rs2,
+                    Instruction::Bne { .. } => rs1 != rs2,
+                    Instruction::Blt { .. } => (rs1 as i32) < rs2 as i32,
+                    Instruction::Bge { .. } => rs1 as i32 >= rs2 as i32,
+                    Instruction::Bltu { .. } => rs1 < rs2,
+                    Instruction::Bgeu { .. } => rs1 >= rs2,
+                    _ => unreachable!(),
+                };
+
+                if take_branch {
+                    let target = pc.wrapping_add(imm);
+                    self.record_edge(pc, target);
+                    self.instret += 1;
+                    return Step::Jump(target);
+                }
+
+                self.record_edge(pc, pc + 4);
+            },
+
+            Instruction::Lb { rd, rs1, imm } | Instruction::Lh { rd, rs1, imm } | Instruction::Lw { rd, rs1, imm } |
+            Instruction::Lbu { rd, rs1, imm } | Instruction::Lhu { rd, rs1, imm } => {
+                let addr = self.read_reg(rs1).wrapping_add(imm);
+
+                let (data, size) = match op {
+                    Instruction::Lb { .. } => (self.mem.read_i8(addr, PERM_READ), 1),
+                    Instruction::Lh { .. } => (self.mem.read_i16(addr, PERM_READ), 2),
+                    Instruction::Lw { .. } => (self.mem.read_u32(addr, PERM_READ), 4),
+                    Instruction::Lbu { .. } => (self.mem.read_u8(addr, PERM_READ), 1),
+                    Instruction::Lhu { .. } => (self.mem.read_u16(addr, PERM_READ), 2),
+                    _ => unreachable!(),
+                };
+                let mut data = match data {
+                    Err(memerr) => return Step::Exit(EmulatorExit::InvalidMemoryAccess(memerr)),
+                    Ok(data) => data,
+                };
+
+                if self.mem.hooked(PERM_READ) {
+                    let mut access = MemoryAccess { pc, addr, size, access: PERM_READ, value: data };
+                    if let HookResult::Stop(exit) = self.mem.call_hooks(&mut access) {
+                        stop = Some(exit);
                     }
+                    data = access.value;
                 }
 
-                // OP-IMM
-                0b0010011 => {
-                    let typ = IType::parse(instr);
+                self.write_reg(rd, data);
+            },
 
-                    // arithmetic right shift?
-                    // imm[11:5]
-                    let arithmetic = (typ.imm & ((1 << 12) - 1)) >> 5;
+            Instruction::Sb { rs1, rs2, imm } | Instruction::Sh { rs1, rs2, imm } | Instruction::Sw { rs1, rs2, imm } => {
+                let addr = self.read_reg(rs1).wrapping_add(imm);
+                let mut data = self.read_reg(rs2);
 
-                    // imm[4:0]
-                    let shamt = typ.imm & 0b11111;
+                let size = match op {
+                    Instruction::Sb { .. } => 1,
+                    Instruction::Sh { .. } => 2,
+                    _ => 4,
+                };
 
-                    match typ.funct3 {
-                        // ADDI
-                        0b000 => {
-                            let data = self.read_reg(typ.rs1).wrapping_add_signed(typ.imm as i32);
-                            self.write_reg(typ.rd, data);
-                        },
-                        // SLTI
-                        0b010 => {
-                            if (self.read_reg(typ.rs1) as i32) < typ.imm as i32 {
-                                self.write_reg(typ.rd, 1);
-                            } else {
-                                self.write_reg(typ.rd, 0);
-                            }
-                        },
-                        // SLTIU
-                        0b011 => {
-                            if self.read_reg(typ.rs1) < typ.imm {
-                                self.write_reg(typ.rd, 1);
-                            } else {
-                                self.write_reg(typ.rd, 0);
-                            }
-                        },
-                        // XORI
-                        0b100 => {
-                            let data = self.read_reg(typ.rs1) ^ typ.imm;
-                            self.write_reg(typ.rd, data);
-                        },
-                        // ORI
-                        0b110 => {
-                            let data = self.read_reg(typ.rs1) | typ.imm;
-                            self.write_reg(typ.rd, data);
-                        },
-                        // ANDI
-                        0b111 => {
-                            let data = self.read_reg(typ.rs1) & typ.imm;
-                            self.write_reg(typ.rd, data);
-                        },
-                        // SLLI
-                        0b001 => {
-                            if arithmetic != 0b0 {
-                                exit!(EmulatorExit::InvalidInstruction(instr));
-                            }
-
-                            let data = self.read_reg(typ.rs1) << shamt;
-                            self.write_reg(typ.rd, data);
-                        },
-                        // SRLI & SRAI
-                        0b101 => {
-                            match arithmetic {
-                                // SRLI
-                                0b0 => {
-                                    let data = self.read_reg(typ.rs1) >> shamt;
-                                    self.write_reg(typ.rd, data);
-                                },
-                                // SRAI
-                                0b0100000 => {
-                                    let data = self.read_reg(typ.rs1) as i32 >> shamt;
-                                    self.write_reg(typ.rd, data as u32);
-                                },
-                                _ => {
-                                    exit!(EmulatorExit::InvalidInstruction(instr));
-                                },
-                            };
-                        },
-                        _ => {
-                            exit!(EmulatorExit::InvalidInstruction(instr));
-                        },
-                    };
-                },
+                if self.mem.hooked(PERM_WRITE) {
+                    let mut access = MemoryAccess { pc, addr, size, access: PERM_WRITE, value: data };
+                    if let HookResult::Stop(exit) = self.mem.call_hooks(&mut access) {
+                        stop = Some(exit);
+                    }
+                    data = access.value;
+                }
 
-                // OP
-                0b0110011 => {
-                    let typ = RType::parse(instr);
+                let res = match size {
+                    1 => self.mem.write_u8(addr, PERM_WRITE, data as u8),
+                    2 => self.mem.write_u16(addr, PERM_WRITE, data as u16),
+                    _ => self.mem.write_u32(addr, PERM_WRITE, data),
+                };
 
-                    match (typ.funct3, typ.funct7) {
-                        // ADD
-                        (0b000, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, rs1.wrapping_add(rs2));
-                        },
-                        // SUB
-                        (0b000, 0b0100000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, rs1.wrapping_sub(rs2));
-                        },
-                        // SLL
-                        (0b001, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            let shamt = rs2 & 0b11111;
-                            self.write_reg(typ.rd, rs1 << shamt);
-                        },
-                        // SLT
-                        (0b010, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1) as i32;
-                            let rs2 = self.read_reg(typ.rs2) as i32;
-                            self.write_reg(typ.rd, if rs1 < rs2 { 1 } else { 0 });
-                        },
-                        // SLTU
-                        (0b011, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, if rs1 < rs2 { 1 } else { 0 });
-                        },
-                        // XOR
-                        (0b100, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, rs1 ^ rs2);
-                        },
-                        // SRL
-                        (0b101, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            let shamt = rs2 & 0b11111;
-                            self.write_reg(typ.rd, rs1 >> shamt);
-                        },
-                        // SRA
-                        (0b101, 0b0100000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            let shamt = rs2 & 0b11111;
-                            self.write_reg(typ.rd, (rs1 as i32 >> shamt) as u32);
-                        },
-                        // OR
-                        (0b110, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, rs1 | rs2);
-                        },
-                        // AND
-                        (0b111, 0b0000000) => {
-                            let rs1 = self.read_reg(typ.rs1);
-                            let rs2 = self.read_reg(typ.rs2);
-                            self.write_reg(typ.rd, rs1 & rs2);
-                        },
-                        _ => {
-                            exit!(EmulatorExit::InvalidInstruction(instr));
-                        },
-                    };
+                if let Err(memerr) = res {
+                    return Step::Exit(EmulatorExit::InvalidMemoryAccess(memerr));
                 }
+            },
+
+            Instruction::Addi { rd, rs1, imm } => self.write_reg(rd, self.read_reg(rs1).wrapping_add(imm)),
+            Instruction::Slti { rd, rs1, imm } => {
+                let rs1 = self.read_reg(rs1);
+                self.record_cmp(rs1, imm);
+                self.write_reg(rd, if (rs1 as i32) < imm as i32 { 1 } else { 0 });
+            },
+            Instruction::Sltiu { rd, rs1, imm } => {
+                let rs1 = self.read_reg(rs1);
+                self.record_cmp(rs1, imm);
+                self.write_reg(rd, if rs1 < imm { 1 } else { 0 });
+            },
+            Instruction::Xori { rd, rs1, imm } => self.write_reg(rd, self.read_reg(rs1) ^ imm),
+            Instruction::Ori { rd, rs1, imm } => self.write_reg(rd, self.read_reg(rs1) | imm),
+            Instruction::Andi { rd, rs1, imm } => self.write_reg(rd, self.read_reg(rs1) & imm),
+            Instruction::Slli { rd, rs1, shamt } => self.write_reg(rd, self.read_reg(rs1) << shamt),
+            Instruction::Srli { rd, rs1, shamt } => self.write_reg(rd, self.read_reg(rs1) >> shamt),
+            Instruction::Srai { rd, rs1, shamt } => self.write_reg(rd, (self.read_reg(rs1) as i32 >> shamt) as u32),
+
+            Instruction::Add { rd, rs1, rs2 } => self.write_reg(rd, self.read_reg(rs1).wrapping_add(self.read_reg(rs2))),
+            Instruction::Sub { rd, rs1, rs2 } => self.write_reg(rd, self.read_reg(rs1).wrapping_sub(self.read_reg(rs2))),
+            Instruction::Sll { rd, rs1, rs2 } => {
+                let shamt = self.read_reg(rs2) & 0b11111;
+                self.write_reg(rd, self.read_reg(rs1) << shamt);
+            },
+            Instruction::Slt { rd, rs1, rs2 } => {
+                let rs1 = self.read_reg(rs1) as i32;
+                let rs2 = self.read_reg(rs2) as i32;
+                self.record_cmp(rs1 as u32, rs2 as u32);
+                self.write_reg(rd, if rs1 < rs2 { 1 } else { 0 });
+            },
+            Instruction::Sltu { rd, rs1, rs2 } => {
+                let rs1 = self.read_reg(rs1);
+                let rs2 = self.read_reg(rs2);
+                self.record_cmp(rs1, rs2);
+                self.write_reg(rd, if rs1 < rs2 { 1 } else { 0 });
+            },
+            Instruction::Xor { rd, rs1, rs2 } => self.write_reg(rd, self.read_reg(rs1) ^ self.read_reg(rs2)),
+            Instruction::Srl { rd, rs1, rs2 } => {
+                let shamt = self.read_reg(rs2) & 0b11111;
+                self.write_reg(rd, self.read_reg(rs1) >> shamt);
+            },
+            Instruction::Sra { rd, rs1, rs2 } => {
+                let shamt = self.read_reg(rs2) & 0b11111;
+                self.write_reg(rd, (self.read_reg(rs1) as i32 >> shamt) as u32);
+            },
+            Instruction::Or { rd, rs1, rs2 } => self.write_reg(rd, self.read_reg(rs1) | self.read_reg(rs2)),
+            Instruction::And { rd, rs1, rs2 } => self.write_reg(rd, self.read_reg(rs1) & self.read_reg(rs2)),
+
+            Instruction::Fence => (),
+
+            Instruction::Ecall => {
+                // the kernel completes the ecall
+                self.instret += 1;
+                return Step::Exit(EmulatorExit::Syscall);
+            },
+            Instruction::Ebreak => return Step::Exit(EmulatorExit::Break),
+        }
 
-                // MISC-MEM
-                0b0001111 => {
-                    let typ = IType::parse(instr);
-                    // FENCE
-                    if typ.funct3 != 0b000 {
-                        exit!(EmulatorExit::InvalidInstruction(instr));
-                    }
-                },
-
-                // SYSTEM
-                0b1110011 => {
-                    let typ = IType::parse(instr);
+        self.instret += 1;
 
-                    if typ.rs1.0 != 0 || typ.rd.0 != 0 || typ.funct3 != 0 {
-                        exit!(EmulatorExit::InvalidInstruction(instr));
-                    }
+        match stop {
+            Some(exit) => Step::Stop(exit),
+            None => Step::Next,
+        }
+    }
 
-                    match typ.imm {
-                        // ECALL
-                        0b0 => {
-                            ret = EmulatorExit::Syscall;
-                            break;
-                        },
-                        0b1 => {
-                            ret = EmulatorExit::Break;
-                            break;
-                        },
-                        _ => {
-                            exit!(EmulatorExit::InvalidInstruction(instr));
-                        },
-                    }
-                },
+    /// Turn a memory error into a more specific exit if we know what the
+    /// faulting address is
+    ///
+    /// Also used for the accesses the kernel makes on behalf of the guest.
+    pub(crate) fn memory_fault(&self, err: MemoryError) -> EmulatorExit {
+        let addr = match &err {
+            MemoryError::BadPermissions { addr, .. } | MemoryError::OutOfBounds { addr } => addr.start,
+            MemoryError::OutOfMemory { .. } => return EmulatorExit::InvalidMemoryAccess(err),
+        };
 
-                _ => {
-                    exit!(EmulatorExit::InvalidInstruction(instr));
-                },
-            }
+        if addr < NULL_PAGE_SIZE {
+            return EmulatorExit::NullDereference { addr };
+        }
 
-            // update pc for next instruction
-            pc = pc + 4;
+        if self.stack_guard.as_ref().is_some_and(|guard| guard.contains(&addr)) {
+            return EmulatorExit::StackOverflow {
+                sp: self.read_reg(RegName::Sp.as_reg()),
+                depth: self.call_stack.len(),
+            };
         }
 
-        self.pc = pc as u32;
+        EmulatorExit::InvalidMemoryAccess(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn memory_hooks() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+            lui t0, 0x11
+            lw a0, 0(t0)
+            sw a0, 4(t0)
+            ebreak
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x10010, PERM_EXEC).unwrap();
+        emu.mem.set_permissions(0x11000..0x11008, PERM_READ | PERM_WRITE).unwrap();
+        emu.pc = 0x10000;
+
+        emu.mem.add_hook(0x11000..0x11004, PERM_READ, |access| {
+            assert_eq!((access.pc, access.addr, access.size), (0x10004, 0x11000, 4));
+            access.value = 42;
+            HookResult::Continue
+        });
+        emu.add_watchpoint(0x11004..0x11008, PERM_WRITE);
+
+        // the store completes before the watchpoint stops
+        assert!(matches!(emu.run(),
+            EmulatorExit::Watchpoint { pc: 0x10008, addr: 0x11004, access: PERM_WRITE }));
+        assert_eq!(emu.pc, 0x1000c);
+        assert_eq!(emu.mem.read_u32(0x11004, PERM_READ).unwrap(), 42);
+
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+    }
+
+    #[test]
+    fn pc_hooks() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+                jal ra, function
+                ebreak
+            function:
+                .word 0         # invalid, replaced by the hook
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x1000c, PERM_EXEC).unwrap();
+        emu.pc = 0x10000;
+
+        emu.add_pc_hook(0x10008, |emu| {
+            assert_eq!(emu.pc, 0x10008);
+            emu.write_reg(RegName::A0.as_reg(), 7);
+            PcHookResult::Return
+        });
+
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.pc, 0x10004);
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 7);
+        assert!(emu.call_stack.is_empty());
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+                li a0, 0
+            loop:
+                addi a0, a0, 1
+                j loop
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x1000c, PERM_EXEC).unwrap();
+        emu.pc = 0x10000;
+
+        // stops in the middle of the block, before the instruction that would
+        // go over the limit
+        emu.instruction_limit = Some(6);
+        assert!(matches!(emu.run(), EmulatorExit::Timeout));
+        assert_eq!((emu.pc, emu.instret), (0x10008, 6));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 3);
+
+        // and carries on from there with a higher limit
+        emu.instruction_limit = Some(11);
+        assert!(matches!(emu.run(), EmulatorExit::Timeout));
+        assert_eq!((emu.pc, emu.instret), (0x10004, 11));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 5);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+                li a0, 1
+                addi a0, a0, 1
+                addi a0, a0, 1
+                ebreak
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x10010, PERM_EXEC).unwrap();
+        emu.pc = 0x10000;
+
+        // a breakpoint in the middle of a block stops before that instruction
+        emu.breakpoints.insert(0x10008);
+        assert!(matches!(emu.run(), EmulatorExit::Breakpoint));
+        assert_eq!((emu.pc, emu.instret), (0x10008, 2));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 2);
+
+        // and running again carries on past it
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 3);
+    }
 
-        return ret;
+    #[test]
+    fn self_modifying_code() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+            lui t0, 0x10
+            li a0, 1
+            sw t1, 12(t0)
+            addi a0, a0, 1  # replaced by the store
+            ebreak
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x10014, PERM_EXEC | PERM_WRITE).unwrap();
+        emu.pc = 0x10000;
+        let replacement = assemble(0x1000c, "addi a0, a0, 5").unwrap();
+        emu.write_reg(RegName::T1.as_reg(), u32::from_le_bytes(replacement.try_into().unwrap()));
+        let snapshot = emu.fork();
+
+        // the store changes the rest of the block it is in
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 6);
+
+        // and resetting changes it back
+        emu.reset(&snapshot);
+        emu.pc = 0x1000c;
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 1);
     }
 }
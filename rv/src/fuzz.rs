@@ -0,0 +1,443 @@
+//! Coverage guided fuzzing of guest programs
+//!
+//! A [`Target`] runs the guest from a snapshot with an input placed where the
+//! harness says, and a [`Fuzzer`] mutates inputs from a [`Corpus`], keeping the
+//! ones that reach new coverage.
+
+pub mod corpus;
+pub mod minimize;
+pub mod mutator;
+pub mod triage;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::emulator::*;
+use crate::instructions::*;
+use crate::kernel::*;
+use crate::taint::Taint;
+
+use corpus::Corpus;
+use mutator::Mutator;
+use triage::{Bucket, Crash};
+
+/// Where the fuzz input is placed in the guest
+#[derive(Debug, Clone)]
+pub enum InputLocation {
+    /// Write the input to guest memory at `addr`, truncated to `max_len` bytes
+    ///
+    /// If `len_addr` is set the length of the input is written there as a u32.
+    Memory {
+        addr: u32,
+        max_len: u32,
+        len_addr: Option<u32>,
+    },
+
+    /// Serve the input as a file in the vfs
+    File(Vec<u8>),
+
+    /// Serve the input on stdin
+    Stdin,
+
+    /// Call the function the snapshot is stopped at with a pointer to the
+    /// input in a0 and its length in a1, see [`Target::persistent`]
+    Arguments {
+        /// Buffer the input is written to
+        addr: u32,
+        max_len: u32,
+    },
+}
+
+/// Return address given to the function under test in persistent mode, the
+/// function returning here ends the run
+pub const RETURN_SENTINEL: u32 = 0xffff_f000;
+
+/// A guest program set up for running many inputs from the same snapshot
+pub struct Target {
+    pub emu: Emulator,
+    pub kernel: Kernel,
+
+    /// Emulator state every run starts from
+    snapshot_emu: Emulator,
+
+    /// Kernel state every run starts from
+    snapshot_kernel: Kernel,
+
+    /// Where inputs are placed
+    input: InputLocation,
+}
+
+impl Target {
+    /// Snapshot the emulator and kernel, all runs will start from this state
+    ///
+    /// Coverage and cmplog collection are enabled.
+    pub fn new(mut emu: Emulator, kernel: Kernel, input: InputLocation) -> Self {
+        emu.coverage = Some(vec![0; COVERAGE_MAP_SIZE].into_boxed_slice());
+        emu.cmplog = Some(vec![]);
+
+        // the emulator itself runs, it keeps its compiled code
+        let snapshot_emu = emu.fork();
+        emu.mem.clear_dirty();
+
+        Target {
+            snapshot_emu,
+            snapshot_kernel: kernel.clone(),
+            emu,
+            kernel,
+            input,
+        }
+    }
+
+    /// Run the guest until it enters `function` and snapshot there, for
+    /// fuzzing a single function in persistent mode
+    ///
+    /// Every run calls the function with the input in a buffer of `max_len`
+    /// bytes, a pointer to it in a0 and the length in a1, and stops when the
+    /// function returns. The run exits with the function's return value.
+    ///
+    /// Returns how the guest stopped if it never reached the function, which
+    /// it has `budget` instructions to do.
+    pub fn persistent(mut emu: Emulator, mut kernel: Kernel, function: u32, max_len: u32, budget: u64)
+        -> Result<Self, KernelExit>
+    {
+        let limit = emu.instruction_limit;
+        emu.instruction_limit = Some(emu.instret.saturating_add(budget));
+        emu.breakpoints.insert(function);
+        let exit = kernel.run(&mut emu);
+        emu.breakpoints.remove(&function);
+        emu.instruction_limit = limit;
+
+        if !matches!(exit, KernelExit::Emulator(EmulatorExit::Breakpoint)) {
+            return Err(exit);
+        }
+
+        // the part of the buffer past the input stays uninitialized
+        let (addr, _) = emu.mem.allocate(max_len, PERM_RAW | PERM_WRITE)
+            .map_err(|err| KernelExit::Emulator(emu.memory_fault(err)))?;
+
+        emu.write_reg(RegName::Ra.as_reg(), RETURN_SENTINEL);
+        emu.breakpoints.insert(RETURN_SENTINEL);
+
+        Ok(Target::new(emu, kernel, InputLocation::Arguments { addr, max_len }))
+    }
+
+    /// Limit every run to `budget` instructions from the snapshot, runs that
+    /// exceed it exit with [`EmulatorExit::Timeout`]
+    pub fn set_timeout(&mut self, budget: Option<u64>) {
+        self.emu.instruction_limit = budget.map(|budget| self.snapshot_emu.instret + budget);
+    }
+
+    /// Track which input bytes reach branches and memory addresses in every
+    /// run, see [`Emulator::taint`]
+    pub fn enable_taint(&mut self) {
+        self.snapshot_emu.taint = Some(Taint::new());
+        self.emu.taint = Some(Taint::new());
+    }
+
+    /// Reset to the snapshot, place the input and run until the guest exits
+    /// or faults
+    ///
+    pub fn run(&mut self, input: &[u8]) -> KernelExit {
+        self.emu.reset(&self.snapshot_emu);
+        self.kernel.clone_from(&self.snapshot_kernel);
+
+        if let Some(coverage) = &mut self.emu.coverage {
+            coverage.fill(0);
+        }
+        if let Some(cmplog) = &mut self.emu.cmplog {
+            cmplog.clear();
+        }
+
+        match &self.input {
+            &InputLocation::Memory { addr, max_len, len_addr } => {
+                let input = &input[..input.len().min(max_len as usize)];
+                if let Err(err) = self.emu.mem.write(addr, PERM_NONE, input) {
+                    return KernelExit::Emulator(self.emu.memory_fault(err));
+                }
+                if let Some(len_addr) = len_addr {
+                    if let Err(err) = self.emu.mem.write_u32(len_addr, PERM_NONE, input.len() as u32) {
+                        return KernelExit::Emulator(self.emu.memory_fault(err));
+                    }
+                }
+                if let Some(taint) = &mut self.emu.taint {
+                    taint.taint_input(addr, 0..input.len() as u32);
+                }
+            },
+            InputLocation::File(path) => {
+                self.kernel.add_file(path, input);
+                if self.emu.taint.is_some() {
+                    self.kernel.taint_file(path);
+                }
+            },
+            InputLocation::Stdin => {
+                self.kernel.set_stdin(input);
+                if self.emu.taint.is_some() {
+                    self.kernel.taint_stdin();
+                }
+            },
+            &InputLocation::Arguments { addr, max_len } => {
+                let input = &input[..input.len().min(max_len as usize)];
+                if let Err(err) = self.emu.mem.write(addr, PERM_NONE, input) {
+                    return KernelExit::Emulator(self.emu.memory_fault(err));
+                }
+                self.emu.write_reg(RegName::A0.as_reg(), addr);
+                self.emu.write_reg(RegName::A1.as_reg(), input.len() as u32);
+                if let Some(taint) = &mut self.emu.taint {
+                    taint.taint_input(addr, 0..input.len() as u32);
+                }
+            },
+        }
+
+        match self.kernel.run(&mut self.emu) {
+            // the function under test returned
+            KernelExit::Emulator(EmulatorExit::Breakpoint) if self.emu.pc == RETURN_SENTINEL =>
+                KernelExit::Exit(self.emu.read_reg(RegName::A0.as_reg()) as i32),
+            exit => exit,
+        }
+    }
+}
+
+/// Bucket a coverage hit count, so that only meaningful changes in the number
+/// of hits count as new coverage
+fn bucket(count: u8) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1 << 0,
+        2 => 1 << 1,
+        3 => 1 << 2,
+        4..=7 => 1 << 3,
+        8..=15 => 1 << 4,
+        16..=31 => 1 << 5,
+        32..=127 => 1 << 6,
+        128.. => 1 << 7,
+    }
+}
+
+/// Merge `coverage` into `seen`, returns true if anything new was seen
+fn merge_coverage(seen: &mut [u8], coverage: &[u8]) -> bool {
+    let mut new = false;
+    for (seen, coverage) in seen.chunks_exact_mut(8).zip(coverage.chunks_exact(8)) {
+        // most of the map is empty, skip it a word at a time
+        if u64::from_ne_bytes(coverage.try_into().unwrap()) == 0 {
+            continue;
+        }
+
+        for (seen, &count) in seen.iter_mut().zip(coverage) {
+            let bucket = bucket(count);
+            if bucket & !*seen != 0 {
+                *seen |= bucket;
+                new = true;
+            }
+        }
+    }
+    new
+}
+
+/// Fuzzing statistics
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Number of inputs run
+    pub execs: u64,
+
+    /// Number of inputs that crashed
+    pub crashes: u64,
+
+    /// Number of inputs that timed out
+    pub timeouts: u64,
+}
+
+/// A coverage guided fuzzer
+pub struct Fuzzer {
+    pub target: Target,
+    pub corpus: Corpus,
+    pub mutator: Mutator,
+    pub stats: Stats,
+
+    /// Coverage buckets seen by all inputs so far
+    seen: Box<[u8]>,
+
+    /// Crash buckets seen so far
+    pub buckets: HashSet<Bucket>,
+}
+
+impl Fuzzer {
+    pub fn new(target: Target, corpus: Corpus, mutator: Mutator) -> Self {
+        Fuzzer {
+            target,
+            corpus,
+            mutator,
+            stats: Stats::default(),
+            seen: vec![0; COVERAGE_MAP_SIZE].into_boxed_slice(),
+            buckets: HashSet::new(),
+        }
+    }
+
+    /// Number of edges hit by any input so far
+    pub fn edges(&self) -> usize {
+        self.seen.iter().filter(|&&seen| seen != 0).count()
+    }
+
+    /// Run an input, adding it to the corpus if it reaches new coverage and
+    /// saving it with a report if it crashes in a bucket we haven't seen yet
+    ///
+    /// Returns true if the input was added to the corpus.
+    pub fn execute(&mut self, input: &[u8]) -> std::io::Result<bool> {
+        let exit = self.target.run(input);
+        self.stats.execs += 1;
+
+        let coverage = self.target.emu.coverage.as_deref().unwrap_or(&[]);
+        let new_coverage = merge_coverage(&mut self.seen, coverage);
+
+        match Crash::classify(&exit, &self.target.emu, &self.target.kernel) {
+            None => {
+                if new_coverage {
+                    let cmplog = self.target.emu.cmplog.as_deref().unwrap_or(&[]);
+                    return self.corpus.add(input, cmplog);
+                }
+            },
+            Some(crash) => {
+                if crash.kind == triage::CrashKind::Timeout {
+                    self.stats.timeouts += 1;
+                } else {
+                    self.stats.crashes += 1;
+                }
+
+                let bucket = crash.bucket();
+                if self.buckets.insert(bucket) {
+                    self.corpus.save_crash(&bucket.to_string(), input, &crash.report(None))?;
+                }
+            },
+        }
+
+        Ok(false)
+    }
+
+    /// Run the inputs already in the corpus to learn their coverage and
+    /// comparisons
+    fn calibrate(&mut self) -> std::io::Result<()> {
+        if self.corpus.is_empty() {
+            self.corpus.add(&[], &[])?;
+        }
+
+        for ii in 0..self.corpus.len() {
+            let input = std::mem::take(&mut self.corpus.inputs[ii].data);
+            self.execute(&input)?;
+
+            let cmplog = self.target.emu.cmplog.as_deref().unwrap_or(&[]).to_vec();
+            let entry = &mut self.corpus.inputs[ii];
+            entry.data = input;
+            entry.cmplog = cmplog;
+        }
+
+        Ok(())
+    }
+
+    /// Fuzz forever, or until `iterations` inputs have been run
+    ///
+    pub fn fuzz(&mut self, iterations: Option<u64>) -> std::io::Result<()> {
+        self.calibrate()?;
+
+        let start = Instant::now();
+        let mut last_report = start;
+        let mut input = vec![];
+
+        while iterations.is_none_or(|iterations| self.stats.execs < iterations) {
+            let rng = &mut self.mutator.rng;
+            let entry = rng.below(self.corpus.len());
+            let other = rng.below(self.corpus.len());
+
+            input.clear();
+            input.extend_from_slice(&self.corpus.inputs[entry].data);
+            self.mutator.mutate(&mut input,
+                &self.corpus.inputs[other].data, &self.corpus.inputs[entry].cmplog);
+
+            self.execute(&input)?;
+
+            if last_report.elapsed() >= Duration::from_secs(1) {
+                last_report = Instant::now();
+                let elapsed = start.elapsed().as_secs_f64();
+                println!("[{elapsed:10.1}s] execs {:10} ({:8.0}/s) corpus {:6} crashes {:6} timeouts {:6} ({} unique) edges {:6}",
+                    self.stats.execs,
+                    self.stats.execs as f64 / elapsed,
+                    self.corpus.len(),
+                    self.stats.crashes,
+                    self.stats.timeouts,
+                    self.buckets.len(),
+                    self.edges());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn persistent() {
+        let elf = testing::elf("
+            _start:
+                li s0, 100
+            .loop:
+                addi s0, s0, -1
+                bnez s0, .loop
+                call check
+                li a7, 93
+                ecall
+            check:
+                lbu t0, 0(a0)
+                add a0, a1, t0
+                ret
+        ");
+        let check = elf.lookup_by_name("check").unwrap().value;
+
+        // the warm-up run has a budget too
+        let (emu, kernel) = testing::guest(&elf);
+        let exit = Target::persistent(emu, kernel, check, 16, 100).err().unwrap();
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::Timeout)));
+
+        let (emu, kernel) = testing::guest(&elf);
+        let mut target = Target::persistent(emu, kernel, check, 16, 1000).unwrap();
+        assert_eq!(target.emu.instruction_limit, None);
+
+        // every run calls the function and exits with what it returns
+        assert!(matches!(target.run(b"\x05ab"), KernelExit::Exit(8)));
+        assert!(matches!(target.run(b"\x01"), KernelExit::Exit(2)));
+        assert!(matches!(target.run(&[0; 20]), KernelExit::Exit(16)));
+
+        // the buffer past the input is uninitialized
+        let exit = target.run(b"");
+        let crash = Crash::classify(&exit, &target.emu, &target.kernel).unwrap();
+        assert_eq!(crash.kind, triage::CrashKind::UninitRead);
+        assert_eq!(crash.pc, check);
+    }
+
+    #[test]
+    fn timeout() {
+        let elf = testing::elf("
+            _start:
+                li a0, 0
+                li a7, 93
+                beqz s0, _start
+                ecall
+        ");
+        let (emu, kernel) = testing::guest(&elf);
+        let mut target = Target::new(emu, kernel, InputLocation::Stdin);
+
+        // the budget counts from the snapshot
+        target.snapshot_emu.instret = 1000;
+        target.set_timeout(Some(50));
+
+        let exit = target.run(b"");
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::Timeout)));
+        assert_eq!(target.emu.instret, 1050);
+
+        // hangs are bucketed by where they are, not the pc they stopped at
+        let crash = Crash::classify(&exit, &target.emu, &target.kernel).unwrap();
+        assert_eq!(crash.kind, triage::CrashKind::Timeout);
+        assert_eq!(crash.bucket().pc, 0);
+    }
+}
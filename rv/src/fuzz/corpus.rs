@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Hash an input, used to name and deduplicate inputs on disk
+///
+/// This is FNV-1a, unlike std's hashers it gives the same hash with every
+/// toolchain, so the names of earlier sessions' files still match.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// An input in the corpus
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The input
+    pub data: Vec<u8>,
+
+    /// Comparisons the guest made when running this input
+    pub cmplog: Vec<(u32, u32)>,
+}
+
+/// A corpus of inputs, stored on disk
+///
+/// The corpus directory has three subdirectories:
+///
+/// - `in`: initial inputs, provided by the user
+/// - `queue`: inputs found while fuzzing that hit new coverage
+/// - `crashes`: one input per unique crash, with a `.txt` report
+///
+#[derive(Debug)]
+pub struct Corpus {
+    /// Root directory of the corpus
+    dir: PathBuf,
+
+    /// All inputs in the corpus
+    pub inputs: Vec<Entry>,
+
+    /// Hashes of all inputs in the corpus
+    hashes: HashSet<u64>,
+}
+
+impl Corpus {
+    /// Open a corpus directory, creating it if it doesn't exist, and load the
+    /// inputs in `in` and `queue`
+    ///
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+
+        for sub in ["in", "queue", "crashes"] {
+            std::fs::create_dir_all(dir.join(sub))?;
+        }
+
+        let mut corpus = Corpus {
+            dir,
+            inputs: vec![],
+            hashes: HashSet::new(),
+        };
+
+        for sub in ["in", "queue"] {
+            let mut paths = std::fs::read_dir(corpus.dir.join(sub))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            // load in a stable order
+            paths.sort();
+
+            for path in paths {
+                if !path.is_file() {
+                    continue;
+                }
+                let data = std::fs::read(path)?;
+                if corpus.hashes.insert(hash(&data)) {
+                    corpus.inputs.push(Entry { data, cmplog: vec![] });
+                }
+            }
+        }
+
+        Ok(corpus)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Add an input to the corpus and save it in `queue`
+    ///
+    /// Returns false if the input was already in the corpus.
+    pub fn add(&mut self, data: &[u8], cmplog: &[(u32, u32)]) -> std::io::Result<bool> {
+        let hash = hash(data);
+        if !self.hashes.insert(hash) {
+            return Ok(false);
+        }
+
+        std::fs::write(self.dir.join("queue").join(format!("{hash:016x}")), data)?;
+
+        self.inputs.push(Entry {
+            data: data.to_vec(),
+            cmplog: cmplog.to_vec(),
+        });
+
+        Ok(true)
+    }
+
+    /// Save a crashing input in `crashes`, with a text report next to it
+    pub fn save_crash(&self, name: &str, data: &[u8], report: &str) -> std::io::Result<PathBuf> {
+        let path = self.dir.join("crashes").join(name);
+        std::fs::write(&path, data)?;
+        std::fs::write(path.with_extension("txt"), report)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
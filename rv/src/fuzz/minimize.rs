@@ -0,0 +1,156 @@
+use crate::fuzz::Target;
+use crate::fuzz::triage::{Bucket, Crash};
+
+/// Byte that bytes are simplified to, `'0'` keeps text inputs readable
+const SIMPLE_BYTE: u8 = b'0';
+
+/// Shrinks a crashing input while keeping it in the same crash bucket
+pub struct Minimizer<'a> {
+    target: &'a mut Target,
+
+    /// Bucket every candidate has to crash in
+    bucket: Bucket,
+
+    /// Number of inputs run
+    pub execs: u64,
+}
+
+impl<'a> Minimizer<'a> {
+    pub fn new(target: &'a mut Target, bucket: Bucket) -> Self {
+        Minimizer {
+            target,
+            bucket,
+            execs: 0,
+        }
+    }
+
+    /// Does `input` still crash in the same bucket
+    fn reproduces(&mut self, input: &[u8]) -> bool {
+        self.execs += 1;
+        let exit = self.target.run(input);
+        Crash::classify(&exit, &self.target.emu, &self.target.kernel)
+            .is_some_and(|crash| crash.bucket() == self.bucket)
+    }
+
+    /// Minimize `input` until none of the strategies make progress
+    ///
+    pub fn minimize(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut input = input.to_vec();
+
+        loop {
+            let before = (input.len(), Self::complexity(&input));
+
+            self.trim(&mut input);
+            self.delete_blocks(&mut input);
+            self.simplify(&mut input);
+
+            if (input.len(), Self::complexity(&input)) == before {
+                return input;
+            }
+        }
+    }
+
+    /// Number of bytes that are not yet simplified
+    fn complexity(input: &[u8]) -> usize {
+        input.iter().filter(|&&byte| byte != SIMPLE_BYTE).count()
+    }
+
+    /// Cut off the end of the input, in decreasing chunks
+    fn trim(&mut self, input: &mut Vec<u8>) {
+        let mut chunk = input.len() / 2;
+        while chunk > 0 {
+            if chunk <= input.len() && self.reproduces(&input[..input.len() - chunk]) {
+                input.truncate(input.len() - chunk);
+            } else {
+                chunk /= 2;
+            }
+        }
+    }
+
+    /// Delete blocks of decreasing size from anywhere in the input
+    fn delete_blocks(&mut self, input: &mut Vec<u8>) {
+        let mut block = input.len() / 2;
+        while block > 0 {
+            let mut pos = 0;
+            while pos + block <= input.len() {
+                let mut candidate = input.clone();
+                candidate.drain(pos..pos + block);
+
+                if self.reproduces(&candidate) {
+                    *input = candidate;
+                } else {
+                    pos += block;
+                }
+            }
+            block /= 2;
+        }
+    }
+
+    /// Replace bytes with [`SIMPLE_BYTE`], first in blocks then one at a time
+    fn simplify(&mut self, input: &mut [u8]) {
+        let mut block = input.len().next_power_of_two() / 2;
+        loop {
+            for start in (0..input.len()).step_by(block.max(1)) {
+                let end = (start + block.max(1)).min(input.len());
+                if Self::complexity(&input[start..end]) == 0 {
+                    continue;
+                }
+
+                let mut candidate = input.to_vec();
+                candidate[start..end].fill(SIMPLE_BYTE);
+
+                if self.reproduces(&candidate) {
+                    input.copy_from_slice(&candidate);
+                }
+            }
+
+            if block <= 1 {
+                break;
+            }
+            block /= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzz::InputLocation;
+    use crate::testing;
+
+    #[test]
+    fn minimize() {
+        // crashes if the input is at least 4 bytes with an x second
+        let elf = testing::elf("
+            _start:
+                addi sp, sp, -64
+                li a0, 0
+                mv a1, sp
+                li a2, 64
+                li a7, 63
+                ecall
+                li t0, 4
+                blt a0, t0, exit
+                lbu t1, 1(sp)
+                li t0, 120
+                bne t1, t0, exit
+                sw zero, 0(zero)
+            exit:
+                li a0, 0
+                li a7, 93
+                ecall
+        ");
+        let (emu, kernel) = testing::guest(&elf);
+        let mut target = Target::new(emu, kernel, InputLocation::Stdin);
+
+        let input = b"axbcdefgh";
+        let exit = target.run(input);
+        let bucket = Crash::classify(&exit, &target.emu, &target.kernel).unwrap().bucket();
+
+        let mut minimizer = Minimizer::new(&mut target, bucket);
+        assert_eq!(minimizer.minimize(input), b"0x00");
+        assert!(minimizer.execs > 0);
+
+        assert!(Crash::classify(&target.run(b"0x0"), &target.emu, &target.kernel).is_none());
+    }
+}
@@ -0,0 +1,402 @@
+use std::path::Path;
+
+/// Values that tend to hit edge cases, as bytes
+const INTERESTING_8: [u8; 9] = [0x00, 0x01, 0x10, 0x20, 0x40, 0x64, 0x7f, 0x80, 0xff];
+
+/// Values that tend to hit edge cases, as 16 bit integers
+const INTERESTING_16: [u16; 10] = [
+    0x0000, 0x0080, 0x00ff, 0x0100, 0x0200, 0x03e8, 0x0400, 0x1000, 0x7fff, 0x8000,
+];
+
+/// Values that tend to hit edge cases, as 32 bit integers
+const INTERESTING_32: [u32; 8] = [
+    0x0000_0000, 0x0000_ffff, 0x0001_0000, 0x05f5_e100,
+    0x7fff_ffff, 0x8000_0000, 0xffff_fffe, 0xffff_ffff,
+];
+
+/// Largest value added or subtracted by arithmetic mutations
+const ARITH_MAX: u32 = 35;
+
+/// A xorshift random number generator
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0, which one seed still mixes to
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    /// Seed a generator from the current time
+    pub fn from_time() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Rng::new(now.as_nanos() as u64)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `0..n`, `n` must not be 0
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Pick a random element of a non-empty slice
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// The mutation strategies
+#[derive(Debug, Clone, Copy)]
+enum Strategy {
+    BitFlip,
+    ByteReplace,
+    Arithmetic,
+    BlockInsert,
+    BlockDelete,
+    Splice,
+    Dictionary,
+    CmpLog,
+}
+
+const STRATEGIES: [Strategy; 8] = [
+    Strategy::BitFlip,
+    Strategy::ByteReplace,
+    Strategy::Arithmetic,
+    Strategy::BlockInsert,
+    Strategy::BlockDelete,
+    Strategy::Splice,
+    Strategy::Dictionary,
+    Strategy::CmpLog,
+];
+
+/// Parse a token from an AFL style dictionary line
+///
+/// Lines look like `name="value"` or `"value"`, where the value can contain
+/// `\\`, `\"` and `\xNN` escapes.
+fn parse_token(line: &str) -> Option<Vec<u8>> {
+    let start = line.find('"')?;
+    let end = line.rfind('"')?;
+    if end <= start {
+        return None;
+    }
+
+    let mut token = vec![];
+    let mut chars = line[start + 1..end].bytes();
+    while let Some(ch) = chars.next() {
+        if ch != b'\\' {
+            token.push(ch);
+            continue;
+        }
+
+        match chars.next()? {
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                token.push(u8::from_str_radix(hex, 16).ok()?);
+            },
+            ch => token.push(ch),
+        }
+    }
+
+    Some(token)
+}
+
+/// Mutates inputs
+#[derive(Debug, Clone)]
+pub struct Mutator {
+    pub rng: Rng,
+
+    /// Tokens to insert into inputs
+    pub dictionary: Vec<Vec<u8>>,
+
+    /// Mutated inputs are never longer than this
+    pub max_len: usize,
+}
+
+impl Mutator {
+    pub fn new(rng: Rng, max_len: usize) -> Self {
+        Mutator {
+            rng,
+            dictionary: vec![],
+            max_len,
+        }
+    }
+
+    /// Add the tokens in an AFL style dictionary file to the dictionary
+    pub fn load_dictionary<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let dict = std::fs::read_to_string(path)?;
+
+        for (lineno, line) in dict.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some(token) = parse_token(line) else {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                    format!("bad dictionary entry on line {}: {line}", lineno + 1)));
+            };
+
+            if !token.is_empty() {
+                self.dictionary.push(token);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply a random number of random mutations to `input`
+    ///
+    /// `other` is another input from the corpus to splice from, `cmplog` are
+    /// the comparisons the guest made when running `input`.
+    pub fn mutate(&mut self, input: &mut Vec<u8>, other: &[u8], cmplog: &[(u32, u32)]) {
+        if self.max_len == 0 {
+            input.clear();
+            return;
+        }
+
+        let count = 1 << self.rng.below(4);
+
+        for _ in 0..count {
+            // keep trying until a strategy applies
+            while !self.mutate_one(input, other, cmplog) {}
+        }
+
+        input.truncate(self.max_len);
+    }
+
+    /// Apply a single random mutation, returns false if the strategy could
+    /// not be applied
+    fn mutate_one(&mut self, input: &mut Vec<u8>, other: &[u8], cmplog: &[(u32, u32)]) -> bool {
+        match *self.rng.choose(&STRATEGIES) {
+            Strategy::BitFlip => {
+                if input.is_empty() {
+                    return false;
+                }
+                let bit = self.rng.below(input.len() * 8);
+                input[bit / 8] ^= 1 << (bit % 8);
+            },
+
+            Strategy::ByteReplace => {
+                if input.is_empty() {
+                    return false;
+                }
+                let pos = self.rng.below(input.len());
+                input[pos] = if self.rng.below(2) == 0 {
+                    self.rng.next() as u8
+                } else {
+                    *self.rng.choose(&INTERESTING_8)
+                };
+            },
+
+            Strategy::Arithmetic => {
+                let width = *self.rng.choose(&[1, 2, 4]);
+                if input.len() < width {
+                    return false;
+                }
+                let pos = self.rng.below(input.len() - width + 1);
+                let big_endian = self.rng.below(2) == 0;
+
+                let mut bytes = [0u8; 4];
+                bytes[..width].copy_from_slice(&input[pos..pos + width]);
+                if big_endian {
+                    bytes[..width].reverse();
+                }
+                let val = u32::from_le_bytes(bytes);
+
+                let val = match self.rng.below(3) {
+                    0 => val.wrapping_add(self.rng.below(ARITH_MAX as usize) as u32 + 1),
+                    1 => val.wrapping_sub(self.rng.below(ARITH_MAX as usize) as u32 + 1),
+                    _ => match width {
+                        1 => *self.rng.choose(&INTERESTING_8) as u32,
+                        2 => *self.rng.choose(&INTERESTING_16) as u32,
+                        _ => *self.rng.choose(&INTERESTING_32),
+                    },
+                };
+
+                let mut bytes = val.to_le_bytes();
+                if big_endian {
+                    bytes[..width].reverse();
+                }
+                input[pos..pos + width].copy_from_slice(&bytes[..width]);
+            },
+
+            Strategy::BlockInsert => {
+                if input.len() >= self.max_len {
+                    return false;
+                }
+                let len = 1 + self.rng.below((self.max_len - input.len()).min(128));
+                let pos = self.rng.below(input.len() + 1);
+
+                let block: Vec<u8> = match self.rng.below(3) {
+                    // random bytes
+                    0 => (0..len).map(|_| self.rng.next() as u8).collect(),
+                    // a repeated byte
+                    1 => vec![self.rng.next() as u8; len],
+                    // a copy of another part of the input
+                    _ => {
+                        if input.is_empty() {
+                            return false;
+                        }
+                        let start = self.rng.below(input.len());
+                        let end = (start + len).min(input.len());
+                        input[start..end].to_vec()
+                    },
+                };
+
+                input.splice(pos..pos, block);
+            },
+
+            Strategy::BlockDelete => {
+                if input.len() < 2 {
+                    return false;
+                }
+                let start = self.rng.below(input.len());
+                let len = 1 + self.rng.below((input.len() - start).min(128));
+                input.drain(start..start + len);
+            },
+
+            Strategy::Splice => {
+                if other.is_empty() {
+                    return false;
+                }
+                let start = self.rng.below(other.len());
+                let len = 1 + self.rng.below(other.len() - start);
+                let block = &other[start..start + len];
+
+                let pos = self.rng.below(input.len() + 1);
+                if self.rng.below(2) == 0 {
+                    // insert the block
+                    input.splice(pos..pos, block.iter().copied());
+                } else {
+                    // overwrite with the block
+                    let end = (pos + len).min(input.len());
+                    input.splice(pos..end, block.iter().copied());
+                }
+            },
+
+            Strategy::Dictionary => {
+                if self.dictionary.is_empty() {
+                    return false;
+                }
+                let token = self.rng.choose(&self.dictionary).clone();
+                let pos = self.rng.below(input.len() + 1);
+
+                if self.rng.below(2) == 0 {
+                    input.splice(pos..pos, token);
+                } else {
+                    let end = (pos + token.len()).min(input.len());
+                    input.splice(pos..end, token);
+                }
+            },
+
+            Strategy::CmpLog => {
+                if cmplog.is_empty() {
+                    return false;
+                }
+                let &(a, b) = self.rng.choose(cmplog);
+                // either operand could come from the input
+                let (from, to) = if self.rng.below(2) == 0 { (a, b) } else { (b, a) };
+
+                return self.replace_value(input, from, to);
+            },
+        }
+
+        true
+    }
+
+    /// Find `from` somewhere in the input, encoded as a 1, 2 or 4 byte little
+    /// or big endian integer, and replace it with `to` encoded the same way
+    fn replace_value(&mut self, input: &mut [u8], from: u32, to: u32) -> bool {
+        let mut candidates = vec![];
+
+        for width in [1usize, 2, 4] {
+            // the value must fit in the width, either zero or sign extended
+            let bits = width as u32 * 8;
+            if bits < 32 {
+                let top = from >> (bits - 1);
+                if top != 0 && top != u32::MAX >> (bits - 1) {
+                    continue;
+                }
+            }
+
+            for big_endian in [false, true] {
+                let mut pattern = from.to_le_bytes();
+                if big_endian {
+                    pattern[..width].reverse();
+                }
+                let pattern = &pattern[..width];
+
+                for pos in 0..input.len().saturating_sub(width - 1) {
+                    if &input[pos..pos + width] == pattern {
+                        candidates.push((pos, width, big_endian));
+                    }
+                }
+            }
+        }
+
+        if candidates.is_empty() {
+            return false;
+        }
+
+        let &(pos, width, big_endian) = self.rng.choose(&candidates);
+        let mut bytes = to.to_le_bytes();
+        if big_endian {
+            bytes[..width].reverse();
+        }
+        input[pos..pos + width].copy_from_slice(&bytes[..width]);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_seed() {
+        for seed in [0, 1, 0x9e37_79b9_7f4a_7c15] {
+            let mut rng = Rng::new(seed);
+            let first = rng.next();
+            assert_ne!(first, 0);
+            assert_ne!(rng.next(), first);
+        }
+    }
+
+    #[test]
+    fn dictionary_tokens() {
+        assert_eq!(parse_token(r#"kw="GET""#), Some(b"GET".to_vec()));
+        assert_eq!(parse_token(r#""\x00\x01\"\\""#), Some(b"\x00\x01\"\\".to_vec()));
+        assert_eq!(parse_token(r#"bad"#), None);
+    }
+
+    #[test]
+    fn cmplog_replace() {
+        let mut mutator = Mutator::new(Rng::new(0), 64);
+
+        let mut input = b"xxxx\x78\x56\x34\x12yyyy".to_vec();
+        assert!(mutator.replace_value(&mut input, 0x12345678, 0xdeadbeef));
+        assert_eq!(input, b"xxxx\xef\xbe\xad\xdeyyyy");
+
+        assert!(!mutator.replace_value(&mut input, 0x12345678, 0));
+    }
+
+    #[test]
+    fn max_len() {
+        let mut mutator = Mutator::new(Rng::new(1), 16);
+        let mut input = vec![];
+        for _ in 0..1000 {
+            mutator.mutate(&mut input, b"splice me", &[(1, 2)]);
+            assert!(input.len() <= 16);
+        }
+    }
+}
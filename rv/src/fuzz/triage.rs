@@ -0,0 +1,482 @@
+use elf::{Elf, Frame};
+
+use crate::emulator::*;
+use crate::fuzz::corpus;
+use crate::kernel::*;
+use crate::sanitizer::*;
+use crate::taint::{format_offsets, SinkKind};
+
+/// Number of innermost call frames hashed into a crash bucket
+pub const BUCKET_FRAMES: usize = 5;
+
+/// Number of innermost call frames printed in a report, a stack overflow can
+/// have a lot of them
+pub const REPORT_FRAMES: usize = 64;
+
+/// What kind of fault a crash is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CrashKind {
+    /// Read from memory without read permission
+    ReadViolation,
+
+    /// Write to memory without write permission
+    WriteViolation,
+
+    /// Execute memory without execute permission
+    ExecViolation,
+
+    /// Read of memory that was never written, `PERM_RAW`
+    UninitRead,
+
+    /// Access outside of guest memory
+    OutOfBounds,
+
+    /// Guest memory ran out
+    OutOfMemory,
+
+    /// Undecodable or unsupported instruction
+    InvalidInstruction,
+
+    /// `ebreak` executed
+    Break,
+
+    /// Syscall we don't implement
+    UnhandledSyscall,
+
+    /// Ran out of instruction budget, the guest is probably hanging
+    Timeout,
+
+    /// Ran into the guard region below the stack
+    StackOverflow,
+
+    /// Access to the reserved low addresses, a null pointer plus some offset
+    NullDereference,
+
+    /// Access to a heap redzone, needs the heap sanitizer
+    HeapOverflow,
+
+    /// Access to a freed heap chunk, needs the heap sanitizer
+    UseAfterFree,
+
+    /// Heap chunk freed twice, needs the heap sanitizer
+    DoubleFree,
+
+    /// Free of a pointer that is not a heap chunk, needs the heap sanitizer
+    InvalidFree,
+}
+
+impl CrashKind {
+    /// Short name, used in file names
+    pub fn name(self) -> &'static str {
+        match self {
+            CrashKind::ReadViolation => "read",
+            CrashKind::WriteViolation => "write",
+            CrashKind::ExecViolation => "exec",
+            CrashKind::UninitRead => "uninit",
+            CrashKind::OutOfBounds => "oob",
+            CrashKind::OutOfMemory => "oom",
+            CrashKind::InvalidInstruction => "invalid-instruction",
+            CrashKind::Break => "break",
+            CrashKind::UnhandledSyscall => "syscall",
+            CrashKind::Timeout => "timeout",
+            CrashKind::StackOverflow => "stack-overflow",
+            CrashKind::NullDereference => "null-deref",
+            CrashKind::HeapOverflow => "heap-overflow",
+            CrashKind::UseAfterFree => "use-after-free",
+            CrashKind::DoubleFree => "double-free",
+            CrashKind::InvalidFree => "invalid-free",
+        }
+    }
+
+    /// Inverse of [`CrashKind::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        const KINDS: [CrashKind; 16] = [
+            CrashKind::ReadViolation,
+            CrashKind::WriteViolation,
+            CrashKind::ExecViolation,
+            CrashKind::UninitRead,
+            CrashKind::OutOfBounds,
+            CrashKind::OutOfMemory,
+            CrashKind::InvalidInstruction,
+            CrashKind::Break,
+            CrashKind::UnhandledSyscall,
+            CrashKind::Timeout,
+            CrashKind::StackOverflow,
+            CrashKind::NullDereference,
+            CrashKind::HeapOverflow,
+            CrashKind::UseAfterFree,
+            CrashKind::DoubleFree,
+            CrashKind::InvalidFree,
+        ];
+        KINDS.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+/// Crashes in the same bucket are considered to be the same bug
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bucket {
+    pub kind: CrashKind,
+
+    /// pc of the faulting instruction
+    pub pc: u32,
+
+    /// Hash of the innermost [`BUCKET_FRAMES`] call frames, the same in every
+    /// build so that bucket names of earlier sessions still match
+    pub stack_hash: u64,
+}
+
+impl std::fmt::Display for Bucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{:08x}-{:016x}", self.kind.name(), self.pc, self.stack_hash)
+    }
+}
+
+impl std::str::FromStr for Bucket {
+    type Err = ();
+
+    /// Parse a bucket from its [`std::fmt::Display`] form, as used for the
+    /// crash file names
+    fn from_str(name: &str) -> Result<Self, ()> {
+        let mut parts = name.rsplitn(3, '-');
+        let stack_hash = u64::from_str_radix(parts.next().ok_or(())?, 16).map_err(|_| ())?;
+        let pc = u32::from_str_radix(parts.next().ok_or(())?, 16).map_err(|_| ())?;
+        let kind = CrashKind::from_name(parts.next().ok_or(())?).ok_or(())?;
+
+        Ok(Bucket { kind, pc, stack_hash })
+    }
+}
+
+/// A classified crash
+#[derive(Debug, Clone)]
+pub struct Crash {
+    pub kind: CrashKind,
+
+    /// pc of the faulting instruction
+    pub pc: u32,
+
+    /// Address range of the faulting memory access, if any
+    pub addr: Option<std::ops::Range<u32>>,
+
+    /// Return addresses of the active calls, innermost first
+    pub backtrace: Vec<u32>,
+
+    /// What went wrong, in more detail
+    pub detail: String,
+
+    /// Register state at the time of the crash
+    pub regs: String,
+
+    /// More context, like where the heap chunk involved was allocated
+    pub notes: String,
+}
+
+impl Crash {
+    /// Classify why the guest stopped, `None` if it exited normally
+    pub fn classify(exit: &KernelExit, emu: &Emulator, kernel: &Kernel) -> Option<Crash> {
+        let mut notes = String::new();
+        let (mut kind, addr, mut detail) = match exit {
+            KernelExit::Exit(_) => return None,
+            KernelExit::UnhandledSyscall(no) =>
+                (CrashKind::UnhandledSyscall, None, format!("unhandled syscall {no}")),
+            KernelExit::Emulator(EmulatorExit::Sanitizer(SanitizerError::DoubleFree { addr })) => {
+                if let Some(chunk) = emu.sanitizer.as_ref().and_then(|san| san.chunk(*addr)) {
+                    notes = chunk_history(chunk);
+                }
+                (CrashKind::DoubleFree, Some(*addr..*addr + 1), format!("double free of {addr:#010x}"))
+            },
+            KernelExit::Emulator(EmulatorExit::Sanitizer(SanitizerError::InvalidFree { addr })) =>
+                (CrashKind::InvalidFree, Some(*addr..*addr + 1), format!("free of {addr:#010x}, which is not a heap chunk")),
+            KernelExit::Emulator(EmulatorExit::Syscall) =>
+                unreachable!("the kernel handles syscalls"),
+            KernelExit::Emulator(EmulatorExit::Break) =>
+                (CrashKind::Break, None, "unhandled break".to_string()),
+            KernelExit::Emulator(EmulatorExit::Breakpoint) =>
+                (CrashKind::Break, None, "unhandled breakpoint".to_string()),
+            KernelExit::Emulator(EmulatorExit::Watchpoint { pc, addr, access }) =>
+                (CrashKind::Break, Some(*addr..*addr + 1),
+                    format!("{} access to watched memory at {addr:#010x} by {pc:#010x}", format_perms(*access))),
+            KernelExit::Emulator(EmulatorExit::Timeout) =>
+                (CrashKind::Timeout, None, format!("timed out after {} instructions", emu.instret)),
+            KernelExit::Emulator(EmulatorExit::StackOverflow { sp, depth }) =>
+                (CrashKind::StackOverflow, None, format!("stack overflow with sp {sp:#010x} at call depth {depth}")),
+            KernelExit::Emulator(EmulatorExit::NullDereference { addr }) =>
+                (CrashKind::NullDereference, Some(*addr..*addr + 1), format!("null pointer dereference at {addr:#010x}")),
+            KernelExit::Emulator(EmulatorExit::InvalidInstruction(instr)) =>
+                (CrashKind::InvalidInstruction, None, format!("invalid instruction {instr:#010x}")),
+            KernelExit::Emulator(EmulatorExit::InvalidMemoryAccess(err)) => match err {
+                MemoryError::BadPermissions { addr, access, perms } => {
+                    let kind = if *access == PERM_READ && perms & PERM_RAW != 0 {
+                        CrashKind::UninitRead
+                    } else if *access == PERM_READ {
+                        CrashKind::ReadViolation
+                    } else if *access == PERM_WRITE {
+                        CrashKind::WriteViolation
+                    } else {
+                        CrashKind::ExecViolation
+                    };
+                    let detail = format!("{} byte access {} to memory with permissions {}",
+                        addr.len(), format_perms(*access), format_perms(*perms));
+                    (kind, Some(addr.clone()), detail)
+                },
+                MemoryError::OutOfBounds { addr } =>
+                    (CrashKind::OutOfBounds, Some(addr.clone()), format!("{} byte access out of bounds", addr.len())),
+                MemoryError::OutOfMemory { err } =>
+                    (CrashKind::OutOfMemory, None, format!("out of memory: {err:?}")),
+            },
+        };
+
+        // faults in the sanitizer arena are heap bugs
+        let heap = addr.as_ref().zip(emu.sanitizer.as_ref())
+            .filter(|_| matches!(kind, CrashKind::ReadViolation | CrashKind::WriteViolation))
+            .and_then(|(addr, sanitizer)| Some((addr, sanitizer.describe(addr.start)?)));
+        if let Some((addr, access)) = heap {
+            let (heap_kind, chunk) = match access {
+                HeapAccess::Overflow(chunk) => (CrashKind::HeapOverflow, chunk),
+                HeapAccess::UseAfterFree(chunk) => (CrashKind::UseAfterFree, chunk),
+            };
+            let chunk_end = chunk.addr + chunk.size;
+            let position = if addr.start < chunk.addr {
+                format!("{} bytes before", chunk.addr - addr.start)
+            } else if addr.start >= chunk_end {
+                format!("{} bytes after", addr.start - chunk_end)
+            } else {
+                format!("{} bytes into", addr.start - chunk.addr)
+            };
+
+            detail = format!("{detail}, {position} the {} byte chunk at {:#010x}", chunk.size, chunk.addr);
+            kind = heap_kind;
+            notes = chunk_history(chunk);
+        }
+
+        // say where uninitialized memory came from
+        let uninit = addr.as_ref().filter(|_| kind == CrashKind::UninitRead)
+            .and_then(|addr| addr.clone().find(|&byte| emu.mem.perms[byte as usize] & PERM_RAW != 0));
+        if let Some(byte) = uninit {
+            let len = addr.as_ref().unwrap().len();
+            match kernel.origin(emu, byte) {
+                Some(origin) => {
+                    detail = format!("{len} byte read of uninitialized memory at {byte:#010x} from a {}",
+                        origin.kind.name());
+                    notes = allocation_history(&origin);
+                },
+                None => detail = format!("{len} byte read of uninitialized memory at {byte:#010x}"),
+            }
+        }
+
+        // say which input bytes the faulting address was computed from
+        if let Some(taint) = emu.taint.as_ref().filter(|_| addr.is_some()) {
+            let mut offsets = taint.sink_offsets(emu.pc, SinkKind::LoadAddress);
+            offsets.extend(taint.sink_offsets(emu.pc, SinkKind::StoreAddress));
+            offsets.sort_unstable();
+            offsets.dedup();
+            if !offsets.is_empty() {
+                notes += &format!("\naddress computed from input bytes: {}\n", format_offsets(&offsets));
+            }
+        }
+
+        Some(Crash {
+            kind,
+            pc: emu.pc,
+            addr,
+            backtrace: emu.call_stack.iter().rev().copied().collect(),
+            detail,
+            regs: emu.format_regs(emu.pc),
+            notes,
+        })
+    }
+
+    /// The bucket this crash belongs to
+    pub fn bucket(&self) -> Bucket {
+        let frames: Vec<u8> = self.backtrace.iter()
+            .take(BUCKET_FRAMES)
+            .flat_map(|frame| frame.to_le_bytes())
+            .collect();
+
+        // a hang stops at whatever pc it happened to be at, only the stack
+        // says anything about where it is
+        let pc = if self.kind == CrashKind::Timeout { 0 } else { self.pc };
+
+        Bucket {
+            kind: self.kind,
+            pc,
+            stack_hash: corpus::hash(&frames),
+        }
+    }
+
+    /// A human readable report of the crash, with the code addresses named
+    /// by the symbols of `elf` if given
+    pub fn report(&self, elf: Option<&Elf>) -> String {
+        let mut out = String::new();
+        out += &format!("crash:   {}\n", self.kind.name());
+        out += &format!("bucket:  {}\n", self.bucket());
+        out += &format!("pc:      {}", describe_addr(elf, self.pc));
+        if let Some(location) = elf.and_then(|elf| elf.addr_to_line(self.pc)) {
+            out += &format!(" at {location}");
+        }
+        out += "\n";
+        if let Some(addr) = &self.addr {
+            out += &format!("address: {:#010x}-{:#010x}\n", addr.start, addr.end);
+        }
+        out += &format!("detail:  {}\n", self.detail);
+        out += "\nbacktrace:\n";
+        out += &describe_frame(elf, 0, self.pc, self.pc);
+        for (ii, &addr) in self.backtrace.iter().enumerate().take(REPORT_FRAMES) {
+            // the source location of a return address is the call before it
+            out += &describe_frame(elf, ii + 1, addr, addr.wrapping_sub(4));
+        }
+        if self.backtrace.len() > REPORT_FRAMES {
+            out += &format!("  ... {} more\n", self.backtrace.len() - REPORT_FRAMES);
+        }
+        out += &self.notes;
+        out += "\nregisters:\n";
+        out += &self.regs;
+        out
+    }
+}
+
+/// A code address and the symbol of `elf` it is in, for crash reports
+fn describe_addr(elf: Option<&Elf>, addr: u32) -> String {
+    match elf.and_then(|elf| elf.addr_to_symbol(addr)) {
+        Some((name, 0)) => format!("{addr:#010x} <{name}>"),
+        Some((name, offset)) => format!("{addr:#010x} <{name}+{offset:#x}>"),
+        None => format!("{addr:#010x}"),
+    }
+}
+
+/// A backtrace line for frame number `ii` at `addr`, with the source location
+/// at `line_addr` and the functions inlined there from the debug information
+/// of `elf`
+fn describe_frame(elf: Option<&Elf>, ii: usize, addr: u32, line_addr: u32) -> String {
+    let mut out = format!("  #{ii} {}", describe_addr(elf, addr));
+
+    let frames = elf.map(|elf| elf.addr_to_frames(line_addr)).unwrap_or_default();
+    if let Some(location) = frames.first().and_then(|frame| frame.location) {
+        out += &format!(" at {location}");
+    }
+    out += "\n";
+
+    for pair in frames.windows(2) {
+        let function = |frame: &Frame| frame.function.unwrap_or("??").to_string();
+        out += &format!("     {} inlined into {}", function(&pair[0]), function(&pair[1]));
+        if let Some(location) = pair[1].location {
+            out += &format!(" at {location}");
+        }
+        out += "\n";
+    }
+
+    out
+}
+
+/// Describe where a heap chunk was allocated and freed, for crash reports
+fn chunk_history(chunk: &Chunk) -> String {
+    let mut out = format!("\nchunk {:#010x} ({} bytes) allocated by:\n", chunk.addr, chunk.size);
+    for (ii, frame) in chunk.allocated.iter().enumerate() {
+        out += &format!("  #{ii} {frame:#010x}\n");
+    }
+    if let Some(freed) = &chunk.freed {
+        out += "\nfreed by:\n";
+        for (ii, frame) in freed.iter().enumerate() {
+            out += &format!("  #{ii} {frame:#010x}\n");
+        }
+    }
+    out
+}
+
+/// Describe where uninitialized memory was allocated, for crash reports
+fn allocation_history(origin: &Allocation) -> String {
+    let mut out = format!("\n{} {:#010x}-{:#010x} ({} bytes) ", origin.kind.name(),
+        origin.range.start, origin.range.end, origin.range.len());
+    match origin.pc {
+        Some(pc) => out += &format!("allocated at pc {pc:#010x}\n"),
+        None => out += "set up before the guest started\n",
+    }
+    for (ii, frame) in origin.backtrace.iter().enumerate() {
+        out += &format!("  #{ii} {frame:#010x}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+    use crate::instructions::RegName;
+
+    #[test]
+    fn bucket_names() {
+        let bucket = Bucket { kind: CrashKind::UseAfterFree, pc: 0x10074, stack_hash: 0x0123_4567_89ab_cdef };
+        assert_eq!(bucket.to_string(), "use-after-free-00010074-0123456789abcdef");
+        assert_eq!(bucket.to_string().parse(), Ok(bucket));
+
+        let bucket = Bucket { kind: CrashKind::Timeout, pc: 0, stack_hash: u64::MAX };
+        assert_eq!(bucket.to_string().parse(), Ok(bucket));
+
+        assert_eq!("use-after-free-00010074".parse::<Bucket>(), Err(()));
+        assert_eq!("bogus-00010074-0123456789abcdef".parse::<Bucket>(), Err(()));
+    }
+
+    #[test]
+    fn classify() {
+        let elf = testing::elf("
+            _start:
+                call store
+            other:
+                call store
+            store:
+                la t0, _start
+                sw zero, 0(t0)      # code isn't writable
+                ret
+        ");
+        let symbol = |name| elf.lookup_by_name(name).unwrap().value;
+
+        let crash = |start| {
+            let (mut emu, mut kernel) = testing::guest(&elf);
+            emu.pc = start;
+            let exit = kernel.run(&mut emu);
+            Crash::classify(&exit, &emu, &kernel).unwrap()
+        };
+
+        let crash1 = crash(symbol("_start"));
+        assert_eq!(crash1.kind, CrashKind::WriteViolation);
+        assert_eq!(crash1.pc, symbol("store") + 8);
+        assert_eq!(crash1.addr, Some(0x10000..0x10004));
+        assert_eq!(crash1.backtrace, [symbol("other")]);
+
+        // the same fault reached through another call is another bucket
+        let crash2 = crash(symbol("other"));
+        assert_eq!(crash2.backtrace, [symbol("store")]);
+        assert_eq!(crash2.bucket().kind, crash1.bucket().kind);
+        assert_eq!(crash2.bucket().pc, crash1.bucket().pc);
+        assert_ne!(crash2.bucket(), crash1.bucket());
+        assert_eq!(crash(symbol("_start")).bucket(), crash1.bucket());
+
+        let (emu, kernel) = testing::guest(&elf);
+        assert!(Crash::classify(&KernelExit::Exit(1), &emu, &kernel).is_none());
+    }
+
+    #[test]
+    fn uninit_origin() {
+        let elf = testing::elf("
+            _start:
+                call func
+            func:
+                addi sp, sp, -16
+                sw zero, 4(sp)
+                lw t0, 4(sp)
+                lw t0, 8(sp)
+        ");
+        let func = elf.lookup_by_name("func").unwrap().value;
+
+        let (mut emu, mut kernel) = testing::guest(&elf);
+        emu.stack_frames = Some(vec![]);
+        let exit = kernel.run(&mut emu);
+        let sp = emu.read_reg(RegName::Sp.as_reg());
+
+        let crash = Crash::classify(&exit, &emu, &kernel).unwrap();
+        assert_eq!(crash.kind, CrashKind::UninitRead);
+        assert_eq!(crash.detail,
+            format!("4 byte read of uninitialized memory at {:#010x} from a stack frame", sp + 8));
+        assert!(crash.notes.contains(&format!("stack frame {sp:#010x}-{:#010x} (16 bytes) allocated at pc {func:#010x}",
+            sp + 16)), "{}", crash.notes);
+    }
+}
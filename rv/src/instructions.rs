@@ -1,5 +1,6 @@
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 pub enum RegName {
     // zero register
     Zero,
@@ -35,7 +36,7 @@ impl RegName {
 
 /// Representing an x0-x31 register
 ///
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct Reg(pub u8);
 
@@ -116,6 +117,14 @@ impl UType {
             rd,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // imm[31:12] -> instr[31:12]
+        (self.imm & !((1 << 12) - 1)) |
+        // rd -> instr[11:7]
+        ((self.rd.0 as u32) << 7) |
+        opcode
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -145,6 +154,20 @@ impl JType {
             rd,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // imm[20] -> instr[31]
+        (((self.imm >> 20) & 1) << 31) |
+        // imm[10:1] -> instr[30:21]
+        (((self.imm >> 1) & ((1 << 10) - 1)) << 21) |
+        // imm[11] -> instr[20]
+        (((self.imm >> 11) & 1) << 20) |
+        // imm[19:12] -> instr[19:12]
+        (self.imm & (((1 << 8) - 1) << 12)) |
+        // rd -> instr[11:7]
+        ((self.rd.0 as u32) << 7) |
+        opcode
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -179,6 +202,18 @@ impl IType {
             rd,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // imm[11:0] -> instr[31:20]
+        ((self.imm & ((1 << 12) - 1)) << 20) |
+        // rs1 -> instr[19:15]
+        ((self.rs1.0 as u32) << 15) |
+        // funct3 -> instr[14:12]
+        ((self.funct3 as u32) << 12) |
+        // rd -> instr[11:7]
+        ((self.rd.0 as u32) << 7) |
+        opcode
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -219,6 +254,24 @@ impl BType {
             funct3,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // imm[12] -> instr[31]
+        (((self.imm >> 12) & 1) << 31) |
+        // imm[10:5] -> instr[30:25]
+        (((self.imm >> 5) & ((1 << 6) - 1)) << 25) |
+        // rs2 -> instr[24:20]
+        ((self.rs2.0 as u32) << 20) |
+        // rs1 -> instr[19:15]
+        ((self.rs1.0 as u32) << 15) |
+        // funct3 -> instr[14:12]
+        ((self.funct3 as u32) << 12) |
+        // imm[4:1] -> instr[11:8]
+        (((self.imm >> 1) & ((1 << 4) - 1)) << 8) |
+        // imm[11] -> instr[7]
+        (((self.imm >> 11) & 1) << 7) |
+        opcode
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -255,6 +308,20 @@ impl SType {
             funct3,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // imm[11:5] -> instr[31:25]
+        (((self.imm >> 5) & ((1 << 7) - 1)) << 25) |
+        // rs2 -> instr[24:20]
+        ((self.rs2.0 as u32) << 20) |
+        // rs1 -> instr[19:15]
+        ((self.rs1.0 as u32) << 15) |
+        // funct3 -> instr[14:12]
+        ((self.funct3 as u32) << 12) |
+        // imm[4:0] -> instr[11:7]
+        ((self.imm & ((1 << 5) - 1)) << 7) |
+        opcode
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -294,4 +361,395 @@ impl RType {
             rd,
         }
     }
+
+    pub fn encode(self, opcode: u32) -> u32 {
+        // funct7 -> instr[31:25]
+        ((self.funct7 as u32) << 25) |
+        // rs2 -> instr[24:20]
+        ((self.rs2.0 as u32) << 20) |
+        // rs1 -> instr[19:15]
+        ((self.rs1.0 as u32) << 15) |
+        // funct3 -> instr[14:12]
+        ((self.funct3 as u32) << 12) |
+        // rd -> instr[11:7]
+        ((self.rd.0 as u32) << 7) |
+        opcode
+    }
+}
+
+/// Why an instruction couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// `opcode` isn't one of the RV32I opcodes
+    UnknownOpcode { instr: u32, opcode: u32 },
+
+    /// `opcode` is known but the funct3, funct7 or other fixed fields don't
+    /// encode an instruction
+    InvalidEncoding { instr: u32, opcode: u32 },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            DecodeError::UnknownOpcode { instr, opcode } =>
+                write!(f, "unknown opcode {opcode:#09b} in {instr:#010x}"),
+            DecodeError::InvalidEncoding { instr, opcode } =>
+                write!(f, "invalid encoding of opcode {opcode:#09b} in {instr:#010x}"),
+        }
+    }
+}
+
+/// A decoded RV32I instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Lui { rd: Reg, imm: u32 },
+    Auipc { rd: Reg, imm: u32 },
+    Jal { rd: Reg, imm: u32 },
+    Jalr { rd: Reg, rs1: Reg, imm: u32 },
+
+    Beq { rs1: Reg, rs2: Reg, imm: u32 },
+    Bne { rs1: Reg, rs2: Reg, imm: u32 },
+    Blt { rs1: Reg, rs2: Reg, imm: u32 },
+    Bge { rs1: Reg, rs2: Reg, imm: u32 },
+    Bltu { rs1: Reg, rs2: Reg, imm: u32 },
+    Bgeu { rs1: Reg, rs2: Reg, imm: u32 },
+
+    Lb { rd: Reg, rs1: Reg, imm: u32 },
+    Lh { rd: Reg, rs1: Reg, imm: u32 },
+    Lw { rd: Reg, rs1: Reg, imm: u32 },
+    Lbu { rd: Reg, rs1: Reg, imm: u32 },
+    Lhu { rd: Reg, rs1: Reg, imm: u32 },
+
+    Sb { rs1: Reg, rs2: Reg, imm: u32 },
+    Sh { rs1: Reg, rs2: Reg, imm: u32 },
+    Sw { rs1: Reg, rs2: Reg, imm: u32 },
+
+    Addi { rd: Reg, rs1: Reg, imm: u32 },
+    Slti { rd: Reg, rs1: Reg, imm: u32 },
+    Sltiu { rd: Reg, rs1: Reg, imm: u32 },
+    Xori { rd: Reg, rs1: Reg, imm: u32 },
+    Ori { rd: Reg, rs1: Reg, imm: u32 },
+    Andi { rd: Reg, rs1: Reg, imm: u32 },
+    Slli { rd: Reg, rs1: Reg, shamt: u32 },
+    Srli { rd: Reg, rs1: Reg, shamt: u32 },
+    Srai { rd: Reg, rs1: Reg, shamt: u32 },
+
+    Add { rd: Reg, rs1: Reg, rs2: Reg },
+    Sub { rd: Reg, rs1: Reg, rs2: Reg },
+    Sll { rd: Reg, rs1: Reg, rs2: Reg },
+    Slt { rd: Reg, rs1: Reg, rs2: Reg },
+    Sltu { rd: Reg, rs1: Reg, rs2: Reg },
+    Xor { rd: Reg, rs1: Reg, rs2: Reg },
+    Srl { rd: Reg, rs1: Reg, rs2: Reg },
+    Sra { rd: Reg, rs1: Reg, rs2: Reg },
+    Or { rd: Reg, rs1: Reg, rs2: Reg },
+    And { rd: Reg, rs1: Reg, rs2: Reg },
+
+    Fence,
+    Ecall,
+    Ebreak,
+}
+
+impl Instruction {
+    /// Does execution leave straight line code after this instruction
+    pub fn ends_block(self) -> bool {
+        matches!(self,
+            Instruction::Jal { .. } | Instruction::Jalr { .. } |
+            Instruction::Beq { .. } | Instruction::Bne { .. } | Instruction::Blt { .. } |
+            Instruction::Bge { .. } | Instruction::Bltu { .. } | Instruction::Bgeu { .. } |
+            Instruction::Ecall | Instruction::Ebreak)
+    }
+
+    /// Assembly name of the instruction
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Instruction::Lui { .. } => "lui",
+            Instruction::Auipc { .. } => "auipc",
+            Instruction::Jal { .. } => "jal",
+            Instruction::Jalr { .. } => "jalr",
+            Instruction::Beq { .. } => "beq",
+            Instruction::Bne { .. } => "bne",
+            Instruction::Blt { .. } => "blt",
+            Instruction::Bge { .. } => "bge",
+            Instruction::Bltu { .. } => "bltu",
+            Instruction::Bgeu { .. } => "bgeu",
+            Instruction::Lb { .. } => "lb",
+            Instruction::Lh { .. } => "lh",
+            Instruction::Lw { .. } => "lw",
+            Instruction::Lbu { .. } => "lbu",
+            Instruction::Lhu { .. } => "lhu",
+            Instruction::Sb { .. } => "sb",
+            Instruction::Sh { .. } => "sh",
+            Instruction::Sw { .. } => "sw",
+            Instruction::Addi { .. } => "addi",
+            Instruction::Slti { .. } => "slti",
+            Instruction::Sltiu { .. } => "sltiu",
+            Instruction::Xori { .. } => "xori",
+            Instruction::Ori { .. } => "ori",
+            Instruction::Andi { .. } => "andi",
+            Instruction::Slli { .. } => "slli",
+            Instruction::Srli { .. } => "srli",
+            Instruction::Srai { .. } => "srai",
+            Instruction::Add { .. } => "add",
+            Instruction::Sub { .. } => "sub",
+            Instruction::Sll { .. } => "sll",
+            Instruction::Slt { .. } => "slt",
+            Instruction::Sltu { .. } => "sltu",
+            Instruction::Xor { .. } => "xor",
+            Instruction::Srl { .. } => "srl",
+            Instruction::Sra { .. } => "sra",
+            Instruction::Or { .. } => "or",
+            Instruction::And { .. } => "and",
+            Instruction::Fence => "fence",
+            Instruction::Ecall => "ecall",
+            Instruction::Ebreak => "ebreak",
+        }
+    }
+}
+
+/// Decode an RV32I instruction
+pub fn decode(instr: u32) -> Result<Instruction, DecodeError> {
+    // first 7 bits are the opcode
+    let opcode: u32 = instr & ((1 << 7) - 1);
+
+    // follow the table on page 130 in the riscv spec
+    let invalid = DecodeError::InvalidEncoding { instr, opcode };
+
+    let op = match opcode {
+        // LUI
+        0b0110111 => {
+            let UType { rd, imm } = UType::parse(instr);
+            Instruction::Lui { rd, imm }
+        },
+        // AUIPC
+        0b0010111 => {
+            let UType { rd, imm } = UType::parse(instr);
+            Instruction::Auipc { rd, imm }
+        },
+        // JAL
+        0b1101111 => {
+            let JType { rd, imm } = JType::parse(instr);
+            Instruction::Jal { rd, imm }
+        },
+        // JALR
+        0b1100111 => {
+            let IType { rd, rs1, imm, funct3 } = IType::parse(instr);
+            if funct3 != 0 {
+                return Err(invalid);
+            }
+            Instruction::Jalr { rd, rs1, imm }
+        },
+
+        // BRANCH
+        0b1100011 => {
+            let BType { rs1, rs2, imm, funct3 } = BType::parse(instr);
+            match funct3 {
+                0b000 => Instruction::Beq { rs1, rs2, imm },
+                0b001 => Instruction::Bne { rs1, rs2, imm },
+                0b100 => Instruction::Blt { rs1, rs2, imm },
+                0b101 => Instruction::Bge { rs1, rs2, imm },
+                0b110 => Instruction::Bltu { rs1, rs2, imm },
+                0b111 => Instruction::Bgeu { rs1, rs2, imm },
+                _ => return Err(invalid),
+            }
+        },
+
+        // LOAD
+        0b0000011 => {
+            let IType { rd, rs1, imm, funct3 } = IType::parse(instr);
+            match funct3 {
+                0b000 => Instruction::Lb { rd, rs1, imm },
+                0b001 => Instruction::Lh { rd, rs1, imm },
+                0b010 => Instruction::Lw { rd, rs1, imm },
+                0b100 => Instruction::Lbu { rd, rs1, imm },
+                0b101 => Instruction::Lhu { rd, rs1, imm },
+                _ => return Err(invalid),
+            }
+        },
+
+        // STORE
+        0b0100011 => {
+            let SType { rs1, rs2, imm, funct3 } = SType::parse(instr);
+            match funct3 {
+                0b000 => Instruction::Sb { rs1, rs2, imm },
+                0b001 => Instruction::Sh { rs1, rs2, imm },
+                0b010 => Instruction::Sw { rs1, rs2, imm },
+                _ => return Err(invalid),
+            }
+        },
+
+        // OP-IMM
+        0b0010011 => {
+            let IType { rd, rs1, imm, funct3 } = IType::parse(instr);
+
+            // imm[11:5], selects arithmetic right shift
+            let arithmetic = (imm & ((1 << 12) - 1)) >> 5;
+
+            // imm[4:0]
+            let shamt = imm & 0b11111;
+
+            match (funct3, arithmetic) {
+                (0b000, _) => Instruction::Addi { rd, rs1, imm },
+                (0b010, _) => Instruction::Slti { rd, rs1, imm },
+                (0b011, _) => Instruction::Sltiu { rd, rs1, imm },
+                (0b100, _) => Instruction::Xori { rd, rs1, imm },
+                (0b110, _) => Instruction::Ori { rd, rs1, imm },
+                (0b111, _) => Instruction::Andi { rd, rs1, imm },
+                (0b001, 0b0) => Instruction::Slli { rd, rs1, shamt },
+                (0b101, 0b0) => Instruction::Srli { rd, rs1, shamt },
+                (0b101, 0b0100000) => Instruction::Srai { rd, rs1, shamt },
+                _ => return Err(invalid),
+            }
+        },
+
+        // OP
+        0b0110011 => {
+            let RType { rd, rs1, rs2, funct3, funct7 } = RType::parse(instr);
+            match (funct3, funct7) {
+                (0b000, 0b0000000) => Instruction::Add { rd, rs1, rs2 },
+                (0b000, 0b0100000) => Instruction::Sub { rd, rs1, rs2 },
+                (0b001, 0b0000000) => Instruction::Sll { rd, rs1, rs2 },
+                (0b010, 0b0000000) => Instruction::Slt { rd, rs1, rs2 },
+                (0b011, 0b0000000) => Instruction::Sltu { rd, rs1, rs2 },
+                (0b100, 0b0000000) => Instruction::Xor { rd, rs1, rs2 },
+                (0b101, 0b0000000) => Instruction::Srl { rd, rs1, rs2 },
+                (0b101, 0b0100000) => Instruction::Sra { rd, rs1, rs2 },
+                (0b110, 0b0000000) => Instruction::Or { rd, rs1, rs2 },
+                (0b111, 0b0000000) => Instruction::And { rd, rs1, rs2 },
+                _ => return Err(invalid),
+            }
+        },
+
+        // MISC-MEM
+        0b0001111 => {
+            // FENCE
+            if IType::parse(instr).funct3 != 0b000 {
+                return Err(invalid);
+            }
+            Instruction::Fence
+        },
+
+        // SYSTEM
+        0b1110011 => {
+            let typ = IType::parse(instr);
+            if typ.rs1.0 != 0 || typ.rd.0 != 0 || typ.funct3 != 0 {
+                return Err(invalid);
+            }
+            match typ.imm {
+                0b0 => Instruction::Ecall,
+                0b1 => Instruction::Ebreak,
+                _ => return Err(invalid),
+            }
+        },
+
+        _ => return Err(DecodeError::UnknownOpcode { instr, opcode }),
+    };
+
+    Ok(op)
+}
+
+
+/// Encode an RV32I instruction, the inverse of [`decode`]
+///
+/// Immediates are truncated to the bits their encoding has room for.
+pub fn encode(instr: Instruction) -> u32 {
+    let branch = |rs1, rs2, imm, funct3| BType { imm, rs2, rs1, funct3 }.encode(0b1100011);
+    let load = |rd, rs1, imm, funct3| IType { imm, rs1, funct3, rd }.encode(0b0000011);
+    let store = |rs1, rs2, imm, funct3| SType { imm, rs2, rs1, funct3 }.encode(0b0100011);
+    let op_imm = |rd, rs1, imm, funct3| IType { imm, rs1, funct3, rd }.encode(0b0010011);
+    let op = |rd, rs1, rs2, funct3, funct7| RType { funct7, rs2, rs1, funct3, rd }.encode(0b0110011);
+
+    match instr {
+        Instruction::Lui { rd, imm } => UType { imm, rd }.encode(0b0110111),
+        Instruction::Auipc { rd, imm } => UType { imm, rd }.encode(0b0010111),
+        Instruction::Jal { rd, imm } => JType { imm, rd }.encode(0b1101111),
+        Instruction::Jalr { rd, rs1, imm } => IType { imm, rs1, funct3: 0, rd }.encode(0b1100111),
+
+        Instruction::Beq { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b000),
+        Instruction::Bne { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b001),
+        Instruction::Blt { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b100),
+        Instruction::Bge { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b101),
+        Instruction::Bltu { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b110),
+        Instruction::Bgeu { rs1, rs2, imm } => branch(rs1, rs2, imm, 0b111),
+
+        Instruction::Lb { rd, rs1, imm } => load(rd, rs1, imm, 0b000),
+        Instruction::Lh { rd, rs1, imm } => load(rd, rs1, imm, 0b001),
+        Instruction::Lw { rd, rs1, imm } => load(rd, rs1, imm, 0b010),
+        Instruction::Lbu { rd, rs1, imm } => load(rd, rs1, imm, 0b100),
+        Instruction::Lhu { rd, rs1, imm } => load(rd, rs1, imm, 0b101),
+
+        Instruction::Sb { rs1, rs2, imm } => store(rs1, rs2, imm, 0b000),
+        Instruction::Sh { rs1, rs2, imm } => store(rs1, rs2, imm, 0b001),
+        Instruction::Sw { rs1, rs2, imm } => store(rs1, rs2, imm, 0b010),
+
+        Instruction::Addi { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b000),
+        Instruction::Slti { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b010),
+        Instruction::Sltiu { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b011),
+        Instruction::Xori { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b100),
+        Instruction::Ori { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b110),
+        Instruction::Andi { rd, rs1, imm } => op_imm(rd, rs1, imm, 0b111),
+        // shamt in imm[4:0], arithmetic shifts set imm[10]
+        Instruction::Slli { rd, rs1, shamt } => op_imm(rd, rs1, shamt & 0b11111, 0b001),
+        Instruction::Srli { rd, rs1, shamt } => op_imm(rd, rs1, shamt & 0b11111, 0b101),
+        Instruction::Srai { rd, rs1, shamt } => op_imm(rd, rs1, (0b0100000 << 5) | (shamt & 0b11111), 0b101),
+
+        Instruction::Add { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b000, 0b0000000),
+        Instruction::Sub { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b000, 0b0100000),
+        Instruction::Sll { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b001, 0b0000000),
+        Instruction::Slt { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b010, 0b0000000),
+        Instruction::Sltu { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b011, 0b0000000),
+        Instruction::Xor { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b100, 0b0000000),
+        Instruction::Srl { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b101, 0b0000000),
+        Instruction::Sra { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b101, 0b0100000),
+        Instruction::Or { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b110, 0b0000000),
+        Instruction::And { rd, rs1, rs2 } => op(rd, rs1, rs2, 0b111, 0b0000000),
+
+        // fence iorw, iorw
+        Instruction::Fence => 0x0ff0000f,
+        Instruction::Ecall => 0x00000073,
+        Instruction::Ebreak => 0x00100073,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_instructions() {
+        // addi a0, a0, -1
+        assert_eq!(decode(0xfff50513),
+            Ok(Instruction::Addi { rd: Reg(10), rs1: Reg(10), imm: -1i32 as u32 }));
+        // sw a0, 8(sp)
+        assert_eq!(decode(0x00a12423),
+            Ok(Instruction::Sw { rs1: Reg(2), rs2: Reg(10), imm: 8 }));
+        // bne a0, zero, -8
+        assert_eq!(decode(0xfe051ce3),
+            Ok(Instruction::Bne { rs1: Reg(10), rs2: Reg(0), imm: -8i32 as u32 }));
+        assert_eq!(decode(0x00000073), Ok(Instruction::Ecall));
+
+        assert_eq!(decode(0x00000000),
+            Err(DecodeError::UnknownOpcode { instr: 0, opcode: 0 }));
+        // jalr with funct3 != 0
+        assert_eq!(decode(0x00001067),
+            Err(DecodeError::InvalidEncoding { instr: 0x00001067, opcode: 0b1100111 }));
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let instrs = [
+            0x000125b7, 0xffffe517, 0x7f00006f, 0x800000ef, 0xff8080e7,
+            0xfe051ce3, 0x00b54863, 0x80b57fe3, 0x7eb56fe3,
+            0x80058503, 0x7ff59583, 0x00812403, 0xfff14503, 0x00215583,
+            0xfea10fa3, 0x00a11123, 0x7ea12fa3,
+            0xfff50513, 0x00a5a593, 0x8005b513, 0x0ff54513, 0xf0056513, 0x00f57513,
+            0x01f51513, 0x00155513, 0x41f55513,
+            0x00b50533, 0x40b50533, 0x00b51533, 0x00b52533, 0x00b53533,
+            0x00b54533, 0x00b55533, 0x40b55533, 0x00b56533, 0x00b57533,
+            0x0ff0000f, 0x00000073, 0x00100073,
+        ];
+        for instr in instrs {
+            assert_eq!(encode(decode(instr).unwrap()), instr, "{instr:#010x}");
+        }
+    }
 }
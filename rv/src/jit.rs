@@ -0,0 +1,1430 @@
+//! x86-64 JIT compiler for the emulator
+//!
+//! Blocks from the [`BlockCache`] are compiled to native code that works on
+//! the emulator's registers and memory directly, checking bounds, permissions
+//! and read after write bytes inline like [`Memory`] does. Compiled blocks
+//! jump straight to each other once both exist, and indirect jumps go through
+//! a small table of recent targets.
+//!
+//! Anything out of the ordinary is left to the interpreter: the generated
+//! code exits at the instruction and [`Emulator::run`] interprets it before
+//! coming back. That covers syscalls, faults, invalid instructions, the first
+//! write to a block of memory since the last reset, writes to code,
+//! breakpoints and pc hooks, and instructions close to the instruction limit.
+//! Memory hooks, stack frame tracking and taint tracking look at every
+//! instruction, so nothing is compiled while they are enabled.
+
+use std::collections::{HashMap, HashSet};
+use std::mem::offset_of;
+use std::ops::Range;
+
+use crate::block_cache::*;
+use crate::emulator::*;
+use crate::instructions::*;
+
+/// Size of the executable memory for compiled code, it is flushed when full
+const CODE_SIZE: usize = 32 << 20;
+
+/// Granularity of the protection of the executable memory
+const CODE_PAGE_SIZE: usize = 4096;
+
+/// Space to leave for compiling a block, more than the largest block needs
+const MAX_BLOCK_CODE: usize = 64 << 10;
+
+/// Number of entries in the indirect jump table, must be a power of two
+const TABLE_SIZE: usize = 4096;
+
+/// Compiled code exited to jump to [`Context::pc`], which isn't compiled yet
+const EXIT_JUMP: u32 = 0;
+
+/// Compiled code exited for the interpreter to run the instruction at
+/// [`Context::pc`]
+const EXIT_INTERPRET: u32 = 1;
+
+/// `rs1` argument of [`track_call`] for jumps without one
+const NO_REG: u32 = u32::MAX;
+
+/// State shared between the compiled code and [`Jit::run`], `rbp` points to
+/// it while compiled code runs
+#[repr(C)]
+struct Context {
+    /// Guest registers x1 to x31, in `rbx`
+    regs: *mut u32,
+
+    /// Guest memory, in `r12`
+    mem: *mut u8,
+
+    /// Permissions of guest memory, in `r13`
+    perms: *mut u8,
+
+    /// Dirty block bitmap of guest memory, in `r14`
+    dirty: *const u64,
+
+    /// Code block bitmap of guest memory, in `r15`
+    code: *const u64,
+
+    mem_len: u64,
+
+    /// Edge coverage map, only used if the code was compiled with coverage
+    coverage: *mut u8,
+
+    table: *const Entry,
+
+    emu: *mut Emulator,
+
+    instret: u64,
+
+    /// Blocks exit to the interpreter rather than take `instret` past this
+    limit: u64,
+
+    /// [`EXIT_JUMP`] or [`EXIT_INTERPRET`]
+    exit: u32,
+
+    /// Where the guest continues after an exit
+    pc: u32,
+
+    /// For [`EXIT_JUMP`] from a direct jump, the address of the jump's rel32
+    /// to point at the target once it is compiled
+    patch: usize,
+
+    /// Target of a `jalr` while calling out to Rust
+    target: u32,
+}
+
+/// Entry of the indirect jump table, indexed by guest address
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    pc: u32,
+
+    /// Compiled code for `pc`, entries that are not in use point to the miss
+    /// stub which exits with [`EXIT_JUMP`]
+    code: usize,
+}
+
+/// Offset of a field of [`Context`], for addressing it from `rbp`
+macro_rules! ctx {
+    ($field:ident) => {
+        at(Rbp, offset_of!(Context, $field) as i32)
+    }
+}
+
+/// Executable memory, only writable while code is being copied into it
+struct CodeMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+const PROT_READ: i32 = 1;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+const PROT_WRITE: i32 = 2;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+const PROT_EXEC: i32 = 4;
+
+impl CodeMemory {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn new(len: usize) -> std::io::Result<Self> {
+        const MAP_PRIVATE: i32 = 2;
+        const MAP_ANONYMOUS: i32 = 0x20;
+
+        let ptr = unsafe {
+            mmap(std::ptr::null_mut(), len, PROT_READ | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+        };
+        if ptr as isize == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(CodeMemory { ptr, len })
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn new(_len: usize) -> std::io::Result<Self> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "the JIT needs x86-64 linux"))
+    }
+
+    fn addr(&self) -> usize {
+        self.ptr as usize
+    }
+
+    /// Copy `code` to `offset`, making the pages it goes in writable for the
+    /// copy
+    fn write(&mut self, offset: usize, code: &[u8]) -> std::io::Result<()> {
+        assert!(offset + code.len() <= self.len);
+        let pages = offset / CODE_PAGE_SIZE * CODE_PAGE_SIZE..
+            (offset + code.len()).next_multiple_of(CODE_PAGE_SIZE);
+        self.protect(pages.clone(), PROT_READ | PROT_WRITE)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), self.ptr.add(offset), code.len());
+        }
+        self.protect(pages, PROT_READ | PROT_EXEC)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn protect(&mut self, pages: Range<usize>, prot: i32) -> std::io::Result<()> {
+        if unsafe { mprotect(self.ptr.add(pages.start), pages.len(), prot) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn protect(&mut self, _pages: Range<usize>, _prot: i32) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for CodeMemory {
+    fn drop(&mut self) {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// x86-64 general purpose registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(dead_code)]
+enum R { Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi, R8, R9, R10, R11, R12, R13, R14, R15 }
+
+use R::*;
+
+/// Memory operand, `[base + index * (1 << scale) + disp]`
+#[derive(Debug, Clone, Copy)]
+struct Mem {
+    base: R,
+    index: Option<(R, u8)>,
+    disp: i32,
+}
+
+fn at(base: R, disp: i32) -> Mem {
+    Mem { base, index: None, disp }
+}
+
+fn indexed(base: R, index: R, scale: u8) -> Mem {
+    Mem { base, index: Some((index, scale)), disp: 0 }
+}
+
+/// Register or memory operand
+#[derive(Debug, Clone, Copy)]
+enum Rm {
+    Reg(R),
+    Mem(Mem),
+}
+
+/// Arithmetic instructions, numbered as in the `/digit` of their immediate
+/// forms
+#[derive(Debug, Clone, Copy)]
+enum Alu { Add = 0, Or = 1, Sbb = 3, And = 4, Sub = 5, Xor = 6, Cmp = 7 }
+
+/// Shift instructions, numbered as in their `/digit`
+#[derive(Debug, Clone, Copy)]
+enum Shift { Shl = 4, Shr = 5, Sar = 7 }
+
+/// Condition codes
+#[derive(Debug, Clone, Copy)]
+enum Cc { B = 2, Ae = 3, E = 4, Ne = 5, A = 7, L = 0xc, Ge = 0xd }
+
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+/// Assembler for the few x86-64 instructions the compiler needs
+struct Asm {
+    /// Address the code will run at
+    base: usize,
+
+    buf: Vec<u8>,
+
+    /// Position of every label, once bound
+    labels: Vec<Option<usize>>,
+
+    /// Positions of rel32s to fill in with the distance to a label
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    fn new(base: usize) -> Self {
+        Asm { base, buf: Vec::new(), labels: Vec::new(), fixups: Vec::new() }
+    }
+
+    /// Address of the next instruction
+    fn addr(&self) -> usize {
+        self.base + self.buf.len()
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// Emit an instruction with a ModRM operand, `wide` for a 64-bit operand
+    /// size
+    fn op(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: Rm) {
+        let (base, index) = match rm {
+            Rm::Reg(reg) => (reg as u8, 0),
+            Rm::Mem(mem) => (mem.base as u8, mem.index.map_or(0, |(index, _)| index as u8)),
+        };
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.bytes(&[rex]);
+        }
+        self.bytes(opcode);
+
+        let mem = match rm {
+            Rm::Reg(rm) => return self.bytes(&[0xc0 | (reg & 7) << 3 | (rm as u8 & 7)]),
+            Rm::Mem(mem) => mem,
+        };
+
+        let base = base & 7;
+        // rbp and r13 as base always need a displacement
+        let mode = if mem.disp == 0 && base != 5 {
+            0
+        } else if i8::try_from(mem.disp).is_ok() {
+            1
+        } else {
+            2
+        };
+
+        // rsp and r12 as base always need a SIB byte
+        if mem.index.is_some() || base == 4 {
+            let (index, scale) = mem.index.map_or((4, 0), |(index, scale)| (index as u8 & 7, scale));
+            self.bytes(&[mode << 6 | (reg & 7) << 3 | 4, scale << 6 | index << 3 | base]);
+        } else {
+            self.bytes(&[mode << 6 | (reg & 7) << 3 | base]);
+        }
+
+        match mode {
+            1 => self.bytes(&[mem.disp as u8]),
+            2 => self.imm32(mem.disp as u32),
+            _ => (),
+        }
+    }
+
+    fn push(&mut self, reg: R) {
+        if reg as u8 >= 8 {
+            self.bytes(&[0x41]);
+        }
+        self.bytes(&[0x50 | (reg as u8 & 7)]);
+    }
+
+    fn pop(&mut self, reg: R) {
+        if reg as u8 >= 8 {
+            self.bytes(&[0x41]);
+        }
+        self.bytes(&[0x58 | (reg as u8 & 7)]);
+    }
+
+    fn ret(&mut self) {
+        self.bytes(&[0xc3]);
+    }
+
+    /// `mov dst, rm`
+    fn mov(&mut self, wide: bool, dst: R, rm: Rm) {
+        self.op(wide, &[0x8b], dst as u8, rm);
+    }
+
+    /// `mov [mem], src`
+    fn store(&mut self, wide: bool, mem: Mem, src: R) {
+        self.op(wide, &[0x89], src as u8, Rm::Mem(mem));
+    }
+
+    /// `mov [mem], src` of the low `size` bytes of `src`
+    fn store_sized(&mut self, size: u32, mem: Mem, src: R) {
+        match size {
+            1 => self.op(false, &[0x88], src as u8, Rm::Mem(mem)),
+            2 => {
+                self.bytes(&[0x66]);
+                self.store(false, mem, src);
+            },
+            _ => self.store(false, mem, src),
+        }
+    }
+
+    /// Zero extending load of `size` bytes
+    fn load_sized(&mut self, size: u32, dst: R, mem: Mem) {
+        match size {
+            1 => self.op(false, &[0x0f, 0xb6], dst as u8, Rm::Mem(mem)),
+            2 => self.op(false, &[0x0f, 0xb7], dst as u8, Rm::Mem(mem)),
+            _ => self.mov(false, dst, Rm::Mem(mem)),
+        }
+    }
+
+    /// `mov rm, imm`, sign extended for 64-bit operands
+    fn mov_imm(&mut self, wide: bool, rm: Rm, imm: u32) {
+        self.op(wide, &[0xc7], 0, rm);
+        self.imm32(imm);
+    }
+
+    /// `mov dst, imm64`
+    fn mov_imm64(&mut self, dst: R, imm: u64) {
+        self.bytes(&[0x48 | (dst as u8 >> 3), 0xb8 | (dst as u8 & 7)]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn lea(&mut self, dst: R, mem: Mem) {
+        self.op(true, &[0x8d], dst as u8, Rm::Mem(mem));
+    }
+
+    /// `op dst, src`
+    fn alu(&mut self, wide: bool, op: Alu, dst: R, src: R) {
+        self.op(wide, &[(op as u8) << 3 | 1], src as u8, Rm::Reg(dst));
+    }
+
+    /// `op dst, [mem]`
+    fn alu_mem(&mut self, wide: bool, op: Alu, dst: R, mem: Mem) {
+        self.op(wide, &[(op as u8) << 3 | 3], dst as u8, Rm::Mem(mem));
+    }
+
+    /// `op rm, imm`
+    fn alu_imm(&mut self, wide: bool, op: Alu, rm: Rm, imm: u32) {
+        self.op(wide, &[0x81], op as u8, rm);
+        self.imm32(imm);
+    }
+
+    /// `op byte [mem], imm`
+    fn alu_byte(&mut self, op: Alu, mem: Mem, imm: u8) {
+        self.op(false, &[0x80], op as u8, Rm::Mem(mem));
+        self.bytes(&[imm]);
+    }
+
+    fn shift_imm(&mut self, op: Shift, dst: R, imm: u8) {
+        self.op(false, &[0xc1], op as u8, Rm::Reg(dst));
+        self.bytes(&[imm]);
+    }
+
+    /// Shift by `cl`
+    fn shift_cl(&mut self, op: Shift, dst: R) {
+        self.op(false, &[0xd3], op as u8, Rm::Reg(dst));
+    }
+
+    fn not(&mut self, dst: R) {
+        self.op(false, &[0xf7], 2, Rm::Reg(dst));
+    }
+
+    fn test_imm(&mut self, dst: R, imm: u32) {
+        self.op(false, &[0xf7], 0, Rm::Reg(dst));
+        self.imm32(imm);
+    }
+
+    /// `imul dst, src, imm`
+    fn imul_imm(&mut self, dst: R, src: R, imm: u32) {
+        self.op(false, &[0x69], dst as u8, Rm::Reg(src));
+        self.imm32(imm);
+    }
+
+    /// `bt rm, bit`, for 64-bit operands
+    fn bt(&mut self, rm: R, bit: R) {
+        self.op(true, &[0x0f, 0xa3], bit as u8, Rm::Reg(rm));
+    }
+
+    /// Set `dst` to 1 if `cc` holds and 0 otherwise
+    fn set(&mut self, cc: Cc, dst: R) {
+        assert!((dst as u8) < 4);
+        self.op(false, &[0x0f, 0x90 | cc as u8], 0, Rm::Reg(dst));
+        self.op(false, &[0x0f, 0xb6], dst as u8, Rm::Reg(dst));
+    }
+
+    fn call(&mut self, rm: Rm) {
+        self.op(false, &[0xff], 2, rm);
+    }
+
+    fn jmp(&mut self, rm: Rm) {
+        self.op(false, &[0xff], 4, rm);
+    }
+
+    fn rel32(&mut self, target: usize) {
+        let rel = target as i64 - (self.addr() as i64 + 4);
+        self.imm32(i32::try_from(rel).unwrap() as u32);
+    }
+
+    /// `jmp` to an absolute address
+    fn jmp_to(&mut self, target: usize) {
+        self.bytes(&[0xe9]);
+        self.rel32(target);
+    }
+
+    /// `jcc` to an absolute address
+    fn jcc_to(&mut self, cc: Cc, target: usize) {
+        self.bytes(&[0x0f, 0x80 | cc as u8]);
+        self.rel32(target);
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.buf.len());
+    }
+
+    fn jcc(&mut self, cc: Cc, label: Label) {
+        self.bytes(&[0x0f, 0x80 | cc as u8]);
+        self.fixups.push((self.buf.len(), label));
+        self.imm32(0);
+    }
+
+    /// Resolve jumps to labels and return the code
+    fn finish(mut self) -> Vec<u8> {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound label");
+            let rel = (target as i32 - (pos as i32 + 4)).to_le_bytes();
+            self.buf[pos..pos + 4].copy_from_slice(&rel);
+        }
+        self.buf
+    }
+}
+
+/// Called by compiled code for jumps that can push or pop the shadow call
+/// stack
+extern "sysv64" fn track_call(emu: *mut Emulator, rd: u32, rs1: u32, return_addr: u32) {
+    let emu = unsafe { &mut *emu };
+    let rs1 = (rs1 != NO_REG).then_some(Reg(rs1 as u8));
+    emu.track_call(Reg(rd as u8), rs1, return_addr);
+}
+
+/// Called by compiled code for comparisons when compiled with cmplog
+extern "sysv64" fn record_cmp(emu: *mut Emulator, a: u32, b: u32) {
+    let emu = unsafe { &mut *emu };
+    emu.record_cmp(a, b);
+}
+
+/// Signature of the code at [`Jit::enter`]
+type EnterFn = unsafe extern "sysv64" fn(ctx: *mut Context, code: usize);
+
+/// Compiled guest code
+pub struct Jit {
+    code: CodeMemory,
+
+    /// Bytes of `code` in use
+    len: usize,
+
+    /// Bytes at the start of `code` taken by the stubs below, kept on flush
+    stubs_len: usize,
+
+    /// Saves the host registers, sets up the pinned ones and jumps to
+    /// compiled code, see [`EnterFn`]
+    enter: usize,
+
+    /// Restores the host registers and returns from `enter`
+    epilogue: usize,
+
+    /// Exits with [`EXIT_JUMP`] to the address in `eax`, for indirect jumps
+    /// that miss the table
+    miss: usize,
+
+    /// Compiled code of every block, by guest address
+    blocks: HashMap<u32, usize>,
+
+    /// Indirect jump targets
+    table: Box<[Entry]>,
+
+    /// Were blocks compiled to collect coverage and cmplog
+    coverage: bool,
+    cmplog: bool,
+
+    /// Breakpoints and pc hooks the compiled code exits at
+    stops: HashSet<u32>,
+
+    /// Check every run of compiled code against the interpreter
+    pub check: bool,
+}
+
+impl std::fmt::Debug for Jit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jit")
+            .field("len", &self.len)
+            .field("blocks", &self.blocks.len())
+            .field("check", &self.check)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jit {
+    pub fn new() -> std::io::Result<Self> {
+        let mut code = CodeMemory::new(CODE_SIZE)?;
+        let mut asm = Asm::new(code.addr());
+
+        let enter = asm.addr();
+        for reg in [Rbx, Rbp, R12, R13, R14, R15] {
+            asm.push(reg);
+        }
+        // keep the stack 16 byte aligned for calls out of compiled code
+        asm.alu_imm(true, Alu::Sub, Rm::Reg(Rsp), 8);
+        asm.mov(true, Rbp, Rm::Reg(Rdi));
+        asm.mov(true, Rbx, Rm::Mem(ctx!(regs)));
+        asm.mov(true, R12, Rm::Mem(ctx!(mem)));
+        asm.mov(true, R13, Rm::Mem(ctx!(perms)));
+        asm.mov(true, R14, Rm::Mem(ctx!(dirty)));
+        asm.mov(true, R15, Rm::Mem(ctx!(code)));
+        asm.jmp(Rm::Reg(Rsi));
+
+        let epilogue = asm.addr();
+        asm.alu_imm(true, Alu::Add, Rm::Reg(Rsp), 8);
+        for reg in [R15, R14, R13, R12, Rbp, Rbx] {
+            asm.pop(reg);
+        }
+        asm.ret();
+
+        let miss = asm.addr();
+        asm.store(false, ctx!(pc), Rax);
+        asm.mov_imm(false, Rm::Mem(ctx!(exit)), EXIT_JUMP);
+        asm.mov_imm(true, Rm::Mem(ctx!(patch)), 0);
+        asm.jmp_to(epilogue);
+
+        let stubs = asm.finish();
+        code.write(0, &stubs)?;
+
+        Ok(Jit {
+            code,
+            len: stubs.len(),
+            stubs_len: stubs.len(),
+            enter,
+            epilogue,
+            miss,
+            blocks: HashMap::new(),
+            table: vec![Entry { pc: 0, code: miss }; TABLE_SIZE].into_boxed_slice(),
+            coverage: false,
+            cmplog: false,
+            stops: HashSet::new(),
+            check: false,
+        })
+    }
+
+    /// Throw away all compiled code
+    pub fn flush(&mut self) {
+        self.len = self.stubs_len;
+        self.blocks.clear();
+        self.table.fill(Entry { pc: 0, code: self.miss });
+    }
+
+    /// Flush if the code was compiled for different instrumentation than
+    /// `emu` has now
+    fn sync(&mut self, emu: &Emulator) {
+        let stops_changed =
+            !emu.breakpoints.iter().copied().chain(emu.hooked_pcs())
+                .all(|pc| self.stops.contains(&pc)) ||
+            !self.stops.iter().all(|&pc| emu.breakpoints.contains(&pc) || emu.has_pc_hook(pc));
+
+        if stops_changed {
+            self.stops = emu.breakpoints.iter().copied().chain(emu.hooked_pcs()).collect();
+        }
+
+        let coverage = emu.coverage.is_some();
+        let cmplog = emu.cmplog.is_some();
+
+        if stops_changed || coverage != self.coverage || cmplog != self.cmplog {
+            self.coverage = coverage;
+            self.cmplog = cmplog;
+            self.flush();
+        }
+    }
+
+    /// Run compiled code from `pc` until the interpreter has to take over,
+    /// without going past `limit` retired instructions
+    ///
+    /// Returns the address of the instruction to interpret.
+    pub fn run(&mut self, emu: &mut Emulator, blocks: &mut BlockCache, pc: u32, limit: u64) -> u32 {
+        if !self.check {
+            return self.execute(emu, blocks, pc, limit);
+        }
+
+        let mut reference = emu.clone();
+        reference.pc = pc;
+
+        let next = self.execute(emu, blocks, pc, limit);
+        emu.pc = next;
+
+        // the interpreter runs exactly as many instructions
+        reference.instruction_limit = Some(emu.instret);
+        reference.run();
+
+        if let Some(diff) = difference(emu, &reference) {
+            panic!("JIT and interpreter differ after running {pc:#010x} to {next:#010x}: {diff}");
+        }
+
+        next
+    }
+
+    fn execute(&mut self, emu: &mut Emulator, blocks: &mut BlockCache, mut pc: u32, limit: u64) -> u32 {
+        self.sync(emu);
+
+        let mut patch = 0;
+
+        loop {
+            let Some(code) = self.block(emu, blocks, pc, patch) else {
+                return pc;
+            };
+
+            let (dirty, code_bitmap) = emu.mem.bitmaps();
+            let mut ctx = Context {
+                regs: emu.regs.as_mut_ptr(),
+                mem: emu.mem.mem.as_mut_ptr(),
+                perms: emu.mem.perms.as_mut_ptr(),
+                dirty,
+                code: code_bitmap,
+                mem_len: emu.mem.mem.len() as u64,
+                coverage: emu.coverage.as_mut().map_or(std::ptr::null_mut(), |coverage| coverage.as_mut_ptr()),
+                table: self.table.as_ptr(),
+                emu,
+                instret: emu.instret,
+                limit,
+                exit: EXIT_INTERPRET,
+                pc,
+                patch: 0,
+                target: 0,
+            };
+
+            let enter: EnterFn = unsafe { std::mem::transmute(self.enter) };
+            unsafe { enter(&mut ctx, code) };
+
+            emu.instret = ctx.instret;
+            pc = ctx.pc;
+            patch = ctx.patch;
+
+            if ctx.exit == EXIT_INTERPRET {
+                return pc;
+            }
+        }
+    }
+
+    /// Compiled code for `pc`, compiling it if needed, `None` if the
+    /// interpreter has to run it
+    ///
+    /// `patch` is the rel32 of the direct jump that got here, if any, which is
+    /// pointed at the code.
+    fn block(&mut self, emu: &mut Emulator, blocks: &mut BlockCache, pc: u32, mut patch: usize) -> Option<usize> {
+        if self.stops.contains(&pc) {
+            return None;
+        }
+
+        let code = match self.blocks.get(&pc) {
+            Some(&code) => code,
+            None => {
+                if self.len + MAX_BLOCK_CODE > self.code.len {
+                    self.flush();
+                    // along with the jump that got here
+                    patch = 0;
+                }
+                self.compile(emu, blocks, pc)?
+            },
+        };
+
+        // the indirect jump table works too if the jump can't be patched
+        let rel = (code as i64 - (patch as i64 + 4)) as i32;
+        if patch == 0 || self.code.write(patch - self.code.addr(), &rel.to_le_bytes()).is_err() {
+            let entry = &mut self.table[(pc as usize >> 2) & (TABLE_SIZE - 1)];
+            *entry = Entry { pc, code };
+        }
+
+        Some(code)
+    }
+
+    /// Compile the block at `pc`, `None` if fetching it faults or the code
+    /// can't be written
+    fn compile(&mut self, emu: &mut Emulator, blocks: &mut BlockCache, pc: u32) -> Option<usize> {
+        let block = blocks.lookup(&mut emu.mem, None, pc).ok()?;
+        let instrs = blocks.instrs(block);
+
+        // breakpoints and pc hooks are left to the interpreter
+        let len = (1..instrs.len())
+            .find(|&ii| self.stops.contains(&(pc + ii as u32 * 4)))
+            .unwrap_or(instrs.len());
+        let instrs = &instrs[..len];
+
+        let mut compiler = Compiler {
+            asm: Asm::new(self.code.addr() + self.len),
+            jit: self,
+            len: len as u32,
+            faults: Vec::new(),
+        };
+        let code = compiler.asm.addr();
+        compiler.block(pc, instrs);
+
+        let buf = compiler.finish();
+        assert!(buf.len() <= MAX_BLOCK_CODE);
+
+        self.code.write(self.len, &buf).ok()?;
+        self.len += buf.len();
+        self.blocks.insert(pc, code);
+
+        Some(code)
+    }
+}
+
+/// Compiles one block
+struct Compiler<'a> {
+    asm: Asm,
+
+    jit: &'a Jit,
+
+    /// Number of instructions in the block
+    len: u32,
+
+    /// Exits for instructions the interpreter has to run: label, address and
+    /// the number of instructions from there to the end of the block
+    faults: Vec<(Label, u32, u32)>,
+}
+
+impl Compiler<'_> {
+    fn block(&mut self, pc: u32, instrs: &[(u32, Result<Instruction, DecodeError>)]) {
+        // leave the last instructions before the limit to the interpreter
+        let limit = self.asm.label();
+        self.faults.push((limit, pc, 0));
+
+        self.asm.mov(true, Rax, Rm::Mem(ctx!(instret)));
+        self.asm.alu_imm(true, Alu::Add, Rm::Reg(Rax), self.len);
+        self.asm.alu_mem(true, Alu::Cmp, Rax, ctx!(limit));
+        self.asm.jcc(Cc::A, limit);
+        self.asm.store(true, ctx!(instret), Rax);
+
+        for (ii, &(_, op)) in instrs.iter().enumerate() {
+            let pc = pc + ii as u32 * 4;
+            let Ok(op) = op else {
+                return self.interpret(pc, self.len - ii as u32);
+            };
+            if op.ends_block() {
+                return self.jump(pc, ii as u32, op);
+            }
+            self.op(pc, ii as u32, op);
+        }
+
+        // ran into a breakpoint, the end of the code or the block size limit
+        self.exit_to(pc + self.len * 4);
+    }
+
+    /// Exit for the interpreter to run the instruction at `pc`, `skipped` is
+    /// the number of instructions of the block from there on, which didn't run
+    fn interpret(&mut self, pc: u32, skipped: u32) {
+        if skipped != 0 {
+            self.asm.alu_imm(true, Alu::Sub, Rm::Mem(ctx!(instret)), skipped);
+        }
+        self.asm.mov_imm(false, Rm::Mem(ctx!(pc)), pc);
+        self.asm.mov_imm(false, Rm::Mem(ctx!(exit)), EXIT_INTERPRET);
+        self.asm.jmp_to(self.jit.epilogue);
+    }
+
+    /// Jump to `target` at the end of the block
+    fn exit_to(&mut self, target: u32) {
+        if self.jit.stops.contains(&target) {
+            return self.interpret(target, 0);
+        }
+        if let Some(&code) = self.jit.blocks.get(&target) {
+            return self.asm.jmp_to(code);
+        }
+
+        // jump to the exit below, until the target is compiled and the jump
+        // is patched to go there instead
+        let patch = self.asm.addr() + 1;
+        self.asm.jmp_to(patch + 4);
+        self.asm.mov_imm(false, Rm::Mem(ctx!(pc)), target);
+        self.asm.mov_imm(false, Rm::Mem(ctx!(exit)), EXIT_JUMP);
+        self.asm.mov_imm64(Rax, patch as u64);
+        self.asm.store(true, ctx!(patch), Rax);
+        self.asm.jmp_to(self.jit.epilogue);
+    }
+
+    /// Load guest register `reg` into `dst`
+    fn load_reg(&mut self, dst: R, reg: Reg) {
+        if reg.0 == 0 {
+            self.asm.alu(false, Alu::Xor, dst, dst);
+        } else {
+            self.asm.mov(false, dst, Rm::Mem(at(Rbx, (reg.0 as i32 - 1) * 4)));
+        }
+    }
+
+    /// Store `src` to guest register `reg`
+    fn store_reg(&mut self, reg: Reg, src: R) {
+        if reg.0 != 0 {
+            self.asm.store(false, at(Rbx, (reg.0 as i32 - 1) * 4), src);
+        }
+    }
+
+    fn store_reg_imm(&mut self, reg: Reg, imm: u32) {
+        if reg.0 != 0 {
+            self.asm.mov_imm(false, Rm::Mem(at(Rbx, (reg.0 as i32 - 1) * 4)), imm);
+        }
+    }
+
+    /// Call `func` with the emulator and `esi`, `edx` and `ecx` as arguments
+    fn call(&mut self, func: usize) {
+        self.asm.mov(true, Rdi, Rm::Mem(ctx!(emu)));
+        self.asm.mov_imm64(Rax, func as u64);
+        self.asm.call(Rm::Reg(Rax));
+    }
+
+    /// Record the operands of a comparison with cmplog, `rs2` is an
+    /// immediate if it is `Err`
+    fn record_cmp(&mut self, rs1: Reg, rs2: Result<Reg, u32>) {
+        if !self.jit.cmplog {
+            return;
+        }
+        self.load_reg(Rsi, rs1);
+        match rs2 {
+            Ok(rs2) => self.load_reg(Rdx, rs2),
+            Err(imm) => self.asm.mov_imm(false, Rm::Reg(Rdx), imm),
+        }
+        self.call(record_cmp as *const () as usize);
+    }
+
+    /// Record the edge from `from` to `to` in the coverage map
+    fn record_edge(&mut self, from: u32, to: u32) {
+        if !self.jit.coverage {
+            return;
+        }
+        let hash = ((from >> 1) ^ to).wrapping_mul(0x9e3779b1) >> 16;
+        let entry = at(Rcx, hash as i32 & (COVERAGE_MAP_SIZE as i32 - 1));
+
+        self.asm.mov(true, Rcx, Rm::Mem(ctx!(coverage)));
+        self.asm.alu_byte(Alu::Add, entry, 1);
+        // saturate
+        self.asm.alu_byte(Alu::Sbb, entry, 0);
+    }
+
+    /// Record the edge from `from` to the address in `eax`
+    fn record_edge_dynamic(&mut self, from: u32) {
+        if !self.jit.coverage {
+            return;
+        }
+        self.asm.mov(false, Rdx, Rm::Reg(Rax));
+        self.asm.alu_imm(false, Alu::Xor, Rm::Reg(Rdx), from >> 1);
+        self.asm.imul_imm(Rdx, Rdx, 0x9e3779b1);
+        self.asm.shift_imm(Shift::Shr, Rdx, 16);
+        self.asm.alu_imm(false, Alu::And, Rm::Reg(Rdx), COVERAGE_MAP_SIZE as u32 - 1);
+
+        let entry = indexed(Rcx, Rdx, 0);
+        self.asm.mov(true, Rcx, Rm::Mem(ctx!(coverage)));
+        self.asm.alu_byte(Alu::Add, entry, 1);
+        self.asm.alu_byte(Alu::Sbb, entry, 0);
+    }
+
+    /// Label exiting to the interpreter for the `index`th instruction
+    fn fault(&mut self, pc: u32, index: u32) -> Label {
+        let label = self.asm.label();
+        self.faults.push((label, pc, self.len - index));
+        label
+    }
+
+    /// Check that the `size` bytes at the guest address in `eax` are in
+    /// bounds and have permission `perm`, going to `fault` if not
+    ///
+    /// Leaves the permission bytes in `edx`.
+    fn check_access(&mut self, size: u32, perm: u8, fault: Label) {
+        let ones = match size {
+            1 => 0x01,
+            2 => 0x0101,
+            _ => 0x01010101,
+        };
+        let mask = ones * perm as u32;
+
+        self.asm.lea(Rdx, at(Rax, size as i32));
+        self.asm.alu_mem(true, Alu::Cmp, Rdx, ctx!(mem_len));
+        self.asm.jcc(Cc::Ae, fault);
+
+        self.asm.load_sized(size, Rdx, indexed(R13, Rax, 0));
+        self.asm.mov(false, Rsi, Rm::Reg(Rdx));
+        self.asm.alu_imm(false, Alu::And, Rm::Reg(Rsi), mask);
+        self.asm.alu_imm(false, Alu::Cmp, Rm::Reg(Rsi), mask);
+        self.asm.jcc(Cc::Ne, fault);
+    }
+
+    /// Compute the address of a load or store into `eax`
+    fn address(&mut self, rs1: Reg, imm: u32) {
+        self.load_reg(Rax, rs1);
+        if imm != 0 {
+            self.asm.alu_imm(false, Alu::Add, Rm::Reg(Rax), imm);
+        }
+    }
+
+    fn load(&mut self, pc: u32, index: u32, op: Instruction) {
+        let (Instruction::Lb { rd, rs1, imm } | Instruction::Lh { rd, rs1, imm } | Instruction::Lw { rd, rs1, imm } |
+            Instruction::Lbu { rd, rs1, imm } | Instruction::Lhu { rd, rs1, imm }) = op else { unreachable!() };
+        let (size, signed) = match op {
+            Instruction::Lb { .. } => (1, true),
+            Instruction::Lh { .. } => (2, true),
+            Instruction::Lbu { .. } => (1, false),
+            Instruction::Lhu { .. } => (2, false),
+            _ => (4, false),
+        };
+
+        let fault = self.fault(pc, index);
+        self.address(rs1, imm);
+        self.check_access(size, PERM_READ, fault);
+
+        if rd.0 == 0 {
+            return;
+        }
+        let mem = indexed(R12, Rax, 0);
+        match (size, signed) {
+            (1, true) => self.asm.op(false, &[0x0f, 0xbe], Rcx as u8, Rm::Mem(mem)),
+            (2, true) => self.asm.op(false, &[0x0f, 0xbf], Rcx as u8, Rm::Mem(mem)),
+            _ => self.asm.load_sized(size, Rcx, mem),
+        }
+        self.store_reg(rd, Rcx);
+    }
+
+    fn store(&mut self, pc: u32, index: u32, op: Instruction) {
+        let (Instruction::Sb { rs1, rs2, imm } | Instruction::Sh { rs1, rs2, imm } | Instruction::Sw { rs1, rs2, imm }) = op
+            else { unreachable!() };
+        let size = match op {
+            Instruction::Sb { .. } => 1,
+            Instruction::Sh { .. } => 2,
+            _ => 4,
+        };
+
+        let fault = self.fault(pc, index);
+        self.address(rs1, imm);
+        self.check_access(size, PERM_WRITE, fault);
+
+        // the first write to a block since the last reset has to note it as
+        // dirty, and writes to code have to flush it, both are left to the
+        // interpreter, as are writes that span two blocks
+        if size > 1 {
+            self.asm.mov(false, Rsi, Rm::Reg(Rax));
+            self.asm.alu_imm(false, Alu::And, Rm::Reg(Rsi), DIRTY_BLOCK_SIZE as u32 - 1);
+            self.asm.alu_imm(false, Alu::Cmp, Rm::Reg(Rsi), DIRTY_BLOCK_SIZE as u32 - size);
+            self.asm.jcc(Cc::A, fault);
+        }
+        self.asm.mov(false, Rsi, Rm::Reg(Rax));
+        self.asm.shift_imm(Shift::Shr, Rsi, DIRTY_BLOCK_SIZE.trailing_zeros() as u8);
+        for (bitmap, cc) in [(R14, Cc::Ae), (R15, Cc::B)] {
+            self.asm.mov(false, Rdi, Rm::Reg(Rsi));
+            self.asm.shift_imm(Shift::Shr, Rdi, 6);
+            self.asm.mov(true, Rdi, Rm::Mem(Mem { base: bitmap, index: Some((Rdi, 3)), disp: 0 }));
+            self.asm.bt(Rdi, Rsi);
+            self.asm.jcc(cc, fault);
+        }
+
+        // reset the RAW bits and set the READ bits
+        let ones = match size {
+            1 => 0x01,
+            2 => 0x0101,
+            _ => 0x01010101,
+        };
+        let no_raw = self.asm.label();
+        self.asm.test_imm(Rdx, ones * PERM_RAW as u32);
+        self.asm.jcc(Cc::E, no_raw);
+        self.asm.mov(false, Rsi, Rm::Reg(Rdx));
+        self.asm.shift_imm(Shift::Shr, Rsi, PERM_RAW.trailing_zeros() as u8);
+        self.asm.alu_imm(false, Alu::And, Rm::Reg(Rsi), ones);
+        self.asm.mov(false, Rdi, Rm::Reg(Rsi));
+        self.asm.shift_imm(Shift::Shl, Rdi, PERM_RAW.trailing_zeros() as u8);
+        self.asm.not(Rdi);
+        self.asm.alu(false, Alu::And, Rdx, Rdi);
+        self.asm.alu(false, Alu::Or, Rdx, Rsi);
+        self.asm.store_sized(size, indexed(R13, Rax, 0), Rdx);
+        self.asm.bind(no_raw);
+
+        self.load_reg(Rcx, rs2);
+        self.asm.store_sized(size, indexed(R12, Rax, 0), Rcx);
+    }
+
+    /// `rd = rs1 op rs2`
+    fn alu(&mut self, op: Alu, rd: Reg, rs1: Reg, rs2: Reg) {
+        if rd.0 == 0 {
+            return;
+        }
+        self.load_reg(Rax, rs1);
+        self.load_reg(Rcx, rs2);
+        self.asm.alu(false, op, Rax, Rcx);
+        self.store_reg(rd, Rax);
+    }
+
+    /// `rd = rs1 op imm`
+    fn alu_imm(&mut self, op: Alu, rd: Reg, rs1: Reg, imm: u32) {
+        if rd.0 == 0 {
+            return;
+        }
+        self.load_reg(Rax, rs1);
+        self.asm.alu_imm(false, op, Rm::Reg(Rax), imm);
+        self.store_reg(rd, Rax);
+    }
+
+    /// `rd = rs1 shift rs2`
+    fn shift(&mut self, op: Shift, rd: Reg, rs1: Reg, rs2: Reg) {
+        if rd.0 == 0 {
+            return;
+        }
+        self.load_reg(Rax, rs1);
+        // x86 masks the shift amount to 5 bits too
+        self.load_reg(Rcx, rs2);
+        self.asm.shift_cl(op, Rax);
+        self.store_reg(rd, Rax);
+    }
+
+    fn shift_imm(&mut self, op: Shift, rd: Reg, rs1: Reg, shamt: u32) {
+        if rd.0 == 0 {
+            return;
+        }
+        self.load_reg(Rax, rs1);
+        self.asm.shift_imm(op, Rax, shamt as u8);
+        self.store_reg(rd, Rax);
+    }
+
+    /// `rd = rs1 < rs2`, `rs2` is an immediate if it is `Err`
+    fn set_less(&mut self, cc: Cc, rd: Reg, rs1: Reg, rs2: Result<Reg, u32>) {
+        self.record_cmp(rs1, rs2);
+        if rd.0 == 0 {
+            return;
+        }
+        self.load_reg(Rax, rs1);
+        match rs2 {
+            Ok(rs2) => {
+                self.load_reg(Rcx, rs2);
+                self.asm.alu(false, Alu::Cmp, Rax, Rcx);
+            },
+            Err(imm) => self.asm.alu_imm(false, Alu::Cmp, Rm::Reg(Rax), imm),
+        }
+        self.asm.set(cc, Rax);
+        self.store_reg(rd, Rax);
+    }
+
+    /// Compile an instruction that doesn't end the block
+    fn op(&mut self, pc: u32, index: u32, op: Instruction) {
+        match op {
+            Instruction::Lui { rd, imm } => self.store_reg_imm(rd, imm),
+            Instruction::Auipc { rd, imm } => self.store_reg_imm(rd, pc.wrapping_add(imm)),
+
+            Instruction::Lb { .. } | Instruction::Lh { .. } | Instruction::Lw { .. } |
+            Instruction::Lbu { .. } | Instruction::Lhu { .. } => self.load(pc, index, op),
+
+            Instruction::Sb { .. } | Instruction::Sh { .. } | Instruction::Sw { .. } => self.store(pc, index, op),
+
+            Instruction::Addi { rd, rs1, imm } => self.alu_imm(Alu::Add, rd, rs1, imm),
+            Instruction::Slti { rd, rs1, imm } => self.set_less(Cc::L, rd, rs1, Err(imm)),
+            Instruction::Sltiu { rd, rs1, imm } => self.set_less(Cc::B, rd, rs1, Err(imm)),
+            Instruction::Xori { rd, rs1, imm } => self.alu_imm(Alu::Xor, rd, rs1, imm),
+            Instruction::Ori { rd, rs1, imm } => self.alu_imm(Alu::Or, rd, rs1, imm),
+            Instruction::Andi { rd, rs1, imm } => self.alu_imm(Alu::And, rd, rs1, imm),
+            Instruction::Slli { rd, rs1, shamt } => self.shift_imm(Shift::Shl, rd, rs1, shamt),
+            Instruction::Srli { rd, rs1, shamt } => self.shift_imm(Shift::Shr, rd, rs1, shamt),
+            Instruction::Srai { rd, rs1, shamt } => self.shift_imm(Shift::Sar, rd, rs1, shamt),
+
+            Instruction::Add { rd, rs1, rs2 } => self.alu(Alu::Add, rd, rs1, rs2),
+            Instruction::Sub { rd, rs1, rs2 } => self.alu(Alu::Sub, rd, rs1, rs2),
+            Instruction::Sll { rd, rs1, rs2 } => self.shift(Shift::Shl, rd, rs1, rs2),
+            Instruction::Slt { rd, rs1, rs2 } => self.set_less(Cc::L, rd, rs1, Ok(rs2)),
+            Instruction::Sltu { rd, rs1, rs2 } => self.set_less(Cc::B, rd, rs1, Ok(rs2)),
+            Instruction::Xor { rd, rs1, rs2 } => self.alu(Alu::Xor, rd, rs1, rs2),
+            Instruction::Srl { rd, rs1, rs2 } => self.shift(Shift::Shr, rd, rs1, rs2),
+            Instruction::Sra { rd, rs1, rs2 } => self.shift(Shift::Sar, rd, rs1, rs2),
+            Instruction::Or { rd, rs1, rs2 } => self.alu(Alu::Or, rd, rs1, rs2),
+            Instruction::And { rd, rs1, rs2 } => self.alu(Alu::And, rd, rs1, rs2),
+
+            Instruction::Fence => (),
+
+            _ => unreachable!("{op:?} ends the block"),
+        }
+    }
+
+    /// Compile the instruction ending the block
+    fn jump(&mut self, pc: u32, index: u32, op: Instruction) {
+        // x1 and x5 are link registers, see `Emulator::track_call`
+        let is_link = |reg: Reg| reg.0 == 1 || reg.0 == 5;
+
+        match op {
+            Instruction::Jal { rd, imm } => {
+                let target = pc.wrapping_add(imm);
+                self.store_reg_imm(rd, pc + 4);
+                self.record_edge(pc, target);
+                if is_link(rd) {
+                    self.asm.mov_imm(false, Rm::Reg(Rsi), rd.0 as u32);
+                    self.asm.mov_imm(false, Rm::Reg(Rdx), NO_REG);
+                    self.asm.mov_imm(false, Rm::Reg(Rcx), pc + 4);
+                    self.call(track_call as *const () as usize);
+                }
+                self.exit_to(target);
+            },
+            Instruction::Jalr { rd, rs1, imm } => {
+                self.address(rs1, imm);
+                self.store_reg_imm(rd, pc + 4);
+                self.record_edge_dynamic(pc);
+                if is_link(rd) || is_link(rs1) {
+                    self.asm.store(false, ctx!(target), Rax);
+                    self.asm.mov_imm(false, Rm::Reg(Rsi), rd.0 as u32);
+                    self.asm.mov_imm(false, Rm::Reg(Rdx), rs1.0 as u32);
+                    self.asm.mov_imm(false, Rm::Reg(Rcx), pc + 4);
+                    self.call(track_call as *const () as usize);
+                    self.asm.mov(false, Rax, Rm::Mem(ctx!(target)));
+                }
+
+                // look the target up in the table
+                self.asm.mov(false, Rdx, Rm::Reg(Rax));
+                self.asm.shift_imm(Shift::Shr, Rdx, 2);
+                self.asm.alu_imm(false, Alu::And, Rm::Reg(Rdx), TABLE_SIZE as u32 - 1);
+                self.asm.shift_imm(Shift::Shl, Rdx, 4);
+                self.asm.alu_mem(true, Alu::Add, Rdx, ctx!(table));
+                self.asm.op(false, &[0x39], Rax as u8, Rm::Mem(at(Rdx, offset_of!(Entry, pc) as i32)));
+                self.asm.jcc_to(Cc::Ne, self.jit.miss);
+                self.asm.jmp(Rm::Mem(at(Rdx, offset_of!(Entry, code) as i32)));
+            },
+
+            Instruction::Beq { rs1, rs2, imm } | Instruction::Bne { rs1, rs2, imm } |
+            Instruction::Blt { rs1, rs2, imm } | Instruction::Bge { rs1, rs2, imm } |
+            Instruction::Bltu { rs1, rs2, imm } | Instruction::Bgeu { rs1, rs2, imm } => {
+                let cc = match op {
+                    Instruction::Beq { .. } => Cc::E,
+                    Instruction::Bne { .. } => Cc::Ne,
+                    Instruction::Blt { .. } => Cc::L,
+                    Instruction::Bge { .. } => Cc::Ge,
+                    Instruction::Bltu { .. } => Cc::B,
+                    Instruction::Bgeu { .. } => Cc::Ae,
+                    _ => unreachable!(),
+                };
+                let target = pc.wrapping_add(imm);
+
+                self.record_cmp(rs1, Ok(rs2));
+                self.load_reg(Rax, rs1);
+                self.load_reg(Rcx, rs2);
+                self.asm.alu(false, Alu::Cmp, Rax, Rcx);
+                let taken = self.asm.label();
+                self.asm.jcc(cc, taken);
+
+                self.record_edge(pc, pc + 4);
+                self.exit_to(pc + 4);
+
+                self.asm.bind(taken);
+                self.record_edge(pc, target);
+                self.exit_to(target);
+            },
+
+            // syscalls and breakpoints
+            _ => self.interpret(pc, self.len - index),
+        }
+    }
+
+    /// Emit the exits to the interpreter and return the code
+    fn finish(mut self) -> Vec<u8> {
+        for (label, pc, skipped) in std::mem::take(&mut self.faults) {
+            self.asm.bind(label);
+            self.interpret(pc, skipped);
+        }
+        self.asm.finish()
+    }
+}
+
+/// Describe the first difference between the guest state of `jit` and
+/// `interp`, if any
+fn difference(jit: &Emulator, interp: &Emulator) -> Option<String> {
+    if jit.pc != interp.pc {
+        return Some(format!("pc {:#010x} vs {:#010x}", jit.pc, interp.pc));
+    }
+    if jit.instret != interp.instret {
+        return Some(format!("instret {} vs {}", jit.instret, interp.instret));
+    }
+    for reg in (1..32).map(Reg) {
+        if jit.read_reg(reg) != interp.read_reg(reg) {
+            return Some(format!("{} {:#010x} vs {:#010x}", reg.abi_name(),
+                jit.read_reg(reg), interp.read_reg(reg)));
+        }
+    }
+    if jit.call_stack != interp.call_stack {
+        return Some(format!("call stack {:08x?} vs {:08x?}", jit.call_stack, interp.call_stack));
+    }
+    if jit.coverage != interp.coverage {
+        return Some("coverage".to_string());
+    }
+    if jit.cmplog != interp.cmplog {
+        return Some(format!("cmplog {:08x?} vs {:08x?}", jit.cmplog, interp.cmplog));
+    }
+
+    let first_difference = |a: &[u8], b: &[u8]| {
+        a.iter().zip(b).position(|(a, b)| a != b)
+    };
+    if let Some(addr) = first_difference(&jit.mem.mem, &interp.mem.mem) {
+        return Some(format!("memory at {addr:#010x} {:#04x} vs {:#04x}",
+            jit.mem.mem[addr], interp.mem.mem[addr]));
+    }
+    if let Some(addr) = first_difference(&jit.mem.perms, &interp.mem.perms) {
+        return Some(format!("permissions at {addr:#010x} {} vs {}",
+            format_perms(jit.mem.perms[addr]), format_perms(interp.mem.perms[addr])));
+    }
+
+    None
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    /// An emulator with each `(addr, text)` of `code` assembled into
+    /// executable memory, starting at the first
+    fn guest(code: &[(u32, &str)], jit: bool) -> Emulator {
+        let mut emu = Emulator::new(0x20000);
+        for &(addr, text) in code {
+            let code = assemble(addr, text).unwrap();
+            emu.mem.write(addr, PERM_NONE, &code).unwrap();
+            emu.mem.set_permissions(addr..addr + code.len() as u32, PERM_EXEC).unwrap();
+        }
+        emu.pc = code[0].0;
+        if jit {
+            emu.enable_jit(false).unwrap();
+        }
+        emu
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut emu = Emulator::new(0x20000);
+        let code = assemble(0x10000, "
+                lui t0, 0x11
+                li t1, 10
+                li a0, 0
+            loop:
+                add a0, a0, t1
+                sw a0, 0(t0)
+                lbu t2, 0(t0)
+                slli a1, a0, 7
+                srai a2, a1, 2
+                slt a2, a0, a1
+                jal ra, func
+                addi t1, t1, -1
+                bnez t1, loop
+                ebreak
+            func:
+                lw a3, 4(t0)    # uninitialized the first time
+                ret
+        ").unwrap();
+        emu.mem.write(0x10000, PERM_NONE, &code).unwrap();
+        emu.mem.set_permissions(0x10000..0x1003c, PERM_EXEC).unwrap();
+        emu.mem.set_permissions(0x11000..0x11004, PERM_READ | PERM_WRITE).unwrap();
+        emu.mem.set_permissions(0x11004..0x11008, PERM_RAW | PERM_WRITE).unwrap();
+        emu.pc = 0x10000;
+        emu.coverage = Some(vec![0; COVERAGE_MAP_SIZE].into_boxed_slice());
+        emu.cmplog = Some(vec![]);
+        emu.enable_jit(true).unwrap();
+        let snapshot = emu.fork();
+
+        // faults at the read of uninitialized memory
+        assert!(matches!(emu.run(), EmulatorExit::InvalidMemoryAccess(_)));
+        assert_eq!(emu.pc, 0x10034);
+
+        // runs to the end once it is initialized, then again with an
+        // instruction limit in the middle of a block
+        for limit in [None, Some(23)] {
+            emu.reset(&snapshot);
+            emu.mem.write_u32(0x11004, PERM_NONE, 0).unwrap();
+            emu.instruction_limit = limit;
+            let exit = emu.run();
+
+            if limit.is_some() {
+                assert!(matches!(exit, EmulatorExit::Timeout));
+                assert_eq!((emu.pc, emu.instret), (0x10028, 23));
+            } else {
+                assert!(matches!(exit, EmulatorExit::Break));
+                assert_eq!(emu.read_reg(RegName::A0.as_reg()), 55);
+                assert_eq!(emu.read_reg(RegName::T2.as_reg()), 55);
+            }
+        }
+    }
+    #[test]
+    fn modified_code() {
+        let mut emu = guest(&[
+            (0x10000, "
+                    lui t0, 0x10
+                    li s0, 2
+                    li a0, 0
+                loop:
+                    jal ra, 0x10100
+                    sw t1, 0x100(t0)    # replaces the first instruction called
+                    addi s0, s0, -1
+                    bnez s0, loop
+                    ebreak
+            "),
+            (0x10100, "
+                    addi a0, a0, 1
+                    ret
+            "),
+        ], true);
+        emu.mem.set_permissions(0x10100..0x10108, PERM_EXEC | PERM_WRITE).unwrap();
+        let replacement = assemble(0x10100, "addi a0, a0, 10").unwrap();
+        emu.write_reg(RegName::T1.as_reg(), u32::from_le_bytes(replacement.try_into().unwrap()));
+
+        // the second call runs the new instruction, not the code compiled for
+        // the first
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 11);
+    }
+
+    #[test]
+    fn indirect_jumps() {
+        // the functions are a multiple of the jump table's size apart, so
+        // they share an entry and keep replacing each other in it
+        let code = [
+            (0x10000, "
+                    li s0, 10
+                    li s1, 0x10100
+                    li s2, 0x14100
+                    li a0, 0
+                loop:
+                    jalr ra, 0(s1)
+                    jalr ra, 0(s2)
+                    addi s0, s0, -1
+                    bnez s0, loop
+                    ebreak
+            "),
+            (0x10100, "
+                    addi a0, a0, 1
+                    ret
+            "),
+            (0x14100, "
+                    addi a0, a0, 100
+                    ret
+            "),
+        ];
+
+        let mut emu = guest(&code, true);
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 1010);
+
+        let mut reference = guest(&code, false);
+        reference.run();
+        assert_eq!((emu.pc, emu.instret), (reference.pc, reference.instret));
+    }
+
+    #[test]
+    fn flushed_chains() {
+        let mut emu = guest(&[(0x10000, "
+                    li a0, 0
+                    li s0, 3
+                loop:
+                    addi a0, a0, 1
+                    j next
+                next:
+                    addi s0, s0, -1
+                    bnez s0, loop
+                    ebreak
+        ")], true);
+        let snapshot = emu.fork();
+
+        // compiles the blocks and patches the jumps between them
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 3);
+
+        // a breakpoint flushes the code, the patched jump to `next` doesn't
+        // skip over it
+        emu.reset(&snapshot);
+        emu.breakpoints.insert(0x10010);
+        assert!(matches!(emu.run(), EmulatorExit::Breakpoint));
+        assert_eq!((emu.pc, emu.read_reg(RegName::A0.as_reg())), (0x10010, 1));
+
+        // and without it the code is compiled and chained again
+        emu.breakpoints.clear();
+        assert!(matches!(emu.run(), EmulatorExit::Break));
+        assert_eq!(emu.read_reg(RegName::A0.as_reg()), 3);
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::emulator::*;
+use crate::instructions::*;
+
+/// Size of the initial stack
+pub const STACK_SIZE: u32 = 1024 * 1096;
+
+/// Size of the unmapped guard region below the stack
+pub const STACK_GUARD_SIZE: u32 = 64 * 1024;
+
+/// Size of the region `brk` can grow into
+pub const HEAP_SIZE: u32 = 2 * 1024 * 1024;
+
+// errno values, returned negated from syscalls
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EINVAL: i32 = 22;
+
+// mmap flags and protections
+const PROT_READ: u32 = 0x1;
+const PROT_WRITE: u32 = 0x2;
+const PROT_EXEC: u32 = 0x4;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+/// Granularity of mmap
+const PAGE_SIZE: u32 = 4096;
+
+/// Turn an errno value into a syscall return value
+fn errno(err: i32) -> u32 {
+    -err as u32
+}
+
+/// An open file descriptor
+#[derive(Debug, Clone)]
+enum Fd {
+    /// stdout or stderr
+    Output,
+
+    /// A file in the vfs, or stdin
+    File {
+        data: Rc<[u8]>,
+        offset: usize,
+    },
+}
+
+/// Why [`Kernel::run`] returned
+#[derive(Debug)]
+pub enum KernelExit {
+    /// The guest called exit
+    Exit(i32),
+
+    /// The guest made a syscall we don't implement
+    UnhandledSyscall(u32),
+
+    /// The emulator stopped for a reason other than a syscall
+    Emulator(EmulatorExit),
+}
+
+/// Why a syscall didn't return to the guest
+#[derive(Debug)]
+enum SyscallError {
+    /// The guest can not continue
+    Exit(KernelExit),
+
+    /// Accessing guest memory on behalf of the guest faulted, this is
+    /// classified like a fault of the guest's own access
+    Fault(MemoryError),
+}
+
+impl From<MemoryError> for SyscallError {
+    fn from(err: MemoryError) -> Self {
+        SyscallError::Fault(err)
+    }
+}
+
+/// What kind of allocation a region of guest memory came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationKind {
+    Stack,
+    HeapChunk,
+    Brk,
+    Mmap,
+}
+
+impl AllocationKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AllocationKind::Stack => "stack frame",
+            AllocationKind::HeapChunk => "heap chunk",
+            AllocationKind::Brk => "brk region",
+            AllocationKind::Mmap => "mmap region",
+        }
+    }
+}
+
+/// A region of guest memory and where it was allocated, for reporting reads
+/// of uninitialized memory
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub kind: AllocationKind,
+
+    pub range: Range<u32>,
+
+    /// pc of the instruction that allocated the region, `None` if it was set
+    /// up by us before the guest started
+    pub pc: Option<u32>,
+
+    /// Return addresses of the active calls when it was allocated, innermost
+    /// first, if known
+    pub backtrace: Vec<u32>,
+}
+
+/// A minimal linux user mode environment for running a guest program
+///
+/// Handles the initial stack layout, the `brk` heap and the syscalls newlib
+/// needs, with files served from an in-memory vfs.
+///
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    /// Print every syscall, its result and guest output
+    pub verbose: bool,
+
+    /// Files the guest can open, by path
+    pub files: BTreeMap<Vec<u8>, Rc<[u8]>>,
+
+    /// Open file descriptors, indexed by fd
+    fds: Vec<Option<Fd>>,
+
+    /// Start of the region `brk` can grow into
+    heap_start: u32,
+
+    /// End of the region `brk` can grow into
+    heap_end: u32,
+
+    /// The current "end of heap" as the program believes it to be
+    current_brk: u32,
+
+    /// The initial stack
+    stack: Range<u32>,
+
+    /// Regions handed out by `brk` and `mmap`, in the order they were
+    /// allocated
+    allocations: Vec<Allocation>,
+
+
+    /// File whose contents are tainted when read, if the emulator tracks
+    /// taint
+    taint_source: Option<Rc<[u8]>>,
+}
+
+impl Kernel {
+    pub fn new() -> Self {
+        Kernel {
+            verbose: false,
+            files: BTreeMap::new(),
+            fds: vec![
+                Some(Fd::File { data: Rc::from(&[][..]), offset: 0 }),
+                Some(Fd::Output),
+                Some(Fd::Output),
+            ],
+            heap_start: 0,
+            heap_end: 0,
+            current_brk: 0,
+            stack: 0..0,
+            allocations: Vec::new(),
+            taint_source: None,
+        }
+    }
+
+    /// Add a file to the vfs, replacing any file with the same path
+    pub fn add_file(&mut self, path: &[u8], data: &[u8]) {
+        self.files.insert(path.to_vec(), Rc::from(data));
+    }
+
+    /// Set the data the guest reads from stdin
+    pub fn set_stdin(&mut self, data: &[u8]) {
+        self.fds[0] = Some(Fd::File { data: Rc::from(data), offset: 0 });
+    }
+
+    /// Taint what the guest reads from stdin with the offsets it is read from
+    pub fn taint_stdin(&mut self) {
+        if let Some(Some(Fd::File { data, .. })) = self.fds.first() {
+            self.taint_source = Some(data.clone());
+        }
+    }
+
+    /// Taint what the guest reads from the file at `path` with the offsets it
+    /// is read from
+    pub fn taint_file(&mut self, path: &[u8]) {
+        self.taint_source = self.files.get(path).cloned();
+    }
+
+    /// Allocate the initial stack and the heap, and set up the stack the way
+    /// the linux entry point expects it
+    ///
+    pub fn setup(&mut self, emu: &mut Emulator, args: &[&[u8]]) -> Result<(), MemoryError> {
+        // TODO: alignment??
+        // a guard region below the stack catches overflows before they run
+        // into other memory
+        let (guard_start, stack_end) =
+            emu.mem.allocate(STACK_GUARD_SIZE + STACK_SIZE, PERM_RAW | PERM_WRITE)?;
+        let stack_start = guard_start + STACK_GUARD_SIZE;
+        emu.mem.set_permissions(guard_start..stack_start, PERM_NONE)?;
+        emu.stack_guard = Some(guard_start..stack_start);
+
+        if self.verbose {
+            println!("allocated stack: {:08x}-{:08x}", stack_start, stack_end);
+        }
+        self.stack = stack_start..stack_end;
+        let mut sp = stack_end;
+
+        // stack layout:
+        // argument strings\0
+        // aux vector, null terminated
+        // env vector, null terminated
+        // arg vector, null terminated
+        // argc
+
+        macro_rules! push {
+            ($val:expr) => {{
+                // allocate space
+                sp -= $val.len() as u32;
+                // write data
+                emu.mem.write(sp, PERM_WRITE, &$val[..])?;
+                if self.verbose {
+                    println!("{sp:08x}: {:02x?}", $val);
+                }
+                sp
+            }}
+        }
+
+        // the argument strings
+        let mut argv = vec![];
+        for arg in args {
+            push!([0u8]);
+            argv.push(push!(arg));
+        }
+
+        // aux vector terminator
+        push!(u32::to_le_bytes(0));
+        push!(u32::to_le_bytes(0));
+        // env vector terminator
+        push!(u32::to_le_bytes(0));
+        // argv vector
+        push!(u32::to_le_bytes(0));
+        for &arg in argv.iter().rev() {
+            push!(u32::to_le_bytes(arg));
+        }
+        // argc
+        push!(u32::to_le_bytes(argv.len() as u32));
+        emu.write_reg(RegName::Sp.as_reg(), sp);
+
+        // allocate a heap
+        let (heap_start, heap_end) = emu.mem.allocate(HEAP_SIZE, PERM_RAW | PERM_WRITE)?;
+
+        if self.verbose {
+            println!("allocated heap:  {heap_start:08x}-{heap_end:08x}");
+        }
+
+        self.heap_start = heap_start;
+        self.heap_end = heap_end;
+        self.current_brk = heap_start;
+
+        Ok(())
+    }
+
+    /// Run the guest until it exits or the emulator stops for a reason other
+    /// than a syscall we can handle
+    ///
+    pub fn run(&mut self, emu: &mut Emulator) -> KernelExit {
+        loop {
+            match emu.run() {
+                EmulatorExit::Syscall => {
+                    if let Some(exit) = self.syscall(emu) {
+                        return exit;
+                    }
+                },
+                exit => return KernelExit::Emulator(exit),
+            }
+        }
+    }
+
+    /// Find the allocation the memory at `addr` came from
+    ///
+    /// Stack frames are only known if the emulator tracks them, see
+    /// [`Emulator::stack_frames`].
+    pub fn origin(&self, emu: &Emulator, addr: u32) -> Option<Allocation> {
+        if self.stack.contains(&addr) {
+            let frames = emu.stack_frames.as_deref().unwrap_or(&[]);
+
+            let frame = frames.iter().find(|frame| (frame.sp..frame.top).contains(&addr));
+            let Some(frame) = frame else {
+                // set up by us, allocated before frames were tracked or
+                // below the stack pointer
+                return Some(Allocation {
+                    kind: AllocationKind::Stack,
+                    range: self.stack.clone(),
+                    pc: None,
+                    backtrace: vec![],
+                });
+            };
+
+            return Some(Allocation {
+                kind: AllocationKind::Stack,
+                range: frame.sp..frame.top,
+                pc: Some(frame.pc),
+                backtrace: vec![],
+            });
+        }
+
+        if let Some(chunk) = emu.sanitizer.as_ref().and_then(|san| san.find(addr)) {
+            return Some(Allocation {
+                kind: AllocationKind::HeapChunk,
+                range: chunk.addr..chunk.addr + chunk.size,
+                // the call to malloc
+                pc: chunk.allocated.first().map(|ra| ra - 4),
+                backtrace: chunk.allocated.clone(),
+            });
+        }
+
+        if let Some(alloc) = self.allocations.iter().rev().find(|alloc| alloc.range.contains(&addr)) {
+            return Some(alloc.clone());
+        }
+
+        if (self.heap_start..self.heap_end).contains(&addr) {
+            return Some(Allocation {
+                kind: AllocationKind::Brk,
+                range: self.current_brk..self.heap_end,
+                pc: None,
+                backtrace: vec![],
+            });
+        }
+
+        None
+    }
+
+    /// Read a NUL terminated string from guest memory
+    fn read_cstr(emu: &Emulator, addr: u32) -> Result<Vec<u8>, MemoryError> {
+        let mut string = vec![];
+        loop {
+            let at = addr.checked_add(string.len() as u32)
+                .ok_or(MemoryError::OutOfBounds { addr: addr..u32::MAX })?;
+            let byte = emu.mem.read_u8(at, PERM_READ)? as u8;
+            if byte == 0 {
+                return Ok(string);
+            }
+            string.push(byte);
+        }
+    }
+
+    /// Open a file in the vfs, returning the new fd
+    fn open(&mut self, emu: &Emulator, path: u32) -> Result<u32, MemoryError> {
+        let path = Self::read_cstr(emu, path)?;
+
+        if self.verbose {
+            println!("open({})", String::from_utf8_lossy(&path));
+        }
+
+        let Some(data) = self.files.get(&path) else {
+            return Ok(errno(ENOENT));
+        };
+
+        let fd = Fd::File { data: data.clone(), offset: 0 };
+
+        // reuse the lowest free fd
+        let fd = match self.fds.iter().position(Option::is_none) {
+            Some(free) => {
+                self.fds[free] = Some(fd);
+                free
+            },
+            None => {
+                self.fds.push(Some(fd));
+                self.fds.len() - 1
+            },
+        };
+
+        Ok(fd as u32)
+    }
+
+    /// Handle the syscall the guest is stopped at
+    ///
+    /// Returns `Some` if the guest can not continue.
+    fn syscall(&mut self, emu: &mut Emulator) -> Option<KernelExit> {
+        // sycall no is in a7/x17
+        let syscall_no = emu.read_reg(Reg(17));
+        if self.verbose {
+            println!("syscall: {syscall_no}");
+        }
+
+        let ret = match self.handle_syscall(emu, syscall_no) {
+            Ok(ret) => ret,
+            Err(SyscallError::Exit(exit)) => return Some(exit),
+            Err(SyscallError::Fault(err)) => return Some(KernelExit::Emulator(emu.memory_fault(err))),
+        };
+
+        if self.verbose {
+            println!("ret = {}", ret as i32);
+        }
+        // set return value
+        emu.write_reg(Reg(10), ret);
+
+        // update pc to next instruction
+        emu.pc += 4;
+
+        None
+    }
+
+    /// Perform a syscall, returning the value to hand back to the guest
+    fn handle_syscall(&mut self, emu: &mut Emulator, syscall_no: u32) -> Result<u32, SyscallError> {
+        let ret = match syscall_no {
+            // long sys_openat(int dfd, const char __user *filename, int flags, umode_t mode);
+            56 => {
+                let path = emu.read_reg(Reg(11));
+                self.open(emu, path)?
+            },
+            // long sys_close(unsigned int fd);
+            57 => {
+                let fd = emu.read_reg(Reg(10));
+                if self.verbose {
+                    println!("close({fd})");
+                }
+
+                match self.fds.get_mut(fd as usize) {
+                    Some(fd @ Some(_)) => {
+                        *fd = None;
+                        0
+                    },
+                    _ => errno(EBADF),
+                }
+            },
+            // off_t sys_lseek(unsigned int fd, off_t offset, unsigned int whence);
+            62 => {
+                let fd = emu.read_reg(Reg(10));
+                let offset = emu.read_reg(Reg(11)) as i32;
+                let whence = emu.read_reg(Reg(12));
+                if self.verbose {
+                    println!("lseek({fd}, {offset}, {whence})");
+                }
+
+                match self.fds.get_mut(fd as usize) {
+                    Some(Some(Fd::File { data, offset: current })) => {
+                        let base = match whence {
+                            // SEEK_SET
+                            0 => 0,
+                            // SEEK_CUR
+                            1 => *current as i64,
+                            // SEEK_END
+                            2 => data.len() as i64,
+                            _ => return Ok(errno(EINVAL)),
+                        };
+
+                        let new = base + offset as i64;
+                        if new < 0 {
+                            errno(EINVAL)
+                        } else {
+                            *current = new as usize;
+                            new as u32
+                        }
+                    },
+                    Some(Some(Fd::Output)) => errno(EINVAL),
+                    _ => errno(EBADF),
+                }
+            },
+            // long sys_read(unsigned int fd, char __user *buf, size_t count);
+            63 => {
+                let fd = emu.read_reg(Reg(10));
+                let buf = emu.read_reg(Reg(11));
+                let count = emu.read_reg(Reg(12));
+                if self.verbose {
+                    println!("read({fd}, {buf:08x}, {count})");
+                }
+
+                match self.fds.get_mut(fd as usize) {
+                    Some(Some(Fd::File { data, offset })) => {
+                        let start = (*offset).min(data.len());
+                        let end = start.saturating_add(count as usize).min(data.len());
+
+                        emu.mem.write(buf, PERM_WRITE, &data[start..end])?;
+                        *offset = end;
+
+                        if let Some(taint) = &mut emu.taint {
+                            let source = self.taint_source.as_ref()
+                                .is_some_and(|source| Rc::ptr_eq(source, data));
+                            if source {
+                                taint.taint_input(buf, start as u32..end as u32);
+                            } else {
+                                taint.clear(buf..buf + (end - start) as u32);
+                            }
+                        }
+
+                        (end - start) as u32
+                    },
+                    _ => errno(EBADF),
+                }
+            },
+            // long sys_write(unsigned int fd, const char __user *buf, size_t count);
+            64 => {
+                let fd = emu.read_reg(Reg(10));
+                let buf = emu.read_reg(Reg(11));
+                let count = emu.read_reg(Reg(12));
+
+                if self.verbose {
+                    println!("write({fd}, {buf:08x}, {count})");
+                }
+
+                match self.fds.get(fd as usize) {
+                    Some(Some(Fd::Output)) => {
+                        // stdout or stderr
+                        let end = buf.checked_add(count)
+                            .ok_or(MemoryError::OutOfBounds { addr: buf..u32::MAX })?;
+                        let bytes = emu.mem.read(buf..end, PERM_READ)?;
+                        if self.verbose {
+                            let string = String::from_utf8_lossy(bytes);
+                            println!("output: {bytes:x?}");
+                            println!("output: {string}");
+                        }
+                        count
+                    },
+                    _ => errno(EBADF),
+                }
+            },
+            // fstat / newfstat(unsigned int fd, struct stat __user *statbuf)
+            80 => {
+                let fd = emu.read_reg(Reg(10));
+                let buf = emu.read_reg(Reg(11));
+                if self.verbose {
+                    println!("fstat({fd}, {buf:08x})");
+                }
+
+                // size of kernel stat struct is 128 bytes
+                let mut stat = [0u8; 128];
+
+                match self.fds.get(fd as usize) {
+                    Some(Some(Fd::Output)) => (),
+                    Some(Some(Fd::File { data, .. })) => {
+                        // st_mode, regular file
+                        stat[16..20].copy_from_slice(&0o100644u32.to_le_bytes());
+                        // st_size
+                        stat[48..56].copy_from_slice(&(data.len() as u64).to_le_bytes());
+                    },
+                    _ => return Ok(errno(EBADF)),
+                }
+
+                emu.mem.write(buf, PERM_WRITE, &stat)?;
+
+                0
+            },
+            // long sys_exit(int error_code);
+            // long sys_exit_group(int error_code);
+            93 | 94 => {
+                let code = emu.read_reg(Reg(10)) as i32;
+                if self.verbose {
+                    println!("exit({code})");
+                }
+
+                return Err(SyscallError::Exit(KernelExit::Exit(code)));
+            },
+            // brk / long sys_brk(unsigned long brk)
+            214 => {
+                // However, the actual Linux system call returns the new program break  on
+                // success.   On  failure, the system call returns the current break.
+
+                let new_brk = emu.read_reg(Reg(10));
+                if self.verbose {
+                    println!("brk({new_brk:08x})");
+                }
+
+                if new_brk < self.heap_start {
+                } else if new_brk > self.heap_end {
+                    // todo do something about oom?
+                } else {
+                    if new_brk > self.current_brk {
+                        self.allocations.push(Allocation {
+                            kind: AllocationKind::Brk,
+                            range: self.current_brk..new_brk,
+                            pc: Some(emu.pc),
+                            backtrace: emu.call_stack.iter().rev().copied().collect(),
+                        });
+                    }
+                    self.current_brk = new_brk;
+                }
+
+                self.current_brk
+            },
+            // long sys_munmap(unsigned long addr, size_t len)
+            215 => {
+                let addr = emu.read_reg(Reg(10));
+                let len = emu.read_reg(Reg(11));
+                if self.verbose {
+                    println!("munmap({addr:08x}, {len})");
+                }
+
+                // only whole mappings can be unmapped
+                let mapping = self.allocations.iter().position(|alloc|
+                    alloc.kind == AllocationKind::Mmap &&
+                    alloc.range.start == addr &&
+                    alloc.range.len() as u32 == len.wrapping_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+                let Some(mapping) = mapping else {
+                    return Ok(errno(EINVAL));
+                };
+
+                let range = self.allocations.remove(mapping).range;
+                emu.mem.set_permissions(range.clone(), PERM_NONE)?;
+                emu.mem.free.insert(range.start, range.end)
+                    .map_err(MemoryError::from_range_error)?;
+
+                0
+            },
+            // long sys_mmap(unsigned long addr, unsigned long len,
+            //     unsigned long prot, unsigned long flags, unsigned long fd,
+            //     unsigned long off)
+            222 => {
+                let addr = emu.read_reg(Reg(10));
+                let len = emu.read_reg(Reg(11));
+                let prot = emu.read_reg(Reg(12));
+                let flags = emu.read_reg(Reg(13));
+                if self.verbose {
+                    println!("mmap({addr:08x}, {len}, {prot:#x}, {flags:#x})");
+                }
+
+                // only fresh anonymous memory, the address is just a hint
+                if flags & MAP_ANONYMOUS == 0 || flags & MAP_FIXED != 0 || len == 0 {
+                    return Ok(errno(EINVAL));
+                }
+                let Some(len) = len.checked_add(PAGE_SIZE - 1) else {
+                    return Ok(errno(ENOMEM));
+                };
+                let len = len & !(PAGE_SIZE - 1);
+
+                // find a page aligned fit and give the slack on either side back
+                let Ok((start, end)) = emu.mem.free.remove_first_fit(len + PAGE_SIZE - 1) else {
+                    return Ok(errno(ENOMEM));
+                };
+                let addr = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+                for (start, end) in [(start, addr), (addr + len, end)] {
+                    if start < end {
+                        emu.mem.free.insert(start, end).map_err(MemoryError::from_range_error)?;
+                    }
+                }
+
+                // like the brk heap the memory starts out uninitialized, so
+                // that allocators built on mmap get their reads checked
+                let mut perms = PERM_NONE;
+                if prot & PROT_READ != 0 {
+                    perms |= PERM_RAW;
+                }
+                if prot & PROT_WRITE != 0 {
+                    perms |= PERM_WRITE;
+                }
+                if prot & PROT_EXEC != 0 {
+                    perms |= PERM_EXEC;
+                }
+                emu.mem.set_permissions(addr..addr + len, perms)?;
+
+                self.allocations.push(Allocation {
+                    kind: AllocationKind::Mmap,
+                    range: addr..addr + len,
+                    pc: Some(emu.pc),
+                    backtrace: emu.call_stack.iter().rev().copied().collect(),
+                });
+
+                addr
+            },
+            // newlib's open, which is openat without the dfd
+            1024 => {
+                let path = emu.read_reg(Reg(10));
+                self.open(emu, path)?
+            },
+            x => {
+                if self.verbose {
+                    let arg0 = emu.read_reg(Reg(10));
+                    let arg1 = emu.read_reg(Reg(11));
+                    let arg2 = emu.read_reg(Reg(12));
+                    let arg3 = emu.read_reg(Reg(13));
+                    let arg4 = emu.read_reg(Reg(14));
+                    let arg5 = emu.read_reg(Reg(15));
+                    println!("Unhandled syscall no: {x} {arg0:08x} {arg1:08x} {arg2:08x} {arg3:08x} {arg4:08x} {arg5:08x}");
+                }
+                return Err(SyscallError::Exit(KernelExit::UnhandledSyscall(x)));
+            },
+        };
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    /// Run `text` with "hello" on stdin
+    fn run(text: &str) -> (Emulator, KernelExit) {
+        let elf = testing::elf(text);
+        let (mut emu, mut kernel) = testing::guest(&elf);
+        kernel.set_stdin(b"hello");
+        let exit = kernel.run(&mut emu);
+        (emu, exit)
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let (emu, exit) = run("
+            _start:
+                addi sp, sp, -1024
+                sw zero, 0(sp)
+                j _start
+        ");
+        let sp = emu.read_reg(RegName::Sp.as_reg());
+        assert!(emu.stack_guard.unwrap().contains(&sp));
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::StackOverflow { sp: s, depth: 0 }) if s == sp));
+
+        // reading into the guard region
+        let (emu, exit) = run(&format!("
+            _start:
+                li t0, {STACK_SIZE:#x}
+                sub a1, sp, t0
+                li a0, 0
+                li a2, 4
+                li a7, 63
+                ecall
+        "));
+        let buf = emu.read_reg(RegName::A1.as_reg());
+        assert!(emu.stack_guard.unwrap().contains(&buf));
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::StackOverflow { .. })));
+    }
+
+    #[test]
+    fn null_dereference() {
+        let (_, exit) = run("
+            _start:
+                lw t0, 8(zero)
+        ");
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::NullDereference { addr: 8 })));
+
+        // read(0, NULL, 4)
+        let (_, exit) = run("
+            _start:
+                li a0, 0
+                li a1, 0
+                li a2, 4
+                li a7, 63
+                ecall
+        ");
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::NullDereference { addr: 0 })));
+
+        // write(1, 0x10, 4)
+        let (_, exit) = run("
+            _start:
+                li a0, 1
+                li a1, 0x10
+                li a2, 4
+                li a7, 64
+                ecall
+        ");
+        assert!(matches!(exit, KernelExit::Emulator(EmulatorExit::NullDereference { addr: 0x10 })));
+    }
+
+    #[test]
+    fn wrapping_accesses() {
+        let out_of_bounds = |exit: &KernelExit, start: u32, end: u32| matches!(exit,
+            KernelExit::Emulator(EmulatorExit::InvalidMemoryAccess(MemoryError::OutOfBounds { addr }))
+                if *addr == (start..end));
+
+        // write(1, 0x10000, 0xffffff00)
+        let (_, exit) = run("
+            _start:
+                li a0, 1
+                li a1, 0x10000
+                li a2, 0xffffff00
+                li a7, 64
+                ecall
+        ");
+        assert!(out_of_bounds(&exit, 0x10000, u32::MAX));
+
+        // read(0, 0xfffffffe, 4)
+        let (_, exit) = run("
+            _start:
+                li a0, 0
+                li a1, 0xfffffffe
+                li a2, 4
+                li a7, 63
+                ecall
+        ");
+        assert!(out_of_bounds(&exit, 0xfffffffe, 2));
+
+        let (_, exit) = run("
+            _start:
+                li t0, -2
+                lw t1, 0(t0)
+        ");
+        assert!(out_of_bounds(&exit, 0xfffffffe, 2));
+    }
+}
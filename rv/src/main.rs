@@ -1,174 +1,590 @@
-#![feature(array_chunks)]
-#![feature(new_uninit)]
-
 mod instructions;
+mod assembler;
 mod disassemble;
+mod cfg;
 mod emulator;
+mod block_cache;
+mod jit;
+mod kernel;
+mod sanitizer;
+mod taint;
+mod fuzz;
+#[cfg(test)]
+mod testing;
+
+use std::io::Write;
 
 // use crate::disassemble::*;
+use crate::assembler::assemble;
 use crate::emulator::*;
-use crate::instructions::*;
+use crate::kernel::*;
+use crate::fuzz::*;
+use crate::sanitizer::Sanitizer;
 use elf::Elf;
 
-fn main() {
-    let elf = Elf::load("../test/test2").unwrap();
+/// Size of guest memory
+const MEMORY_SIZE: u32 = 25*1024*1024;
+
+/// Instructions per fuzz case before it counts as a hang
+const DEFAULT_TIMEOUT: u64 = 10_000_000;
+
+fn usage() -> ! {
+    eprintln!("usage:");
+    eprintln!("  rv [run] [--timeout <n>] [--watch <addr>:<len>] [setup] <elf> [args...]");
+    eprintln!("  rv fuzz <elf> <corpus dir> [options] [-- args...]");
+    eprintln!("  rv minimize <elf> <crashing input> [options] [-- args...]");
+    eprintln!("  rv taint <elf> <input> [options] [-- args...]");
+    eprintln!("  rv disas <elf> [disas options]");
+    eprintln!("  rv cfg <elf> [--function <name>]");
+    eprintln!();
+    eprintln!("setup options:");
+    eprintln!("  --heap-sanitizer    replace malloc and friends with an allocator that");
+    eprintln!("                      catches heap overflows, use after free and double free");
+    eprintln!("  --stack-frames      track stack frames, catching reads of stale stack slots");
+    eprintln!("                      and reporting which frame uninitialized memory is from");
+    eprintln!("  --skip <symbol>     make the function <symbol> return 0 without running it");
+    eprintln!("  --base <addr>       where to load objects and position independent executables");
+    eprintln!("                      (default {:#x})", elf::DEFAULT_BASE);
+    eprintln!("  --jit               compile the guest to x86-64 code instead of interpreting it");
+    eprintln!("  --jit-check         like --jit, but repeat everything the compiled code runs");
+    eprintln!("                      in the interpreter and panic if the results differ");
+    eprintln!();
+    eprintln!("fuzz, minimize and taint options:");
+    eprintln!("  --input <location>  where the input goes, one of:");
+    eprintln!("                        stdin (default)");
+    eprintln!("                        file:<path>");
+    eprintln!("                        mem:<addr>:<max len>[:<len addr>]");
+    eprintln!("  --function <name>   persistent mode: snapshot at the entry of <name> and");
+    eprintln!("                      call it with the input in a0 and its length in a1,");
+    eprintln!("                      instead of placing it with --input");
+    eprintln!("  --timeout <n>       instructions per run before it counts as a hang, and for");
+    eprintln!("                      reaching the --function (default {DEFAULT_TIMEOUT})");
+    eprintln!();
+    eprintln!("fuzz options:");
+    eprintln!("  --dict <file>       AFL style dictionary of tokens");
+    eprintln!("  --max-len <n>       maximum input length (default 4096)");
+    eprintln!("  --seed <n>          seed for the random number generator");
+    eprintln!("  --iterations <n>    stop after running n inputs");
+    eprintln!();
+    eprintln!("minimize options:");
+    eprintln!("  --bucket <bucket>   crash bucket to stay in, as in the crash file name");
+    eprintln!("                      (default: the bucket the input crashes in)");
+    eprintln!("  --output <file>     where to write the result (default: <input>.min)");
+    eprintln!();
+    eprintln!("disas options:");
+    eprintln!("  --function <name>   only disassemble the function <name>");
+    eprintln!("  --start <addr>      start disassembling at <addr>");
+    eprintln!("  --stop <addr>       stop disassembling at <addr>");
+    eprintln!("  --line-numbers      show the source lines from the debug information");
+    eprintln!();
+    eprintln!("cfg writes the control flow graph in Graphviz dot format, of only the");
+    eprintln!("function <name> with --function");
+    std::process::exit(1);
+}
+
+/// Parse a decimal or 0x prefixed hex number
+fn parse_num(num: &str) -> u64 {
+    let res = match num.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => num.parse(),
+    };
+    res.unwrap_or_else(|_| {
+        eprintln!("invalid number: {num}");
+        usage();
+    })
+}
+
+/// Parse an `--input` location
+fn parse_input(location: &str) -> InputLocation {
+    if location == "stdin" {
+        InputLocation::Stdin
+    } else if let Some(path) = location.strip_prefix("file:") {
+        InputLocation::File(path.as_bytes().to_vec())
+    } else if let Some(mem) = location.strip_prefix("mem:") {
+        let parts: Vec<&str> = mem.split(':').collect();
+        let (addr, max_len, len_addr) = match parts[..] {
+            [addr, max_len] => (addr, max_len, None),
+            [addr, max_len, len_addr] => (addr, max_len, Some(len_addr)),
+            _ => usage(),
+        };
+        InputLocation::Memory {
+            addr: parse_num(addr) as u32,
+            max_len: parse_num(max_len) as u32,
+            len_addr: len_addr.map(|addr| parse_num(addr) as u32),
+        }
+    } else {
+        usage();
+    }
+}
+
+/// Optional checks on the guest, and functions to skip
+#[derive(Debug, Default, Clone)]
+struct Setup {
+    /// Install the heap sanitizer
+    heap_sanitizer: bool,
 
-    let mut emu = Emulator::new(25*1024*1024);
+    /// Track stack frames
+    stack_frames: bool,
+
+    /// Functions that return 0 straight away
+    skip: Vec<String>,
+
+    /// Where to load objects and position independent executables
+    base: Option<u32>,
+
+    /// Run the guest with the JIT
+    jit: bool,
+
+    /// Check the JIT against the interpreter
+    jit_check: bool,
+}
+
+impl Setup {
+    /// Enable the check for a command line option, returns false if the
+    /// option is not a check
+    fn parse(&mut self, option: &str) -> bool {
+        match option {
+            "--heap-sanitizer" => self.heap_sanitizer = true,
+            "--stack-frames" => self.stack_frames = true,
+            "--jit" => self.jit = true,
+            "--jit-check" => self.jit_check = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Load an elf and set up the kernel environment for it as `setup` says
+fn load(path: &str, args: &[String], verbose: bool, setup: &Setup) -> (Elf, Emulator, Kernel) {
+    let elf = Elf::load_at(path, setup.base.unwrap_or(elf::DEFAULT_BASE)).unwrap_or_else(|err| {
+        eprintln!("failed to load {path}: {err}");
+        std::process::exit(1);
+    });
+
+    let mut emu = Emulator::new(MEMORY_SIZE);
     emu.load(&elf).unwrap();
 
-    // allocate an initial stack
+    let mut kernel = Kernel::new();
+    kernel.verbose = verbose;
 
-    // TODO: alignment??
-    let stack_size = 1 * 1024 * 1096;
-    let (stack_start, stack_end) = emu.mem.allocate(stack_size, PERM_RAW | PERM_WRITE).unwrap();
+    let mut guest_args = vec![path.as_bytes()];
+    guest_args.extend(args.iter().map(|arg| arg.as_bytes()));
+    kernel.setup(&mut emu, &guest_args).unwrap();
 
-    println!("allocated stack: {:08x}-{:08x}", stack_start, stack_end);
-    let mut sp = stack_end;
+    // patch skipped functions to return straight away, so that compiled
+    // code doesn't have to leave to a hook for them
+    for name in &setup.skip {
+        let Some(symbol) = elf.lookup_by_name(name) else {
+            eprintln!("no symbol {name} in {path}");
+            std::process::exit(1);
+        };
+        let code = assemble(symbol.value, "
+            li a0, 0
+            ret
+        ").unwrap();
+        if symbol.size != 0 && (symbol.size as usize) < code.len() {
+            eprintln!("{name} in {path} is too small to skip");
+            std::process::exit(1);
+        }
+        emu.mem.write(symbol.value, PERM_NONE, &code).unwrap_or_else(|err| {
+            eprintln!("failed to skip {name} in {path}: {err:?}");
+            std::process::exit(1);
+        });
+    }
 
-    // stack layout:
-    // progname\0
-    // aux vector, null terminated
-    // env vector, null terminated
-    // arg vector, null terminated
-    // argc
+    if setup.stack_frames {
+        emu.stack_frames = Some(vec![]);
+    }
 
-    macro_rules! push {
-        ($val:expr) => {{
-            // allocate space
-            sp -= $val.len() as u32;
-            // write data
-            emu.mem.write(sp, PERM_WRITE, &$val[..]).unwrap();
-            println!("{sp:08x}: {:02x?}", $val);
-            sp
-        }}
+    if setup.jit || setup.jit_check {
+        emu.enable_jit(setup.jit_check).unwrap_or_else(|err| {
+            eprintln!("failed to enable the JIT: {err}");
+            std::process::exit(1);
+        });
     }
 
-    let progname = b"test\0";
-    let progname = push!(progname);
+    if setup.heap_sanitizer {
+        let hooked = Sanitizer::install(&mut emu, &elf).unwrap();
+        if hooked.is_empty() {
+            eprintln!("warning: no allocator functions in {path}, the heap sanitizer does nothing");
+        } else if verbose {
+            println!("heap sanitizer hooked: {}", hooked.join(", "));
+        }
+    }
 
-    // aux vector terminator
-    push!(u32::to_le_bytes(0));
-    push!(u32::to_le_bytes(0));
-    // env vector terminator
-    push!(u32::to_le_bytes(0));
-    // argv vector
-    push!(u32::to_le_bytes(0));
-    push!(u32::to_le_bytes(progname));
-    // argc
-    push!(u32::to_le_bytes(1));
-    emu.write_reg(RegName::Sp.as_reg(), sp);
+    (elf, emu, kernel)
+}
 
-    // allocate a heap
+/// Set up a fuzz target, in persistent mode if `function` is given
+///
+/// Every run, and reaching the function in persistent mode, is limited to
+/// `timeout` instructions. The input goes where `input` says, on stdin if it
+/// is `None`.
+fn make_target(path: &str, args: &[String], input: Option<InputLocation>,
+    function: Option<&str>, max_len: usize, timeout: u64, setup: &Setup) -> (Elf, Target)
+{
+    if function.is_some() && input.is_some() {
+        eprintln!("--input can't be used with --function, the function gets the input as arguments");
+        std::process::exit(1);
+    }
 
-    let heap_size = 2 * 1024 * 1024;
-    let (heap_start, heap_end) = emu.mem.allocate(heap_size, PERM_RAW | PERM_WRITE).unwrap();
+    let (elf, emu, kernel) = load(path, args, false, setup);
 
-    println!("allocated heap:  {heap_start:08x}-{heap_end:08x}");
+    let Some(function) = function else {
+        let mut target = Target::new(emu, kernel, input.unwrap_or(InputLocation::Stdin));
+        target.set_timeout(Some(timeout));
+        return (elf, target);
+    };
 
-    // store the current "end of heap" as the program believes it to be
-    let mut current_brk = heap_start;
+    let Some(symbol) = elf.lookup_by_name(function) else {
+        eprintln!("no symbol {function} in {path}");
+        std::process::exit(1);
+    };
 
+    let mut target = Target::persistent(emu, kernel, symbol.value, max_len as u32, timeout)
+        .unwrap_or_else(|exit| {
+            eprintln!("guest never reached {function}: {exit:08x?}");
+            std::process::exit(1);
+        });
+    target.set_timeout(Some(timeout));
+    (elf, target)
+}
 
+fn run_main(mut args: &[String]) {
+    let mut timeout = None;
+    let mut watches = vec![];
+    let mut setup = Setup::default();
     loop {
-        let res = emu.run();
-
-        match res {
-            EmulatorExit::Syscall => {
-                // sycall no is in a7/x17
-                let syscall_no = emu.read_reg(Reg(17));
-                println!("syscall: {syscall_no}");
-                let ret = match syscall_no {
-                    // long sys_close(unsigned int fd);
-                    57 => {
-                        let fd = emu.read_reg(Reg(10));
-                        println!("close({fd})");
-
-                        // return ok
-                        0
-                    },
-                    // long sys_write(unsigned int fd, const char __user *buf, size_t count);
-                    64 => {
-                        let fd = emu.read_reg(Reg(10));
-                        let buf = emu.read_reg(Reg(11));
-                        let count = emu.read_reg(Reg(12));
-
-                        println!("write({fd}, {buf:08x}, {count})");
-
-                        if fd == 1 || fd == 2 {
-                            // stdout or stderr
-                            let bytes = emu.mem.read(buf..buf+count, PERM_READ).unwrap();
-                            let string = String::from_utf8_lossy(bytes);
-                            println!("output: {bytes:x?}");
-                            println!("output: {string}");
-                            0
-                        } else {
-                            !1 // TODO: right return for write ??
-                        }
-                    }
-                    // fstat / newfstat(unsigned int fd, struct stat __user *statbuf)
-                    80 => {
-                        let fd = emu.read_reg(Reg(10));
-                        let buf = emu.read_reg(Reg(11));
-                        println!("fstat({fd}, {buf:08x})");
-                        // size of kernel stat struct is 128 bytes
-                        emu.mem.write(buf, PERM_WRITE, &[0; 128]).unwrap();
-
-                        // just return ok
-                        0
-                    },
-                    // long sys_exit(int error_code);
-                    93 => {
-                        let code = emu.read_reg(Reg(10)) as i32;
-                        println!("exit({code})");
-
-                        return;
-                    },
-                    // brk / long sys_brk(unsigned long brk)
-                    214 => {
-                        // However, the actual Linux system call returns the new program break  on
-                        // success.   On  failure, the system call returns the current break.
-
-                        let new_brk = emu.read_reg(Reg(10));
-                        println!("brk({new_brk:08x})");
-
-                        if new_brk == 0 {
-                        } else if new_brk > heap_end {
-                            // todo do something about oom?
-                        } else {
-                            current_brk = new_brk;
-                        }
-
-                        current_brk
-                    },
-                    x => {
-                        let arg0 = emu.read_reg(Reg(10));
-                        let arg1 = emu.read_reg(Reg(11));
-                        let arg2 = emu.read_reg(Reg(12));
-                        let arg3 = emu.read_reg(Reg(13));
-                        let arg4 = emu.read_reg(Reg(14));
-                        let arg5 = emu.read_reg(Reg(15));
-                        panic!("Unhandled syscall no: {x} {arg0:08x} {arg1:08x} {arg2:08x} {arg3:08x} {arg4:08x} {arg5:08x}");
-                    }
-                };
-
-                println!("ret = {ret}");
-                // set return value
-                emu.write_reg(Reg(10), ret);
-
-                // update pc to next instruction
-                emu.pc = emu.pc + 4;
+        match args {
+            [option, value, rest @ ..] if option == "--timeout" => {
+                timeout = Some(parse_num(value));
+                args = rest;
             },
-            EmulatorExit::Break => {
-                panic!("Unhandled break");
+            [option, value, rest @ ..] if option == "--watch" => {
+                let Some((addr, len)) = value.split_once(':') else { usage() };
+                let addr = parse_num(addr) as u32;
+                let Some(end) = addr.checked_add(parse_num(len) as u32) else { usage() };
+                watches.push(addr..end);
+                args = rest;
             },
-            EmulatorExit::InvalidInstruction(instr) => {
-                let pc = emu.pc;
-                panic!("Invalid instruction: 0x{pc:08x} {instr:#010x}");
+            [option, value, rest @ ..] if option == "--skip" => {
+                setup.skip.push(value.to_string());
+                args = rest;
             },
-            EmulatorExit::InvalidMemoryAccess(err) => {
-                panic!("Invalid memory access: {err:08x?}");
-            }
+            [option, value, rest @ ..] if option == "--base" => {
+                setup.base = Some(parse_num(value) as u32);
+                args = rest;
+            },
+            [option, rest @ ..] if setup.parse(option) => args = rest,
+            _ => break,
+        }
+    }
+
+    let Some(path) = args.first() else { usage() };
+
+    let (elf, mut emu, mut kernel) = load(path, &args[1..], true, &setup);
+    emu.instruction_limit = timeout;
+
+    // stop with a report at the first access to watched memory
+    for range in watches {
+        emu.add_watchpoint(range, PERM_READ | PERM_WRITE);
+    }
+
+    let exit = kernel.run(&mut emu);
+
+    if let Some(crash) = triage::Crash::classify(&exit, &emu, &kernel) {
+        eprint!("{}", crash.report(Some(&elf)));
+        std::process::exit(1);
+    }
+
+    // exit the way the guest did
+    if let KernelExit::Exit(code) = exit {
+        std::process::exit(code);
+    }
+}
+
+fn fuzz_main(args: &[String]) {
+    let (path, corpus_dir) = match args {
+        [path, corpus_dir, ..] => (path, corpus_dir),
+        _ => usage(),
+    };
+
+    let mut input = None;
+    let mut function = None;
+    let mut dict = None;
+    let mut max_len = 4096;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut setup = Setup::default();
+    let mut seed = None;
+    let mut iterations = None;
+    let mut guest_args: &[String] = &[];
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().unwrap_or_else(|| usage()).as_str();
+        match option.as_str() {
+            "--input" => input = Some(parse_input(value())),
+            "--function" => function = Some(value().to_string()),
+            "--dict" => dict = Some(value().to_string()),
+            "--max-len" => max_len = parse_num(value()) as usize,
+            "--timeout" => timeout = parse_num(value()),
+            "--seed" => seed = Some(parse_num(value())),
+            "--iterations" => iterations = Some(parse_num(value())),
+            "--" => {
+                guest_args = options.as_slice();
+                break;
+            },
+            "--skip" => setup.skip.push(value().to_string()),
+            "--base" => setup.base = Some(parse_num(value()) as u32),
+            option if setup.parse(option) => (),
+            _ => usage(),
+        }
+    }
+
+    let (_, target) = make_target(path, guest_args, input, function.as_deref(), max_len, timeout, &setup);
+
+    let corpus = corpus::Corpus::open(corpus_dir).unwrap_or_else(|err| {
+        eprintln!("failed to open corpus {corpus_dir}: {err}");
+        std::process::exit(1);
+    });
+
+    let rng = match seed {
+        Some(seed) => mutator::Rng::new(seed),
+        None => mutator::Rng::from_time(),
+    };
+    let mut mutator = mutator::Mutator::new(rng, max_len);
+    if let Some(dict) = dict {
+        mutator.load_dictionary(&dict).unwrap_or_else(|err| {
+            eprintln!("failed to load dictionary {dict}: {err}");
+            std::process::exit(1);
+        });
+    }
+
+    let mut fuzzer = Fuzzer::new(target, corpus, mutator);
+    fuzzer.fuzz(iterations).unwrap();
+}
+
+fn minimize_main(args: &[String]) {
+    let (path, crash_path) = match args {
+        [path, crash_path, ..] => (path, crash_path),
+        _ => usage(),
+    };
+
+    let mut input = None;
+    let mut function = None;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut setup = Setup::default();
+    let mut bucket = None;
+    let mut output = format!("{crash_path}.min");
+    let mut guest_args: &[String] = &[];
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().unwrap_or_else(|| usage()).as_str();
+        match option.as_str() {
+            "--input" => input = Some(parse_input(value())),
+            "--function" => function = Some(value().to_string()),
+            "--timeout" => timeout = parse_num(value()),
+            "--bucket" => {
+                let name = value();
+                bucket = Some(name.parse::<triage::Bucket>().unwrap_or_else(|_| {
+                    eprintln!("invalid bucket: {name}");
+                    usage();
+                }));
+            },
+            "--output" => output = value().to_string(),
+            "--" => {
+                guest_args = options.as_slice();
+                break;
+            },
+            "--skip" => setup.skip.push(value().to_string()),
+            "--base" => setup.base = Some(parse_num(value()) as u32),
+            option if setup.parse(option) => (),
+            _ => usage(),
+        }
+    }
+
+    let crash = std::fs::read(crash_path).unwrap_or_else(|err| {
+        eprintln!("failed to read {crash_path}: {err}");
+        std::process::exit(1);
+    });
+
+    let (_, mut target) = make_target(path, guest_args, input, function.as_deref(), crash.len(), timeout, &setup);
+
+    let exit = target.run(&crash);
+    let Some(first) = triage::Crash::classify(&exit, &target.emu, &target.kernel) else {
+        eprintln!("{crash_path} does not crash");
+        std::process::exit(1);
+    };
+    let bucket = bucket.unwrap_or_else(|| first.bucket());
+    if first.bucket() != bucket {
+        eprintln!("{crash_path} crashes in {}, not {bucket}", first.bucket());
+        std::process::exit(1);
+    }
+
+    let mut minimizer = minimize::Minimizer::new(&mut target, bucket);
+    let minimized = minimizer.minimize(&crash);
+
+    println!("minimized {} bytes to {} bytes in {} execs, bucket {bucket}",
+        crash.len(), minimized.len(), minimizer.execs);
+
+    std::fs::write(&output, &minimized).unwrap_or_else(|err| {
+        eprintln!("failed to write {output}: {err}");
+        std::process::exit(1);
+    });
+}
+
+fn taint_main(args: &[String]) {
+    let (path, input_path) = match args {
+        [path, input_path, ..] => (path, input_path),
+        _ => usage(),
+    };
+
+    let mut input = None;
+    let mut function = None;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut setup = Setup::default();
+    let mut guest_args: &[String] = &[];
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().unwrap_or_else(|| usage()).as_str();
+        match option.as_str() {
+            "--input" => input = Some(parse_input(value())),
+            "--function" => function = Some(value().to_string()),
+            "--timeout" => timeout = parse_num(value()),
+            "--" => {
+                guest_args = options.as_slice();
+                break;
+            },
+            "--skip" => setup.skip.push(value().to_string()),
+            "--base" => setup.base = Some(parse_num(value()) as u32),
+            option if setup.parse(option) => (),
+            _ => usage(),
+        }
+    }
+
+    let data = std::fs::read(input_path).unwrap_or_else(|err| {
+        eprintln!("failed to read {input_path}: {err}");
+        std::process::exit(1);
+    });
+
+    let (elf, mut target) = make_target(path, guest_args, input, function.as_deref(), data.len(), timeout, &setup);
+    target.enable_taint();
+
+    let exit = target.run(&data);
+    println!("exit: {exit:?}");
+
+    // where the input influenced control flow and addresses
+    let taint = target.emu.taint.as_ref().unwrap();
+    for (pc, kind, offsets) in taint.sinks() {
+        println!("{pc:#010x} {:<13} {}", kind.name(), taint::format_offsets(&offsets));
+    }
+
+    if let Some(crash) = triage::Crash::classify(&exit, &target.emu, &target.kernel) {
+        println!();
+        print!("{}", crash.report(Some(&elf)));
+    }
+}
+
+fn disas_main(args: &[String]) {
+    let Some(path) = args.first() else { usage() };
+
+    let mut function = None;
+    let mut start = 0;
+    let mut stop = u32::MAX;
+    let mut line_numbers = false;
+
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().unwrap_or_else(|| usage()).as_str();
+        match option.as_str() {
+            "--function" => function = Some(value().to_string()),
+            "--start" => start = parse_num(value()) as u32,
+            "--stop" => stop = parse_num(value()) as u32,
+            "--line-numbers" => line_numbers = true,
+            _ => usage(),
+        }
+    }
+
+    let elf = Elf::load(path).unwrap_or_else(|err| {
+        eprintln!("failed to load {path}: {err}");
+        std::process::exit(1);
+    });
+
+    if let Some(function) = function {
+        let Some(symbol) = elf.lookup_by_name(&function) else {
+            eprintln!("no symbol {function} in {path}");
+            std::process::exit(1);
         };
+
+        // without a size the function runs up to the next symbol
+        let end = match symbol.size {
+            0 => elf.symbols().iter().filter(|sym| sym.is_address()).map(|sym| sym.value)
+                .filter(|&value| value > symbol.value)
+                .min().unwrap_or(u32::MAX),
+            size => symbol.value.saturating_add(size),
+        };
+        start = start.max(symbol.value);
+        stop = stop.min(end);
     }
 
+    // stop quietly when the output is closed, as when piped to `head`
+    let mut out = std::io::stdout().lock();
+    let result = writeln!(out, "{path}:     file format elf32-littleriscv")
+        .and_then(|()| disassemble::listing(&elf, start..stop, line_numbers, &mut out));
+    match result {
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+            eprintln!("failed to write the listing: {err}");
+            std::process::exit(1);
+        },
+        _ => (),
+    }
+}
+
+fn cfg_main(args: &[String]) {
+    let (path, function) = match args {
+        [path] => (path, None),
+        [path, option, name] if option == "--function" => (path, Some(name)),
+        _ => usage(),
+    };
+
+    let elf = Elf::load(path).unwrap_or_else(|err| {
+        eprintln!("failed to load {path}: {err}");
+        std::process::exit(1);
+    });
+
+    let mut graph = cfg::Cfg::from_elf(&elf);
+    if let Some(function) = function {
+        graph.functions.retain(|_, func| func.name == *function);
+        if graph.functions.is_empty() {
+            eprintln!("no function {function} in {path}");
+            std::process::exit(1);
+        }
+    }
+
+    // stop quietly when the output is closed, like disas
+    let mut out = std::io::stdout().lock();
+    match graph.dot(&cfg::Code::from_elf(&elf), &mut out) {
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+            eprintln!("failed to write the graph: {err}");
+            std::process::exit(1);
+        },
+        _ => (),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("fuzz") => fuzz_main(&args[2..]),
+        Some("minimize") => minimize_main(&args[2..]),
+        Some("taint") => taint_main(&args[2..]),
+        Some("disas") => disas_main(&args[2..]),
+        Some("cfg") => cfg_main(&args[2..]),
+        Some("run") => run_main(&args[2..]),
+        Some(_) => run_main(&args[1..]),
+        None => run_main(&["../test/test2".to_string()]),
+    }
 }
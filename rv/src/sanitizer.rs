@@ -0,0 +1,525 @@
+//! Heap sanitizer
+//!
+//! Replaces the guest's `malloc`, `calloc`, `realloc` and `free` (and the
+//! newlib reentrant `_r` variants) with host side implementations. Every chunk
+//! is surrounded by redzones without any permissions, and freed chunks lose
+//! their permissions and sit in a quarantine before their memory is reused.
+//! Heap overflows and use after free then fault on the first bad access, and
+//! double and invalid frees are caught in `free`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+
+use elf::Elf;
+use rangeset::RangeSet;
+
+use crate::emulator::*;
+use crate::instructions::*;
+
+/// Size of the region all sanitized chunks are allocated from
+pub const ARENA_SIZE: u32 = 8 * 1024 * 1024;
+
+/// Minimum number of inaccessible bytes on either side of a chunk
+pub const REDZONE: u32 = 32;
+
+/// Alignment of the chunks handed to the guest
+pub const ALIGN: u32 = 16;
+
+/// Number of freed bytes kept inaccessible before their memory is reused
+pub const QUARANTINE_SIZE: u32 = 1024 * 1024;
+
+/// An allocator function we replace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+}
+
+/// Symbols that are hooked, and whether they take a newlib `struct _reent *`
+/// as their first argument
+const HOOKS: [(&str, Function, bool); 8] = [
+    ("malloc", Function::Malloc, false),
+    ("calloc", Function::Calloc, false),
+    ("realloc", Function::Realloc, false),
+    ("free", Function::Free, false),
+    ("_malloc_r", Function::Malloc, true),
+    ("_calloc_r", Function::Calloc, true),
+    ("_realloc_r", Function::Realloc, true),
+    ("_free_r", Function::Free, true),
+];
+
+/// A chunk handed out to the guest
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Address of the chunk as seen by the guest
+    pub addr: u32,
+
+    /// Size the guest asked for
+    pub size: u32,
+
+    /// Memory reserved for the chunk, including its redzones
+    span: Range<u32>,
+
+    /// Return addresses of the calls that allocated the chunk, innermost first
+    pub allocated: Vec<u32>,
+
+    /// Return addresses of the calls that freed the chunk, innermost first,
+    /// if it is freed
+    pub freed: Option<Vec<u32>>,
+}
+
+/// Misuse of the allocator caught when it happens
+#[derive(Debug, Clone)]
+pub enum SanitizerError {
+    /// `free` or `realloc` of a chunk that is already freed
+    DoubleFree { addr: u32 },
+
+    /// `free` or `realloc` of a pointer that did not come from the allocator
+    InvalidFree { addr: u32 },
+}
+
+/// Why a replaced function didn't return to the guest
+#[derive(Debug)]
+enum CallError {
+    /// The guest misused the allocator
+    Misuse(SanitizerError),
+
+    /// Accessing guest memory faulted
+    Fault(MemoryError),
+}
+
+impl From<SanitizerError> for CallError {
+    fn from(err: SanitizerError) -> Self {
+        CallError::Misuse(err)
+    }
+}
+
+impl From<MemoryError> for CallError {
+    fn from(err: MemoryError) -> Self {
+        CallError::Fault(err)
+    }
+}
+
+/// Where a bad access landed relative to the closest chunk
+#[derive(Debug)]
+pub enum HeapAccess<'a> {
+    /// In the redzone before or after a live chunk, or past its end
+    Overflow(&'a Chunk),
+
+    /// Inside a freed chunk
+    UseAfterFree(&'a Chunk),
+}
+
+/// State of the heap sanitizer
+///
+/// Lives in [`Emulator::sanitizer`], so that it is reset along with the guest
+/// memory, and pc hooks on the allocator functions run it.
+///
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    /// Memory the chunks are allocated from
+    pub arena: Range<u32>,
+
+    /// Parts of the arena that are not reserved by any chunk
+    free: RangeSet,
+
+    /// Live and quarantined chunks, by address
+    chunks: BTreeMap<u32, Chunk>,
+
+    /// Freed chunks in the order they were freed
+    quarantine: VecDeque<u32>,
+
+    /// Total size of the chunks in quarantine
+    quarantine_size: u32,
+}
+
+fn align_up(val: u32, align: u32) -> u32 {
+    (val + align - 1) & !(align - 1)
+}
+
+impl Sanitizer {
+    /// Reserve the arena, put the sanitizer in [`Emulator::sanitizer`] and
+    /// hook the allocator functions found in the symbols of `elf`
+    ///
+    /// Returns the names of the hooked functions, if there are none the guest
+    /// keeps its own allocator.
+    pub fn install(emu: &mut Emulator, elf: &Elf) -> Result<Vec<&'static str>, MemoryError> {
+        let (start, _) = emu.mem.allocate(ARENA_SIZE + ALIGN, PERM_NONE)?;
+        let start = align_up(start, ALIGN);
+        let arena = start..start + ARENA_SIZE;
+
+        emu.sanitizer = Some(Sanitizer {
+            free: RangeSet::new(arena.start, arena.end),
+            arena,
+            chunks: BTreeMap::new(),
+            quarantine: VecDeque::new(),
+            quarantine_size: 0,
+        });
+
+        let mut hooked = vec![];
+        for (name, function, reentrant) in HOOKS {
+            let found = emu.hook_symbol(elf, name, move |emu| {
+                // without a sanitizer the guest's own function runs
+                let Some(mut sanitizer) = emu.sanitizer.take() else {
+                    return PcHookResult::Continue;
+                };
+                let result = sanitizer.call(emu, function, reentrant);
+                emu.sanitizer = Some(sanitizer);
+
+                match result {
+                    Ok(()) => PcHookResult::Return,
+                    Err(exit) => PcHookResult::Stop(exit),
+                }
+            });
+            if found {
+                hooked.push(name);
+            }
+        }
+
+        Ok(hooked)
+    }
+
+    /// Run `function` in place of the guest's and set its return value, the
+    /// hook then returns to the caller
+    fn call(&mut self, emu: &mut Emulator, function: Function, reentrant: bool) -> Result<(), EmulatorExit> {
+        let ret = match self.dispatch(emu, function, reentrant) {
+            Ok(ret) => ret,
+            Err(CallError::Misuse(err)) => return Err(EmulatorExit::Sanitizer(err)),
+            Err(CallError::Fault(err)) => return Err(emu.memory_fault(err)),
+        };
+
+        emu.set_return_value(ret);
+
+        Ok(())
+    }
+
+    /// Run `function` with the arguments the guest passed, returning its
+    /// return value
+    fn dispatch(&mut self, emu: &mut Emulator, function: Function, reentrant: bool) -> Result<u32, CallError> {
+        let arg = |n: u8| emu.read_reg(Reg(10 + n + reentrant as u8));
+        let (arg0, arg1) = (arg(0), arg(1));
+
+        let backtrace: Vec<u32> = emu.call_stack.iter().rev().copied().collect();
+
+        Ok(match function {
+            Function::Malloc => self.malloc(emu, arg0, PERM_RAW | PERM_WRITE, backtrace)?,
+            Function::Calloc => match arg0.checked_mul(arg1) {
+                Some(size) => {
+                    let addr = self.malloc(emu, size, PERM_READ | PERM_WRITE, backtrace)?;
+                    if addr != 0 {
+                        emu.mem.write(addr, PERM_NONE, &vec![0; size as usize])?;
+                    }
+                    addr
+                },
+                None => 0,
+            },
+            Function::Realloc => self.realloc(emu, arg0, arg1, backtrace)?,
+            Function::Free => {
+                self.free(emu, arg0, backtrace)?;
+                0
+            },
+        })
+    }
+
+    /// Allocate a chunk of `size` bytes with permissions `perms`, returns 0 if
+    /// the arena is full
+    fn malloc(&mut self, emu: &mut Emulator, size: u32, perms: u8, allocated: Vec<u32>)
+        -> Result<u32, MemoryError>
+    {
+        let Some(span_size) = size.checked_add(ALIGN - 1 + 2 * REDZONE) else {
+            return Ok(0);
+        };
+        let span_size = span_size & !(ALIGN - 1);
+
+        let Ok((start, end)) = self.free.remove_first_fit(span_size) else {
+            return Ok(0);
+        };
+
+        let addr = start + REDZONE;
+        emu.mem.set_permissions(addr..addr + size, perms)?;
+        if let Some(taint) = &mut emu.taint {
+            taint.clear(addr..addr + size);
+        }
+
+        self.chunks.insert(addr, Chunk {
+            addr,
+            size,
+            span: start..end,
+            allocated,
+            freed: None,
+        });
+
+        Ok(addr)
+    }
+
+    /// Look up the live chunk at `addr`, for `free` and `realloc`
+    fn live_chunk(&mut self, addr: u32) -> Result<&mut Chunk, SanitizerError> {
+        match self.chunks.get_mut(&addr) {
+            Some(chunk) if chunk.freed.is_none() => Ok(chunk),
+            Some(_) => Err(SanitizerError::DoubleFree { addr }),
+            None => Err(SanitizerError::InvalidFree { addr }),
+        }
+    }
+
+    /// Free the chunk at `addr` and put it in quarantine
+    fn free(&mut self, emu: &mut Emulator, addr: u32, freed: Vec<u32>) -> Result<(), CallError> {
+        if addr == 0 {
+            return Ok(());
+        }
+
+        let chunk = self.live_chunk(addr)?;
+        chunk.freed = Some(freed);
+        let size = chunk.size;
+
+        emu.mem.set_permissions(addr..addr + size, PERM_NONE)?;
+
+        self.quarantine.push_back(addr);
+        self.quarantine_size += size;
+
+        // reuse the oldest freed chunks once the quarantine is full
+        while self.quarantine_size > QUARANTINE_SIZE {
+            let Some(addr) = self.quarantine.pop_front() else { break };
+            let chunk = self.chunks.remove(&addr).unwrap();
+            self.quarantine_size -= chunk.size;
+            self.free.insert(chunk.span.start, chunk.span.end).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Move the chunk at `addr` to a new chunk of `size` bytes
+    ///
+    /// Always moves, so that stale pointers to the old chunk are caught.
+    fn realloc(&mut self, emu: &mut Emulator, addr: u32, size: u32, backtrace: Vec<u32>)
+        -> Result<u32, CallError>
+    {
+        if addr == 0 {
+            return Ok(self.malloc(emu, size, PERM_RAW | PERM_WRITE, backtrace)?);
+        }
+
+        let old_size = self.live_chunk(addr)?.size;
+
+        if size == 0 {
+            self.free(emu, addr, backtrace)?;
+            return Ok(0);
+        }
+
+        let new = self.malloc(emu, size, PERM_RAW | PERM_WRITE, backtrace.clone())?;
+        if new == 0 {
+            return Ok(0);
+        }
+
+        // copy the contents along with the permissions, so that bytes that
+        // were never written stay uninitialized, and the taint
+        let copied = addr..addr + old_size.min(size);
+        emu.mem.copy_within(copied.clone(), new)?;
+        if let Some(taint) = &mut emu.taint {
+            taint.copy(copied, new);
+        }
+
+        self.free(emu, addr, backtrace)?;
+
+        Ok(new)
+    }
+
+    /// Find the live or quarantined chunk whose span, including redzones,
+    /// contains `addr`
+    pub fn find(&self, addr: u32) -> Option<&Chunk> {
+        if !self.arena.contains(&addr) {
+            return None;
+        }
+
+        // chunks don't overlap, so the only chunk that can contain `addr` in
+        // its span is the last one starting before `addr + REDZONE`
+        let (_, chunk) = self.chunks.range(..=addr + REDZONE).next_back()?;
+        chunk.span.contains(&addr).then_some(chunk)
+    }
+
+    /// Find the chunk a bad access to `addr` is about
+    pub fn describe(&self, addr: u32) -> Option<HeapAccess<'_>> {
+        let chunk = self.find(addr)?;
+
+        match chunk.freed {
+            Some(_) => Some(HeapAccess::UseAfterFree(chunk)),
+            None => Some(HeapAccess::Overflow(chunk)),
+        }
+    }
+
+    /// The chunk at exactly `addr`, live or in quarantine
+    pub fn chunk(&self, addr: u32) -> Option<&Chunk> {
+        self.chunks.get(&addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzz::triage::{Crash, CrashKind};
+    use crate::kernel::{Kernel, KernelExit};
+    use crate::taint::{SinkKind, Taint};
+    use crate::testing;
+
+    /// Set up `body` with the heap sanitizer replacing the allocator
+    /// functions, exiting with 0 if it gets to the end
+    fn setup(body: &str) -> (Elf, Emulator, Kernel) {
+        let elf = testing::elf(&format!("
+            _start:
+                {body}
+                li a0, 0
+                li a7, 93
+                ecall
+            malloc:
+                .word 0
+            realloc:
+                .word 0
+            free:
+                .word 0
+        "));
+        let (mut emu, kernel) = testing::guest(&elf);
+
+        let hooked = Sanitizer::install(&mut emu, &elf).unwrap();
+        assert_eq!(hooked, ["malloc", "realloc", "free"]);
+
+        (elf, emu, kernel)
+    }
+
+    fn run(body: &str) -> (Emulator, Kernel, KernelExit) {
+        let (_, mut emu, mut kernel) = setup(body);
+        let exit = kernel.run(&mut emu);
+        (emu, kernel, exit)
+    }
+
+    fn crash(body: &str) -> Crash {
+        let (emu, kernel, exit) = run(body);
+        Crash::classify(&exit, &emu, &kernel).unwrap()
+    }
+
+    #[test]
+    fn heap_overflow() {
+        let (emu, kernel, exit) = run("
+            li a0, 10
+            call malloc
+            sb zero, 9(a0)
+            sb zero, 10(a0)
+        ");
+        let chunk = emu.read_reg(RegName::A0.as_reg());
+        let crash = Crash::classify(&exit, &emu, &kernel).unwrap();
+        assert_eq!(crash.kind, CrashKind::HeapOverflow);
+        assert_eq!(crash.addr, Some(chunk + 10..chunk + 11));
+        assert!(crash.detail.ends_with(&format!("0 bytes after the 10 byte chunk at {chunk:#010x}")));
+    }
+
+    #[test]
+    fn use_after_free() {
+        let crash = crash("
+            li a0, 16
+            call malloc
+            mv s0, a0
+            call free
+            li a0, 16
+            call malloc
+            mv s1, a0
+            lw t0, 0(s0)
+        ");
+        assert_eq!(crash.kind, CrashKind::UseAfterFree);
+        assert!(crash.notes.contains("freed by:"));
+
+        // freed memory isn't handed out again while it is in quarantine
+        let (emu, _, _) = run("
+            li a0, 16
+            call malloc
+            mv s0, a0
+            call free
+            li a0, 16
+            call malloc
+            mv s1, a0
+        ");
+        assert_ne!(emu.read_reg(RegName::S0.as_reg()), emu.read_reg(RegName::S1.as_reg()));
+    }
+
+    #[test]
+    fn double_free() {
+        let (emu, kernel, exit) = run("
+            li a0, 16
+            call malloc
+            mv s0, a0
+            call free
+            mv a0, s0
+            call free
+        ");
+        let addr = emu.read_reg(RegName::S0.as_reg());
+        assert!(matches!(exit,
+            KernelExit::Emulator(EmulatorExit::Sanitizer(SanitizerError::DoubleFree { addr: a })) if a == addr));
+        assert_eq!(Crash::classify(&exit, &emu, &kernel).unwrap().kind, CrashKind::DoubleFree);
+
+        let (_, _, exit) = run("
+            li a0, 0x1234
+            call free
+        ");
+        assert!(matches!(exit,
+            KernelExit::Emulator(EmulatorExit::Sanitizer(SanitizerError::InvalidFree { addr: 0x1234 }))));
+    }
+
+    #[test]
+    fn realloc() {
+        // the written word is copied, the never written one stays
+        // uninitialized
+        let (emu, kernel, exit) = run("
+            li a0, 8
+            call malloc
+            mv s0, a0
+            li t0, 0x12345678
+            sw t0, 0(a0)
+            li a1, 64
+            call realloc
+            lw s1, 0(a0)
+            lw s2, 4(a0)
+        ");
+        let (old, new) = (emu.read_reg(RegName::S0.as_reg()), emu.read_reg(RegName::A0.as_reg()));
+        assert_ne!(old, new);
+        assert_eq!(emu.read_reg(RegName::S1.as_reg()), 0x12345678);
+
+        let crash = Crash::classify(&exit, &emu, &kernel).unwrap();
+        assert_eq!(crash.kind, CrashKind::UninitRead);
+        assert_eq!(crash.addr, Some(new + 4..new + 8));
+
+        // and the old chunk is freed
+        assert!(emu.sanitizer.unwrap().chunk(old).unwrap().freed.is_some());
+    }
+
+    #[test]
+    fn taint() {
+        let (elf, mut emu, mut kernel) = setup("
+                lbu a0, -16(sp)
+                call malloc
+                mv s0, a0
+            check:
+                beqz s0, check
+                lbu t0, -15(sp)
+                sb t0, 3(s0)
+                mv a0, s0
+                li a1, 64
+                call realloc
+                mv s1, a0
+        ");
+        let sp = emu.read_reg(RegName::Sp.as_reg());
+        emu.mem.write(sp - 16, PERM_NONE, &[8, 0x42]).unwrap();
+        let mut taint = Taint::new();
+        taint.taint_input(sp - 16, 0..2);
+        emu.taint = Some(taint);
+
+        assert!(matches!(kernel.run(&mut emu), KernelExit::Exit(0)));
+        let new = emu.read_reg(RegName::S1.as_reg());
+        let taint = emu.taint.as_mut().unwrap();
+
+        // the size is tainted, the pointer malloc returns isn't
+        let check = elf.lookup_by_name("check").unwrap().value;
+        assert!(taint.sink_offsets(check, SinkKind::Branch).is_empty());
+
+        // realloc copies the taint along with the contents
+        let label = taint.mem(new + 3..new + 4);
+        assert_eq!(taint.offsets(label), [1]);
+    }
+}
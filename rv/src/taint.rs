@@ -0,0 +1,348 @@
+//! Byte-level taint tracking
+//!
+//! Every byte of guest memory and every register has a label saying which
+//! input offsets its value was computed from. Labels are propagated by
+//! [`Taint::step`] for every instruction the emulator runs, and the labels
+//! that reach branches, load and store addresses and indirect jumps are
+//! recorded as sinks.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+use crate::instructions::*;
+
+/// Set of input offsets, 0 is the empty set
+pub type Label = u32;
+
+/// How a label was made
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    /// A single input offset
+    Offset(u32),
+
+    /// The union of two labels
+    Union(Label, Label),
+}
+
+/// Where a tainted value was used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SinkKind {
+    /// Operands of a conditional branch
+    Branch,
+
+    /// Address of a load
+    LoadAddress,
+
+    /// Address of a store
+    StoreAddress,
+
+    /// Target of a `jalr`
+    IndirectJump,
+}
+
+impl SinkKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SinkKind::Branch => "branch",
+            SinkKind::LoadAddress => "load address",
+            SinkKind::StoreAddress => "store address",
+            SinkKind::IndirectJump => "indirect jump",
+        }
+    }
+}
+
+/// Shadow state for taint tracking
+#[derive(Debug, Clone, Default)]
+pub struct Taint {
+    /// Label `n` is made as `nodes[n - 1]` says
+    nodes: Vec<Node>,
+
+    /// Labels of single offsets, so that they are only made once
+    offset_labels: HashMap<u32, Label>,
+
+    /// Labels of unions, so that they are only made once
+    union_labels: HashMap<(Label, Label), Label>,
+
+    /// Label of every register, x0 is always untainted
+    regs: [Label; 32],
+
+    /// Label of every tainted byte of memory
+    mem: HashMap<u32, Label>,
+
+    /// Union of the labels that reached each sink, by pc
+    sinks: BTreeMap<(u32, SinkKind), Label>,
+}
+
+impl Taint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn make(&mut self, node: Node) -> Label {
+        self.nodes.push(node);
+        self.nodes.len() as Label
+    }
+
+    /// The label of input offset `offset`
+    pub fn offset_label(&mut self, offset: u32) -> Label {
+        if let Some(&label) = self.offset_labels.get(&offset) {
+            return label;
+        }
+        let label = self.make(Node::Offset(offset));
+        self.offset_labels.insert(offset, label);
+        label
+    }
+
+    /// The union of two labels
+    pub fn union(&mut self, a: Label, b: Label) -> Label {
+        if a == 0 || a == b {
+            return b;
+        }
+        if b == 0 {
+            return a;
+        }
+
+        let key = (a.min(b), a.max(b));
+        if let Some(&label) = self.union_labels.get(&key) {
+            return label;
+        }
+        let label = self.make(Node::Union(key.0, key.1));
+        self.union_labels.insert(key, label);
+        label
+    }
+
+    /// The input offsets in `label`, sorted
+    pub fn offsets(&self, label: Label) -> Vec<u32> {
+        let mut offsets = vec![];
+        let mut seen = HashSet::new();
+        let mut todo = vec![label];
+
+        while let Some(label) = todo.pop() {
+            if label == 0 || !seen.insert(label) {
+                continue;
+            }
+            match self.nodes[label as usize - 1] {
+                Node::Offset(offset) => offsets.push(offset),
+                Node::Union(a, b) => todo.extend([a, b]),
+            }
+        }
+
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// Mark the bytes at `addr` as coming from the input at `offsets`
+    pub fn taint_input(&mut self, addr: u32, offsets: Range<u32>) {
+        for (ii, offset) in offsets.enumerate() {
+            let label = self.offset_label(offset);
+            self.mem.insert(addr + ii as u32, label);
+        }
+    }
+
+    /// Mark the bytes in `range` as untainted
+    pub fn clear(&mut self, range: Range<u32>) {
+        if self.mem.is_empty() {
+            return;
+        }
+        if range.len() > self.mem.len() {
+            self.mem.retain(|addr, _| !range.contains(addr));
+            return;
+        }
+        for addr in range {
+            self.mem.remove(&addr);
+        }
+    }
+
+    /// Copy the labels of the bytes in `src` to the bytes at `dest`, for
+    /// memory copied on behalf of the guest
+    pub fn copy(&mut self, src: Range<u32>, dest: u32) {
+        let labels: Vec<Label> = src.map(|addr| self.mem.get(&addr).copied().unwrap_or(0)).collect();
+        for (ii, label) in labels.into_iter().enumerate() {
+            self.set_mem(dest + ii as u32..dest + ii as u32 + 1, label);
+        }
+    }
+
+    pub fn reg(&self, reg: Reg) -> Label {
+        self.regs[reg.0 as usize]
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, label: Label) {
+        if reg.0 != 0 {
+            self.regs[reg.0 as usize] = label;
+        }
+    }
+
+    /// The union of the labels of the bytes in `range`
+    pub fn mem(&mut self, range: Range<u32>) -> Label {
+        let mut label = 0;
+        for addr in range {
+            let byte = self.mem.get(&addr).copied().unwrap_or(0);
+            label = self.union(label, byte);
+        }
+        label
+    }
+
+    fn set_mem(&mut self, range: Range<u32>, label: Label) {
+        if label == 0 {
+            return self.clear(range);
+        }
+        for addr in range {
+            self.mem.insert(addr, label);
+        }
+    }
+
+    fn sink(&mut self, pc: u32, kind: SinkKind, label: Label) {
+        if label == 0 {
+            return;
+        }
+        let old = self.sinks.get(&(pc, kind)).copied().unwrap_or(0);
+        let new = self.union(old, label);
+        self.sinks.insert((pc, kind), new);
+    }
+
+    /// The tainted sinks reached so far, by pc, with the input offsets that
+    /// reached them
+    pub fn sinks(&self) -> impl Iterator<Item = (u32, SinkKind, Vec<u32>)> + '_ {
+        self.sinks.iter().map(|(&(pc, kind), &label)| (pc, kind, self.offsets(label)))
+    }
+
+    /// The input offsets that reached sink `kind` at `pc`
+    pub fn sink_offsets(&self, pc: u32, kind: SinkKind) -> Vec<u32> {
+        self.offsets(self.sinks.get(&(pc, kind)).copied().unwrap_or(0))
+    }
+
+    /// Propagate taint for the instruction `op` at `pc`, before it runs with
+    /// registers `regs` (x1 to x31)
+    pub fn step(&mut self, regs: &[u32; 31], pc: u32, op: Instruction) {
+        let read_reg = |reg: Reg| if reg.0 == 0 { 0 } else { regs[reg.0 as usize - 1] };
+
+        match op {
+            Instruction::Lui { rd, .. } | Instruction::Auipc { rd, .. } | Instruction::Jal { rd, .. } => {
+                self.set_reg(rd, 0);
+            },
+            Instruction::Jalr { rd, rs1, .. } => {
+                self.sink(pc, SinkKind::IndirectJump, self.reg(rs1));
+                self.set_reg(rd, 0);
+            },
+            Instruction::Beq { rs1, rs2, .. } | Instruction::Bne { rs1, rs2, .. } |
+            Instruction::Blt { rs1, rs2, .. } | Instruction::Bge { rs1, rs2, .. } |
+            Instruction::Bltu { rs1, rs2, .. } | Instruction::Bgeu { rs1, rs2, .. } => {
+                let label = self.union(self.reg(rs1), self.reg(rs2));
+                self.sink(pc, SinkKind::Branch, label);
+            },
+            Instruction::Lb { rd, rs1, imm } | Instruction::Lh { rd, rs1, imm } | Instruction::Lw { rd, rs1, imm } |
+            Instruction::Lbu { rd, rs1, imm } | Instruction::Lhu { rd, rs1, imm } => {
+                let addr = read_reg(rs1).wrapping_add(imm);
+                let size = match op {
+                    Instruction::Lb { .. } | Instruction::Lbu { .. } => 1,
+                    Instruction::Lh { .. } | Instruction::Lhu { .. } => 2,
+                    _ => 4,
+                };
+
+                self.sink(pc, SinkKind::LoadAddress, self.reg(rs1));
+                let label = self.mem(addr..addr.wrapping_add(size));
+                self.set_reg(rd, label);
+            },
+            Instruction::Sb { rs1, rs2, imm } | Instruction::Sh { rs1, rs2, imm } | Instruction::Sw { rs1, rs2, imm } => {
+                let addr = read_reg(rs1).wrapping_add(imm);
+                let size = match op {
+                    Instruction::Sb { .. } => 1,
+                    Instruction::Sh { .. } => 2,
+                    _ => 4,
+                };
+
+                self.sink(pc, SinkKind::StoreAddress, self.reg(rs1));
+                self.set_mem(addr..addr.wrapping_add(size), self.reg(rs2));
+            },
+            Instruction::Addi { rd, rs1, .. } | Instruction::Slti { rd, rs1, .. } |
+            Instruction::Sltiu { rd, rs1, .. } | Instruction::Xori { rd, rs1, .. } |
+            Instruction::Ori { rd, rs1, .. } | Instruction::Andi { rd, rs1, .. } |
+            Instruction::Slli { rd, rs1, .. } | Instruction::Srli { rd, rs1, .. } |
+            Instruction::Srai { rd, rs1, .. } => {
+                self.set_reg(rd, self.reg(rs1));
+            },
+            Instruction::Add { rd, rs1, rs2 } | Instruction::Sub { rd, rs1, rs2 } |
+            Instruction::Sll { rd, rs1, rs2 } | Instruction::Slt { rd, rs1, rs2 } |
+            Instruction::Sltu { rd, rs1, rs2 } | Instruction::Xor { rd, rs1, rs2 } |
+            Instruction::Srl { rd, rs1, rs2 } | Instruction::Sra { rd, rs1, rs2 } |
+            Instruction::Or { rd, rs1, rs2 } | Instruction::And { rd, rs1, rs2 } => {
+                let label = self.union(self.reg(rs1), self.reg(rs2));
+                self.set_reg(rd, label);
+            },
+            // the kernel sets a0 and taints what it reads
+            Instruction::Ecall => self.set_reg(RegName::A0.as_reg(), 0),
+            Instruction::Fence | Instruction::Ebreak => (),
+        }
+    }
+}
+
+/// Format input offsets compactly, with runs as ranges: `0-3, 8, 10-11`
+pub fn format_offsets(offsets: &[u32]) -> String {
+    let mut out = String::new();
+    let mut ii = 0;
+    while ii < offsets.len() {
+        let start = offsets[ii];
+        while ii + 1 < offsets.len() && offsets[ii + 1] == offsets[ii] + 1 {
+            ii += 1;
+        }
+        if !out.is_empty() {
+            out += ", ";
+        }
+        if offsets[ii] == start {
+            out += &format!("{start}");
+        } else {
+            out += &format!("{start}-{}", offsets[ii]);
+        }
+        ii += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    /// Propagate taint through `text` assembled at address 0
+    fn run(taint: &mut Taint, regs: &[u32; 31], text: &str) {
+        let code = assemble(0, text).unwrap();
+        for (ii, instr) in code.chunks(4).enumerate() {
+            let instr = u32::from_le_bytes(instr.try_into().unwrap());
+            taint.step(regs, ii as u32 * 4, decode(instr).unwrap());
+        }
+    }
+
+    #[test]
+    fn propagation() {
+        let mut taint = Taint::new();
+        let mut regs = [0; 31];
+        taint.taint_input(0x1000, 0..4);
+
+        // a1 = 0x1000
+        regs[10] = 0x1000;
+        run(&mut taint, &regs, "
+            lbu a0, 1(a1)
+            lbu a2, 3(a1)
+        ");
+        assert_eq!(taint.offsets(taint.reg(Reg(10))), [1]);
+
+        run(&mut taint, &regs, "
+            add a0, a0, a2
+            beq a0, zero, 0
+        ");
+        assert_eq!(taint.sink_offsets(4, SinkKind::Branch), [1, 3]);
+
+        // a stored label comes back when loading
+        run(&mut taint, &regs, "
+            sw a0, 0(a1)
+            lw a3, 0(a1)
+        ");
+        assert_eq!(taint.offsets(taint.reg(Reg(13))), [1, 3]);
+
+        // and constants clear it
+        run(&mut taint, &regs, "lui a0, 0");
+        assert_eq!(taint.reg(Reg(10)), 0);
+
+        assert_eq!(format_offsets(&[0, 1, 2, 5, 7, 8]), "0-2, 5, 7-8");
+    }
+}
@@ -0,0 +1,97 @@
+//! Guest programs for tests, assembled and linked into an ELF in memory
+
+use elf::Elf;
+
+use crate::assembler::assemble_with_labels;
+use crate::emulator::*;
+use crate::kernel::*;
+
+/// Address the code of test programs is loaded at
+pub const CODE_ADDR: u32 = 0x10000;
+
+/// File offset of the code, a page in like a linker would put it
+const CODE_OFFSET: u32 = 0x1000;
+
+/// Assemble `text` at [`CODE_ADDR`] into a static executable starting at the
+/// label `_start`
+///
+/// Every label that doesn't start with a `.` gets a function symbol reaching
+/// up to the next one.
+pub fn elf(text: &str) -> Elf {
+    let (code, labels) = assemble_with_labels(CODE_ADDR, text).unwrap();
+
+    let mut labels: Vec<(String, u32)> = labels.into_iter()
+        .filter(|(label, _)| !label.starts_with('.'))
+        .collect();
+    labels.sort_by_key(|&(_, addr)| addr);
+
+    let code_end = CODE_ADDR + code.len() as u32;
+    let mut symtab = vec![0; 0x10];
+    let mut strtab = vec![0];
+    for (ii, (label, addr)) in labels.iter().enumerate() {
+        let end = labels.get(ii + 1).map_or(code_end, |&(_, next)| next);
+        for field in [strtab.len() as u32, *addr, end - addr] {
+            symtab.extend(field.to_le_bytes());
+        }
+        symtab.extend([(elf::STB_GLOBAL << 4) | elf::STT_FUNC, 0]);
+        symtab.extend(1u16.to_le_bytes());
+        strtab.extend(label.as_bytes());
+        strtab.push(0);
+    }
+    let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+
+    // code, then the symbols and names, then the section headers
+    let symtab_offset = CODE_OFFSET + code.len() as u32;
+    let strtab_offset = symtab_offset + symtab.len() as u32;
+    let shstrtab_offset = strtab_offset + strtab.len() as u32;
+    let shoff = (shstrtab_offset + shstrtab.len() as u32).next_multiple_of(4);
+
+    let entry = labels.iter().find(|(label, _)| label == "_start").map_or(CODE_ADDR, |&(_, addr)| addr);
+
+    let mut data = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0];
+    data.resize(0x10, 0);
+    data.extend(elf::ET_EXEC.to_le_bytes());
+    data.extend(0xf3u16.to_le_bytes());
+    for field in [1, entry, 0x34, shoff, 0] {
+        data.extend(field.to_le_bytes());
+    }
+    for field in [0x34u16, 0x20, 1, 0x28, 5, 4] {
+        data.extend(field.to_le_bytes());
+    }
+
+    // PT_LOAD of the code, readable and executable
+    for field in [1, CODE_OFFSET, CODE_ADDR, CODE_ADDR, code.len() as u32, code.len() as u32, 5, 0x1000] {
+        data.extend(field.to_le_bytes());
+    }
+
+    data.resize(CODE_OFFSET as usize, 0);
+    data.extend(&code);
+    data.extend(&symtab);
+    data.extend(&strtab);
+    data.extend(shstrtab);
+    data.resize(shoff as usize, 0);
+
+    let sections = [
+        [0; 10],
+        [1, elf::SHT_PROGBITS, 6, CODE_ADDR, CODE_OFFSET, code.len() as u32, 0, 0, 4, 0],
+        [7, elf::SHT_SYMTAB, 0, 0, symtab_offset, symtab.len() as u32, 3, 1, 4, 0x10],
+        [15, elf::SHT_STRTAB, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0],
+        [23, elf::SHT_STRTAB, 0, 0, shstrtab_offset, shstrtab.len() as u32, 0, 0, 1, 0],
+    ];
+    for field in sections.iter().flatten() {
+        data.extend(field.to_le_bytes());
+    }
+
+    Elf::parse(&data).unwrap()
+}
+
+/// Load `elf` into a new emulator and set up the kernel environment for it
+pub fn guest(elf: &Elf) -> (Emulator, Kernel) {
+    let mut emu = Emulator::new(crate::MEMORY_SIZE);
+    emu.load(elf).unwrap();
+
+    let mut kernel = Kernel::new();
+    kernel.setup(&mut emu, &[b"test"]).unwrap();
+
+    (emu, kernel)
+}